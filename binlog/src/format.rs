@@ -0,0 +1,244 @@
+//! Formatting records on the host.
+//!
+//! Supports the subset of `core::fmt` syntax used in log messages: `{}` and `{:?}` placeholders,
+//! and hexadecimal or binary numbers with an optional `#` prefix, zero padding and width (like
+//! `{:#06x}` or `{:08b}`). Placeholders are filled with the arguments in order; positional and
+//! named arguments aren't supported.
+
+use {
+    crate::record::Arg,
+    core::fmt::{self, Write},
+};
+
+/// How a placeholder formats its argument.
+#[derive(Default)]
+struct Spec {
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    ty: char,
+}
+
+impl Spec {
+    /// Parses the part of a placeholder between the braces.
+    fn parse(placeholder: &str) -> Option<Self> {
+        let mut spec = Spec::default();
+        let rest = match placeholder.find(':') {
+            Some(colon) => &placeholder[colon + 1..],
+            None => return Some(spec),
+        };
+
+        let mut chars = rest.chars().peekable();
+        if chars.peek() == Some(&'#') {
+            spec.alternate = true;
+            chars.next();
+        }
+        if chars.peek() == Some(&'0') {
+            spec.zero = true;
+            chars.next();
+        }
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            spec.width = spec.width * 10 + digit as usize;
+            chars.next();
+        }
+        if let Some(ty) = chars.next() {
+            spec.ty = ty;
+        }
+
+        match (spec.ty, chars.next()) {
+            ('\0', None) | ('?', None) | ('x', None) | ('X', None) | ('b', None) => Some(spec),
+            _ => None,
+        }
+    }
+}
+
+/// Formats `fmt` with `args` into `out`.
+///
+/// Missing arguments are shown as `{?}`, and arguments that weren't sent because the record was
+/// too long as `…`.
+pub fn format<'a, W: Write>(
+    out: &mut W,
+    fmt: &str,
+    mut args: impl Iterator<Item = Arg<'a>>,
+) -> fmt::Result {
+    let mut truncated = false;
+    let mut rest = fmt;
+
+    while let Some(i) = rest.find(&['{', '}'][..]) {
+        out.write_str(&rest[..i])?;
+        let brace = &rest[i..];
+
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            out.write_str(&brace[..1])?;
+            rest = &brace[2..];
+            continue;
+        }
+        let end = match brace.find('}') {
+            Some(end) if brace.starts_with('{') => end,
+            // A lone closing brace or an unclosed placeholder
+            _ => {
+                out.write_str(&brace[..1])?;
+                rest = &brace[1..];
+                continue;
+            }
+        };
+        let placeholder = &brace[1..end];
+        rest = &brace[end + 1..];
+
+        let arg = if truncated { None } else { args.next() };
+        match (arg, Spec::parse(placeholder)) {
+            (Some(Arg::Truncated), _) => {
+                truncated = true;
+                out.write_str("…")?;
+            }
+            (None, _) if truncated => out.write_str("…")?,
+            (None, _) => out.write_str("{?}")?,
+            (Some(arg), Some(spec)) => format_arg(out, arg, &spec)?,
+            // Unsupported placeholders get the default format
+            (Some(arg), None) => format_arg(out, arg, &Spec::default())?,
+        }
+    }
+
+    out.write_str(rest)
+}
+
+fn format_arg<W: Write>(out: &mut W, arg: Arg, spec: &Spec) -> fmt::Result {
+    let debug = spec.ty == '?';
+    match arg {
+        Arg::Unsigned(value) => format_int(out, value, false, spec),
+        Arg::Signed(value) if value < 0 && spec.ty != 'x' && spec.ty != 'X' && spec.ty != 'b' => {
+            format_int(out, value.wrapping_neg() as u64, true, spec)
+        }
+        Arg::Signed(value) => format_int(out, value as u64, false, spec),
+        Arg::Bool(value) => write!(out, "{}", value),
+        Arg::Char(value) if debug => write!(out, "{:?}", value),
+        Arg::Char(value) => write!(out, "{}", value),
+        Arg::Str(value) if debug => write!(out, "{:?}", value),
+        Arg::Str(value) => out.write_str(value),
+        Arg::F32(value) if debug => write!(out, "{:?}", value),
+        Arg::F32(value) => write!(out, "{}", value),
+        Arg::Formatted(value) => out.write_str(value),
+        Arg::Truncated => out.write_str("…"),
+    }
+}
+
+fn format_int<W: Write>(out: &mut W, value: u64, negative: bool, spec: &Spec) -> fmt::Result {
+    let (radix, prefix, upper) = match spec.ty {
+        'x' => (16, "0x", false),
+        'X' => (16, "0x", true),
+        'b' => (2, "0b", false),
+        _ => (10, "", false),
+    };
+    let prefix = if spec.alternate { prefix } else { "" };
+    let sign = if negative { "-" } else { "" };
+
+    // 64 binary digits
+    let mut digits = [0u8; 64];
+    let mut len = 0;
+    let mut rest = value;
+    loop {
+        let digit = (rest % radix) as u8;
+        digits[len] = match digit {
+            0..=9 => b'0' + digit,
+            _ if upper => b'A' + digit - 10,
+            _ => b'a' + digit - 10,
+        };
+        len += 1;
+        rest /= radix;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    let padding = spec.width.saturating_sub(sign.len() + prefix.len() + len);
+    if !spec.zero {
+        for _ in 0..padding {
+            out.write_char(' ')?;
+        }
+    }
+    out.write_str(sign)?;
+    out.write_str(prefix)?;
+    if spec.zero {
+        for _ in 0..padding {
+            out.write_char('0')?;
+        }
+    }
+    for &digit in digits[..len].iter().rev() {
+        out.write_char(char::from(digit))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    fn formatted(fmt: &str, args: &[Arg]) -> String {
+        let mut out = String::new();
+        format(&mut out, fmt, args.iter().cloned()).unwrap();
+        out
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(formatted("READY", &[]), "READY");
+        assert_eq!(
+            formatted(
+                "got {} and {:?}, {}",
+                &[Arg::Unsigned(42), Arg::Str("x"), Arg::Str("y")]
+            ),
+            "got 42 and \"x\", y"
+        );
+        assert_eq!(
+            formatted(
+                "{} {} {:?} {}",
+                &[
+                    Arg::Signed(-5),
+                    Arg::Bool(true),
+                    Arg::Char('c'),
+                    Arg::F32(0.5)
+                ]
+            ),
+            "-5 true 'c' 0.5"
+        );
+        assert_eq!(
+            formatted("error: {:?}", &[Arg::Formatted("Erase")]),
+            "error: Erase"
+        );
+        assert_eq!(formatted("{{{}}}", &[Arg::Unsigned(1)]), "{1}");
+    }
+
+    #[test]
+    fn numbers() {
+        let n = Arg::Unsigned(0xAB);
+        assert_eq!(formatted("{:x}", &[n]), "ab");
+        assert_eq!(formatted("{:X}", &[n]), "AB");
+        assert_eq!(formatted("{:#06x}", &[n]), "0x00ab");
+        assert_eq!(formatted("{:08b}", &[Arg::Unsigned(5)]), "00000101");
+        assert_eq!(formatted("{:4}", &[Arg::Unsigned(7)]), "   7");
+        assert_eq!(formatted("{:04}", &[Arg::Signed(-7)]), "-007");
+        assert_eq!(formatted("{}", &[Arg::Unsigned(0)]), "0");
+        assert_eq!(
+            formatted("{}", &[Arg::Unsigned(u64::MAX)]),
+            "18446744073709551615"
+        );
+        assert_eq!(
+            formatted("{}", &[Arg::Signed(i64::MIN)]),
+            "-9223372036854775808"
+        );
+    }
+
+    #[test]
+    fn missing_arguments() {
+        assert_eq!(formatted("{} {}", &[Arg::Unsigned(1)]), "1 {?}");
+        assert_eq!(
+            formatted("{} {} {}", &[Arg::Unsigned(1), Arg::Truncated]),
+            "1 … …"
+        );
+        assert_eq!(formatted("extra", &[Arg::Unsigned(1)]), "extra");
+        assert_eq!(formatted("{:>5} }", &[Arg::Unsigned(1)]), "1 }");
+        assert_eq!(formatted("open {", &[]), "open {");
+    }
+}
@@ -0,0 +1,199 @@
+//! Compact binary log records with deferred formatting.
+//!
+//! Formatting log messages with `core::fmt` costs a lot of flash and CPU time on the nRF52810. With
+//! the `binary` feature, the logging macros of this crate don't format anything on the device.
+//! Every format string becomes the name of a 1 Byte static in the `.binlog` section, which isn't
+//! loaded into flash, and the address of that static is the ID of the format string. A record
+//! only carries this ID, a timestamp and the raw arguments (see [`record`]), and `log-tool`
+//! formats it on the host using the format strings from the firmware's ELF file.
+//!
+//! Without the `binary` feature, the macros forward to the `log` crate, so the firmwares use the
+//! same macros either way. Binary records are passed to the logger set with [`set_logger`].
+//!
+//! The firmwares need a `.binlog` section at address 0 in their linker script:
+//!
+//! ```notrust
+//! SECTIONS
+//! {
+//!   .binlog 0 (INFO) : { *(.binlog .binlog.*); }
+//! }
+//! ```
+//!
+//! Since format strings are symbol names, a format string may only be used once per firmware.
+//! Arguments must implement [`Encode`]; other types can be wrapped in [`Fmt`], which formats them
+//! on the device. Only `{}`, `{:?}` and hexadecimal or binary placeholders (like `{:#06x}`) are
+//! supported (see [`format`]).
+//!
+//! [`record`]: record/index.html
+//! [`format`]: format/index.html
+//! [`set_logger`]: fn.set_logger.html
+//! [`Encode`]: record/trait.Encode.html
+//! [`Fmt`]: record/struct.Fmt.html
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub mod format;
+pub mod record;
+pub mod stream;
+
+pub use record::{Encode, Fmt};
+
+#[doc(hidden)]
+pub use log as __log;
+
+use {
+    core::{
+        fmt,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    log::Level,
+};
+
+/// A binary log record, as passed to the `Logger`.
+pub struct Record<'a> {
+    pub level: Level,
+    /// The module the record was logged from.
+    pub target: &'a str,
+    /// ID of the format string.
+    pub id: u16,
+    pub args: &'a [&'a dyn Encode],
+}
+
+/// Writes binary log records.
+pub trait Logger: Sync {
+    /// Returns whether records of `level` from the module `target` are logged.
+    ///
+    /// Checked before the record is built.
+    fn enabled(&self, level: Level, target: &str) -> bool;
+
+    fn log(&self, record: &Record);
+}
+
+const UNINITIALIZED: usize = 0;
+const INITIALIZING: usize = 1;
+const INITIALIZED: usize = 2;
+
+static STATE: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+static mut LOGGER: Option<&'static dyn Logger> = None;
+
+/// Error returned by `set_logger` when a logger was already set.
+#[derive(Debug)]
+pub struct SetLoggerError;
+
+impl fmt::Display for SetLoggerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a logger was already set")
+    }
+}
+
+/// Sets the logger binary records are passed to. Can only be called once.
+pub fn set_logger(logger: &'static dyn Logger) -> Result<(), SetLoggerError> {
+    match STATE.compare_exchange(
+        UNINITIALIZED,
+        INITIALIZING,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    ) {
+        Ok(_) => {
+            // Nobody reads `LOGGER` until `STATE` says it's initialized
+            unsafe { LOGGER = Some(logger) };
+            STATE.store(INITIALIZED, Ordering::SeqCst);
+            Ok(())
+        }
+        Err(_) => Err(SetLoggerError),
+    }
+}
+
+#[doc(hidden)]
+pub fn __write(level: Level, target: &str, id: u16, args: &[&dyn Encode]) {
+    if STATE.load(Ordering::SeqCst) != INITIALIZED {
+        return;
+    }
+
+    // Never written again once initialized
+    if let Some(logger) = unsafe { LOGGER } {
+        if logger.enabled(level, target) {
+            logger.log(&Record {
+                level,
+                target,
+                id,
+                args,
+            });
+        }
+    }
+}
+
+#[cfg(feature = "binary")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __binlog {
+    ($level:expr, $fmt:tt $(, $arg:expr)* $(,)?) => {{
+        let level = $level;
+        if level <= $crate::__log::max_level() {
+            // The address of this static is the ID of the format string
+            #[export_name = $fmt]
+            #[link_section = ".binlog"]
+            static FORMAT: u8 = 0;
+
+            $crate::__write(
+                level,
+                module_path!(),
+                &FORMAT as *const u8 as u16,
+                &[$(&$arg as &dyn $crate::Encode),*],
+            );
+        }
+    }};
+}
+
+#[cfg(not(feature = "binary"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __binlog {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::__log::log!($level, $($arg)+)
+    };
+}
+
+/// Logs a message at the error level.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::__binlog!($crate::__log::Level::Error, $($arg)+)
+    };
+}
+
+/// Logs a message at the warn level.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::__binlog!($crate::__log::Level::Warn, $($arg)+)
+    };
+}
+
+/// Logs a message at the info level.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::__binlog!($crate::__log::Level::Info, $($arg)+)
+    };
+}
+
+/// Logs a message at the debug level.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::__binlog!($crate::__log::Level::Debug, $($arg)+)
+    };
+}
+
+/// Logs a message at the trace level.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::__binlog!($crate::__log::Level::Trace, $($arg)+)
+    };
+}
@@ -0,0 +1,459 @@
+//! The binary encoding of log records.
+//!
+//! A record starts with `START` and the number of Bytes that follow it, so it can be picked out
+//! of the serial output (see `stream`). Then follow the level, the ID of the format string and a
+//! timestamp in microseconds, and finally the arguments. Every argument starts with a tag Byte
+//! telling its type. Strings are prefixed with their length, everything else has a fixed size.
+//!
+//! ```notrust
+//! START | len | level | id (u16) | timestamp (u32) | tag | arg | tag | arg | ...
+//! ```
+//!
+//! All integers are little endian. Records that don't fit into `MAX_RECORD_LEN` Bytes end with
+//! a `TRUNCATED` tag instead of the remaining arguments.
+
+use {
+    core::fmt::{self, Write},
+    log::Level,
+};
+
+/// First Byte of every record. Never appears in UTF-8 text, so records can be sent along with
+/// text over the same serial port.
+pub const START: u8 = 0xFF;
+
+/// Maximum size of an encoded record.
+pub const MAX_RECORD_LEN: usize = 64;
+
+/// Size of a record without arguments.
+pub const HEADER_LEN: usize = 9;
+
+/// ID of records carrying a message that was formatted on the device, as its only argument.
+pub const TEXT_ID: u16 = 0xFFFF;
+
+const TAG_U8: u8 = 0x01;
+const TAG_U16: u8 = 0x02;
+const TAG_U32: u8 = 0x03;
+const TAG_U64: u8 = 0x04;
+const TAG_I8: u8 = 0x05;
+const TAG_I16: u8 = 0x06;
+const TAG_I32: u8 = 0x07;
+const TAG_I64: u8 = 0x08;
+const TAG_BOOL: u8 = 0x09;
+const TAG_CHAR: u8 = 0x0A;
+const TAG_STR: u8 = 0x0B;
+const TAG_F32: u8 = 0x0C;
+const TAG_FORMATTED: u8 = 0x0D;
+const TAG_TRUNCATED: u8 = 0x0F;
+
+/// Writes the arguments of a record.
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    truncated: bool,
+}
+
+impl<'a> Encoder<'a> {
+    fn put(&mut self, tag: u8, value: &[u8]) {
+        // Keep a Byte for the `TRUNCATED` tag
+        if self.truncated || self.len + 1 + value.len() >= self.buf.len() {
+            self.truncated = true;
+            return;
+        }
+
+        self.buf[self.len] = tag;
+        self.buf[self.len + 1..self.len + 1 + value.len()].copy_from_slice(value);
+        self.len += 1 + value.len();
+    }
+
+    fn put_str(&mut self, tag: u8, s: &str) {
+        let space = self.buf.len().saturating_sub(self.len + 3);
+        if self.truncated || space == 0 {
+            self.truncated = true;
+            return;
+        }
+
+        // Strings are cut off at a character boundary if they don't fit
+        let mut len = s.len().min(space).min(usize::from(u8::MAX));
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.buf[self.len] = tag;
+        self.buf[self.len + 1] = len as u8;
+        self.buf[self.len + 2..self.len + 2 + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += 2 + len;
+        if len < s.len() {
+            self.truncated = true;
+        }
+    }
+}
+
+/// A type that can be sent as the argument of a binary log record.
+pub trait Encode {
+    fn encode(&self, encoder: &mut Encoder);
+}
+
+macro_rules! impl_encode {
+    ($($ty:ty => $tag:ident,)*) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self, encoder: &mut Encoder) {
+                    encoder.put($tag, &self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_encode! {
+    u8 => TAG_U8,
+    u16 => TAG_U16,
+    u32 => TAG_U32,
+    u64 => TAG_U64,
+    i8 => TAG_I8,
+    i16 => TAG_I16,
+    i32 => TAG_I32,
+    i64 => TAG_I64,
+}
+
+impl Encode for usize {
+    fn encode(&self, encoder: &mut Encoder) {
+        (*self as u64).encode(encoder);
+    }
+}
+
+impl Encode for isize {
+    fn encode(&self, encoder: &mut Encoder) {
+        (*self as i64).encode(encoder);
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put(TAG_BOOL, &[*self as u8]);
+    }
+}
+
+impl Encode for char {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put(TAG_CHAR, &(*self as u32).to_le_bytes());
+    }
+}
+
+impl Encode for f32 {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put(TAG_F32, &self.to_bits().to_le_bytes());
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_str(TAG_STR, self);
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, encoder: &mut Encoder) {
+        (**self).encode(encoder);
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Some(value) => value.encode(encoder),
+            None => encoder.put_str(TAG_FORMATTED, "None"),
+        }
+    }
+}
+
+/// Sends an argument that doesn't implement `Encode` by formatting it with `fmt::Debug` on the
+/// device.
+///
+/// Use it for errors and other rare messages; it costs as much as text logging.
+pub struct Fmt<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Fmt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Display for Fmt<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> Encode for Fmt<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let mut writer = BufWriter {
+            buf: &mut buf,
+            len: 0,
+        };
+        write!(writer, "{:?}", self.0).ok();
+        let len = writer.len;
+
+        // `BufWriter` only cuts off at character boundaries
+        encoder.put_str(
+            TAG_FORMATTED,
+            core::str::from_utf8(&buf[..len]).unwrap_or(""),
+        );
+    }
+}
+
+struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> fmt::Write for BufWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Encodes a record into `buf`, returning its size.
+///
+/// Arguments that don't fit into `buf` (or `MAX_RECORD_LEN`) are left out.
+pub fn encode(
+    buf: &mut [u8],
+    level: Level,
+    id: u16,
+    timestamp: u32,
+    args: &[&dyn Encode],
+) -> usize {
+    let end = buf.len().min(MAX_RECORD_LEN);
+    let buf = &mut buf[..end];
+
+    buf[0] = START;
+    buf[2] = level as u8;
+    buf[3..5].copy_from_slice(&id.to_le_bytes());
+    buf[5..9].copy_from_slice(&timestamp.to_le_bytes());
+
+    let mut encoder = Encoder {
+        buf: &mut buf[HEADER_LEN..],
+        len: 0,
+        truncated: false,
+    };
+    for arg in args {
+        arg.encode(&mut encoder);
+    }
+    if encoder.truncated {
+        encoder.buf[encoder.len] = TAG_TRUNCATED;
+        encoder.len += 1;
+    }
+
+    let len = HEADER_LEN + encoder.len;
+    buf[1] = (len - 2) as u8;
+    len
+}
+
+/// A decoded argument.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Arg<'a> {
+    Unsigned(u64),
+    Signed(i64),
+    Bool(bool),
+    Char(char),
+    Str(&'a str),
+    F32(f32),
+    /// An argument that was formatted on the device.
+    Formatted(&'a str),
+    /// The remaining arguments didn't fit into the record.
+    Truncated,
+}
+
+/// A record received from the device.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RawRecord<'a> {
+    pub level: Level,
+    pub id: u16,
+    /// Time the record was logged at, in microseconds since the device was started.
+    pub timestamp: u32,
+    args: &'a [u8],
+}
+
+impl<'a> RawRecord<'a> {
+    /// Decodes a whole record, starting with `START`.
+    pub fn decode(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN || buf[0] != START || usize::from(buf[1]) != buf.len() - 2 {
+            return None;
+        }
+
+        let level = match buf[2] {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            5 => Level::Trace,
+            _ => return None,
+        };
+
+        Some(Self {
+            level,
+            id: u16::from_le_bytes([buf[3], buf[4]]),
+            timestamp: u32::from_le_bytes([buf[5], buf[6], buf[7], buf[8]]),
+            args: &buf[HEADER_LEN..],
+        })
+    }
+
+    /// Returns the arguments of the record.
+    ///
+    /// Malformed arguments end the iterator.
+    pub fn args(&self) -> Args<'a> {
+        Args(self.args)
+    }
+}
+
+/// Iterator over the arguments of a `RawRecord`.
+#[derive(Clone)]
+pub struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            self.0 = &[];
+            return None;
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(value)
+    }
+
+    fn take_u64(&mut self, len: usize) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(self.take(len)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Arg<'a>;
+
+    fn next(&mut self) -> Option<Arg<'a>> {
+        let tag = self.take(1)?[0];
+
+        Some(match tag {
+            TAG_U8 => Arg::Unsigned(self.take_u64(1)?),
+            TAG_U16 => Arg::Unsigned(self.take_u64(2)?),
+            TAG_U32 => Arg::Unsigned(self.take_u64(4)?),
+            TAG_U64 => Arg::Unsigned(self.take_u64(8)?),
+            TAG_I8 => Arg::Signed(i64::from(self.take_u64(1)? as i8)),
+            TAG_I16 => Arg::Signed(i64::from(self.take_u64(2)? as i16)),
+            TAG_I32 => Arg::Signed(i64::from(self.take_u64(4)? as i32)),
+            TAG_I64 => Arg::Signed(self.take_u64(8)? as i64),
+            TAG_BOOL => Arg::Bool(self.take(1)?[0] != 0),
+            TAG_CHAR => Arg::Char(core::char::from_u32(self.take_u64(4)? as u32)?),
+            TAG_STR | TAG_FORMATTED => {
+                let len = usize::from(self.take(1)?[0]);
+                let s = core::str::from_utf8(self.take(len)?).ok()?;
+                if tag == TAG_STR {
+                    Arg::Str(s)
+                } else {
+                    Arg::Formatted(s)
+                }
+            }
+            TAG_F32 => Arg::F32(f32::from_bits(self.take_u64(4)? as u32)),
+            TAG_TRUNCATED => Arg::Truncated,
+            _ => {
+                self.0 = &[];
+                return None;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(args: &[&dyn Encode], expected: &[Arg]) {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = encode(&mut buf, Level::Warn, 0x1234, 0xDEAD_BEEF, args);
+
+        let record = RawRecord::decode(&buf[..len]).unwrap();
+        assert_eq!(record.level, Level::Warn);
+        assert_eq!(record.id, 0x1234);
+        assert_eq!(record.timestamp, 0xDEAD_BEEF);
+        assert_eq!(record.args().collect::<std::vec::Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn arguments() {
+        roundtrip(&[], &[]);
+        roundtrip(
+            &[&7u8, &0xBEEFu16, &-3i8, &-70_000i32, &u64::MAX],
+            &[
+                Arg::Unsigned(7),
+                Arg::Unsigned(0xBEEF),
+                Arg::Signed(-3),
+                Arg::Signed(-70_000),
+                Arg::Unsigned(u64::MAX),
+            ],
+        );
+        roundtrip(
+            &[&true, &'ü', &"hello", &1.5f32, &Some(3u8), &None::<u8>],
+            &[
+                Arg::Bool(true),
+                Arg::Char('ü'),
+                Arg::Str("hello"),
+                Arg::F32(1.5),
+                Arg::Unsigned(3),
+                Arg::Formatted("None"),
+            ],
+        );
+        roundtrip(&[&Fmt(Some("x"))], &[Arg::Formatted("Some(\"x\")")]);
+    }
+
+    #[test]
+    fn truncation() {
+        // The string is cut off after 47 Bytes, without splitting the 'ä'
+        let long = "äaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        roundtrip(
+            &[&1u32, &long, &2u32],
+            &[Arg::Unsigned(1), Arg::Str(&long[..47]), Arg::Truncated],
+        );
+
+        let x = 0u64;
+        roundtrip(
+            &[&x, &x, &x, &x, &x, &x, &x, &x],
+            &[
+                Arg::Unsigned(0),
+                Arg::Unsigned(0),
+                Arg::Unsigned(0),
+                Arg::Unsigned(0),
+                Arg::Unsigned(0),
+                Arg::Unsigned(0),
+                Arg::Truncated,
+            ],
+        );
+    }
+
+    #[test]
+    fn malformed_records() {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = encode(&mut buf, Level::Info, 1, 2, &[&"abc"]);
+        assert!(RawRecord::decode(&buf[..len]).is_some());
+        assert!(RawRecord::decode(&buf[..len - 1]).is_none());
+
+        let mut bad = buf;
+        bad[2] = 0;
+        assert!(RawRecord::decode(&bad[..len]).is_none());
+
+        // A string running past the end of the record
+        let mut bad = buf;
+        bad[HEADER_LEN + 1] = 10;
+        let record = RawRecord::decode(&bad[..len]).unwrap();
+        assert_eq!(record.args().count(), 0);
+    }
+}
@@ -0,0 +1,112 @@
+//! Picking records out of the serial output.
+//!
+//! The serial port carries binary records mixed with text (shell output and the "messages dropped"
+//! marker of the firmware's logger). Text never contains `START`, so everything outside of a
+//! record is passed on as text.
+
+use crate::record::{MAX_RECORD_LEN, START};
+
+/// A piece of the serial output.
+#[derive(Debug, PartialEq)]
+pub enum Item<'a> {
+    Text(u8),
+    /// A whole record, starting with `START` (see `RawRecord::decode`).
+    Record(&'a [u8]),
+}
+
+/// Splits the serial output into text and records.
+pub struct Decoder {
+    buf: [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_RECORD_LEN],
+            len: 0,
+        }
+    }
+
+    /// Feeds a received Byte into the decoder, returning what it completed.
+    ///
+    /// Returns `None` while a record is being received, and drops records with an invalid length.
+    pub fn feed(&mut self, byte: u8) -> Option<Item<'_>> {
+        match self.len {
+            0 if byte != START => return Some(Item::Text(byte)),
+            1 if usize::from(byte) + 2 > MAX_RECORD_LEN => {
+                self.len = 0;
+                return None;
+            }
+            _ => (),
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len >= 2 && self.len == usize::from(self.buf[1]) + 2 {
+            let len = self.len;
+            self.len = 0;
+            Some(Item::Record(&self.buf[..len]))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::record::{encode, Arg, RawRecord},
+        log::Level,
+        std::vec::Vec,
+    };
+
+    #[test]
+    fn text_and_records() {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = encode(&mut buf, Level::Debug, 3, 100, &[&0xFFu8, &"x"]);
+
+        let mut input = b"> status\r\n".to_vec();
+        input.extend_from_slice(&buf[..len]);
+        input.extend_from_slice(b"2 messages dropped\n");
+        input.extend_from_slice(&buf[..len]);
+
+        let mut decoder = Decoder::new();
+        let mut text = Vec::new();
+        let mut records = 0;
+        for &byte in &input {
+            match decoder.feed(byte) {
+                Some(Item::Text(byte)) => text.push(byte),
+                Some(Item::Record(record)) => {
+                    let record = RawRecord::decode(record).unwrap();
+                    assert_eq!(record.id, 3);
+                    assert_eq!(
+                        record.args().collect::<Vec<_>>(),
+                        [Arg::Unsigned(0xFF), Arg::Str("x")]
+                    );
+                    records += 1;
+                }
+                None => (),
+            }
+        }
+
+        assert_eq!(text, b"> status\r\n2 messages dropped\n".to_vec());
+        assert_eq!(records, 2);
+    }
+
+    #[test]
+    fn invalid_length() {
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.feed(START), None);
+        assert_eq!(decoder.feed(0xF0), None);
+        assert_eq!(decoder.feed(b'a'), Some(Item::Text(b'a')));
+    }
+}
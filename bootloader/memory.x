@@ -0,0 +1,7 @@
+/* nRF52810: the bootloader occupies the first 32 KB of flash (see `bluefly_dfu::layout`) */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00000000, LENGTH = 32K
+  /* The last 256 Bytes hold the firmware's crash reports across resets */
+  RAM : ORIGIN = 0x20000000, LENGTH = 24K - 256
+}
@@ -0,0 +1,127 @@
+//! Bootloader for the controller and the receiver.
+//!
+//! Installs pending updates or rolls back failed ones (see `bluefly_dfu::swap`), then boots the
+//! firmware in slot 0 if it is signed with the built-in key. Receiving updates is up to the
+//! firmware itself.
+//!
+//! The firmwares start the watchdog, which keeps running across soft resets. While it runs, the
+//! bootloader reloads it during the long flash operations of an update.
+//!
+//! When developing with a debugger, firmware is flashed without an image header. The
+//! `skip-verification` feature makes the bootloader boot such firmware; it must never be enabled
+//! in release builds.
+
+#![no_std]
+#![no_main]
+
+// We need to import this crate explicitly so we have a panic handler
+extern crate panic_semihosting;
+
+use {
+    bluefly_dfu::{
+        flash::{Flash, FlashError, Nvmc},
+        image::{self, HEADER_SIZE},
+        layout::{self, FLASH_SIZE},
+        sign::PUBLIC_KEY,
+        swap,
+    },
+    core::{mem, ptr},
+    cortex_m::{asm, peripheral::SCB, register::msp},
+    cortex_m_rt::entry,
+};
+
+const RAM_START: u32 = 0x2000_0000;
+const RAM_END: u32 = 0x2000_6000;
+
+/// Base address of the WDT peripheral.
+const WDT: usize = 0x4001_0000;
+const WDT_RUNSTATUS: usize = WDT + 0x400;
+const WDT_RREN: usize = WDT + 0x508;
+const WDT_RR: usize = WDT + 0x600;
+const WDT_RELOAD: u32 = 0x6E52_4635;
+
+/// Flash that reloads the watchdog before every write and erase.
+///
+/// Swapping slots takes far longer than the watchdog timeout, but every step only takes a single
+/// erase or write.
+struct FedFlash<F>(F);
+
+impl<F: Flash> Flash for FedFlash<F> {
+    fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.0.read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        feed_watchdog();
+        self.0.write(addr, data)
+    }
+
+    fn erase_page(&mut self, addr: u32) -> Result<(), FlashError> {
+        feed_watchdog();
+        self.0.erase_page(addr)
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let layout = layout::NRF52810;
+
+    // Safe, since we're the only code running, and we never write to our own flash region
+    let mut flash = FedFlash(unsafe { Nvmc::new(FLASH_SIZE) });
+
+    // There's no way to report errors here. If the swap fails, it will be retried on the next
+    // reset; until then, boot whatever is in slot 0.
+    swap::boot(&mut flash, &layout, &PUBLIC_KEY).ok();
+    feed_watchdog();
+
+    if cfg!(feature = "skip-verification")
+        || image::verify(&flash, layout.slot0, layout.slot_size, &PUBLIC_KEY).is_ok()
+    {
+        unsafe { boot(layout.slot0 + HEADER_SIZE) }
+    } else {
+        halt()
+    }
+}
+
+/// Boots the firmware whose vector table is located at `addr`.
+///
+/// If there's no firmware at `addr`, this halts.
+unsafe fn boot(addr: u32) -> ! {
+    let sp = *(addr as *const u32);
+    let reset = *((addr + 4) as *const u32);
+
+    // An erased slot reads as all ones
+    if sp < RAM_START || sp > RAM_END || reset == 0xFFFF_FFFF {
+        halt()
+    }
+
+    (*SCB::ptr()).vtor.write(addr);
+    msp::write(sp);
+
+    let reset: extern "C" fn() -> ! = mem::transmute(reset as usize);
+    reset()
+}
+
+/// Reloads every enabled reload request register of the watchdog, if it's running.
+fn feed_watchdog() {
+    // Safe, since reloading the watchdog has no other effect
+    unsafe {
+        if ptr::read_volatile(WDT_RUNSTATUS as *const u32) == 0 {
+            return;
+        }
+
+        let enabled = ptr::read_volatile(WDT_RREN as *const u32);
+        for i in 0..8 {
+            if enabled & (1 << i) != 0 {
+                ptr::write_volatile((WDT_RR + 4 * i) as *mut u32, WDT_RELOAD);
+            }
+        }
+    }
+}
+
+/// Sleeps forever, since there's nothing that could be booted.
+fn halt() -> ! {
+    loop {
+        asm::wfi();
+    }
+}
@@ -0,0 +1,408 @@
+//! Runtime configuration.
+//!
+//! Values live in RAM, each entry in an atomic, so interrupt handlers and the shell can access them
+//! without locking. Most are reset to their defaults on every boot. Entries describing how the
+//! vehicle is put together, like which receiver drives which motor, are created with
+//! `Entry::persistent` instead: `save` stores their changes in the settings pages of the flash (see
+//! `bluefly_dfu::layout::SETTINGS`), and `load` restores them on boot. The entries themselves are
+//! defined by each firmware, which passes them to the shell.
+//!
+//! The settings pages hold a log of records, each the hash of a key followed by its value, in which
+//! the last record of a key wins. Only one of the two pages is in use. Once it's full, the current
+//! values are copied to the other one, whose header is written last, so a reset in between leaves
+//! the old page in use.
+
+use {
+    crate::logger::{Filter, FilterError},
+    bluefly_dfu::{
+        flash::{Flash, FlashError},
+        layout::{PAGE_SIZE, SETTINGS},
+    },
+    core::{
+        fmt,
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+};
+
+/// A named configuration value with an allowed range.
+pub struct Entry {
+    pub key: &'static str,
+    value: AtomicU32,
+    min: u32,
+    max: u32,
+    /// Whether the value is stored in flash.
+    persistent: bool,
+    /// Whether the value was set since it was loaded or saved.
+    dirty: AtomicBool,
+}
+
+impl Entry {
+    pub const fn new(key: &'static str, default: u32, min: u32, max: u32) -> Self {
+        Self {
+            key,
+            value: AtomicU32::new(default),
+            min,
+            max,
+            persistent: false,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates an entry whose value is stored in flash by `save`.
+    pub const fn persistent(key: &'static str, default: u32, min: u32, max: u32) -> Self {
+        Self {
+            persistent: true,
+            ..Self::new(key, default, min, max)
+        }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Changes the value, if it is within the allowed range.
+    pub fn set(&self, value: u32) -> Result<(), OutOfRange> {
+        if value < self.min || value > self.max {
+            return Err(OutOfRange {
+                min: self.min,
+                max: self.max,
+            });
+        }
+
+        self.value.store(value, Ordering::Relaxed);
+        if self.persistent {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when a configuration value is outside of the allowed range.
+pub struct OutOfRange {
+    min: u32,
+    max: u32,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "value must be between {} and {}", self.min, self.max)
+    }
+}
+
+/// Log filter used on boot (see `logger::Filter`).
+///
+/// Can be overridden at build time with the `BLUEFLY_LOG` environment variable.
+const LOG_FILTER: &str = "info";
+
+/// Returns the log filter the firmware was built with.
+pub fn log_filter() -> Result<Filter, FilterError> {
+    Filter::parse(option_env!("BLUEFLY_LOG").unwrap_or(LOG_FILTER))
+}
+
+/// Looks up a configuration entry in `entries` by its key.
+pub fn find(entries: &[&'static Entry], key: &str) -> Option<&'static Entry> {
+    entries.iter().cloned().find(|entry| entry.key == key)
+}
+
+/// Marks a settings page whose header was written, after all of its initial records.
+const PAGE_MAGIC: u32 = 0xB1EF_5E77;
+
+/// Length of a settings page's header: the magic and a generation counting up with each copy.
+const HEADER_LEN: u32 = 8;
+
+/// Length of a record: the hash of the key, then the value.
+const RECORD_LEN: u32 = 8;
+
+const ERASED: u32 = u32::MAX;
+
+/// Start addresses of the settings pages.
+const PAGES: [u32; 2] = [SETTINGS, SETTINGS + PAGE_SIZE];
+
+/// Restores the values of the persistent entries in `entries` from flash.
+///
+/// Stored values outside of an entry's range, eg. after an update changed it, are ignored.
+pub fn load<F: Flash>(flash: &F, entries: &[&'static Entry]) -> Result<(), FlashError> {
+    let page = match active_page(flash)? {
+        Some((page, _)) => page,
+        None => return Ok(()),
+    };
+
+    scan(flash, page, |hash, value| {
+        for entry in entries.iter().filter(|entry| entry.persistent) {
+            if key_hash(entry.key) == hash && value >= entry.min && value <= entry.max {
+                entry.value.store(value, Ordering::Relaxed);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Stores the values of the persistent entries in `entries` that were set since they were loaded
+/// or last saved.
+///
+/// Usually this only writes a few words. When the settings page is full, or before the first
+/// value is stored, it erases a page, which halts the CPU for about 85 ms.
+pub fn save<F: Flash>(flash: &mut F, entries: &[&'static Entry]) -> Result<(), FlashError> {
+    let dirty = entries
+        .iter()
+        .filter(|entry| entry.dirty.load(Ordering::Relaxed))
+        .count() as u32;
+    if dirty == 0 {
+        return Ok(());
+    }
+
+    let (page, generation) = match active_page(flash)? {
+        Some(active) => active,
+        None => return copy(flash, None, PAGES[0], 0, entries),
+    };
+    let free = scan(flash, page, |_, _| {})?;
+    if free + dirty * RECORD_LEN > page + PAGE_SIZE {
+        let other = if page == PAGES[0] { PAGES[1] } else { PAGES[0] };
+        return copy(
+            flash,
+            Some(page),
+            other,
+            generation.wrapping_add(1),
+            entries,
+        );
+    }
+
+    let mut addr = free;
+    for entry in entries.iter() {
+        if entry.dirty.load(Ordering::Relaxed) {
+            write_record(flash, addr, entry)?;
+            entry.dirty.store(false, Ordering::Relaxed);
+            addr += RECORD_LEN;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the values of all persistent entries to the settings page at `to`, which then replaces
+/// the one at `from`.
+fn copy<F: Flash>(
+    flash: &mut F,
+    from: Option<u32>,
+    to: u32,
+    generation: u32,
+    entries: &[&'static Entry],
+) -> Result<(), FlashError> {
+    flash.erase_page(to)?;
+    let mut addr = to + HEADER_LEN;
+    for entry in entries.iter().filter(|entry| entry.persistent) {
+        write_record(flash, addr, entry)?;
+        addr += RECORD_LEN;
+    }
+
+    flash.write_word(to + 4, generation)?;
+    flash.write_word(to, PAGE_MAGIC)?;
+    for entry in entries.iter() {
+        entry.dirty.store(false, Ordering::Relaxed);
+    }
+    match from {
+        Some(from) => flash.erase_page(from),
+        None => Ok(()),
+    }
+}
+
+/// Appends a record of the value of `entry` at `addr`.
+fn write_record<F: Flash>(flash: &mut F, addr: u32, entry: &Entry) -> Result<(), FlashError> {
+    // The key goes last: a record whose key is still erased is skipped
+    flash.write_word(addr + 4, entry.get())?;
+    flash.write_word(addr, key_hash(entry.key))
+}
+
+/// Returns the start address and generation of the settings page in use, if any.
+fn active_page<F: Flash>(flash: &F) -> Result<Option<(u32, u32)>, FlashError> {
+    let mut active = None;
+    for &page in PAGES.iter() {
+        if flash.read_word(page)? != PAGE_MAGIC {
+            continue;
+        }
+
+        let generation = flash.read_word(page + 4)?;
+        active = match active {
+            // The generation wraps around, so the newer page is less than half the range ahead
+            Some((_, newest)) if generation.wrapping_sub(newest) > u32::MAX / 2 => active,
+            _ => Some((page, generation)),
+        };
+    }
+    Ok(active)
+}
+
+/// Calls `f` with the key hash and value of each record in the settings page at `page`, oldest
+/// first, and returns the address of the free space behind them.
+fn scan<F: Flash>(flash: &F, page: u32, mut f: impl FnMut(u32, u32)) -> Result<u32, FlashError> {
+    let mut addr = page + HEADER_LEN;
+    while addr < page + PAGE_SIZE {
+        let hash = flash.read_word(addr)?;
+        let value = flash.read_word(addr + 4)?;
+        if hash == ERASED && value == ERASED {
+            break;
+        }
+        if hash != ERASED {
+            f(hash, value);
+        }
+        addr += RECORD_LEN;
+    }
+    Ok(addr)
+}
+
+/// Returns the 32-bit FNV-1a hash of `key`, which never looks like erased flash.
+fn key_hash(key: &str) -> u32 {
+    let hash = key.bytes().fold(0x811C_9DC5, |hash: u32, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    if hash == ERASED {
+        0
+    } else {
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        bluefly_dfu::layout::FLASH_SIZE,
+        std::{vec, vec::Vec},
+    };
+
+    static RATE: Entry = Entry::new("test.rate", 50, 1, 200);
+    static ENTRIES: [&Entry; 1] = [&RATE];
+
+    /// Flash in RAM, counting page erases.
+    struct RamFlash {
+        data: Vec<u8>,
+        erases: u32,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                data: vec![0xFF; FLASH_SIZE as usize],
+                erases: 0,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+            let addr = addr as usize;
+            buf.copy_from_slice(&self.data[addr..addr + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+            let addr = addr as usize;
+            for (stored, byte) in self.data[addr..addr + data.len()].iter_mut().zip(data) {
+                // Writes only clear bits
+                *stored &= byte;
+            }
+            Ok(())
+        }
+
+        fn erase_page(&mut self, addr: u32) -> Result<(), FlashError> {
+            let addr = addr as usize;
+            for byte in &mut self.data[addr..addr + PAGE_SIZE as usize] {
+                *byte = 0xFF;
+            }
+            self.erases += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn entries() {
+        assert!(find(&ENTRIES, "test.rate").is_some());
+        assert!(find(&ENTRIES, "test").is_none());
+
+        assert!(RATE.set(200).is_ok());
+        assert_eq!(RATE.get(), 200);
+        assert!(RATE.set(0).is_err());
+        assert!(RATE.set(201).is_err());
+        assert_eq!(RATE.get(), 200);
+    }
+
+    #[test]
+    fn persistence() {
+        static ID: Entry = Entry::persistent("test.id", 0, 0, 3);
+        static MASK: Entry = Entry::persistent("test.mask", 1, 1, 15);
+        static LEVEL: Entry = Entry::new("test.level", 9, 0, 9);
+        static STORED: [&Entry; 3] = [&ID, &MASK, &LEVEL];
+        let reboot = |flash: &RamFlash| {
+            ID.value.store(0, Ordering::Relaxed);
+            MASK.value.store(1, Ordering::Relaxed);
+            LEVEL.value.store(9, Ordering::Relaxed);
+            load(flash, &STORED).unwrap();
+        };
+        let mut flash = RamFlash::new();
+
+        // Nothing stored yet
+        reboot(&flash);
+        assert_eq!(ID.get(), 0);
+        save(&mut flash, &STORED).unwrap();
+        assert_eq!(flash.erases, 0);
+
+        assert!(ID.set(1).is_ok());
+        assert!(LEVEL.set(3).is_ok());
+        save(&mut flash, &STORED).unwrap();
+        reboot(&flash);
+        assert_eq!((ID.get(), MASK.get(), LEVEL.get()), (1, 1, 9));
+
+        // The last value wins, also across copies to the other page
+        for i in 0..1000 {
+            assert!(MASK.set(i % 15 + 1).is_ok());
+            save(&mut flash, &STORED).unwrap();
+        }
+        assert!(flash.erases > 2);
+        reboot(&flash);
+        assert_eq!((ID.get(), MASK.get()), (1, 999 % 15 + 1));
+    }
+
+    #[test]
+    fn interrupted_writes() {
+        static ID: Entry = Entry::persistent("test.id", 0, 0, 3);
+        static STORED: [&Entry; 1] = [&ID];
+        let reboot = |flash: &RamFlash| {
+            ID.value.store(0, Ordering::Relaxed);
+            load(flash, &STORED).unwrap();
+            ID.get()
+        };
+        let mut flash = RamFlash::new();
+        assert!(ID.set(2).is_ok());
+        save(&mut flash, &STORED).unwrap();
+
+        // Reset after writing the value of a record, but before its key
+        let free = scan(&flash, PAGES[0], |_, _| {}).unwrap();
+        flash.write_word(free + 4, 3).unwrap();
+        assert_eq!(reboot(&flash), 2);
+        // The next record goes behind it
+        assert!(ID.set(1).is_ok());
+        save(&mut flash, &STORED).unwrap();
+        assert_eq!(reboot(&flash), 1);
+
+        // Reset while copying to the other page, before its header was written
+        flash
+            .write_word(PAGES[1] + HEADER_LEN, key_hash(ID.key))
+            .unwrap();
+        flash.write_word(PAGES[1] + HEADER_LEN + 4, 3).unwrap();
+        assert_eq!(reboot(&flash), 1);
+
+        // Reset after the copy, before the old page was erased
+        flash.write_word(PAGES[1] + 4, 1).unwrap();
+        flash.write_word(PAGES[1], PAGE_MAGIC).unwrap();
+        assert_eq!(reboot(&flash), 3);
+    }
+
+    #[test]
+    fn out_of_range() {
+        static ID: Entry = Entry::persistent("test.id", 0, 0, 3);
+        static WIDER: Entry = Entry::persistent("test.id", 0, 0, 7);
+        let mut flash = RamFlash::new();
+        assert!(WIDER.set(5).is_ok());
+        save(&mut flash, &[&WIDER]).unwrap();
+        load(&flash, &[&ID]).unwrap();
+        assert_eq!(ID.get(), 0);
+    }
+}
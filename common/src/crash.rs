@@ -0,0 +1,181 @@
+//! Crash reports that survive a reset.
+//!
+//! Panics and HardFaults are recorded in a region of RAM that isn't initialized at startup (see
+//! `memory.x`), and then reset the device, which puts all outputs back into their reset state. The
+//! next boot takes the record and reports it in the log, and on the receiver also in the log
+//! service of the GATT server (the controller has none).
+
+use core::{fmt, ptr, str};
+
+/// Marks a valid record; RAM holds random data after power-on.
+const MAGIC: u32 = 0xB1EF_C4A5;
+
+const KIND_PANIC: u32 = 1;
+const KIND_HARD_FAULT: u32 = 2;
+
+const FILE_LEN: usize = 48;
+const MESSAGE_LEN: usize = 96;
+
+/// A crash before the last reset.
+#[derive(Clone)]
+#[repr(C)]
+pub struct Crash {
+    magic: u32,
+    kind: u32,
+    line: u32,
+    file_len: u32,
+    message_len: u32,
+    file: [u8; FILE_LEN],
+    message: [u8; MESSAGE_LEN],
+    /// r0-r3, r12, lr, pc and xpsr, as stacked on entry to the HardFault handler.
+    registers: [u32; 8],
+}
+
+#[link_section = ".retained"]
+static mut CRASH: Crash = Crash {
+    magic: 0,
+    kind: 0,
+    line: 0,
+    file_len: 0,
+    message_len: 0,
+    file: [0; FILE_LEN],
+    message: [0; MESSAGE_LEN],
+    registers: [0; 8],
+};
+
+impl Crash {
+    fn file(&self) -> &str {
+        str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or("?")
+    }
+
+    fn message(&self) -> &str {
+        str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("?")
+    }
+}
+
+impl fmt::Display for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.kind == KIND_HARD_FAULT {
+            let r = &self.registers;
+            return write!(
+                f,
+                "HardFault at pc {:#010x}, lr {:#010x}, xpsr {:#010x}, \
+                 r0-r3 {:#x} {:#x} {:#x} {:#x}, r12 {:#x}",
+                r[6], r[5], r[7], r[0], r[1], r[2], r[3], r[4]
+            );
+        }
+
+        if self.file_len == 0 {
+            write!(f, "panic: {}", self.message())
+        } else {
+            write!(
+                f,
+                "panic at {}:{}: {}",
+                self.file(),
+                self.line,
+                self.message()
+            )
+        }
+    }
+}
+
+impl fmt::Debug for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Takes the record of a crash before the last reset, if there was one.
+pub fn take() -> Option<Crash> {
+    // Safe, since it's only called from `init`, with interrupts off
+    unsafe {
+        let magic = ptr::read_volatile(ptr::addr_of!(CRASH.magic));
+        let valid = magic == MAGIC
+            && (CRASH.kind == KIND_PANIC || CRASH.kind == KIND_HARD_FAULT)
+            && CRASH.file_len as usize <= FILE_LEN
+            && CRASH.message_len as usize <= MESSAGE_LEN;
+        ptr::write_volatile(ptr::addr_of_mut!(CRASH.magic), 0);
+
+        if valid {
+            Some((*ptr::addr_of!(CRASH)).clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// The handlers recording crashes. On the host, they'd get in the way of the tests and the
+/// simulation.
+#[cfg(target_os = "none")]
+mod handlers {
+    use {
+        super::*,
+        crate::{logger::SliceWriter, shell},
+        core::{
+            fmt::Write,
+            panic::PanicInfo,
+            sync::atomic::{compiler_fence, Ordering},
+        },
+        cortex_m::interrupt,
+        cortex_m_rt::{exception, ExceptionFrame},
+    };
+
+    /// Set while a panic is being recorded, so that a panic while formatting its message doesn't
+    /// recurse.
+    static mut PANICKING: bool = false;
+
+    /// Writes `crash` to the retained RAM and resets.
+    fn record_and_reset(crash: &mut Crash, kind: u32) -> ! {
+        crash.kind = kind;
+        compiler_fence(Ordering::SeqCst);
+        // Written last, so a reset while recording doesn't leave a half-written record behind
+        unsafe { ptr::write_volatile(&mut crash.magic, MAGIC) };
+        shell::reboot()
+    }
+
+    #[panic_handler]
+    fn panic(info: &PanicInfo) -> ! {
+        interrupt::disable();
+
+        // Safe, since interrupts are off and the handlers never return
+        unsafe {
+            if PANICKING {
+                shell::reboot();
+            }
+            PANICKING = true;
+
+            let crash = &mut *ptr::addr_of_mut!(CRASH);
+            crash.file_len = 0;
+            crash.line = 0;
+            if let Some(location) = info.location() {
+                let mut writer = SliceWriter::new(&mut crash.file);
+                writer.write_str(location.file()).ok();
+                crash.file_len = writer.len() as u32;
+                crash.line = location.line();
+            }
+
+            let mut writer = SliceWriter::new(&mut crash.message);
+            write!(writer, "{}", info.message()).ok();
+            crash.message_len = writer.len() as u32;
+            crash.registers = [0; 8];
+
+            record_and_reset(crash, KIND_PANIC)
+        }
+    }
+
+    #[exception]
+    fn HardFault(frame: &ExceptionFrame) -> ! {
+        // Safe, since the handler never returns and can't be preempted
+        unsafe {
+            let crash = &mut *ptr::addr_of_mut!(CRASH);
+            crash.file_len = 0;
+            crash.line = 0;
+            crash.message_len = 0;
+            crash.registers = [
+                frame.r0, frame.r1, frame.r2, frame.r3, frame.r12, frame.lr, frame.pc, frame.xpsr,
+            ];
+
+            record_and_reset(crash, KIND_HARD_FAULT)
+        }
+    }
+}
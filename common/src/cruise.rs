@@ -0,0 +1,444 @@
+//! Cruise control: holding the throttle without keeping the thumb on it.
+//!
+//! The controller has no button, so cruise is engaged with a gesture on the throttle: while riding,
+//! flick the thumb back to rest and forward again twice within `GESTURE_MS`. The controller then
+//! keeps sending the throttle reading it was engaged at (see `Cruise`), and the thumb can rest. The
+//! ESC doesn't report its speed to the receiver, so cruise holds a throttle, not a speed.
+//!
+//! Cruise disengages on any brake input, on any throttle input once the thumb rested, and on
+//! pushing the throttle beyond the held reading before that. It also disengages when the link
+//! degrades: throttle beacons sent while cruising are flagged (see `protocol::set_cruise`), and the
+//! receiver brings the output back to neutral as soon as it misses one of them, until the
+//! controller stops cruising (see `Guard`). It tells the controller with `cancel_frame`, which
+//! disengages. In ESB mode, the controller also disengages when a packet isn't acknowledged.
+
+use {
+    crate::{mode::ANSWER_DELAY_MICROS, power::THROTTLE_DEADBAND},
+    rubble::time::Instant,
+};
+
+/// Longest time the thumb rests in a flick of the gesture, in milliseconds.
+pub const FLICK_MS: u32 = 400;
+
+/// Longest time both flicks of the gesture take, in milliseconds.
+pub const GESTURE_MS: u32 = 1500;
+
+/// Time the display shows that cruise disengaged, in seconds.
+pub const NOTICE_SECS: u32 = 2;
+
+/// First Byte of the receiver's notice that it disengaged cruise, which tells it apart from relay
+/// frames, mode answers and map answers.
+pub const CANCEL_TAG: u8 = 0xB4;
+
+/// Encodes the receiver's notice that it disengaged cruise.
+pub fn cancel_frame() -> [u8; 1] {
+    [CANCEL_TAG]
+}
+
+/// Whether `frame` is the receiver's notice that it disengaged cruise.
+pub fn is_cancel(frame: &[u8]) -> bool {
+    frame == [CANCEL_TAG]
+}
+
+fn millis_since(now: Instant, earlier: Instant) -> u32 {
+    now.raw_micros().wrapping_sub(earlier.raw_micros()) / 1000
+}
+
+/// Where the thumb is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Position {
+    Brake,
+    Rest,
+    Forward,
+}
+
+impl Position {
+    fn of(adc: u16, rest: u16) -> Self {
+        if adc > rest.saturating_add(THROTTLE_DEADBAND) {
+            Position::Forward
+        } else if adc < rest.saturating_sub(THROTTLE_DEADBAND) {
+            Position::Brake
+        } else {
+            Position::Rest
+        }
+    }
+}
+
+/// Why cruise disengaged.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Reason {
+    Brake,
+    Throttle,
+    /// The link degraded.
+    Link,
+    /// Cruise was turned off in the configuration.
+    Disabled,
+}
+
+impl Reason {
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::Brake => "brake",
+            Reason::Throttle => "throttle",
+            Reason::Link => "link",
+            Reason::Disabled => "disabled",
+        }
+    }
+}
+
+/// A change of the cruise state.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Cruise engaged, holding the 14-bit throttle reading.
+    Engaged(u16),
+    Disengaged(Reason),
+}
+
+/// What the display shows about cruise.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    Off,
+    Engaged,
+    /// Cruise disengaged within the last `NOTICE_SECS`.
+    Disengaged,
+}
+
+enum State {
+    Off {
+        /// When the thumb came back to rest after riding.
+        rest_since: Option<Instant>,
+        /// When the first flick of the gesture started.
+        first_flick: Option<Instant>,
+    },
+    Engaged {
+        target: u16,
+        /// Whether the thumb rested since cruise engaged.
+        rested: bool,
+    },
+}
+
+/// The controller's cruise state, following the throttle readings.
+pub struct Cruise {
+    state: State,
+    last: Position,
+    disengaged_at: Option<Instant>,
+}
+
+impl Default for Cruise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cruise {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Off {
+                rest_since: None,
+                first_flick: None,
+            },
+            last: Position::Rest,
+            disengaged_at: None,
+        }
+    }
+
+    /// Handles the 14-bit throttle reading `adc` at `now`, given the reading `rest` with the thumb
+    /// released, and returns what changed, if anything.
+    ///
+    /// The gesture is ignored unless cruise is `enabled`, and cruise disengages once it isn't.
+    pub fn update(&mut self, adc: u16, rest: u16, enabled: bool, now: Instant) -> Option<Event> {
+        let position = Position::of(adc, rest);
+        let last = self.last;
+        self.last = position;
+
+        match &mut self.state {
+            State::Off { .. } if !enabled => None,
+            State::Off {
+                rest_since,
+                first_flick,
+            } => {
+                match position {
+                    Position::Rest if last == Position::Forward => *rest_since = Some(now),
+                    Position::Rest => {}
+                    Position::Brake => {
+                        *rest_since = None;
+                        *first_flick = None;
+                    }
+                    Position::Forward if last == Position::Forward => {}
+                    Position::Forward => {
+                        let flick = rest_since
+                            .take()
+                            .filter(|&since| millis_since(now, since) <= FLICK_MS);
+                        match (flick, *first_flick) {
+                            (Some(_), Some(first)) if millis_since(now, first) <= GESTURE_MS => {
+                                self.state = State::Engaged {
+                                    target: adc,
+                                    rested: false,
+                                };
+                                return Some(Event::Engaged(adc));
+                            }
+                            (flick, _) => *first_flick = flick,
+                        }
+                    }
+                }
+                None
+            }
+            State::Engaged { .. } if !enabled => self.disengage(Reason::Disabled, now),
+            State::Engaged { target, rested } => match position {
+                Position::Brake => self.disengage(Reason::Brake, now),
+                Position::Rest => {
+                    *rested = true;
+                    None
+                }
+                Position::Forward if *rested || adc > target.saturating_add(THROTTLE_DEADBAND) => {
+                    self.disengage(Reason::Throttle, now)
+                }
+                // The thumb is on its way back to rest
+                Position::Forward => None,
+            },
+        }
+    }
+
+    /// Disengages cruise at `now` for `reason`, and returns the event if it was engaged.
+    pub fn disengage(&mut self, reason: Reason, now: Instant) -> Option<Event> {
+        match self.state {
+            State::Engaged { .. } => {
+                self.state = State::Off {
+                    rest_since: None,
+                    first_flick: None,
+                };
+                self.disengaged_at = Some(now);
+                Some(Event::Disengaged(reason))
+            }
+            State::Off { .. } => None,
+        }
+    }
+
+    /// Whether cruise is engaged.
+    pub fn is_engaged(&self) -> bool {
+        match self.state {
+            State::Engaged { .. } => true,
+            State::Off { .. } => false,
+        }
+    }
+
+    /// Returns the throttle reading to send for the reading `adc`: the held one while cruise is
+    /// engaged, `adc` otherwise.
+    pub fn throttle(&self, adc: u16) -> u16 {
+        match self.state {
+            State::Engaged { target, .. } => target,
+            State::Off { .. } => adc,
+        }
+    }
+
+    /// Returns what the display shows about cruise at `now`.
+    pub fn status(&self, now: Instant) -> Status {
+        match self.disengaged_at {
+            _ if self.is_engaged() => Status::Engaged,
+            Some(at) if millis_since(now, at) < NOTICE_SECS * 1000 => Status::Disengaged,
+            _ => Status::Off,
+        }
+    }
+}
+
+/// The receiver's side of cruise, which brings the output back to neutral when the link degrades
+/// while the controller cruises.
+pub struct Guard {
+    last_seq: Option<u8>,
+    /// Whether cruise frames are dropped until the controller stops cruising.
+    cancelled: bool,
+    /// When the last cruise frame was dropped, until the controller is told.
+    pending: Option<Instant>,
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Guard {
+    pub const fn new() -> Self {
+        Self {
+            last_seq: None,
+            cancelled: false,
+            pending: None,
+        }
+    }
+
+    /// Handles a throttle frame with the sequence number `seq`, sent while the controller cruises
+    /// or not, and received at `now`.
+    ///
+    /// Returns whether its throttle value is to be applied. If it isn't, the output goes back to
+    /// neutral, and `take_notice` tells when to send `cancel_frame`.
+    pub fn frame(&mut self, seq: Option<u8>, cruise: bool, now: Instant) -> bool {
+        let missed = match (self.last_seq, seq) {
+            (Some(last), Some(seq)) => seq != last.wrapping_add(1),
+            _ => true,
+        };
+        self.last_seq = seq;
+
+        if !cruise {
+            self.cancelled = false;
+            return true;
+        }
+        if missed {
+            self.cancelled = true;
+        }
+        if self.cancelled {
+            self.pending = Some(now);
+        }
+        !self.cancelled
+    }
+
+    /// Whether cruise frames are dropped.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Returns whether to send `cancel_frame` now that the controller is done sending the beacon
+    /// that was dropped.
+    pub fn take_notice(&mut self, now: Instant) -> bool {
+        match self.pending {
+            Some(at) if now.raw_micros().wrapping_sub(at.raw_micros()) >= ANSWER_DELAY_MICROS => {
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records that the link was lost, so the next cruise frame is dropped.
+    pub fn link_lost(&mut self) {
+        self.last_seq = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REST: u16 = 7360;
+    const RIDING: u16 = 12000;
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_raw_micros(ms * 1000)
+    }
+
+    /// Rides at `RIDING` from `start`, and flicks the thumb twice.
+    fn gesture(cruise: &mut Cruise, start: u32) -> Option<Event> {
+        let mut event = None;
+        for (ms, adc) in &[
+            (0, RIDING),
+            (50, RIDING),
+            (100, REST),
+            (200, REST),
+            (300, RIDING),
+            (400, RIDING),
+            (500, REST),
+            (700, RIDING),
+        ] {
+            event = cruise.update(*adc, REST, true, at(start + ms));
+        }
+        event
+    }
+
+    #[test]
+    fn engaging() {
+        let mut cruise = Cruise::new();
+        assert_eq!(gesture(&mut cruise, 0), Some(Event::Engaged(RIDING)));
+        assert!(cruise.is_engaged());
+        assert_eq!(cruise.status(at(700)), Status::Engaged);
+
+        // The thumb can rest now
+        assert_eq!(cruise.update(9000, REST, true, at(800)), None);
+        assert_eq!(cruise.update(REST, REST, true, at(900)), None);
+        assert_eq!(cruise.throttle(REST), RIDING);
+
+        // Not when disabled, or when the flicks are too slow
+        let mut cruise = Cruise::new();
+        for (ms, adc) in &[(0, RIDING), (100, REST), (300, RIDING), (500, REST)] {
+            assert_eq!(cruise.update(*adc, REST, false, at(*ms)), None);
+        }
+        assert_eq!(cruise.update(RIDING, REST, false, at(700)), None);
+        let mut cruise = Cruise::new();
+        for (ms, adc) in &[(0, RIDING), (100, REST), (600, RIDING), (700, REST)] {
+            assert_eq!(cruise.update(*adc, REST, true, at(*ms)), None);
+        }
+        assert_eq!(cruise.update(RIDING, REST, true, at(900)), None);
+        assert_eq!(cruise.update(REST, REST, true, at(2000)), None);
+        assert_eq!(cruise.update(RIDING, REST, true, at(2300)), None);
+        assert!(!cruise.is_engaged());
+        assert_eq!(cruise.throttle(RIDING), RIDING);
+
+        // Nor starting from rest
+        let mut cruise = Cruise::new();
+        for (ms, adc) in &[(0, REST), (100, RIDING), (200, REST), (300, RIDING)] {
+            assert_eq!(cruise.update(*adc, REST, true, at(*ms)), None);
+        }
+    }
+
+    #[test]
+    fn disengaging() {
+        let mut cruise = Cruise::new();
+        gesture(&mut cruise, 0);
+        assert_eq!(
+            cruise.update(RIDING + 1000, REST, true, at(800)),
+            Some(Event::Disengaged(Reason::Throttle))
+        );
+        assert_eq!(cruise.throttle(RIDING + 1000), RIDING + 1000);
+        assert_eq!(cruise.status(at(2700)), Status::Disengaged);
+        assert_eq!(cruise.status(at(2800)), Status::Off);
+
+        gesture(&mut cruise, 3000);
+        cruise.update(REST, REST, true, at(3800));
+        assert_eq!(
+            cruise.update(REST + 500, REST, true, at(3900)),
+            Some(Event::Disengaged(Reason::Throttle))
+        );
+
+        gesture(&mut cruise, 5000);
+        assert_eq!(
+            cruise.update(0, REST, true, at(5800)),
+            Some(Event::Disengaged(Reason::Brake))
+        );
+
+        gesture(&mut cruise, 7000);
+        assert_eq!(
+            cruise.disengage(Reason::Link, at(7800)),
+            Some(Event::Disengaged(Reason::Link))
+        );
+        assert_eq!(cruise.disengage(Reason::Link, at(7900)), None);
+
+        gesture(&mut cruise, 9000);
+        assert_eq!(
+            cruise.update(REST, REST, false, at(9800)),
+            Some(Event::Disengaged(Reason::Disabled))
+        );
+    }
+
+    #[test]
+    fn guard() {
+        let mut guard = Guard::new();
+        assert!(guard.frame(Some(0), false, at(0)));
+        assert!(guard.frame(Some(1), true, at(20)));
+        assert!(!guard.take_notice(at(30)));
+
+        // A missed frame drops cruise frames until the controller stops cruising
+        assert!(!guard.frame(Some(3), true, at(60)));
+        assert!(guard.is_cancelled());
+        assert!(!guard.take_notice(at(61)));
+        assert!(guard.take_notice(at(62)));
+        assert!(!guard.take_notice(at(63)));
+        assert!(!guard.frame(Some(4), true, at(80)));
+        assert!(guard.take_notice(at(82)));
+        assert!(guard.frame(Some(5), false, at(100)));
+        assert!(guard.frame(Some(6), true, at(120)));
+
+        // So does a lost link
+        guard.link_lost();
+        assert!(!guard.frame(Some(7), true, at(500)));
+        // Missed frames don't matter while the controller doesn't cruise
+        assert!(guard.frame(Some(20), false, at(600)));
+    }
+}
@@ -0,0 +1,72 @@
+//! What the controller shows on its SSD1306 display.
+//!
+//! Drawing goes through embedded-graphics' `Drawing` trait, so the same code renders into the
+//! display driver on the controller and into a frame buffer in the simulation.
+
+use {
+    crate::{cruise::Status, logger::SliceWriter},
+    core::fmt::Write,
+    embedded_graphics::{
+        fonts::{Font12x16, Font6x8},
+        image::Image1BPP,
+        pixelcolor::PixelColorU8,
+        prelude::*,
+        Drawing,
+    },
+};
+
+/// Width of the display in pixels, as mounted (rotated by 90°).
+pub const WIDTH: u32 = 64;
+
+/// Height of the display in pixels, as mounted.
+pub const HEIGHT: u32 = 128;
+
+/// Converts a 14-bit throttle reading into the percentage shown on the display.
+pub fn throttle_percent(adc: u16) -> u16 {
+    adc / 164
+}
+
+/// Draws the throttle screen for the 14-bit throttle reading `adc`.
+///
+/// This only sets pixels, so the display has to be cleared before, and flushed afterwards.
+pub fn render<D: Drawing<PixelColorU8>>(display: &mut D, adc: u16) {
+    render_dimmed(display, adc);
+    display.draw(
+        Image1BPP::new(include_bytes!("./rust.raw"), 32, 32)
+            .translate(Coord::new(32, 96))
+            .into_iter(),
+    );
+}
+
+/// Draws the dimmed throttle screen, which only shows the throttle percentage.
+///
+/// The OLED's current draw goes with the number of lit pixels, and the SSD1306 driver doesn't
+/// support changing the contrast.
+pub fn render_dimmed<D: Drawing<PixelColorU8>>(display: &mut D, adc: u16) {
+    let mut buf = [0; 8];
+    let mut text = SliceWriter::new(&mut buf);
+    write!(text, "{}%", throttle_percent(adc)).ok();
+
+    display.draw(
+        Font12x16::render_str(text.as_str())
+            .with_stroke(Some(1u8.into()))
+            .translate(Coord::new(16, 16))
+            .into_iter(),
+    );
+}
+
+/// Draws the cruise status below the throttle percentage: "CRUISE" while engaged, and
+/// "CRUISE OFF" for a while after it disengaged.
+pub fn render_cruise<D: Drawing<PixelColorU8>>(display: &mut D, status: Status) {
+    let (text, x) = match status {
+        Status::Engaged => ("CRUISE", 14),
+        Status::Disengaged => ("CRUISE OFF", 2),
+        Status::Off => return,
+    };
+    display.draw(
+        Font6x8::render_str(text)
+            .with_stroke(Some(1u8.into()))
+            .translate(Coord::new(x, 48))
+            .into_iter(),
+    );
+}
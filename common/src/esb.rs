@@ -0,0 +1,329 @@
+//! A proprietary packet mode modeled on Nordic's Enhanced ShockBurst (ESB), as an alternative to
+//! BLE beacons for the throttle link.
+//!
+//! Throttle beacons are broadcast without knowing whether the receiver is listening, so the
+//! receiver only gets them when its scanning happens to line up. In ESB mode, both devices stay on
+//! a single channel instead: the controller sends each throttle frame as a packet, and the
+//! receiver acknowledges it right away. Packets that aren't acknowledged within
+//! `ACK_TIMEOUT_MICROS` are sent again, up to `MAX_ATTEMPTS` times in total, so a throttle value
+//! arrives within a few milliseconds or not at all. The sender listens for acknowledgements in the
+//! background, and a timer tells it when one is overdue, so sending doesn't block.
+//!
+//! Packets use the Nordic 2 Mbit mode, a 4-Byte base address plus a prefix (`BASE_ADDRESS` and
+//! `PREFIX`), and a 16-bit CRC over the address and the packet. In the radio's packet buffer, they
+//! look like this (see `radio` for the general frame layout):
+//!
+//! ```notrust
+//! +----------+--------------------------+--------------+
+//! |  Length  |            S1            |   Payload    |
+//! |  (1 B)   |          (1 B)           | (`Length` B) |
+//! +----------+--------------------------+--------------+
+//!             \ PID (bits 1-2), NO_ACK (bit 0) /
+//! ```
+//!
+//! Only the lower 6 bits of the length and the lower 3 bits of S1 are sent. The packet ID (PID)
+//! counts up for every new packet, so the receiver can tell a packet that was sent again because
+//! its acknowledgement got lost from the next one. Acknowledgements are empty packets carrying the
+//! PID of the packet they acknowledge.
+//!
+//! The payload of throttle packets is the sequence number followed by a throttle frame (see
+//! `protocol::throttle_frame`), so the receiver handles them like throttle beacons. The PHY
+//! requested in the frame is ignored.
+//!
+//! Relayed updates, the PHY negotiation and the GATT services all need BLE, so they're unavailable
+//! in ESB mode.
+
+use core::cmp;
+
+/// Base address of ESB packets.
+pub const BASE_ADDRESS: u32 = 0xB1EF_1E5B;
+
+/// Address prefix of ESB packets.
+pub const PREFIX: u8 = 0xE7;
+
+/// Channel used by default, as an offset from 2400 MHz. It's clear of the BLE advertising channels
+/// (2, 26 and 80) and the common WiFi channels 1, 6 and 11.
+pub const DEFAULT_CHANNEL: u8 = 76;
+
+/// Polynomial of the 16-bit CRC (CRC-16-CCITT).
+pub const CRC_POLY: u32 = 0x1_1021;
+
+/// Initial value of the CRC.
+pub const CRC_INIT: u32 = 0xFFFF;
+
+/// Maximum length of a payload.
+pub const MAX_PAYLOAD_LEN: usize = 32;
+
+/// Length of the header in the packet buffer: the length and S1.
+pub const HEADER_LEN: usize = 2;
+
+/// Maximum length of a packet in the packet buffer.
+pub const MAX_PACKET_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN;
+
+/// Time the sender waits for an acknowledgement after sending a packet, in microseconds. An
+/// acknowledgement starts about 150 µs after the packet, once the receiver's radio ramped up.
+pub const ACK_TIMEOUT_MICROS: u32 = 500;
+
+/// Number of times a packet is sent before giving up on it.
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// Encodes a packet with `pid` and `payload` into `buf`, returning the length of the packet.
+///
+/// `payload` is cut off after `MAX_PAYLOAD_LEN` Bytes, and `buf` must have room for the packet.
+pub fn encode(pid: u8, no_ack: bool, payload: &[u8], buf: &mut [u8]) -> usize {
+    let len = cmp::min(payload.len(), MAX_PAYLOAD_LEN);
+    buf[0] = len as u8;
+    buf[1] = (pid & 0b11) << 1 | no_ack as u8;
+    buf[HEADER_LEN..][..len].copy_from_slice(&payload[..len]);
+    HEADER_LEN + len
+}
+
+/// A packet decoded from the packet buffer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Packet<'a> {
+    pub pid: u8,
+    /// Whether the sender doesn't want an acknowledgement.
+    pub no_ack: bool,
+    pub payload: &'a [u8],
+}
+
+/// Decodes the packet in `buf`, or returns `None` if its length is out of bounds.
+pub fn decode(buf: &[u8]) -> Option<Packet<'_>> {
+    let len = usize::from(*buf.first()? & 0x3F);
+    let s1 = *buf.get(1)?;
+    if len > MAX_PAYLOAD_LEN {
+        return None;
+    }
+
+    Some(Packet {
+        pid: (s1 >> 1) & 0b11,
+        no_ack: s1 & 1 != 0,
+        payload: buf.get(HEADER_LEN..HEADER_LEN + len)?,
+    })
+}
+
+/// Encodes the payload of a throttle packet: `seq`, followed by the throttle frame.
+pub fn throttle_payload(seq: u8, frame: &[u8], buf: &mut [u8; MAX_PAYLOAD_LEN]) -> usize {
+    let len = cmp::min(frame.len(), MAX_PAYLOAD_LEN - 1);
+    buf[0] = seq;
+    buf[1..][..len].copy_from_slice(&frame[..len]);
+    len + 1
+}
+
+/// Splits the payload of a throttle packet into its sequence number and throttle frame.
+pub fn split_throttle(payload: &[u8]) -> Option<(u8, &[u8])> {
+    let (&seq, frame) = payload.split_first()?;
+    Some((seq, frame))
+}
+
+/// Counts of what happened to the packets sent.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SendStats {
+    pub packets: u32,
+    /// Packets that were acknowledged.
+    pub acked: u32,
+    /// Attempts after the first one.
+    pub retransmits: u32,
+}
+
+/// The sending side, which numbers the packets, checks the acknowledgements and decides when to
+/// send a packet again.
+pub struct Sender {
+    pid: u8,
+    /// Times the last packet was sent, while it's waiting for its acknowledgement.
+    pending: Option<u32>,
+    stats: SendStats,
+}
+
+impl Default for Sender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sender {
+    pub const fn new() -> Self {
+        Self {
+            pid: 0,
+            pending: None,
+            stats: SendStats {
+                packets: 0,
+                acked: 0,
+                retransmits: 0,
+            },
+        }
+    }
+
+    /// Encodes a new packet carrying `payload` into `buf`, returning its length.
+    ///
+    /// A packet still waiting for its acknowledgement counts as not acknowledged.
+    pub fn packet(&mut self, payload: &[u8], buf: &mut [u8]) -> usize {
+        if self.pending.is_some() {
+            self.finish(false);
+        }
+        self.pid = (self.pid + 1) & 0b11;
+        self.pending = Some(0);
+        encode(self.pid, false, payload, buf)
+    }
+
+    /// Records that the last packet is sent (again), and returns whether it may be.
+    ///
+    /// Once it was sent `MAX_ATTEMPTS` times, it counts as not acknowledged instead.
+    pub fn attempt(&mut self) -> bool {
+        match self.pending {
+            Some(attempts) if attempts < MAX_ATTEMPTS => {
+                self.pending = Some(attempts + 1);
+                true
+            }
+            Some(_) => {
+                self.finish(false);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the last packet is waiting for its acknowledgement.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Checks whether the packet in `buf` acknowledges the last packet, and records it if it does.
+    pub fn ack(&mut self, buf: &[u8]) -> bool {
+        let acked = match decode(buf) {
+            Some(packet) => self.pending.is_some() && packet.pid == self.pid,
+            None => false,
+        };
+        if acked {
+            self.finish(true);
+        }
+        acked
+    }
+
+    /// Records whether the last packet was acknowledged.
+    fn finish(&mut self, acked: bool) {
+        let attempts = self.pending.take().unwrap_or(0);
+        self.stats.packets += 1;
+        self.stats.retransmits += attempts.saturating_sub(1);
+        if acked {
+            self.stats.acked += 1;
+        }
+    }
+
+    pub fn stats(&self) -> SendStats {
+        self.stats
+    }
+}
+
+/// The receiving side, which drops packets that were sent again after the acknowledgement got
+/// lost.
+pub struct Deduplicator {
+    /// PID and CRC of the last packet received.
+    last: Option<(u8, u32)>,
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deduplicator {
+    pub const fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Whether a packet with `pid` and `crc` is new, rather than the last one sent again.
+    ///
+    /// The PID alone wraps around after 4 packets, so the CRC has to match as well.
+    pub fn is_new(&mut self, pid: u8, crc: u32) -> bool {
+        let new = self.last != Some((pid, crc));
+        self.last = Some((pid, crc));
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::mode::Phy, crate::protocol, rubble::time::Duration};
+
+    #[test]
+    fn packets() {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let len = encode(3, true, &[1, 2, 3], &mut buf);
+        assert_eq!(&buf[..len], &[3, 0b111, 1, 2, 3]);
+        assert_eq!(
+            decode(&buf[..len]),
+            Some(Packet {
+                pid: 3,
+                no_ack: true,
+                payload: &[1, 2, 3],
+            })
+        );
+
+        // Too long for the buffer, or for the packet
+        assert_eq!(encode(0, false, &[0; 40], &mut buf), MAX_PACKET_LEN);
+        assert_eq!(decode(&[4, 0, 1, 2]), None);
+        assert_eq!(decode(&[33, 0]), None);
+        assert_eq!(decode(&[]), None);
+
+        let frame = protocol::throttle_frame(0x3FFF, Duration::from_millis(20), Phy::Ble1M);
+        let mut payload = [0; MAX_PAYLOAD_LEN];
+        let len = throttle_payload(7, &frame, &mut payload);
+        assert_eq!(split_throttle(&payload[..len]), Some((7, &frame[..])));
+        assert_eq!(split_throttle(&[]), None);
+    }
+
+    #[test]
+    fn acknowledgements() {
+        let mut sender = Sender::new();
+        let mut buf = [0; MAX_PACKET_LEN];
+        let mut ack = [0; MAX_PACKET_LEN];
+
+        sender.packet(&[1], &mut buf);
+        assert!(sender.attempt());
+        let pid = decode(&buf).unwrap().pid;
+        let len = encode(pid, false, &[], &mut ack);
+        assert!(sender.ack(&ack[..len]));
+        assert!(!sender.is_pending());
+        // Only counted once
+        assert!(!sender.ack(&ack[..len]));
+
+        // An acknowledgement of the previous packet arriving late
+        sender.packet(&[2], &mut buf);
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(sender.attempt());
+            assert!(!sender.ack(&ack[..len]));
+        }
+        assert!(!sender.attempt());
+        assert!(!sender.is_pending());
+
+        // Still waiting when the next one is due
+        sender.packet(&[3], &mut buf);
+        assert!(sender.attempt());
+        sender.packet(&[4], &mut buf);
+        assert!(sender.attempt());
+        let len = encode(decode(&buf).unwrap().pid, false, &[], &mut ack);
+        assert!(sender.ack(&ack[..len]));
+
+        assert_eq!(
+            sender.stats(),
+            SendStats {
+                packets: 4,
+                acked: 2,
+                retransmits: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut dedup = Deduplicator::new();
+        assert!(dedup.is_new(1, 0x1234));
+        // Sent again, since the acknowledgement was lost
+        assert!(!dedup.is_new(1, 0x1234));
+        assert!(dedup.is_new(2, 0x1234));
+        // Same PID after wrapping around, but a different packet
+        assert!(dedup.is_new(2, 0x4321));
+    }
+}
@@ -0,0 +1,11 @@
+//! Attribute types shared by the services of the GATT server.
+//!
+//! Each firmware puts its services together into its own attribute table; the types here are the
+//! ones defined by the Bluetooth specification.
+
+use rubble::uuid::Uuid16;
+
+pub const PRIMARY_SERVICE: Uuid16 = Uuid16(0x2800);
+pub const CHARACTERISTIC: Uuid16 = Uuid16(0x2803);
+pub const USER_DESCRIPTION: Uuid16 = Uuid16(0x2901);
+pub const CLIENT_CONFIG: Uuid16 = Uuid16(0x2902);
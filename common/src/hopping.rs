@@ -0,0 +1,666 @@
+//! Frequency hopping of throttle beacons over the BLE data channels.
+//!
+//! Throttle beacons normally go out on the three advertising channels (see `crate::redundancy`),
+//! which every BLE device nearby shares, and which a single WiFi network can cover. With hopping,
+//! the controller sends them on the 37 data channels instead. The copies of a frame go out on
+//! consecutive hops of a pseudo-random `HopSequence`, which both devices derive from the
+//! controller's address, the identity the receiver is paired to by filtering for it. The sequence
+//! number of the frame picks the hops, so the devices don't need a common time base.
+//!
+//! The receiver follows the sequence by its `BleTimer` (see `Follower`): after hearing a copy, it
+//! moves on to the channel of the next one, and it expects the next frame the advertised beacon
+//! interval after this one. After missing `MAX_MISSED` frames in a row, it loses sync, and parks on
+//! one channel until a copy comes by, whose sequence number tells it where the controller is in
+//! the sequence.
+//!
+//! The receiver keeps track of how many of the copies it listened for were lost on each channel,
+//! and blacklists channels that lose most of them. It sends the controller the map of the channels
+//! left, in an answer like the one to a PHY request (see `crate::mode`), and both switch to it once
+//! the answer is sent. Hops landing on a blacklisted channel are remapped to one of the channels
+//! left, so hops on the others stay the same even if the answer gets lost. Blacklisted channels get
+//! another chance after `BLACKLIST_SECS`. Like on 2M, the receiver repeats its answer while
+//! channels are blacklisted, and both devices go back to all channels when the link is lost.
+//!
+//! Relay frames go out on the channel of the next frame's first copy, where the receiver listens
+//! once it heard the copies. Answers to the controller still go out on the advertising channels.
+
+use {
+    crate::{mode, protocol, redundancy},
+    rubble::{
+        link::{advertising, data, Transmitter},
+        phy::{AdvertisingChannel, DataChannel},
+        time::{Duration, Instant},
+    },
+};
+
+/// Number of data channels.
+pub const CHANNELS: u8 = 37;
+
+/// Number of copies of each frame, which go out on consecutive hops.
+pub const COPIES: u8 = 3;
+
+/// Fewest channels the receiver keeps in the map.
+pub const MIN_CHANNELS: u8 = 8;
+
+/// Number of frames missed in a row after which the receiver loses sync.
+pub const MAX_MISSED: u32 = 4;
+
+/// Number of copies listened for on a channel after which its losses are looked at.
+pub const HEALTH_WINDOW: u8 = 8;
+
+/// Number of copies lost out of `HEALTH_WINDOW` that get a channel blacklisted.
+pub const BLACKLIST_LOSSES: u8 = 5;
+
+/// Time after which a blacklisted channel is used again.
+pub const BLACKLIST_SECS: u32 = 30;
+
+/// First Byte of channel map answers, which tells them apart from relay frames and mode answers.
+pub const MAP_TAG: u8 = 0xB3;
+
+/// The data channels in use, as a bit for every channel index.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelMap(u64);
+
+impl ChannelMap {
+    pub const ALL: Self = ChannelMap((1 << CHANNELS) - 1);
+
+    /// Returns the map with the bits in `bits`, or `None` if it has channels that don't exist or
+    /// fewer than `MIN_CHANNELS`.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        let map = ChannelMap(bits);
+        if bits & !Self::ALL.0 != 0 || map.count() < MIN_CHANNELS {
+            None
+        } else {
+            Some(map)
+        }
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, channel: u8) -> bool {
+        self.0 & 1 << channel != 0
+    }
+
+    /// Returns the number of channels in the map.
+    pub fn count(self) -> u8 {
+        self.0.count_ones() as u8
+    }
+
+    pub fn with(self, channel: u8) -> Self {
+        ChannelMap(self.0 | 1 << channel)
+    }
+
+    pub fn without(self, channel: u8) -> Self {
+        ChannelMap(self.0 & !(1 << channel))
+    }
+
+    /// Returns the `n`th channel in the map.
+    fn nth(self, n: u8) -> u8 {
+        (0..CHANNELS)
+            .filter(|&channel| self.contains(channel))
+            .nth(usize::from(n))
+            .unwrap()
+    }
+}
+
+/// Encodes the receiver's answer that it uses `map`.
+pub fn map_answer_frame(map: ChannelMap) -> [u8; 6] {
+    let bits = map.bits().to_le_bytes();
+    [MAP_TAG, bits[0], bits[1], bits[2], bits[3], bits[4]]
+}
+
+/// Decodes the channel map from a map answer, or returns `None` if `frame` isn't one.
+pub fn map_answer(frame: &[u8]) -> Option<ChannelMap> {
+    if frame.len() != 6 || frame[0] != MAP_TAG {
+        return None;
+    }
+    let mut bits = [0; 8];
+    bits[..5].copy_from_slice(&frame[1..]);
+    ChannelMap::from_bits(u64::from_le_bytes(bits))
+}
+
+/// The channels the copies of every frame go out on.
+pub struct HopSequence {
+    start: u8,
+    increment: u8,
+    map: ChannelMap,
+}
+
+impl HopSequence {
+    /// Derives the sequence from the controller's `address`, using the channels in `map`.
+    pub fn new(address: [u8; 6], map: ChannelMap) -> Self {
+        // FNV-1a
+        let hash = address.iter().fold(0x811C_9DC5_u32, |hash, &byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        });
+
+        Self {
+            start: (hash % u32::from(CHANNELS)) as u8,
+            // Like in BLE, every increment visits all channels, since their number is prime
+            increment: 5 + ((hash >> 8) % 12) as u8,
+            map,
+        }
+    }
+
+    pub fn map(&self) -> ChannelMap {
+        self.map
+    }
+
+    pub fn set_map(&mut self, map: ChannelMap) {
+        self.map = map;
+    }
+
+    /// Returns the channel the copy `copy` of the frame with the sequence number `seq` goes out on.
+    pub fn channel(&self, seq: u8, copy: u8) -> DataChannel {
+        let hop = u32::from(seq) * u32::from(COPIES) + u32::from(copy);
+        let unmapped =
+            (u32::from(self.start) + u32::from(self.increment) * hop) % u32::from(CHANNELS);
+        let unmapped = unmapped as u8;
+
+        if self.map.contains(unmapped) {
+            DataChannel::new(unmapped)
+        } else {
+            DataChannel::new(self.map.nth(unmapped % self.map.count()))
+        }
+    }
+
+    /// Returns which copy of the frame with the sequence number `seq` goes out on `channel`, if
+    /// any.
+    pub fn copy_on(&self, seq: u8, channel: DataChannel) -> Option<u8> {
+        (0..COPIES).find(|&copy| self.channel(seq, copy).index() == channel.index())
+    }
+}
+
+fn micros_since(now: Instant, earlier: Instant) -> u32 {
+    now.raw_micros().wrapping_sub(earlier.raw_micros())
+}
+
+/// The controller's side of hopping.
+pub struct Hopper {
+    address: [u8; 6],
+    sequence: HopSequence,
+    last_answer: Option<Instant>,
+}
+
+impl Hopper {
+    /// Creates the hopper of the controller sending from `address`, using all channels.
+    pub fn new(address: [u8; 6]) -> Self {
+        Self {
+            address,
+            sequence: HopSequence::new(address, ChannelMap::ALL),
+            last_answer: None,
+        }
+    }
+
+    /// Returns the address of the controller whose hops these are.
+    pub fn address(&self) -> [u8; 6] {
+        self.address
+    }
+
+    pub fn map(&self) -> ChannelMap {
+        self.sequence.map()
+    }
+
+    /// Returns the channels the copies of the frame with the sequence number `seq` go out on.
+    pub fn channels(&self, seq: u8) -> [DataChannel; COPIES as usize] {
+        let channel = |copy| self.sequence.channel(seq, copy);
+        [channel(0), channel(1), channel(2)]
+    }
+
+    /// Returns the channel relay frames sent after the frame with the sequence number `seq` go out
+    /// on.
+    pub fn relay_channel(&self, seq: u8) -> DataChannel {
+        self.sequence.channel(seq.wrapping_add(1), 0)
+    }
+
+    /// Handles a channel map answer of the receiver received at `now`, and returns the map if it
+    /// changed.
+    pub fn answer(&mut self, map: ChannelMap, now: Instant) -> Option<ChannelMap> {
+        self.last_answer = Some(now);
+        self.switch(map)
+    }
+
+    /// Goes back to all channels if the receiver didn't answer for too long, and returns the map if
+    /// it changed.
+    pub fn update(&mut self, now: Instant) -> Option<ChannelMap> {
+        match self.last_answer {
+            Some(last) if micros_since(now, last) / 1_000_000 < mode::ANSWER_TIMEOUT_SECS => None,
+            _ => self.switch(ChannelMap::ALL),
+        }
+    }
+
+    fn switch(&mut self, map: ChannelMap) -> Option<ChannelMap> {
+        if map == self.sequence.map() {
+            None
+        } else {
+            self.sequence.set_map(map);
+            Some(map)
+        }
+    }
+}
+
+/// What the receiver observed on a channel.
+#[derive(Copy, Clone, Default)]
+struct Health {
+    /// Copies listened for since the losses were last looked at.
+    listens: u8,
+    losses: u8,
+    /// When the channel was blacklisted, if it is.
+    blacklisted: Option<Instant>,
+}
+
+/// The receiver's side of hopping, which picks the channel to listen on and the channels to use.
+pub struct Follower {
+    sequence: HopSequence,
+    /// The sequence number and copy listened for, or `None` while searching for the controller.
+    next: Option<(u8, u8)>,
+    /// Position in the sequence whose first channel is searched on.
+    search: u8,
+    listening: DataChannel,
+    /// Sequence number of the last frame heard.
+    frame: Option<u8>,
+    /// When the first copy of the last frame was heard.
+    frame_start: Instant,
+    /// When a copy was last heard, or the search last moved on to another channel.
+    last_heard: Instant,
+    interval: u32,
+    missed: u32,
+    health: [Health; CHANNELS as usize],
+    /// The channels the receiver wants to use, which the controller doesn't know about until it
+    /// got the answer.
+    wanted: ChannelMap,
+    /// When the frame was heard that a pending answer follows.
+    pending: Option<Instant>,
+    last_answer: Option<Instant>,
+}
+
+impl Follower {
+    /// Starts out searching for the controller sending from `address` at `now`, on all channels.
+    pub fn new(address: [u8; 6], now: Instant) -> Self {
+        let sequence = HopSequence::new(address, ChannelMap::ALL);
+
+        Self {
+            listening: sequence.channel(0, 0),
+            sequence,
+            next: None,
+            search: 0,
+            frame: None,
+            frame_start: now,
+            last_heard: now,
+            interval: redundancy::DEFAULT_INTERVAL_MICROS,
+            missed: 0,
+            health: [Health::default(); CHANNELS as usize],
+            wanted: ChannelMap::ALL,
+            pending: None,
+            last_answer: None,
+        }
+    }
+
+    /// Returns the channel to listen on.
+    pub fn channel(&self) -> DataChannel {
+        self.listening
+    }
+
+    /// Returns the channels in use.
+    pub fn map(&self) -> ChannelMap {
+        self.sequence.map()
+    }
+
+    /// Whether the receiver knows where the controller is in the sequence.
+    pub fn is_synced(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Handles a copy of the throttle frame with the sequence number `seq`, heard at `now` on the
+    /// channel listened on, which advertises `interval` until the next frame.
+    ///
+    /// Afterwards, `channel` returns the channel the next copy is sent on. If an answer is due,
+    /// it's returned by `take_answer` once the controller listens for it.
+    pub fn copy(&mut self, seq: u8, interval: Option<Duration>, now: Instant) {
+        let copy = match self.sequence.copy_on(seq, self.listening) {
+            Some(copy) => copy,
+            // Not sent on this channel, so not from the sequence followed
+            None => return,
+        };
+        self.record(self.listening.index(), false, now);
+        if let Some(interval) = interval {
+            self.interval = interval.as_micros();
+        }
+
+        if self.frame != Some(seq) || copy == 0 {
+            self.frame = Some(seq);
+            self.frame_start = now;
+            self.request_answer(now);
+        }
+        self.last_heard = now;
+        self.missed = 0;
+        self.next = Some(if copy + 1 < COPIES {
+            (seq, copy + 1)
+        } else {
+            (seq.wrapping_add(1), 0)
+        });
+        self.listen();
+    }
+
+    /// Updates the channel to listen on for `now`, and returns it if it changed.
+    ///
+    /// Once the copies of a frame are over, the receiver moves on to the first copy of the next
+    /// frame. If that doesn't arrive in time, it moves on to the frame after. While searching, it
+    /// moves on to another channel if nothing is heard for one and a half beacon intervals.
+    pub fn update(&mut self, now: Instant) -> Option<DataChannel> {
+        self.expire(now);
+
+        let listening = self.listening.index();
+        match self.next {
+            Some((seq, copy)) => {
+                let since_frame = micros_since(now, self.frame_start);
+                let due = self.interval * (self.missed + 1) + redundancy::COPY_WINDOW_MICROS;
+                if copy > 0 && since_frame > redundancy::COPY_WINDOW_MICROS {
+                    self.record(listening, true, now);
+                    self.next = Some((seq.wrapping_add(1), 0));
+                } else if copy == 0 && since_frame > due {
+                    self.record(listening, true, now);
+                    self.missed += 1;
+                    self.next = if self.missed < MAX_MISSED {
+                        Some((seq.wrapping_add(1), 0))
+                    } else {
+                        self.search = seq;
+                        self.last_heard = now;
+                        None
+                    };
+                }
+            }
+            None => {
+                if micros_since(now, self.last_heard) > self.interval + self.interval / 2 {
+                    self.search = self.search.wrapping_add(1);
+                    self.last_heard = now;
+                }
+            }
+        }
+
+        self.listen();
+        if self.listening.index() == listening {
+            None
+        } else {
+            Some(self.listening)
+        }
+    }
+
+    /// Returns the channel map to answer with, once the controller is done sending the frame the
+    /// answer follows. After sending the answer, the receiver switches to that map, which `map`
+    /// returns from now on.
+    pub fn take_answer(&mut self, now: Instant) -> Option<ChannelMap> {
+        match self.pending {
+            Some(at) if micros_since(now, at) >= mode::ANSWER_DELAY_MICROS => {
+                self.pending = None;
+                self.sequence.set_map(self.wanted);
+                self.listen();
+                Some(self.wanted)
+            }
+            _ => None,
+        }
+    }
+
+    /// Goes back to all channels after the link was lost, and searches for the controller.
+    pub fn link_lost(&mut self) {
+        self.wanted = ChannelMap::ALL;
+        self.sequence.set_map(ChannelMap::ALL);
+        self.health = [Health::default(); CHANNELS as usize];
+        self.pending = None;
+        self.next = None;
+        self.listen();
+    }
+
+    fn listen(&mut self) {
+        self.listening = match self.next {
+            Some((seq, copy)) => self.sequence.channel(seq, copy),
+            None => self.sequence.channel(self.search, 0),
+        };
+    }
+
+    fn request_answer(&mut self, now: Instant) {
+        let all = self.wanted == ChannelMap::ALL;
+        let due = match self.last_answer {
+            _ if self.wanted != self.sequence.map() => true,
+            Some(last) => !all && micros_since(now, last) / 1_000_000 >= mode::ANSWER_INTERVAL_SECS,
+            None => !all,
+        };
+        if due && self.pending.is_none() {
+            self.pending = Some(now);
+            self.last_answer = Some(now);
+        }
+    }
+
+    /// Records whether the copy listened for on `channel` was lost, and blacklists the channel if
+    /// it loses too many.
+    fn record(&mut self, channel: u8, lost: bool, now: Instant) {
+        let health = &mut self.health[usize::from(channel)];
+        health.listens += 1;
+        if lost {
+            health.losses += 1;
+        }
+        if health.listens < HEALTH_WINDOW {
+            return;
+        }
+
+        let bad = health.losses >= BLACKLIST_LOSSES;
+        health.listens = 0;
+        health.losses = 0;
+        if bad && self.wanted.contains(channel) && self.wanted.count() > MIN_CHANNELS {
+            self.wanted = self.wanted.without(channel);
+            health.blacklisted = Some(now);
+        }
+    }
+
+    /// Gives blacklisted channels another chance once their time is up.
+    fn expire(&mut self, now: Instant) {
+        for channel in 0..CHANNELS {
+            let health = &mut self.health[usize::from(channel)];
+            match health.blacklisted {
+                Some(at) if micros_since(now, at) / 1_000_000 >= BLACKLIST_SECS => {
+                    health.blacklisted = None;
+                    self.wanted = self.wanted.with(channel);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A `Transmitter` that can also send beacons on the data channels.
+pub trait HopTransmitter: Transmitter {
+    /// Sends the advertising channel PDU in the TX buffer on the data channel `channel`.
+    fn transmit_hopping(&mut self, header: advertising::Header, channel: DataChannel);
+}
+
+/// Sends the beacons broadcast through it on the data channel in `channels` that stands in for
+/// each advertising channel, if any.
+struct OnDataChannels<'a, T> {
+    tx: &'a mut T,
+    channels: [Option<DataChannel>; 3],
+}
+
+impl<'a, T: HopTransmitter> Transmitter for OnDataChannels<'a, T> {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        self.tx.tx_payload_buf()
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        let copy = AdvertisingChannel::iter_all().position(|c| c.freq() == channel.freq());
+        if let Some(Some(channel)) = copy.map(|copy| self.channels[copy]) {
+            self.tx.transmit_hopping(header, channel);
+        }
+    }
+
+    fn transmit_data(
+        &mut self,
+        _access_address: u32,
+        _crc_iv: u32,
+        _header: data::Header,
+        _channel: DataChannel,
+    ) {
+        unreachable!("only beacons hop")
+    }
+}
+
+/// Broadcasts a throttle beacon like `protocol::broadcast_sequenced`, but with its copies on
+/// `channels`.
+pub fn broadcast_sequenced<T: HopTransmitter>(
+    tx: &mut T,
+    address: [u8; 6],
+    seq: u8,
+    payload: &[u8],
+    channels: [DataChannel; COPIES as usize],
+) {
+    let channels = [Some(channels[0]), Some(channels[1]), Some(channels[2])];
+    protocol::broadcast_sequenced(&mut OnDataChannels { tx, channels }, address, seq, payload);
+}
+
+/// Broadcasts a beacon like `protocol::broadcast`, but only once, on `channel`.
+pub fn broadcast<T: HopTransmitter>(
+    tx: &mut T,
+    address: [u8; 6],
+    payload: &[u8],
+    channel: DataChannel,
+) {
+    let channels = [Some(channel), None, None];
+    protocol::broadcast(&mut OnDataChannels { tx, channels }, address, payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::vec::Vec};
+
+    fn at(micros: u32) -> Instant {
+        Instant::from_raw_micros(micros)
+    }
+
+    const ADDRESS: [u8; 6] = protocol::CONTROLLER_ADDRESS;
+
+    #[test]
+    fn sequence() {
+        let mut sequence = HopSequence::new(ADDRESS, ChannelMap::ALL);
+        let hops = |sequence: &HopSequence| {
+            (0..=255)
+                .flat_map(|seq| (0..COPIES).map(move |copy| (seq, copy)))
+                .map(|(seq, copy)| sequence.channel(seq, copy).index())
+                .collect::<Vec<_>>()
+        };
+
+        // Every channel is used equally often, and consecutive hops are far apart
+        let all = hops(&sequence);
+        for channel in 0..CHANNELS {
+            let uses = all.iter().filter(|&&c| c == channel).count();
+            assert!(uses == 20 || uses == 21, "{}: {}", channel, uses);
+        }
+        assert!(all.windows(2).all(|w| w[0] != w[1]));
+        assert_eq!(sequence.copy_on(7, sequence.channel(7, 2)), Some(2),);
+
+        // Hops on blacklisted channels are remapped, and the others stay
+        let map = (0..9).fold(ChannelMap::ALL, |map, channel| map.without(channel));
+        sequence.set_map(map);
+        let remapped = hops(&sequence);
+        assert!(remapped.iter().all(|&c| c >= 9));
+        for (before, after) in all.iter().zip(&remapped) {
+            if *before >= 9 {
+                assert_eq!(before, after);
+            }
+        }
+
+        // Another controller hops differently
+        let other = HopSequence::new([1, 2, 3, 4, 5, 6], ChannelMap::ALL);
+        assert_ne!(hops(&other), all);
+    }
+
+    #[test]
+    fn maps() {
+        let map = ChannelMap::ALL.without(0).without(36);
+        assert_eq!(map.count(), 35);
+        assert!(!map.contains(36) && map.contains(35));
+        assert_eq!(map_answer(&map_answer_frame(map)), Some(map));
+        assert_eq!(
+            map_answer(&[MAP_TAG, 0xFF, 0, 0, 0, 0]),
+            Some(ChannelMap(0xFF))
+        );
+        // Too few channels, channels that don't exist, or not an answer
+        assert_eq!(map_answer(&[MAP_TAG, 0x7F, 0, 0, 0, 0]), None);
+        assert_eq!(map_answer(&[MAP_TAG, 0xFF, 0, 0, 0, 0x20]), None);
+        assert_eq!(map_answer(&[mode::ANSWER_TAG, 0xFF, 0, 0, 0, 0]), None);
+        assert_eq!(map_answer(&[MAP_TAG, 0xFF]), None);
+    }
+
+    /// Follows a controller sending a frame every 20 ms, of which `heard` returns which copies
+    /// arrive, and returns the channel map the follower ends up answering with.
+    fn follow<F>(follower: &mut Follower, frames: u32, mut heard: F) -> Option<ChannelMap>
+    where
+        F: FnMut(u8, u8, DataChannel) -> bool,
+    {
+        let interval = Some(Duration::from_millis(20));
+        let mut answer = None;
+        for frame in 0..frames {
+            let seq = frame as u8;
+            let start = frame * 20_000;
+            for copy in 0..COPIES {
+                let now = at(start + u32::from(copy) * 400);
+                follower.update(now);
+                let channel = follower.sequence.channel(seq, copy);
+                if follower.channel().index() == channel.index() && heard(seq, copy, channel) {
+                    follower.copy(seq, interval, now);
+                }
+            }
+            for micros in (1000..20_000).step_by(1000) {
+                follower.update(at(start + micros));
+                if let Some(map) = follower.take_answer(at(start + micros)) {
+                    answer = Some(map);
+                }
+            }
+        }
+        answer
+    }
+
+    #[test]
+    fn following() {
+        let mut follower = Follower::new(ADDRESS, at(0));
+        assert!(!follower.is_synced());
+
+        // Picks up the sequence from the first copy that comes by
+        follow(&mut follower, 40, |_, _, _| true);
+        assert!(follower.is_synced());
+        assert_eq!(follower.map(), ChannelMap::ALL);
+
+        // Stays in sync through a few lost frames, but not through more
+        let mut follower = Follower::new(ADDRESS, at(0));
+        follow(&mut follower, 40, |seq, _, _| !(20..23).contains(&seq));
+        assert!(follower.is_synced());
+        let mut follower = Follower::new(ADDRESS, at(0));
+        follow(&mut follower, 40, |seq, _, _| seq < 30);
+        assert!(!follower.is_synced());
+    }
+
+    #[test]
+    fn blacklisting() {
+        let mut follower = Follower::new(ADDRESS, at(0));
+        let jammed = |channel: DataChannel| channel.index() < 9;
+        let answer = follow(&mut follower, 500, |_, _, channel| !jammed(channel));
+        let map = answer.unwrap();
+        assert_eq!(follower.map(), map);
+        assert!((0..9).all(|channel| !map.contains(channel)));
+        assert!(map.count() >= 28);
+
+        // The controller follows the answer, and falls back without answers
+        let mut hopper = Hopper::new(ADDRESS);
+        assert_eq!(hopper.answer(map, at(0)), Some(map));
+        assert!(hopper.channels(5).iter().all(|&channel| !jammed(channel)));
+        assert_eq!(hopper.update(at(2_999_999)), None);
+        assert_eq!(hopper.update(at(3_000_000)), Some(ChannelMap::ALL));
+
+        // Blacklisted channels return after a while, and all of them once the link is lost
+        follower.update(at(10_000_000 + BLACKLIST_SECS * 1_000_000));
+        assert_eq!(follower.wanted, ChannelMap::ALL);
+        follower.link_lost();
+        assert_eq!(follower.map(), ChannelMap::ALL);
+        assert!(!follower.is_synced());
+    }
+}
@@ -0,0 +1,44 @@
+//! Code shared by the controller and the receiver firmware.
+//!
+//! This covers everything that doesn't depend on the board: logging (including the crash reports
+//! and the log GATT service), the serial shell, runtime configuration, the BLE radio and timer
+//! drivers for Rubble, the beacon protocol between both devices, and the pin map check used by the
+//! board support crates.
+//!
+//! The application logic that doesn't touch peripherals directly (the beacon protocol, the
+//! receiver's output mapping and failsafe, and the controller's display) is also used by the host
+//! simulation in `bluefly-sim`.
+//!
+//! The hardware independent parts are tested on the host:
+//!
+//! ```notrust
+//! cargo test -p bluefly-common --target x86_64-unknown-linux-gnu
+//! ```
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub mod config;
+pub mod crash;
+pub mod cruise;
+pub mod display;
+pub mod esb;
+pub mod gatt;
+pub mod hopping;
+pub mod link_stats;
+pub mod logger;
+pub mod mode;
+pub mod output;
+pub mod ownership;
+pub mod pins;
+pub mod power;
+pub mod protocol;
+pub mod radio;
+pub mod receivers;
+pub mod redundancy;
+pub mod shell;
+pub mod speed;
+pub mod timer;
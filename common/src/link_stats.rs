@@ -0,0 +1,299 @@
+//! Link quality statistics of the receiver.
+//!
+//! For every throttle frame it receives, the receiver records the RSSI, the number of frames that
+//! went missing before it (judging by the gap in sequence numbers), and how far its arrival was
+//! off from the interval the previous frame advertised. Packets with a bad CRC are counted as they
+//! come in. The statistics cover a sliding window of the last `WINDOW` frames received.
+//!
+//! Sequence numbers wrap around after 256 frames, so a longer loss of link counts as fewer missed
+//! frames than it was.
+
+use {
+    core::{cmp, fmt},
+    rubble::time::{Duration, Instant},
+};
+
+/// Number of received frames the statistics cover.
+pub const WINDOW: usize = 32;
+
+/// What was recorded about a received frame.
+#[derive(Copy, Clone)]
+struct Sample {
+    /// `None` if the radio didn't measure it.
+    rssi: Option<i8>,
+    /// Frames missed since the previous one.
+    missed: u8,
+    /// Deviation of the arrival from the advertised interval, in microseconds, if it's known.
+    jitter: Option<u32>,
+    /// Packets with a bad CRC since the previous frame.
+    crc_errors: u16,
+}
+
+/// Link quality over the window of received frames.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub received: u32,
+    /// Frames that didn't arrive in between.
+    pub missed: u32,
+    /// Average RSSI of the received frames whose RSSI was measured, in dBm.
+    pub rssi: i8,
+    /// Lowest RSSI of the received frames whose RSSI was measured, in dBm.
+    pub rssi_min: i8,
+    /// Average deviation of frame arrivals from the advertised interval, in microseconds.
+    pub jitter: u32,
+    /// Packets received with a bad CRC.
+    pub crc_errors: u32,
+}
+
+impl Summary {
+    /// Length of the encoded summary.
+    pub const LEN: usize = 12;
+
+    /// Returns the share of frames that didn't arrive, in tenths of a percent.
+    pub fn loss_permille(&self) -> u32 {
+        let total = self.received + self.missed;
+        (self.missed * 1000).checked_div(total).unwrap_or(0)
+    }
+
+    /// Encodes the summary for the telemetry characteristic: received and missed frames as
+    /// little-endian `u16`s, the average and lowest RSSI as `i8`s, then the jitter as a
+    /// little-endian `u32` and the CRC errors as a little-endian `u16`.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let narrow = |val: u32| cmp::min(val, u32::from(u16::MAX)) as u16;
+        let mut buf = [0; Self::LEN];
+        buf[0..2].copy_from_slice(&narrow(self.received).to_le_bytes());
+        buf[2..4].copy_from_slice(&narrow(self.missed).to_le_bytes());
+        buf[4] = self.rssi as u8;
+        buf[5] = self.rssi_min as u8;
+        buf[6..10].copy_from_slice(&self.jitter.to_le_bytes());
+        buf[10..12].copy_from_slice(&narrow(self.crc_errors).to_le_bytes());
+        buf
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} frames lost, rssi {} dBm (min {}), jitter {} us, {} crc errors",
+            self.missed,
+            self.received + self.missed,
+            self.rssi,
+            self.rssi_min,
+            self.jitter,
+            self.crc_errors
+        )
+    }
+}
+
+/// Records the quality of received frames.
+pub struct LinkStats {
+    samples: [Sample; WINDOW],
+    len: usize,
+    /// Where the next sample goes.
+    next: usize,
+    last_seq: Option<u8>,
+    /// When the last frame arrived, and the interval until the next one it advertised.
+    last_arrival: Option<(Instant, Option<Duration>)>,
+    /// Packets with a bad CRC since the last frame.
+    crc_errors: u16,
+}
+
+impl Default for LinkStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkStats {
+    pub const fn new() -> Self {
+        Self {
+            samples: [Sample {
+                rssi: None,
+                missed: 0,
+                jitter: None,
+                crc_errors: 0,
+            }; WINDOW],
+            len: 0,
+            next: 0,
+            last_seq: None,
+            last_arrival: None,
+            crc_errors: 0,
+        }
+    }
+
+    /// Records a packet received with a bad CRC.
+    pub fn crc_error(&mut self) {
+        self.crc_errors = self.crc_errors.saturating_add(1);
+    }
+
+    /// Records a throttle frame with the sequence number `seq`, received at `now` with `rssi` (if
+    /// it was measured), which advertises `interval` until the next frame.
+    ///
+    /// Frames without an RSSI don't count towards the RSSI statistics, so a radio that stops
+    /// measuring it doesn't keep reporting an old measurement.
+    pub fn frame(
+        &mut self,
+        seq: Option<u8>,
+        rssi: Option<i8>,
+        interval: Option<Duration>,
+        now: Instant,
+    ) {
+        let missed = match (self.last_seq, seq) {
+            (Some(last), Some(seq)) => seq.wrapping_sub(last).wrapping_sub(1),
+            _ => 0,
+        };
+        // A frame arriving out of order looks like most of the sequence numbers went missing. It
+        // doesn't say anything about the frames after it.
+        let reordered = missed >= 128;
+        let missed = if reordered { 0 } else { missed };
+
+        let jitter = match self.last_arrival {
+            Some((last, Some(expected))) if missed == 0 && !reordered => {
+                let actual = now.duration_since(last).as_micros();
+                let expected = expected.as_micros();
+                Some(cmp::max(actual, expected) - cmp::min(actual, expected))
+            }
+            _ => None,
+        };
+
+        self.samples[self.next] = Sample {
+            rssi,
+            missed,
+            jitter,
+            crc_errors: self.crc_errors,
+        };
+        self.next = (self.next + 1) % WINDOW;
+        self.len = cmp::min(self.len + 1, WINDOW);
+
+        self.crc_errors = 0;
+        if !reordered {
+            self.last_seq = seq;
+            self.last_arrival = Some((now, interval));
+        }
+    }
+
+    /// Summarizes the frames in the window.
+    pub fn summary(&self) -> Summary {
+        let samples = &self.samples[..self.len];
+        let mut summary = Summary {
+            received: self.len as u32,
+            crc_errors: u32::from(self.crc_errors),
+            ..Summary::default()
+        };
+        if samples.is_empty() {
+            return summary;
+        }
+
+        let (mut rssi_sum, mut rssi_count) = (0, 0);
+        let (mut jitter_sum, mut jitter_count) = (0, 0);
+        let mut rssi_min = i8::MAX;
+        for sample in samples {
+            summary.missed += u32::from(sample.missed);
+            summary.crc_errors += u32::from(sample.crc_errors);
+            if let Some(rssi) = sample.rssi {
+                rssi_sum += i32::from(rssi);
+                rssi_count += 1;
+                rssi_min = cmp::min(rssi_min, rssi);
+            }
+            if let Some(jitter) = sample.jitter {
+                jitter_sum += jitter;
+                jitter_count += 1;
+            }
+        }
+        if let Some(rssi) = rssi_sum.checked_div(rssi_count) {
+            summary.rssi = rssi as i8;
+            summary.rssi_min = rssi_min;
+        }
+        if let Some(jitter) = jitter_sum.checked_div(jitter_count) {
+            summary.jitter = jitter;
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::string::ToString};
+
+    #[test]
+    fn summary() {
+        let at = |ms: u32| Instant::from_raw_micros(ms * 1000);
+        let interval = Some(Duration::from_millis(20));
+        let mut stats = LinkStats::new();
+        assert_eq!(stats.summary(), Summary::default());
+
+        stats.frame(Some(250), Some(-40), interval, at(0));
+        stats.frame(Some(251), Some(-50), interval, at(21));
+        stats.crc_error();
+        stats.crc_error();
+        // 4 frames missing, across the wraparound
+        stats.frame(Some(0), Some(-60), interval, at(100));
+        stats.frame(Some(1), Some(-70), interval, at(119));
+        // Out of order
+        stats.frame(Some(0), Some(-70), interval, at(120));
+
+        let summary = stats.summary();
+        assert_eq!(
+            summary,
+            Summary {
+                received: 5,
+                missed: 4,
+                rssi: -58,
+                rssi_min: -70,
+                jitter: 1000,
+                crc_errors: 2,
+            }
+        );
+        assert_eq!(summary.loss_permille(), 444);
+        assert_eq!(
+            summary.to_string(),
+            "4 of 9 frames lost, rssi -58 dBm (min -70), jitter 1000 us, 2 crc errors"
+        );
+        assert_eq!(
+            summary.to_bytes(),
+            [5, 0, 4, 0, 0xC6, 0xBA, 0xE8, 0x03, 0, 0, 2, 0]
+        );
+    }
+
+    #[test]
+    fn unmeasured_rssi() {
+        let mut stats = LinkStats::new();
+        let at = Instant::from_raw_micros(0);
+        stats.frame(Some(0), Some(-40), None, at);
+        stats.frame(Some(1), None, None, at);
+        stats.frame(Some(2), Some(-60), None, at);
+        let summary = stats.summary();
+        assert_eq!(summary.received, 3);
+        assert_eq!((summary.rssi, summary.rssi_min), (-50, -60));
+
+        // Once the radio stops measuring it, the old RSSI drops out of the window
+        for seq in 3..3 + WINDOW as u8 {
+            stats.frame(Some(seq), None, None, at);
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.received, WINDOW as u32);
+        assert_eq!((summary.rssi, summary.rssi_min), (0, 0));
+    }
+
+    #[test]
+    fn window() {
+        let mut stats = LinkStats::new();
+        for seq in 0..WINDOW as u8 {
+            // Every other frame is missing
+            stats.frame(Some(seq * 2), Some(-40), None, Instant::from_raw_micros(0));
+        }
+        assert_eq!(stats.summary().missed, WINDOW as u32 - 1);
+
+        // Older frames drop out of the window
+        for seq in 0..WINDOW as u8 {
+            stats.frame(Some(64 + seq), Some(-80), None, Instant::from_raw_micros(0));
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.received, WINDOW as u32);
+        assert_eq!(summary.missed, 1);
+        assert_eq!(summary.rssi, -80);
+        assert_eq!(summary.jitter, 0);
+    }
+}
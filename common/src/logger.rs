@@ -0,0 +1,751 @@
+//! Log output.
+//!
+//! Log records are written to a `BbqLogger`, whose queue the firmware's `idle` sends out over the
+//! serial port. Records are either formatted on the device (`WriteLogger`), or, with the
+//! `binary-log` feature, sent in the binary format of `bluefly_binlog` (`BinaryLogger`), which
+//! `log-tool` decodes.
+
+use {
+    crate::{
+        crash::Crash,
+        gatt::{CHARACTERISTIC, CLIENT_CONFIG, PRIMARY_SERVICE, USER_DESCRIPTION},
+    },
+    bbqueue::Producer,
+    core::{
+        cell::RefCell,
+        fmt::{self, Write},
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    cortex_m::interrupt::{self, Mutex},
+    log::{Level, LevelFilter, Log, Metadata, Record},
+    rubble::{
+        att::{AttUuid, Attribute, Handle},
+        time::Timer,
+        uuid::Uuid128,
+        Error,
+    },
+};
+
+#[cfg(feature = "binary-log")]
+use bluefly_binlog::{
+    record::{self, MAX_RECORD_LEN, TEXT_ID},
+    Encode,
+};
+
+/// A `fmt::Write` adapter that prints a timestamp before each line.
+#[cfg(not(feature = "binary-log"))]
+pub struct StampedLogger<T: Timer, L: fmt::Write> {
+    timer: T,
+    inner: L,
+    /// Whether the next written character starts a new line.
+    ///
+    /// The timestamp is only printed once the line has content, so that a finished log record
+    /// doesn't leave the start of the next one behind in the output.
+    line_start: bool,
+}
+
+#[cfg(not(feature = "binary-log"))]
+impl<T: Timer, L: fmt::Write> StampedLogger<T, L> {
+    /// Creates a new `StampedLogger` that will print to `inner` and obtains timestamps using
+    /// `timer`.
+    pub fn new(inner: L, timer: T) -> Self {
+        Self {
+            inner,
+            timer,
+            line_start: true,
+        }
+    }
+}
+
+#[cfg(not(feature = "binary-log"))]
+impl<T: Timer, L: fmt::Write> fmt::Write for StampedLogger<T, L> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i != 0 {
+                self.inner.write_char('\n')?;
+                self.line_start = true;
+            }
+
+            if !line.is_empty() {
+                if self.line_start {
+                    write!(self.inner, "{} - ", self.timer.now())?;
+                    self.line_start = false;
+                }
+
+                self.inner.write_str(line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Total number of log messages dropped because the log buffer was full.
+pub static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a BLE client subscribed to the log stream characteristic of the `LogService`.
+///
+/// While set, the log goes to the client instead of the serial port. Subscriptions end with the
+/// connection, so this has to be cleared when the client disconnects.
+pub static STREAMING: AtomicBool = AtomicBool::new(false);
+
+/// Space reserved for the "N messages dropped" marker.
+const MARKER_LEN: usize = 32;
+
+/// A `fmt::Write` sink that writes to a `BBQueue`.
+///
+/// Logging must never block or crash the firmware, so when the `BBQueue` is full, messages are
+/// dropped instead. Each message ends with a newline. If a message doesn't fit, the rest of it is
+/// discarded. Once there's space again, a marker with the number of dropped messages is written
+/// before the next message.
+pub struct BbqLogger {
+    p: Producer,
+    /// Number of messages dropped since the last marker.
+    dropped: u32,
+    /// Whether the next Byte starts a new message.
+    message_start: bool,
+    /// Whether the rest of the current message is discarded.
+    discarding: bool,
+    /// Whether the last Byte written to the queue ended a line.
+    line_start: bool,
+}
+
+impl BbqLogger {
+    pub fn new(p: Producer) -> Self {
+        Self {
+            p,
+            dropped: 0,
+            message_start: true,
+            discarding: false,
+            line_start: true,
+        }
+    }
+
+    /// Writes as much of `bytes` as fits, returning the number of Bytes written.
+    fn write_bytes(&mut self, mut bytes: &[u8]) -> usize {
+        let len = bytes.len();
+
+        while !bytes.is_empty() {
+            let mut grant = match self.p.grant_max(bytes.len()) {
+                Ok(grant) => grant,
+                Err(_) => break,
+            };
+            let size = grant.buf().len();
+            grant.buf().copy_from_slice(&bytes[..size]);
+            bytes = &bytes[size..];
+            self.p.commit(size, grant);
+        }
+
+        len - bytes.len()
+    }
+
+    /// Writes the "N messages dropped" marker, if messages were dropped.
+    ///
+    /// Returns `false` if there's no space for the marker yet.
+    fn write_marker(&mut self) -> bool {
+        if self.dropped == 0 {
+            return true;
+        }
+
+        let mut buf = [0; MARKER_LEN];
+        let mut writer = SliceWriter::new(&mut buf);
+        // A truncated message doesn't end its line
+        let newline = if self.line_start { "" } else { "\n" };
+        writeln!(writer, "{}{} messages dropped", newline, self.dropped).ok();
+        let len = writer.len();
+
+        // Only written as a whole, so it's never truncated itself
+        match self.p.grant(len) {
+            Ok(mut grant) => {
+                grant.buf().copy_from_slice(&buf[..len]);
+                self.p.commit(len, grant);
+                self.dropped = 0;
+                self.line_start = true;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Writes a binary log record, or drops it if it doesn't fit as a whole.
+    #[cfg(feature = "binary-log")]
+    pub fn write_record(&mut self, record: &[u8]) {
+        if !self.write_marker() {
+            self.drop_message(true);
+            return;
+        }
+
+        match self.p.grant(record.len()) {
+            Ok(mut grant) => {
+                grant.buf().copy_from_slice(record);
+                self.p.commit(record.len(), grant);
+            }
+            Err(_) => self.drop_message(true),
+        }
+    }
+
+    /// Drops the message currently being written.
+    fn drop_message(&mut self, ends_message: bool) {
+        self.dropped = self.dropped.saturating_add(1);
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        self.discarding = !ends_message;
+    }
+}
+
+impl fmt::Write for BbqLogger {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s;
+        while !rest.is_empty() {
+            let end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+            let (piece, tail) = rest.split_at(end);
+            rest = tail;
+            let ends_message = piece.ends_with('\n');
+            let message_start = self.message_start;
+            self.message_start = ends_message;
+
+            if self.discarding {
+                self.discarding = !ends_message;
+                continue;
+            }
+            if message_start && !self.write_marker() {
+                self.drop_message(ends_message);
+                continue;
+            }
+
+            let written = self.write_bytes(piece.as_bytes());
+            if written == piece.len() {
+                self.line_start = ends_message;
+            } else {
+                if written != 0 {
+                    self.line_start = false;
+                }
+                self.drop_message(ends_message);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A `fmt::Write` sink for formatting into a small buffer.
+///
+/// Output that doesn't fit is cut off at a character boundary.
+pub struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// Returns the number of Bytes written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether nothing was written yet.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the output written so far.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<'a> fmt::Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Maximum number of modules a `Filter` can set a level for.
+const MAX_MODULES: usize = 4;
+
+/// Maximum length of a module path in a `Filter`.
+const MODULE_LEN: usize = 32;
+
+/// A module path with the level to log it at.
+#[derive(Copy, Clone)]
+struct ModuleLevel {
+    path: [u8; MODULE_LEN],
+    len: u8,
+    level: LevelFilter,
+}
+
+impl ModuleLevel {
+    fn path(&self) -> &str {
+        // Only ever copied from a `&str`
+        core::str::from_utf8(&self.path[..usize::from(self.len)]).unwrap_or("")
+    }
+
+    /// Returns whether `target` is the module or one of its submodules.
+    fn matches(&self, target: &str) -> bool {
+        let path = self.path();
+        target.starts_with(path)
+            && (target.len() == path.len() || target[path.len()..].starts_with("::"))
+    }
+}
+
+/// Decides which log records are written, based on the module they're logged from.
+///
+/// A filter has a default level and overrides it for up to 4 modules, including their
+/// submodules. It's written as comma-separated directives, like `warn,controller::relay=debug`:
+/// a bare level sets the default, `<module>=<level>` the level of a module.
+#[derive(Copy, Clone)]
+pub struct Filter {
+    level: LevelFilter,
+    modules: [Option<ModuleLevel>; MAX_MODULES],
+}
+
+impl Filter {
+    /// Creates a filter that logs every module at `level`.
+    pub const fn new(level: LevelFilter) -> Self {
+        Self {
+            level,
+            modules: [None; MAX_MODULES],
+        }
+    }
+
+    /// Parses a filter from its directives.
+    pub fn parse(spec: &str) -> Result<Self, FilterError> {
+        let mut filter = Self::new(LevelFilter::Info);
+        filter.apply(spec)?;
+        Ok(filter)
+    }
+
+    /// Applies directives to the filter, keeping the levels they don't change.
+    ///
+    /// The filter is left unchanged if the directives are invalid.
+    pub fn apply(&mut self, spec: &str) -> Result<(), FilterError> {
+        let mut filter = *self;
+        for directive in spec.split(',').filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(level), None) => filter.level = parse_level(level)?,
+                (Some(path), Some(level)) => filter.set_module(path, parse_level(level)?)?,
+                _ => return Err(FilterError::InvalidLevel),
+            }
+        }
+
+        *self = filter;
+        Ok(())
+    }
+
+    fn set_module(&mut self, path: &str, level: LevelFilter) -> Result<(), FilterError> {
+        if path.is_empty() || path.len() > MODULE_LEN {
+            return Err(FilterError::InvalidModule);
+        }
+
+        let slot = match self
+            .modules
+            .iter()
+            .position(|m| m.is_some_and(|m| m.path() == path))
+        {
+            Some(i) => i,
+            None => self
+                .modules
+                .iter()
+                .position(Option::is_none)
+                .ok_or(FilterError::TooManyModules)?,
+        };
+
+        let mut module = ModuleLevel {
+            path: [0; MODULE_LEN],
+            len: path.len() as u8,
+            level,
+        };
+        module.path[..path.len()].copy_from_slice(path.as_bytes());
+        self.modules[slot] = Some(module);
+        Ok(())
+    }
+
+    /// Returns the level records from `target` are logged at.
+    ///
+    /// If several modules match, the most specific one wins.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter_map(|m| m.as_ref())
+            .filter(|m| m.matches(target))
+            .max_by_key(|m| m.len)
+            .map_or(self.level, |m| m.level)
+    }
+
+    /// Returns the most verbose level any module is logged at.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter_map(|m| m.as_ref())
+            .map(|m| m.level)
+            .fold(self.level, core::cmp::max)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.level)?;
+        for module in self.modules.iter().filter_map(|m| m.as_ref()) {
+            write!(f, ",{}={}", module.path(), module.level)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, FilterError> {
+    level.parse().map_err(|_| FilterError::InvalidLevel)
+}
+
+/// Error returned when filter directives are invalid.
+#[derive(Debug)]
+pub enum FilterError {
+    InvalidLevel,
+    InvalidModule,
+    TooManyModules,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterError::InvalidLevel => write!(f, "invalid log level"),
+            FilterError::InvalidModule => {
+                write!(f, "module path must be 1 to {} characters", MODULE_LEN)
+            }
+            FilterError::TooManyModules => {
+                write!(f, "at most {} modules can have a level", MAX_MODULES)
+            }
+        }
+    }
+}
+
+/// The filter used by `WriteLogger`.
+static FILTER: Mutex<RefCell<Filter>> = Mutex::new(RefCell::new(Filter::new(LevelFilter::Info)));
+
+/// Returns the current log filter.
+pub fn filter() -> Filter {
+    interrupt::free(|cs| *FILTER.borrow(cs).borrow())
+}
+
+/// Replaces the log filter.
+///
+/// The `log` crate's maximum level is updated as well, so the logging macros skip disabled levels
+/// without calling the logger.
+pub fn set_filter(filter: Filter) {
+    interrupt::free(|cs| *FILTER.borrow(cs).borrow_mut() = filter);
+    log::set_max_level(filter.max_level());
+}
+
+/// Returns whether records of `level` from the module `target` pass the log filter.
+fn enabled(level: Level, target: &str) -> bool {
+    level <= interrupt::free(|cs| FILTER.borrow(cs).borrow().level(target))
+}
+
+/// Wraps a `fmt::Write` implementor and forwards the `log` crates logging macros to it.
+///
+/// The inner `fmt::Write` is made `Sync` by wrapping it in a `Mutex` from the `cortex_m` crate.
+/// Records are checked against the log filter (see `set_filter`) before they're formatted.
+#[cfg(not(feature = "binary-log"))]
+pub struct WriteLogger<W: fmt::Write + Send> {
+    writer: Mutex<RefCell<W>>,
+}
+
+#[cfg(not(feature = "binary-log"))]
+impl<W: fmt::Write + Send> WriteLogger<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(RefCell::new(writer)),
+        }
+    }
+}
+
+#[cfg(not(feature = "binary-log"))]
+impl<W: fmt::Write + Send> Log for WriteLogger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        enabled(metadata.level(), metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            interrupt::free(|cs| {
+                let mut writer = self.writer.borrow(cs).borrow_mut();
+                // The writer drops messages it has no space for, so errors can be ignored
+                writeln!(writer, "{} - {}", record.level(), record.args()).ok();
+            })
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Space for the text of records from the `log` crate in binary mode.
+#[cfg(feature = "binary-log")]
+const TEXT_LEN: usize = 48;
+
+/// Writes log records in the binary format of `bluefly_binlog` to a `BbqLogger`.
+///
+/// Records from the `bluefly_binlog` macros aren't formatted on the device. Records from the `log`
+/// crate (eg. from rubble) are formatted and sent as text records.
+#[cfg(feature = "binary-log")]
+pub struct BinaryLogger<T: Timer + Send> {
+    inner: Mutex<RefCell<(T, BbqLogger)>>,
+}
+
+#[cfg(feature = "binary-log")]
+impl<T: Timer + Send> BinaryLogger<T> {
+    pub fn new(sink: BbqLogger, timer: T) -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new((timer, sink))),
+        }
+    }
+
+    fn write(&self, level: Level, id: u16, args: &[&dyn Encode]) {
+        interrupt::free(|cs| {
+            let (timer, sink) = &mut *self.inner.borrow(cs).borrow_mut();
+            let mut buf = [0; MAX_RECORD_LEN];
+            let len = record::encode(&mut buf, level, id, timer.now().raw_micros(), args);
+            sink.write_record(&buf[..len]);
+        })
+    }
+}
+
+#[cfg(feature = "binary-log")]
+impl<T: Timer + Send> Log for BinaryLogger<T> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        enabled(metadata.level(), metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let mut buf = [0; TEXT_LEN];
+            let mut text = SliceWriter::new(&mut buf);
+            write!(text, "{}", record.args()).ok();
+            self.write(record.level(), TEXT_ID, &[&text.as_str()]);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(feature = "binary-log")]
+impl<T: Timer + Send> bluefly_binlog::Logger for BinaryLogger<T> {
+    fn enabled(&self, level: Level, target: &str) -> bool {
+        enabled(level, target)
+    }
+
+    fn log(&self, record: &bluefly_binlog::Record) {
+        self.write(record.level, record.id, record.args);
+    }
+}
+
+/// UUID of the log service (`b1ef1b00-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const SERVICE_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x00, 0x1B, 0xEF, 0xB1,
+];
+
+/// UUID of the log filter characteristic (`b1ef1b01-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const FILTER_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01, 0x1B, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: read and write, value handle 7, followed by the UUID.
+const FILTER_DECL: [u8; 19] = [
+    0x0A, 0x07, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01,
+    0x1B, 0xEF, 0xB1,
+];
+
+const FILTER_DESC: &[u8] = b"Log filter";
+
+/// UUID of the log stream characteristic (`b1ef1b02-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const STREAM_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x02, 0x1B, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: notify, value handle 10, followed by the UUID.
+const STREAM_DECL: [u8; 19] = [
+    0x10, 0x0A, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x02,
+    0x1B, 0xEF, 0xB1,
+];
+
+const STREAM_DESC: &[u8] = b"Log stream";
+
+/// UUID of the crash report characteristic (`b1ef1b03-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const CRASH_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x03, 0x1B, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: read, value handle 14, followed by the UUID.
+const CRASH_DECL: [u8; 19] = [
+    0x02, 0x0E, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x03,
+    0x1B, 0xEF, 0xB1,
+];
+
+const CRASH_DESC: &[u8] = b"Last crash";
+
+/// Length of the longest crash report (see `crash::Crash`).
+const CRASH_LEN: usize = 176;
+
+/// Length of the longest possible filter: a level, and a directive for every module.
+const FILTER_LEN: usize = 5 + MAX_MODULES * (MODULE_LEN + 7);
+
+/// The log service of the receiver's GATT server. The controller doesn't accept BLE connections.
+///
+/// Reading the filter characteristic returns the current log filter. Writing directives to it
+/// applies them to the filter, like `log level` on the shell does.
+///
+/// A client subscribing to notifications of the log stream characteristic receives the log output
+/// (see `STREAMING`). The crash report characteristic describes the crash
+/// before the last reset, if there was one.
+pub struct LogService {
+    filter: [u8; FILTER_LEN],
+    filter_len: usize,
+    crash: [u8; CRASH_LEN],
+    crash_len: usize,
+    /// The last attribute of the service, which ends its group.
+    description: Attribute<'static>,
+}
+
+impl LogService {
+    /// Handle of the service declaration.
+    pub const FIRST_HANDLE: u16 = 5;
+    /// Handle of the last attribute of the service.
+    pub const LAST_HANDLE: u16 = 15;
+    /// Handle of the log stream characteristic's value, which log output is notified on.
+    pub const STREAM_HANDLE: u16 = 10;
+
+    pub fn new(crash: Option<&Crash>) -> Self {
+        let mut service = Self {
+            filter: [0; FILTER_LEN],
+            filter_len: 0,
+            crash: [0; CRASH_LEN],
+            crash_len: 0,
+            description: Attribute::new(USER_DESCRIPTION.into(), Handle::from_raw(15), CRASH_DESC),
+        };
+        service.refresh();
+
+        if let Some(crash) = crash {
+            let mut writer = SliceWriter::new(&mut service.crash);
+            write!(writer, "{}", crash).ok();
+            service.crash_len = writer.len();
+        }
+
+        service
+    }
+
+    /// Updates the value of the filter characteristic to the current filter.
+    pub fn refresh(&mut self) {
+        let mut writer = SliceWriter::new(&mut self.filter);
+        write!(writer, "{}", filter()).ok();
+        self.filter_len = writer.len();
+    }
+
+    pub fn attribute(&self, handle: u16) -> Option<Attribute<'_>> {
+        let (uuid, value): (AttUuid, &[u8]) = match handle {
+            5 => (PRIMARY_SERVICE.into(), &SERVICE_UUID),
+            6 => (CHARACTERISTIC.into(), &FILTER_DECL),
+            7 => (
+                Uuid128::from_bytes(FILTER_UUID).into(),
+                &self.filter[..self.filter_len],
+            ),
+            8 => (USER_DESCRIPTION.into(), FILTER_DESC),
+            9 => (CHARACTERISTIC.into(), &STREAM_DECL),
+            // Log output is only sent in notifications
+            10 => (Uuid128::from_bytes(STREAM_UUID).into(), &[]),
+            11 if STREAMING.load(Ordering::Relaxed) => (CLIENT_CONFIG.into(), &[0x01, 0x00]),
+            11 => (CLIENT_CONFIG.into(), &[0x00, 0x00]),
+            12 => (USER_DESCRIPTION.into(), STREAM_DESC),
+            13 => (CHARACTERISTIC.into(), &CRASH_DECL),
+            14 => (
+                Uuid128::from_bytes(CRASH_UUID).into(),
+                &self.crash[..self.crash_len],
+            ),
+            15 => return Some(self.description.clone()),
+            _ => return None,
+        };
+
+        Some(Attribute::new(uuid, Handle::from_raw(handle), value))
+    }
+
+    /// Returns the last attribute of the service, which ends its group.
+    pub fn group_end(&self) -> &Attribute<'_> {
+        &self.description
+    }
+
+    pub fn is_writeable(&self, handle: u16) -> bool {
+        handle == 7 || handle == 11
+    }
+
+    pub fn write(&mut self, handle: u16, data: &[u8]) -> Result<(), Error> {
+        match (handle, data) {
+            (7, _) => {}
+            // The client characteristic configuration; bit 0 enables notifications
+            (11, &[flags, _]) => {
+                STREAMING.store(flags & 0x01 != 0, Ordering::Relaxed);
+                return Ok(());
+            }
+            _ => return Err(Error::InvalidValue),
+        }
+
+        let spec = core::str::from_utf8(data).map_err(|_| Error::InvalidValue)?;
+        let mut filter = filter();
+        filter.apply(spec).map_err(|_| Error::InvalidValue)?;
+        set_filter(filter);
+        self.refresh();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::string::ToString};
+
+    #[test]
+    fn filter_levels() {
+        let filter =
+            Filter::parse("warn,controller::relay=debug,controller=error,rubble=off").unwrap();
+        assert_eq!(filter.level("receiver"), LevelFilter::Warn);
+        assert_eq!(filter.level("controller"), LevelFilter::Error);
+        assert_eq!(filter.level("controller::dfu"), LevelFilter::Error);
+        assert_eq!(filter.level("controller::relay"), LevelFilter::Debug);
+        assert_eq!(filter.level("controller::relayed"), LevelFilter::Error);
+        assert_eq!(filter.level("rubble::link"), LevelFilter::Off);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+        assert_eq!(
+            filter.to_string(),
+            "WARN,controller::relay=DEBUG,controller=ERROR,rubble=OFF"
+        );
+    }
+
+    #[test]
+    fn filter_apply() {
+        let mut filter = Filter::parse("rubble=off").unwrap();
+        filter.apply("debug,rubble=info").unwrap();
+        assert_eq!(filter.to_string(), "DEBUG,rubble=INFO");
+
+        // Invalid directives leave the filter unchanged
+        assert!(filter.apply("trace,rubble=loud").is_err());
+        assert!(filter.apply("=info").is_err());
+        assert!(filter.apply("a=info,b=info,c=info,d=info").is_err());
+        assert_eq!(filter.to_string(), "DEBUG,rubble=INFO");
+    }
+
+    #[test]
+    fn slice_writer_truncates() {
+        let mut buf = [0; 4];
+        let mut writer = SliceWriter::new(&mut buf);
+        write!(writer, "ab€").unwrap();
+        assert_eq!(writer.len(), 2);
+        assert_eq!(&buf[..2], b"ab");
+    }
+}
@@ -0,0 +1,433 @@
+//! The radio mode of the beacon link: the PHY and the TX power.
+//!
+//! The TX power is up to each device. The PHY has to be the same on both sides, so it's negotiated:
+//! the controller requests the PHY it's configured for in every throttle beacon (see
+//! `protocol::throttle_frame`), and the receiver answers with the one it agrees to. Answers are
+//! beacons from the receiver's address carrying `answer_frame`, sent once the controller is done
+//! sending the beacon. The controller switches to the agreed PHY once it receives the answer. The
+//! receiver switches after sending it, but only commits to the new PHY once it hears the
+//! controller's next request on it: if it doesn't within `CONFIRM_BEACONS` beacon intervals, the
+//! answer got lost and the controller is still on the old PHY, so the receiver goes back to it
+//! before its failsafe kicks in, and answers again.
+//!
+//! The 2M PHY halves the time on air, but needs a stronger signal than 1M. The receiver only
+//! agrees to it while the signal is strong enough (see `Answering`), and repeats its answer every
+//! `ANSWER_INTERVAL_SECS` while on 2M, so the controller knows it's still heard. Both sides start
+//! out on 1M, and fall back to it when the link is lost: the receiver when its failsafe kicks in,
+//! the controller when it goes without an answer for `ANSWER_TIMEOUT_SECS`.
+//!
+//! The nRF52810 doesn't support the Coded PHY. Phones only see the devices' advertising on 1M, so
+//! the GATT services can't be reached on 2M.
+
+use {
+    core::cmp,
+    rubble::time::{Duration, Instant},
+};
+
+/// The PHY the radio sends and receives with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phy {
+    Ble1M,
+    Ble2M,
+}
+
+impl Phy {
+    /// Returns the PHY for a `radio.phy` configuration value (1 or 2, in Mbit/s).
+    pub fn from_config(value: u32) -> Self {
+        if value == 2 {
+            Phy::Ble2M
+        } else {
+            Phy::Ble1M
+        }
+    }
+
+    /// Returns the code of the PHY in beacons.
+    pub fn code(self) -> u8 {
+        match self {
+            Phy::Ble1M => 1,
+            Phy::Ble2M => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Phy::Ble1M),
+            2 => Some(Phy::Ble2M),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the PHY, for log messages and the shell.
+    pub fn name(self) -> &'static str {
+        match self {
+            Phy::Ble1M => "1M",
+            Phy::Ble2M => "2M",
+        }
+    }
+}
+
+/// TX powers supported by the nRF52810, in dBm, from lowest to highest.
+pub const TX_POWERS: [i8; 10] = [-40, -30, -20, -16, -12, -8, -4, 0, 3, 4];
+
+/// Returns the TX power for a `radio.tx_level` configuration value, which indexes `TX_POWERS`.
+pub fn tx_power(level: u32) -> i8 {
+    TX_POWERS[cmp::min(level as usize, TX_POWERS.len() - 1)]
+}
+
+/// Settings of the radio that can be changed at runtime.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RadioMode {
+    pub phy: Phy,
+    /// TX power in dBm, one of `TX_POWERS`.
+    pub tx_power: i8,
+}
+
+impl RadioMode {
+    /// The mode the radio starts out in.
+    pub const DEFAULT: Self = Self {
+        phy: Phy::Ble1M,
+        tx_power: 4,
+    };
+}
+
+/// First Byte of mode answers, which tells them apart from relay frames.
+pub const ANSWER_TAG: u8 = 0xB2;
+
+/// Time after which the receiver repeats its answer while on 2M.
+pub const ANSWER_INTERVAL_SECS: u32 = 1;
+
+/// Time without an answer after which the controller falls back to 1M.
+pub const ANSWER_TIMEOUT_SECS: u32 = 3;
+
+/// Time after receiving a throttle beacon after which the receiver answers it, in microseconds.
+/// By then, the controller is done sending the copies of the beacon (see `crate::redundancy`), and
+/// listens for answers.
+pub const ANSWER_DELAY_MICROS: u32 = 2000;
+
+/// Number of beacon intervals the receiver waits to hear the controller on the PHY it answered
+/// with, before it assumes the answer got lost and goes back to the PHY before. This is less than
+/// the receiver's failsafe timeout (see `output::DEFAULT_FAILSAFE_BEACONS`).
+pub const CONFIRM_BEACONS: u32 = 2;
+
+/// Time the receiver waits to hear the controller on the PHY it answered with, if the beacon it
+/// answered didn't advertise an interval, in microseconds.
+pub const CONFIRM_DEFAULT_MICROS: u32 = 80_000;
+
+/// Weakest signal the receiver agrees to switch to 2M at, in dBm.
+pub const MIN_2M_RSSI: i8 = -80;
+
+/// Weakest signal the receiver stays on 2M at, in dBm. Below this, it switches back to 1M before
+/// the link is lost.
+pub const KEEP_2M_RSSI: i8 = -88;
+
+/// Encodes the receiver's answer that it agrees to `phy`.
+pub fn answer_frame(phy: Phy) -> [u8; 2] {
+    [ANSWER_TAG, phy.code()]
+}
+
+/// Decodes the PHY from a mode answer, or returns `None` if `frame` isn't one.
+pub fn answer(frame: &[u8]) -> Option<Phy> {
+    match frame {
+        [ANSWER_TAG, code] => Phy::from_code(*code),
+        _ => None,
+    }
+}
+
+fn secs_since(now: Instant, earlier: Instant) -> u32 {
+    now.raw_micros().wrapping_sub(earlier.raw_micros()) / 1_000_000
+}
+
+/// The controller's side of the negotiation.
+pub struct Negotiation {
+    phy: Phy,
+    last_answer: Option<Instant>,
+}
+
+impl Default for Negotiation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Negotiation {
+    pub const fn new() -> Self {
+        Self {
+            phy: Phy::Ble1M,
+            last_answer: None,
+        }
+    }
+
+    /// Returns the PHY to use.
+    pub fn phy(&self) -> Phy {
+        self.phy
+    }
+
+    /// Handles an answer of the receiver received at `now`, and returns the PHY to switch to if it
+    /// changed.
+    pub fn answer(&mut self, phy: Phy, now: Instant) -> Option<Phy> {
+        self.last_answer = Some(now);
+        self.switch(phy)
+    }
+
+    /// Falls back to 1M if the receiver didn't answer for too long, and returns it if the PHY
+    /// changed.
+    pub fn update(&mut self, now: Instant) -> Option<Phy> {
+        match self.last_answer {
+            Some(last) if secs_since(now, last) < ANSWER_TIMEOUT_SECS => None,
+            _ => self.switch(Phy::Ble1M),
+        }
+    }
+
+    fn switch(&mut self, phy: Phy) -> Option<Phy> {
+        if phy == self.phy {
+            None
+        } else {
+            self.phy = phy;
+            Some(phy)
+        }
+    }
+}
+
+/// A PHY the receiver answered with, which the controller hasn't been heard on yet.
+#[derive(Copy, Clone)]
+struct Trial {
+    phy: Phy,
+    /// When the beacon answered was received.
+    since: Instant,
+    /// Time after `since` by which the controller should have been heard on `phy`, in
+    /// microseconds.
+    within: u32,
+}
+
+/// The receiver's side of the negotiation.
+pub struct Answering {
+    /// The PHY agreed on with the controller.
+    phy: Phy,
+    /// The answer to send, until it's taken.
+    pending: Option<Trial>,
+    /// The PHY answered with, until the controller is heard on it.
+    trial: Option<Trial>,
+    last_answer: Option<Instant>,
+}
+
+impl Default for Answering {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Answering {
+    pub const fn new() -> Self {
+        Self {
+            phy: Phy::Ble1M,
+            pending: None,
+            trial: None,
+            last_answer: None,
+        }
+    }
+
+    /// Returns the PHY to listen on.
+    pub fn phy(&self) -> Phy {
+        self.trial.map_or(self.phy, |trial| trial.phy)
+    }
+
+    /// Handles a throttle beacon requesting `requested`, received at `now` with `rssi`, which
+    /// advertised `interval` until the next one. `allowed` is the fastest PHY the receiver is
+    /// configured for.
+    ///
+    /// If an answer is due, it's returned by `take_answer` once the controller listens for it.
+    pub fn request(
+        &mut self,
+        requested: Phy,
+        allowed: Phy,
+        rssi: i8,
+        interval: Option<Duration>,
+        now: Instant,
+    ) {
+        // The receiver listens on the PHY it answered with, so the controller got the answer
+        if let Some(trial) = self.trial.take() {
+            self.phy = trial.phy;
+        }
+
+        let current = self.pending.map_or(self.phy, |pending| pending.phy);
+        let strong_enough = match current {
+            Phy::Ble1M => rssi >= MIN_2M_RSSI,
+            Phy::Ble2M => rssi >= KEEP_2M_RSSI,
+        };
+        let agreed = if strong_enough {
+            cmp::min(requested, allowed)
+        } else {
+            Phy::Ble1M
+        };
+
+        let due = match self.last_answer {
+            _ if agreed != current => true,
+            Some(last) => agreed != Phy::Ble1M && secs_since(now, last) >= ANSWER_INTERVAL_SECS,
+            None => agreed != Phy::Ble1M,
+        };
+        if due {
+            let within = interval.map_or(CONFIRM_DEFAULT_MICROS, |interval| {
+                interval.as_micros().saturating_mul(CONFIRM_BEACONS)
+            });
+            self.pending = Some(Trial {
+                phy: agreed,
+                since: now,
+                within,
+            });
+            self.last_answer = Some(now);
+        }
+    }
+
+    /// Returns the PHY to answer with, once the controller is done sending the beacon that
+    /// requested it. After sending the answer, the receiver listens on that PHY, which `phy`
+    /// returns from now on, unless `update` finds that the controller didn't get the answer.
+    pub fn take_answer(&mut self, now: Instant) -> Option<Phy> {
+        match self.pending {
+            Some(pending)
+                if now.raw_micros().wrapping_sub(pending.since.raw_micros())
+                    >= ANSWER_DELAY_MICROS =>
+            {
+                self.pending = None;
+                if pending.phy != self.phy {
+                    self.trial = Some(pending);
+                }
+                Some(pending.phy)
+            }
+            _ => None,
+        }
+    }
+
+    /// Goes back to the PHY agreed on before if the controller wasn't heard on the one answered
+    /// with in time, since it then didn't get the answer. Returns the PHY if it changed.
+    pub fn update(&mut self, now: Instant) -> Option<Phy> {
+        match self.trial {
+            Some(trial)
+                if now.raw_micros().wrapping_sub(trial.since.raw_micros()) > trial.within =>
+            {
+                self.trial = None;
+                Some(self.phy)
+            }
+            _ => None,
+        }
+    }
+
+    /// Falls back to 1M after the link was lost, and returns it if the PHY changed.
+    pub fn link_lost(&mut self) -> Option<Phy> {
+        let listening = self.phy();
+        self.pending = None;
+        self.trial = None;
+        self.phy = Phy::Ble1M;
+        if listening == Phy::Ble1M {
+            None
+        } else {
+            Some(Phy::Ble1M)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_raw_micros(ms * 1000)
+    }
+
+    #[test]
+    fn config() {
+        assert_eq!(Phy::from_config(2), Phy::Ble2M);
+        assert_eq!(Phy::from_config(1), Phy::Ble1M);
+        assert_eq!(tx_power(0), -40);
+        assert_eq!(tx_power(9), 4);
+        assert_eq!(tx_power(100), 4);
+        assert_eq!(answer(&answer_frame(Phy::Ble2M)), Some(Phy::Ble2M));
+        assert_eq!(answer(&[ANSWER_TAG, 3]), None);
+        assert_eq!(answer(&[0xB1, 2]), None);
+    }
+
+    #[test]
+    fn negotiation() {
+        let (mut controller, mut receiver) = (Negotiation::new(), Answering::new());
+        let (fast, slow) = (Phy::Ble2M, Phy::Ble1M);
+        let interval = Some(Duration::from_millis(20));
+        let mut request = |requested, allowed, rssi, ms| {
+            receiver.request(requested, allowed, rssi, interval, at(ms));
+            assert_eq!(receiver.take_answer(at(ms + 1)), None);
+            let answer = receiver.take_answer(at(ms + 2));
+            (answer, receiver.phy())
+        };
+
+        // Nothing to negotiate on 1M
+        assert_eq!(request(slow, fast, -50, 0), (None, slow));
+        // Not while the signal is weak
+        assert_eq!(request(fast, fast, -81, 20), (None, slow));
+        // Nor if the receiver doesn't allow it
+        assert_eq!(request(fast, slow, -50, 40), (None, slow));
+
+        assert_eq!(request(fast, fast, -80, 60), (Some(fast), fast));
+        assert_eq!(controller.answer(fast, at(62)), Some(fast));
+        assert_eq!(controller.update(at(1000)), None);
+
+        // Answers are repeated every second while on 2M
+        assert_eq!(request(fast, fast, -85, 80), (None, fast));
+        assert_eq!(request(fast, fast, -85, 1060), (Some(fast), fast));
+        assert_eq!(controller.answer(fast, at(1062)), None);
+
+        // The signal gets too weak
+        assert_eq!(request(fast, fast, -89, 1080), (Some(slow), slow));
+        assert_eq!(controller.answer(slow, at(1082)), Some(slow));
+
+        // Back on 2M, until the link is lost
+        assert_eq!(request(fast, fast, -50, 2000), (Some(fast), fast));
+        assert_eq!(controller.answer(fast, at(2002)), Some(fast));
+        let mut receiver = Answering::new();
+        receiver.request(fast, fast, -50, interval, at(0));
+        assert_eq!(receiver.take_answer(at(2)), Some(fast));
+        assert_eq!(receiver.link_lost(), Some(slow));
+        assert_eq!(receiver.link_lost(), None);
+        assert_eq!(controller.update(at(5001)), None);
+        assert_eq!(controller.update(at(5003)), Some(slow));
+        assert_eq!(controller.update(at(6000)), None);
+    }
+
+    #[test]
+    fn lost_answer() {
+        let (fast, slow) = (Phy::Ble2M, Phy::Ble1M);
+        let interval = Some(Duration::from_millis(20));
+        let mut receiver = Answering::new();
+
+        // The receiver listens on 2M after answering, but the controller didn't get the answer and
+        // stays on 1M. The receiver goes back to 1M before the failsafe kicks in.
+        receiver.request(fast, fast, -50, interval, at(0));
+        assert_eq!(receiver.take_answer(at(2)), Some(fast));
+        assert_eq!(receiver.phy(), fast);
+        assert_eq!(receiver.update(at(40)), None);
+        assert_eq!(receiver.update(at(41)), Some(slow));
+        assert_eq!(receiver.phy(), slow);
+        assert_eq!(receiver.update(at(60)), None);
+
+        // It answers the next request again, and commits once it hears the controller on 2M
+        receiver.request(fast, fast, -50, interval, at(60));
+        assert_eq!(receiver.take_answer(at(62)), Some(fast));
+        receiver.request(fast, fast, -50, interval, at(80));
+        assert_eq!(receiver.take_answer(at(82)), None);
+        assert_eq!(receiver.update(at(1000)), None);
+        assert_eq!(receiver.phy(), fast);
+
+        // The same goes for going back to 1M
+        receiver.request(fast, fast, -89, interval, at(1020));
+        assert_eq!(receiver.take_answer(at(1022)), Some(slow));
+        assert_eq!(receiver.phy(), slow);
+        assert_eq!(receiver.update(at(1061)), Some(fast));
+        assert_eq!(receiver.phy(), fast);
+
+        // Losing the link while waiting falls back to 1M
+        receiver.request(fast, fast, -89, interval, at(1080));
+        assert_eq!(receiver.take_answer(at(1082)), Some(slow));
+        receiver.request(fast, fast, -50, None, at(1100));
+        assert_eq!(receiver.take_answer(at(1102)), Some(fast));
+        assert_eq!(receiver.link_lost(), Some(slow));
+        assert_eq!(receiver.update(at(2000)), None);
+        assert_eq!(receiver.phy(), slow);
+    }
+}
@@ -0,0 +1,262 @@
+//! Turning received throttle values into the receiver's PWM output.
+//!
+//! `OutputMap` maps throttle values to compare values of the servo PWM driving the ESC, and
+//! `Failsafe` notices when the controller has gone quiet, so the output can be brought back to
+//! neutral. How long that takes depends on the beacon interval the controller advertises (see
+//! `failsafe_timeout`).
+
+use {
+    crate::ownership,
+    core::cmp,
+    rubble::time::{Duration, Instant},
+};
+
+/// Maps throttle values to PWM compare values.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OutputMap {
+    /// Compare value that stops the motor, used before the first throttle value is received and
+    /// when the link is lost.
+    pub neutral: u16,
+    /// Compare value for a throttle value of 0.
+    pub base: u16,
+    /// Compare ticks added per step of the throttle value.
+    pub scale: u16,
+    /// Highest compare value a throttle value is mapped to, limiting the speed of the current
+    /// riding mode.
+    pub limit: u16,
+    /// The PWM period in compare ticks, which the output never exceeds.
+    pub top: u16,
+}
+
+impl OutputMap {
+    /// The mapping the receiver's configuration starts out with.
+    pub const DEFAULT: Self = Self {
+        neutral: 7220,
+        base: 6990,
+        scale: 2,
+        limit: 7500,
+        top: 8000,
+    };
+
+    /// Returns the compare value for the throttle value `throttle`.
+    pub fn pwm(&self, throttle: u8) -> u16 {
+        let value = u32::from(self.base) + u32::from(throttle) * u32::from(self.scale);
+        cmp::min(value, u32::from(cmp::min(self.limit, self.top))) as u16
+    }
+}
+
+/// Minimum time without a throttle value after which the output goes back to neutral, by
+/// default.
+pub const DEFAULT_FAILSAFE_MS: u16 = 100;
+
+/// Number of advertised beacon intervals without a throttle value after which the output goes
+/// back to neutral, by default.
+pub const DEFAULT_FAILSAFE_BEACONS: u32 = 4;
+
+/// Maximum time without a throttle value after which the output goes back to neutral, however
+/// long the advertised beacon interval, by default.
+pub const DEFAULT_FAILSAFE_MAX_MS: u16 = 500;
+
+/// Lowest rate, in beacons per second, the controller may send throttle beacons at.
+///
+/// Slower beacons would need a failsafe timeout longer than `DEFAULT_FAILSAFE_MAX_MS` to allow for
+/// `DEFAULT_FAILSAFE_BEACONS` of their intervals, so the receiver would fail safe after losing
+/// fewer of them, down to none at 2 beacons per second.
+pub const MIN_BEACON_RATE: u32 = 1000 * DEFAULT_FAILSAFE_BEACONS / DEFAULT_FAILSAFE_MAX_MS as u32;
+
+/// Returns the failsafe timeout for a throttle value whose beacon advertised `interval` until the
+/// next one: `beacons` of those intervals, but no less than `min` and no more than `max`.
+///
+/// Beacons without an interval get `min`. If `max` is less than `min`, `max` wins, so the output
+/// never holds a throttle value for longer than that.
+pub fn failsafe_timeout(
+    interval: Option<Duration>,
+    beacons: u32,
+    min: Duration,
+    max: Duration,
+) -> Duration {
+    let timeout = interval.map_or(0, |interval| interval.as_micros().saturating_mul(beacons));
+    Duration::from_micros(cmp::min(
+        cmp::max(timeout, min.as_micros()),
+        max.as_micros(),
+    ))
+}
+
+/// Returns whether the receiver may write to its flash while the output is at the compare value
+/// `pwm`, given the `neutral` one.
+///
+/// Erasing a page stalls the CPU for about 85 ms, in which neither throttle values nor the
+/// failsafe reach the output, so flash is only written while the motor is stopped. Braking doesn't
+/// count as stopped (see `ownership::is_stopped`).
+pub fn may_write_flash(pwm: u16, neutral: u16) -> bool {
+    ownership::is_stopped(pwm, neutral)
+}
+
+/// Keeps track of when the last throttle value was received.
+pub struct Failsafe {
+    /// Time of the last throttle value and its timeout, or `None` before the first one and after
+    /// a timeout.
+    last_frame: Option<(Instant, Duration)>,
+}
+
+impl Default for Failsafe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Failsafe {
+    pub const fn new() -> Self {
+        Self { last_frame: None }
+    }
+
+    /// Records that a throttle value was received at `now`, which goes stale after `timeout`.
+    pub fn frame(&mut self, now: Instant, timeout: Duration) {
+        self.last_frame = Some((now, timeout));
+    }
+
+    /// Returns the timeout once the last throttle value has become stale, and then nothing until
+    /// the next one is received.
+    ///
+    /// The output should then be set to neutral. A throttle value received after `now`, by a task
+    /// that preempted the caller after it read the time, isn't stale.
+    pub fn check(&mut self, now: Instant) -> Option<Duration> {
+        match self.last_frame {
+            Some((last, timeout)) if is_after(now, last, timeout) => {
+                self.last_frame = None;
+                Some(timeout)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a throttle value was received within the last timeout.
+    pub fn is_linked(&self) -> bool {
+        self.last_frame.is_some()
+    }
+}
+
+/// Whether `now` is more than `timeout` after `earlier`, and not before it.
+fn is_after(now: Instant, earlier: Instant, timeout: Duration) -> bool {
+    let elapsed = now.raw_micros().wrapping_sub(earlier.raw_micros());
+    // Instants wrap around, so `earlier` being later shows as more than half the range elapsed
+    elapsed > timeout.as_micros() && elapsed <= u32::MAX / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping() {
+        let map = OutputMap::DEFAULT;
+        assert_eq!(map.pwm(0), 6990);
+        assert_eq!(map.pwm(115), 7220);
+        assert_eq!(map.pwm(255), 7500);
+
+        let map = OutputMap { limit: 7300, ..map };
+        assert_eq!(map.pwm(115), 7220);
+        assert_eq!(map.pwm(255), 7300);
+
+        let map = OutputMap {
+            scale: 10,
+            limit: 9000,
+            ..map
+        };
+        assert_eq!(map.pwm(255), 8000);
+    }
+
+    #[test]
+    fn failsafe() {
+        let at = |ms: u32| Instant::from_raw_micros(ms * 1000);
+        let timeout = Duration::from_millis(100);
+        let mut failsafe = Failsafe::new();
+
+        // Nothing to time out before the first frame
+        assert!(failsafe.check(at(500)).is_none());
+        assert!(!failsafe.is_linked());
+
+        failsafe.frame(at(1000), timeout);
+        assert!(failsafe.is_linked());
+        assert!(failsafe.check(at(1100)).is_none());
+        failsafe.frame(at(1100), timeout);
+        assert!(failsafe.check(at(1200)).is_none());
+        assert!(failsafe.check(at(1201)).is_some());
+        assert!(!failsafe.is_linked());
+        // Only reported once
+        assert!(failsafe.check(at(1300)).is_none());
+
+        // Each frame brings its own timeout
+        failsafe.frame(at(2000), Duration::from_millis(400));
+        assert!(failsafe.check(at(2400)).is_none());
+        assert!(failsafe.check(at(2401)).is_some());
+    }
+
+    #[test]
+    fn failsafe_preempted() {
+        let at = |us: u32| Instant::from_raw_micros(us);
+        let timeout = Duration::from_millis(100);
+        let mut failsafe = Failsafe::new();
+
+        // The radio task received a frame after idle read the time, but before it checked
+        failsafe.frame(at(1_000_000), timeout);
+        assert!(failsafe.check(at(999_990)).is_none());
+        assert!(failsafe.is_linked());
+        assert!(failsafe.check(at(1_100_000)).is_none());
+        assert!(failsafe.check(at(1_100_001)).is_some());
+
+        // Also across the wraparound of the timer
+        failsafe.frame(at(5), timeout);
+        assert!(failsafe.check(at(u32::MAX - 5)).is_none());
+        assert!(failsafe.is_linked());
+        failsafe.frame(at(u32::MAX - 5), timeout);
+        assert!(failsafe.check(at(99_000)).is_none());
+        assert!(failsafe.check(at(100_000)).is_some());
+    }
+
+    #[test]
+    fn flash_writes() {
+        let map = OutputMap::DEFAULT;
+        assert!(may_write_flash(map.neutral, map.neutral));
+        assert!(!may_write_flash(map.pwm(255), map.neutral));
+        // The vehicle usually still rolls while braking
+        assert!(!may_write_flash(map.pwm(0), map.neutral));
+        assert!(!may_write_flash(map.neutral - 1, map.neutral));
+    }
+
+    #[test]
+    fn slowest_beacons() {
+        let interval = Duration::from_micros(1_000_000 / MIN_BEACON_RATE);
+        let timeout = failsafe_timeout(
+            Some(interval),
+            DEFAULT_FAILSAFE_BEACONS,
+            Duration::from_millis(DEFAULT_FAILSAFE_MS),
+            Duration::from_millis(DEFAULT_FAILSAFE_MAX_MS),
+        );
+        assert_eq!(
+            timeout.as_micros(),
+            interval.as_micros() * DEFAULT_FAILSAFE_BEACONS
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let ms = |ms| Duration::from_millis(ms);
+        let timeout = |interval| failsafe_timeout(interval, 4, ms(100), ms(500)).as_micros() / 1000;
+        assert_eq!(timeout(None), 100);
+        assert_eq!(timeout(Some(ms(20))), 100);
+        assert_eq!(timeout(Some(ms(50))), 200);
+        // Slow beacons don't hold the throttle for longer than the maximum
+        assert_eq!(timeout(Some(ms(125))), 500);
+        assert_eq!(timeout(Some(ms(1000))), 500);
+        assert_eq!(
+            failsafe_timeout(Some(ms(1275)), 20, ms(100), ms(500)).as_micros() / 1000,
+            500
+        );
+        // Nor for longer than a maximum below the minimum
+        assert_eq!(
+            failsafe_timeout(None, 4, ms(100), ms(50)).as_micros() / 1000,
+            50
+        );
+    }
+}
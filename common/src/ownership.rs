@@ -0,0 +1,214 @@
+//! Which of the controllers a receiver is paired to drives it.
+//!
+//! A receiver can be paired to up to `protocol::MAX_CONTROLLERS` controllers, each sending from an
+//! address of its own (see `protocol::controller_address`). It only listens to the paired ones
+//! (see `PairedFilter`), and only one of them, the owner, drives the output at a time. The first
+//! paired controller heard becomes the owner. Another one only takes over once the owner released
+//! the receiver, which it does while it's idle (see `protocol::release`), or once the vehicle is
+//! stationary and the owner's link was lost or the other controller has priority. Controllers with
+//! lower numbers have priority over those with higher ones, so the owner of a shared vehicle can
+//! always take it back once it stands still.
+//!
+//! The vehicle counts as stationary once the output has been stopped for `STATIONARY_SECS`, since
+//! the receiver can't tell whether it's still rolling. Only the neutral output counts as stopped
+//! (see `is_stopped`), since the vehicle usually still rolls while braking. Controllers releasing
+//! the receiver never take it over.
+
+use {
+    crate::protocol,
+    rubble::{
+        link::{filter::AddressFilter, DeviceAddress},
+        time::Instant,
+    },
+};
+
+/// Time the output has to be stopped for until the vehicle counts as stationary.
+pub const STATIONARY_SECS: u32 = 2;
+
+/// Returns whether the output is stopped at the compare value `pwm`, given the `neutral` one.
+///
+/// Brake and reverse pulses below neutral don't count as stopped.
+pub fn is_stopped(pwm: u16, neutral: u16) -> bool {
+    pwm == neutral
+}
+
+/// Decides which controller drives the receiver.
+pub struct Ownership {
+    owner: Option<u8>,
+    /// Whether the owner released the receiver.
+    released: bool,
+    /// Whether the owner's link was lost.
+    lost: bool,
+    /// Since when the output has been stopped, if it is.
+    stopped_since: Option<Instant>,
+}
+
+impl Default for Ownership {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ownership {
+    pub const fn new() -> Self {
+        Self {
+            owner: None,
+            released: false,
+            lost: false,
+            stopped_since: None,
+        }
+    }
+
+    /// Returns the number of the controller driving the receiver, if any.
+    pub fn owner(&self) -> Option<u8> {
+        self.owner
+    }
+
+    /// Handles a throttle beacon from controller `id` received at `now`, which `released` the
+    /// receiver or not, while the output is `stopped` or not.
+    ///
+    /// Returns whether the beacon's throttle value is to be applied, in which case `id` is the
+    /// owner from now on.
+    pub fn frame(&mut self, id: u8, released: bool, stopped: bool, now: Instant) -> bool {
+        self.stopped_since = match self.stopped_since {
+            _ if !stopped => None,
+            Some(since) => Some(since),
+            None => Some(now),
+        };
+
+        if self.owner != Some(id) {
+            if released || !self.may_take_over(id, now) {
+                return false;
+            }
+            self.owner = Some(id);
+        }
+        self.released = released;
+        self.lost = false;
+        true
+    }
+
+    /// Records that the owner's link was lost, so other controllers can take over once the
+    /// vehicle is stationary.
+    pub fn link_lost(&mut self) {
+        self.lost = self.owner.is_some();
+    }
+
+    fn may_take_over(&self, id: u8, now: Instant) -> bool {
+        let stationary = self.stopped_since.is_some_and(|since| {
+            now.raw_micros().wrapping_sub(since.raw_micros()) / 1_000_000 >= STATIONARY_SECS
+        });
+        match self.owner {
+            None => true,
+            Some(_) if self.released => true,
+            Some(owner) => stationary && (self.lost || id < owner),
+        }
+    }
+}
+
+/// Lets the beacons of the paired controllers through to the scanner, in place of a
+/// `WhitelistFilter` of a single address.
+///
+/// `paired` returns the paired controllers as a bit mask, with bit `n` set for controller `n`.
+/// It's asked for every beacon, so pairing changes apply right away.
+pub struct PairedFilter<F> {
+    paired: F,
+}
+
+impl<F: Fn() -> u32> PairedFilter<F> {
+    pub fn new(paired: F) -> Self {
+        Self { paired }
+    }
+}
+
+impl<F: Fn() -> u32> AddressFilter for PairedFilter<F> {
+    fn matches(&self, address: DeviceAddress) -> bool {
+        match protocol::controller_id(*address.raw()) {
+            Some(id) => (self.paired)() & 1 << id != 0,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::output::OutputMap, rubble::link::AddressKind};
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_raw_micros(ms * 1000)
+    }
+
+    #[test]
+    fn handover() {
+        let mut ownership = Ownership::new();
+        assert_eq!(ownership.owner(), None);
+
+        // The first controller heard takes over, unless it releases the receiver
+        assert!(!ownership.frame(1, true, true, at(0)));
+        assert!(ownership.frame(1, false, true, at(0)));
+        assert_eq!(ownership.owner(), Some(1));
+
+        // Others can't take over while it drives, nor while it's stopped for a moment
+        assert!(!ownership.frame(2, false, false, at(100)));
+        assert!(!ownership.frame(0, false, true, at(1000)));
+        assert!(!ownership.frame(0, false, true, at(2900)));
+        // Controllers with priority can once it's stationary, but not those without
+        assert!(!ownership.frame(2, false, true, at(3000)));
+        assert!(ownership.frame(0, false, true, at(3000)));
+        assert_eq!(ownership.owner(), Some(0));
+
+        // Anyone can once the owner released the receiver
+        assert!(ownership.frame(0, true, false, at(4000)));
+        assert!(ownership.frame(2, false, false, at(4100)));
+        assert_eq!(ownership.owner(), Some(2));
+
+        // Or once the owner's link is lost and the vehicle stationary
+        ownership.link_lost();
+        assert!(!ownership.frame(3, false, false, at(5000)));
+        assert!(!ownership.frame(3, false, true, at(5100)));
+        assert!(ownership.frame(3, false, true, at(7100)));
+        assert_eq!(ownership.owner(), Some(3));
+
+        // The owner coming back keeps driving
+        ownership.link_lost();
+        assert!(ownership.frame(3, false, false, at(7200)));
+        assert!(!ownership.frame(2, false, false, at(9500)));
+        assert_eq!(ownership.owner(), Some(3));
+    }
+
+    #[test]
+    fn braking_isnt_stationary() {
+        let map = OutputMap::DEFAULT;
+        assert!(is_stopped(map.neutral, map.neutral));
+        assert!(!is_stopped(map.base, map.neutral));
+        assert!(!is_stopped(map.pwm(255), map.neutral));
+
+        // Braking for longer than `STATIONARY_SECS` doesn't let others take over
+        let mut ownership = Ownership::new();
+        assert!(ownership.frame(1, false, false, at(0)));
+        let braking = is_stopped(map.pwm(0), map.neutral);
+        assert!(ownership.frame(1, false, braking, at(1000)));
+        ownership.link_lost();
+        assert!(!ownership.frame(0, false, braking, at(4000)));
+        assert_eq!(ownership.owner(), Some(1));
+
+        // Standing still at neutral does
+        assert!(!ownership.frame(0, false, true, at(5000)));
+        assert!(ownership.frame(0, false, true, at(7000)));
+        assert_eq!(ownership.owner(), Some(0));
+    }
+
+    #[test]
+    fn pairing() {
+        let filter = PairedFilter::new(|| 0b101);
+        let address =
+            |id| DeviceAddress::new(protocol::controller_address(id), AddressKind::Random);
+        assert!(filter.matches(address(0)));
+        assert!(!filter.matches(address(1)));
+        assert!(filter.matches(address(2)));
+        assert!(!filter.matches(address(3)));
+        assert!(!filter.matches(DeviceAddress::new(
+            protocol::RECEIVER_ADDRESS,
+            AddressKind::Random
+        )));
+    }
+}
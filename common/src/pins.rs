@@ -0,0 +1,52 @@
+//! Compile-time checked pin assignments for the board support crates.
+//!
+//! A board lists the port 0 pin of every signal it uses in a `pin_map!`. The HAL's typed pins
+//! already make sure a pin isn't handed out twice, but peripherals like the PWM are configured with
+//! raw pin numbers, which bypass that. The map catches those too:
+//!
+//! ```notrust
+//! bluefly_common::pin_map! {
+//!     /// UART RX, J1 pin 1.
+//!     SERIAL_RX = 8,
+//!     /// Servo signal to the ESC.
+//!     ESC = 8,
+//! }
+//! ```
+//!
+//! fails to compile with a type mismatch in `PIN_CONFLICT`, since both signals use P0.08.
+
+/// Defines a `pub const NAME: u8` for every pin, and fails to compile if two of them are the same.
+#[macro_export]
+macro_rules! pin_map {
+    ($($(#[$attr:meta])* $name:ident = $pin:expr,)*) => {
+        $(
+            $(#[$attr])*
+            pub const $name: u8 = $pin;
+        )*
+
+        /// Only has the right type if all pins are distinct: with a duplicate, the sum of the pin
+        /// masks differs from their union.
+        #[allow(dead_code)]
+        const PIN_CONFLICT: [(); 0] =
+            [(); ((0 $(+ (1u64 << $name))*) != (0 $(| (1u64 << $name))*)) as usize];
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    mod board {
+        pin_map! {
+            /// A documented pin.
+            LED = 17,
+            BUTTON = 13,
+            ANALOG = 2,
+        }
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(board::LED, 17);
+        assert_eq!(board::BUTTON, 13);
+        assert_eq!(board::ANALOG, 2);
+    }
+}
@@ -0,0 +1,142 @@
+//! The controller's power states.
+//!
+//! The longer the controller goes without activity, the more of it is powered down: first the
+//! display is dimmed, then it's turned off and beacons go out at a lower rate, and finally the
+//! controller enters System OFF, which only a reset ends. Moving the throttle away from its rest
+//! position counts as activity, as does anything the firmware reports through
+//! `Power::activity`.
+
+use rubble::time::{Duration, Instant};
+
+/// How much of the controller is powered, from most to least.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    Active,
+    /// The display only shows the throttle.
+    Dimmed,
+    /// The display is off and beacons are sent at the idle rate.
+    Idle,
+    /// The controller is about to enter System OFF.
+    Off,
+}
+
+impl PowerState {
+    /// Returns the name of the state, for log messages and the shell.
+    pub fn name(self) -> &'static str {
+        match self {
+            PowerState::Active => "active",
+            PowerState::Dimmed => "dimmed",
+            PowerState::Idle => "idle",
+            PowerState::Off => "off",
+        }
+    }
+}
+
+/// Times without activity after which the controller enters the lower power states.
+#[derive(Copy, Clone, Debug)]
+pub struct Timeouts {
+    pub dim: Duration,
+    pub idle: Duration,
+    pub off: Duration,
+}
+
+/// Distance of a 14-bit throttle reading from the rest position that still counts as resting, to
+/// ignore ADC noise.
+pub const THROTTLE_DEADBAND: u16 = 256;
+
+/// Tracks activity and the resulting power state.
+pub struct Power {
+    state: PowerState,
+    last_activity: Instant,
+}
+
+impl Power {
+    /// Starts out active at `now`.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            state: PowerState::Active,
+            last_activity: now,
+        }
+    }
+
+    /// Records activity at `now`.
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Records the throttle reading `adc`, which is activity unless it's close to `rest`.
+    pub fn throttle(&mut self, adc: u16, rest: u16, now: Instant) {
+        if adc.abs_diff(rest) > THROTTLE_DEADBAND {
+            self.activity(now);
+        }
+    }
+
+    /// Updates the power state for `now`, and returns it if it changed.
+    ///
+    /// Once `Off` is reached, the state doesn't change anymore.
+    pub fn update(&mut self, now: Instant, timeouts: &Timeouts) -> Option<PowerState> {
+        if self.state == PowerState::Off {
+            return None;
+        }
+
+        let inactive = now.duration_since(self.last_activity).as_micros();
+        let state = if inactive > timeouts.off.as_micros() {
+            PowerState::Off
+        } else if inactive > timeouts.idle.as_micros() {
+            PowerState::Idle
+        } else if inactive > timeouts.dim.as_micros() {
+            PowerState::Dimmed
+        } else {
+            PowerState::Active
+        };
+
+        if state == self.state {
+            None
+        } else {
+            self.state = state;
+            Some(state)
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states() {
+        let at = |s: u32| Instant::from_raw_micros(s * 1_000_000);
+        let timeouts = Timeouts {
+            dim: Duration::from_secs(10),
+            idle: Duration::from_secs(30),
+            off: Duration::from_secs(120),
+        };
+        let rest = 7360;
+        let mut power = Power::new(at(0));
+
+        assert_eq!(power.update(at(10), &timeouts), None);
+        assert_eq!(power.update(at(11), &timeouts), Some(PowerState::Dimmed));
+        assert_eq!(power.update(at(12), &timeouts), None);
+
+        // Noise around the rest position
+        power.throttle(rest + THROTTLE_DEADBAND, rest, at(20));
+        power.throttle(rest - THROTTLE_DEADBAND, rest, at(20));
+        assert_eq!(power.update(at(31), &timeouts), Some(PowerState::Idle));
+
+        power.throttle(rest + THROTTLE_DEADBAND + 1, rest, at(40));
+        assert_eq!(power.update(at(40), &timeouts), Some(PowerState::Active));
+
+        power.activity(at(50));
+        assert_eq!(power.update(at(170), &timeouts), Some(PowerState::Idle));
+        assert_eq!(power.update(at(171), &timeouts), Some(PowerState::Off));
+
+        // There's no coming back from System OFF
+        power.activity(at(172));
+        assert_eq!(power.update(at(172), &timeouts), None);
+        assert_eq!(power.state(), PowerState::Off);
+    }
+}
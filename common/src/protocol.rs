@@ -0,0 +1,390 @@
+//! The beacon link between the controller and the receiver.
+//!
+//! On every tick of its beacon timer, the controller broadcasts a throttle beacon, followed by a
+//! frame of a relayed update if there is one (see `bluefly_dfu::relay`). The receiver answers relay
+//! frames with beacons of its own. Every beacon carries its payload in a single AD structure of
+//! type `AD_TYPE`.
+//!
+//! Throttle beacons go out at a high rate while the throttle moves, and back off while it's
+//! steady (see `BeaconRate`). Each one advertises the time until the next, so the receiver knows
+//! how long it can go without one. They also request the PHY the controller wants to use, which
+//! the receiver answers (see `crate::mode`). A controller can drive more than one receiver, with a
+//! throttle value for each (see `crate::receivers`).
+//!
+//! Beacons go out on all three advertising channels. Throttle beacons carry a sequence number in
+//! an additional AD structure in front of the payload, so the receiver can tell the copies apart
+//! (see `crate::redundancy`). Receivers that predate it only look at the last AD structure.
+//!
+//! Scanner callbacks pass received payloads on through a `bbqueue`, with a length prefix (see
+//! `push_frame` and `Frames`).
+
+use {
+    crate::mode::Phy,
+    bbqueue::Producer,
+    core::cmp,
+    rubble::{
+        beacon::Beacon,
+        link::{ad_structure::AdStructure, AddressKind, DeviceAddress, Transmitter},
+        time::Duration,
+    },
+};
+
+/// Address controller 0 sends its beacons from, and the receiver filters for.
+pub const CONTROLLER_ADDRESS: [u8; 6] = [169, 255, 235, 206, 50, 121];
+
+/// Number of controllers a receiver can be paired to (see `crate::ownership`).
+pub const MAX_CONTROLLERS: u8 = 4;
+
+/// Returns the address controller `id` sends its beacons from, which differs from
+/// `CONTROLLER_ADDRESS` in the first Byte.
+pub fn controller_address(id: u8) -> [u8; 6] {
+    let mut address = CONTROLLER_ADDRESS;
+    address[0] = address[0].wrapping_add(id);
+    address
+}
+
+/// Returns the number of the controller sending from `address`, or `None` if it isn't one.
+pub fn controller_id(address: [u8; 6]) -> Option<u8> {
+    let id = address[0].wrapping_sub(CONTROLLER_ADDRESS[0]);
+    if address[1..] == CONTROLLER_ADDRESS[1..] && id < MAX_CONTROLLERS {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// Address the receiver sends its relay responses from.
+pub const RECEIVER_ADDRESS: [u8; 6] = [0, 0, 0, 0, 0, 0];
+
+/// AD type of the structure carrying the payload (manufacturer specific data).
+pub const AD_TYPE: u8 = 0xFF;
+
+/// Unit of the beacon interval in throttle beacons, in milliseconds. The intervals of the usual
+/// beacon rates (10, 20 or 50 Hz) are multiples of it.
+const INTERVAL_UNIT_MS: u32 = 5;
+
+/// Encodes a 14-bit reading of the throttle ADC as the payload of a throttle beacon, which
+/// advertises that the next one follows within `interval`, and requests the receiver to switch to
+/// `phy` (see `crate::mode`).
+///
+/// Unlike relay frames, throttle beacons carry a sequence number, which is how the receiver tells
+/// them apart.
+pub fn throttle_frame(adc: u16, interval: Duration, phy: Phy) -> [u8; 3] {
+    let units = interval.as_micros().div_ceil(INTERVAL_UNIT_MS * 1000);
+    [(adc / 64) as u8, cmp::min(units, 255) as u8, phy.code()]
+}
+
+/// Decodes the throttle value from the payload of a throttle beacon.
+pub fn throttle(frame: &[u8]) -> Option<u8> {
+    frame.first().cloned()
+}
+
+/// Decodes the interval until the next throttle beacon from the payload of a throttle beacon.
+///
+/// Controllers from before the adaptive beacon rate don't send it.
+pub fn beacon_interval(frame: &[u8]) -> Option<Duration> {
+    let units = u32::from(*frame.get(1)?);
+    Some(Duration::from_micros(units * INTERVAL_UNIT_MS * 1000))
+}
+
+/// Decodes the PHY the controller requests from the payload of a throttle beacon.
+///
+/// Controllers from before the PHY negotiation only support 1M.
+pub fn requested_phy(frame: &[u8]) -> Phy {
+    frame
+        .get(2)
+        .and_then(|&code| Phy::from_code(code & !(RELEASE_FLAG | CRUISE_FLAG)))
+        .unwrap_or(Phy::Ble1M)
+}
+
+/// Flag in the Byte of throttle beacons requesting a PHY, set when the controller releases the
+/// receiver to other controllers (see `crate::ownership`).
+const RELEASE_FLAG: u8 = 0x80;
+
+/// Marks the payload of a throttle beacon as releasing the receiver.
+pub fn release(frame: &mut [u8]) {
+    if let Some(code) = frame.get_mut(2) {
+        *code |= RELEASE_FLAG;
+    }
+}
+
+/// Whether the payload of a throttle beacon releases the receiver.
+pub fn is_released(frame: &[u8]) -> bool {
+    frame.get(2).is_some_and(|&code| code & RELEASE_FLAG != 0)
+}
+
+/// Flag in the Byte of throttle beacons requesting a PHY, set while the controller cruises (see
+/// `crate::cruise`).
+const CRUISE_FLAG: u8 = 0x40;
+
+/// Marks the payload of a throttle beacon as sent while the controller cruises.
+pub fn set_cruise(frame: &mut [u8]) {
+    if let Some(code) = frame.get_mut(2) {
+        *code |= CRUISE_FLAG;
+    }
+}
+
+/// Whether the payload of a throttle beacon was sent while the controller cruises.
+pub fn is_cruise(frame: &[u8]) -> bool {
+    frame.get(2).is_some_and(|&code| code & CRUISE_FLAG != 0)
+}
+
+/// Readings of the throttle ADC that differ by less than this count as steady.
+const THROTTLE_STEADY: u16 = 128;
+
+/// Decides when the controller sends the next throttle beacon.
+///
+/// While the throttle moves, beacons go out at the fastest interval. Every beacon with the
+/// throttle unchanged doubles the interval, up to the slowest one.
+pub struct BeaconRate {
+    /// The last reading that counted as a change.
+    last: Option<u16>,
+    interval: u32,
+}
+
+impl Default for BeaconRate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BeaconRate {
+    pub const fn new() -> Self {
+        Self {
+            last: None,
+            interval: 0,
+        }
+    }
+
+    /// Returns the interval until the beacon after the one carrying the reading `adc`.
+    pub fn next(&mut self, adc: u16, fastest: Duration, slowest: Duration) -> Duration {
+        let moved = match self.last {
+            Some(last) => cmp::max(adc, last) - cmp::min(adc, last) >= THROTTLE_STEADY,
+            None => true,
+        };
+
+        self.interval = if moved {
+            self.last = Some(adc);
+            fastest.as_micros()
+        } else {
+            self.interval.saturating_mul(2)
+        };
+        self.interval = cmp::max(
+            cmp::min(self.interval, slowest.as_micros()),
+            fastest.as_micros(),
+        );
+        Duration::from_micros(self.interval)
+    }
+}
+
+/// Broadcasts a beacon from `address` carrying `payload`.
+///
+/// # Panics
+///
+/// If `payload` doesn't fit into a beacon.
+pub fn broadcast<T: Transmitter>(tx: &mut T, address: [u8; 6], payload: &[u8]) {
+    Beacon::new(
+        DeviceAddress::new(address, AddressKind::Random),
+        &[AdStructure::Unknown {
+            ty: AD_TYPE,
+            data: payload,
+        }],
+    )
+    .unwrap()
+    .broadcast(tx);
+}
+
+/// Broadcasts a throttle beacon from `address` carrying `payload` and the sequence number `seq`.
+///
+/// # Panics
+///
+/// If `payload` doesn't fit into a beacon.
+pub fn broadcast_sequenced<T: Transmitter>(tx: &mut T, address: [u8; 6], seq: u8, payload: &[u8]) {
+    Beacon::new(
+        DeviceAddress::new(address, AddressKind::Random),
+        &[
+            AdStructure::Unknown {
+                ty: AD_TYPE,
+                data: &[seq],
+            },
+            AdStructure::Unknown {
+                ty: AD_TYPE,
+                data: payload,
+            },
+        ],
+    )
+    .unwrap()
+    .broadcast(tx);
+}
+
+/// Returns the payload of a received beacon, given its AD structures.
+pub fn payload<'a, I>(adv_data: I) -> Option<&'a [u8]>
+where
+    I: Iterator<Item = AdStructure<'a>>,
+{
+    match adv_data.last() {
+        Some(AdStructure::Unknown { ty: AD_TYPE, data }) => Some(data),
+        _ => None,
+    }
+}
+
+/// Returns the payload of a received beacon and its sequence number, if it has one, given its AD
+/// structures.
+pub fn sequenced_payload<'a, I>(adv_data: I) -> Option<(Option<u8>, &'a [u8])>
+where
+    I: Iterator<Item = AdStructure<'a>>,
+{
+    let mut seq = None;
+    let mut payload: Option<&[u8]> = None;
+    for structure in adv_data {
+        match structure {
+            AdStructure::Unknown { ty: AD_TYPE, data } => {
+                seq = match payload {
+                    Some(&[seq]) => Some(seq),
+                    _ => None,
+                };
+                payload = Some(data);
+            }
+            _ => {
+                seq = None;
+                payload = None;
+            }
+        }
+    }
+    payload.map(|payload| (seq, payload))
+}
+
+/// Puts a received payload into `queue` with a length prefix, or drops it if the queue is full.
+pub fn push_frame(queue: &mut Producer, data: &[u8]) {
+    if data.len() > 255 {
+        return;
+    }
+
+    if let Ok(mut grant) = queue.grant(data.len() + 1) {
+        grant.buf()[0] = data.len() as u8;
+        grant.buf()[1..].copy_from_slice(data);
+        queue.commit(data.len() + 1, grant);
+    }
+}
+
+/// Iterator over the length-prefixed frames in a read grant of a frame queue.
+pub struct Frames<'a>(pub &'a [u8]);
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let (&len, rest) = self.0.split_first()?;
+        let len = usize::from(len);
+        if rest.len() < len {
+            return None;
+        }
+
+        let (frame, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_roundtrip() {
+        let interval = Duration::from_millis(20);
+        let frame = |adc, interval| throttle_frame(adc, interval, Phy::Ble1M);
+        assert_eq!(throttle(&frame(0, interval)), Some(0));
+        assert_eq!(throttle(&frame(0x3FFF, interval)), Some(255));
+        assert_eq!(throttle(&frame(128, interval)), Some(2));
+        assert_eq!(throttle(&[]), None);
+
+        let ms = |frame: &[u8]| beacon_interval(frame).map(|i| i.as_micros() / 1000);
+        assert_eq!(ms(&frame(0, interval)), Some(20));
+        // Rounded up, so the receiver never expects the next beacon too early
+        assert_eq!(ms(&frame(0, Duration::from_micros(20_001))), Some(25));
+        assert_eq!(ms(&frame(0, Duration::from_secs(2))), Some(1275));
+        assert_eq!(ms(&[115]), None);
+
+        assert_eq!(
+            requested_phy(&throttle_frame(0, interval, Phy::Ble2M)),
+            Phy::Ble2M
+        );
+        assert_eq!(requested_phy(&frame(0, interval)), Phy::Ble1M);
+        assert_eq!(requested_phy(&[115, 4]), Phy::Ble1M);
+
+        let mut released = throttle_frame(0, interval, Phy::Ble2M);
+        assert!(!is_released(&released));
+        release(&mut released);
+        assert!(is_released(&released));
+        assert_eq!(requested_phy(&released), Phy::Ble2M);
+        assert!(!is_released(&[115, 4]));
+
+        let mut cruising = throttle_frame(0, interval, Phy::Ble2M);
+        assert!(!is_cruise(&cruising));
+        set_cruise(&mut cruising);
+        release(&mut cruising);
+        assert!(is_cruise(&cruising) && is_released(&cruising));
+        assert_eq!(requested_phy(&cruising), Phy::Ble2M);
+        assert!(!is_cruise(&[115, 4]));
+    }
+
+    #[test]
+    fn controller_addresses() {
+        assert_eq!(controller_address(0), CONTROLLER_ADDRESS);
+        assert_eq!(controller_id(controller_address(3)), Some(3));
+        assert_eq!(controller_id(controller_address(MAX_CONTROLLERS)), None);
+        assert_eq!(controller_id(RECEIVER_ADDRESS), None);
+    }
+
+    #[test]
+    fn beacon_rate() {
+        let fastest = Duration::from_millis(20);
+        let slowest = Duration::from_millis(100);
+        let mut rate = BeaconRate::new();
+        let mut next = |adc| rate.next(adc, fastest, slowest).as_micros() / 1000;
+
+        assert_eq!(next(1000), 20);
+        assert_eq!(next(1000), 40);
+        assert_eq!(next(1100), 80);
+        assert_eq!(next(1000), 100);
+        assert_eq!(next(1000), 100);
+        // Slow drift adds up
+        assert_eq!(next(1127), 100);
+        assert_eq!(next(1128), 20);
+        assert_eq!(next(1128), 40);
+    }
+
+    #[test]
+    fn sequenced_payloads() {
+        let unknown = |data| AdStructure::Unknown { ty: AD_TYPE, data };
+        let parse = |adv_data: &[AdStructure<'static>]| sequenced_payload(adv_data.iter().cloned());
+
+        assert_eq!(
+            parse(&[unknown(&[7]), unknown(&[1, 2])]),
+            Some((Some(7), &[1, 2][..]))
+        );
+        // Beacons without a sequence number, like relay frames
+        assert_eq!(parse(&[unknown(&[1, 2])]), Some((None, &[1, 2][..])));
+        assert_eq!(
+            parse(&[unknown(&[7, 8]), unknown(&[1])]),
+            Some((None, &[1][..]))
+        );
+        assert_eq!(
+            parse(&[unknown(&[7]), AdStructure::CompleteLocalName("x")]),
+            None
+        );
+        assert_eq!(parse(&[]), None);
+    }
+
+    #[test]
+    fn frames() {
+        let buf = [2, 0xAA, 0xBB, 0, 1, 0xCC, 3, 0xDD];
+        let mut frames = Frames(&buf);
+        assert_eq!(frames.next(), Some(&[0xAA, 0xBB][..]));
+        assert_eq!(frames.next(), Some(&[][..]));
+        assert_eq!(frames.next(), Some(&[0xCC][..]));
+        // Truncated
+        assert_eq!(frames.next(), None);
+    }
+}
@@ -40,9 +40,19 @@
 //! In our case, this involves "splitting" the header into the `S0` field (everything preceding the
 //! length), the `Length` field, and the `S1` field (which just contains 2 unused bits, but they
 //! must still be sent, of course).
+//!
+//! # ESB
+//!
+//! Instead of BLE, the radio can also be switched to the proprietary packet mode of `crate::esb`,
+//! which uses the same frame layout with a 6-bit `Length` and a 3-bit `S1`, and no `S0`.
 
 use {
-    nrf52810_hal::nrf52810_pac::{radio::state::STATER, RADIO},
+    crate::{
+        esb,
+        hopping::HopTransmitter,
+        mode::{Phy, RadioMode},
+    },
+    nrf52810_pac::{radio::state::STATER, RADIO},
     rubble::{
         beacon::{BeaconScanner, ScanCallback},
         link::{
@@ -60,6 +70,14 @@ pub type PacketBuffer = [u8; MAX_PDU_SIZE];
 // BLE inter frame spacing in microseconds.
 //const BLE_TIFS: u8 = 150;
 
+/// What the radio measured about a received packet.
+#[derive(Copy, Clone, Debug)]
+pub struct Reception {
+    /// Received signal strength, in dBm, or `None` if the radio didn't measure it.
+    pub rssi: Option<i8>,
+    pub crc_ok: bool,
+}
+
 /// An interface to the nRF radio in BLE mode.
 pub struct BleRadio {
     /// `true` if the radio is operating on an advertising channel, `false` if it's a data channel.
@@ -72,6 +90,16 @@ pub struct BleRadio {
     /// This is an `Option` because we need to pass a `&mut BleRadio` to the BLE stack while still
     /// having access to this buffer.
     rx_buf: Option<&'static mut PacketBuffer>,
+
+    /// The last packet received on an advertising channel, until it's taken.
+    reception: Option<Reception>,
+
+    mode: RadioMode,
+
+    /// The channel, once the radio was switched to ESB.
+    esb: Option<u8>,
+    /// Drops ESB packets that were sent again.
+    esb_dedup: esb::Deduplicator,
 }
 
 impl BleRadio {
@@ -84,9 +112,6 @@ impl BleRadio {
     ) -> Self {
         assert!(radio.state.read().state().is_disabled());
 
-        radio.mode.write(|w| w.mode().ble_1mbit());
-        radio.txpower.write(|w| w.txpower().pos4d_bm());
-
         unsafe {
             radio.pcnf1.write(|w| {
                 // no packet length limit
@@ -134,22 +159,263 @@ impl BleRadio {
             radio.tifs.write(|w| w.tifs().bits(BLE_TIFS));
         }*/
 
-        // Configure shortcuts to simplify and speed up sending and receiving packets.
-        radio.shorts.write(|w| {
-            // start transmission/recv immediately after ramp-up
-            // disable radio when transmission/recv is done
-            w.ready_start().enabled().end_disable().enabled()
-        });
+        Self::write_beacon_shorts(&radio);
 
         // We can now start the TXEN/RXEN tasks and the radio will do the rest and return to the
         // disabled state.
 
-        Self {
+        let this = Self {
             advertising: false,
             radio,
             tx_buf,
             rx_buf: Some(rx_buf),
+            reception: None,
+            mode: RadioMode::DEFAULT,
+            esb: None,
+            esb_dedup: esb::Deduplicator::new(),
+        };
+        this.write_mode();
+        this
+    }
+
+    /// Configures the shortcuts used for beacons, which simplify and speed up sending and receiving
+    /// packets.
+    ///
+    /// Connections replace them with their own, so they're written again whenever the radio goes
+    /// back to beacons.
+    fn write_beacon_shorts(radio: &RADIO) {
+        radio.shorts.write(|w| {
+            // start transmission/recv immediately after ramp-up
+            // disable radio when transmission/recv is done
+            // measure the RSSI of received packets from their address on
+            w.ready_start()
+                .enabled()
+                .end_disable()
+                .enabled()
+                .address_rssistart()
+                .enabled()
+                .disabled_rssistop()
+                .enabled()
+        });
+    }
+
+    /// Returns the RSSI measured for the packet just received, in dBm, or `None` if the
+    /// measurement didn't finish, in which case `RSSISAMPLE` still holds an older one.
+    fn sample_rssi(&mut self) -> Option<i8> {
+        if self.radio.events_rssiend.read().bits() == 0 {
+            return None;
         }
+        self.radio.events_rssiend.reset();
+        // The sample is the magnitude of the (negative) RSSI
+        Some(-(self.radio.rssisample.read().rssisample().bits() as i8))
+    }
+
+    /// Returns the PHY and TX power the radio currently uses.
+    pub fn mode(&self) -> RadioMode {
+        self.mode
+    }
+
+    /// Switches the radio to `mode`.
+    ///
+    /// This stops reception, so the radio has to be configured for receiving again afterwards.
+    pub fn set_mode(&mut self, mode: RadioMode) {
+        if mode == self.mode {
+            return;
+        }
+
+        self.configure_receiver(RadioCmd::Off);
+        self.mode = mode;
+        self.write_mode();
+    }
+
+    /// Writes the mode registers. The packet layout depends on the PHY as well, but it's written
+    /// whenever a channel is prepared.
+    fn write_mode(&self) {
+        let phy = self.mode.phy;
+        let esb = self.esb.is_some();
+        self.radio.mode.write(|w| match phy {
+            _ if esb => w.mode().nrf_2mbit(),
+            Phy::Ble1M => w.mode().ble_1mbit(),
+            Phy::Ble2M => w.mode().ble_2mbit(),
+        });
+        // The register holds the power in dBm, as a two's complement
+        let tx_power = self.mode.tx_power as u8;
+        self.radio
+            .txpower
+            .write(|w| unsafe { w.txpower().bits(tx_power) });
+    }
+
+    /// Switches the radio from BLE to ESB on `channel`, an offset from 2400 MHz (see
+    /// `crate::esb`).
+    ///
+    /// There's no way back to BLE other than a reset, so the BLE stack must not be used afterwards.
+    /// The PHY of the mode is ignored from now on. Calling this again changes the channel.
+    pub fn start_esb(&mut self, channel: u8) {
+        self.configure_receiver(RadioCmd::Off);
+        self.esb = Some(channel);
+        self.advertising = false;
+        self.write_mode();
+
+        unsafe {
+            self.radio
+                .pcnf0
+                .write(|w| w.lflen().bits(6).s0len().bit(false).s1len().bits(3));
+            self.radio.pcnf1.write(|w| {
+                w.maxlen()
+                    .bits(esb::MAX_PAYLOAD_LEN as u8)
+                    .statlen()
+                    .bits(0)
+                    // 4-Byte Base Address + 1-Byte Address Prefix
+                    .balen()
+                    .bits(4)
+                    .endian()
+                    .big()
+                    .whiteen()
+                    .disabled()
+            });
+            // The CRC covers the address as well
+            self.radio
+                .crccnf
+                .write(|w| w.skipaddr().include().len().two());
+            self.radio
+                .crcpoly
+                .write(|w| w.crcpoly().bits(esb::CRC_POLY));
+            self.radio
+                .crcinit
+                .write(|w| w.crcinit().bits(esb::CRC_INIT));
+
+            // Unlike in BLE, the whole base address is used
+            self.radio.base0.write(|w| w.bits(esb::BASE_ADDRESS));
+            self.radio.prefix0.write(|w| w.ap0().bits(esb::PREFIX));
+            self.radio.txaddress.write(|w| w.txaddress().bits(0));
+            self.radio.rxaddresses.write(|w| w.addr0().enabled());
+
+            self.radio.frequency.write(|w| w.frequency().bits(channel));
+        }
+    }
+
+    /// Whether the radio was switched to ESB.
+    pub fn is_esb(&self) -> bool {
+        self.esb.is_some()
+    }
+
+    /// Returns the channel if the radio was switched to ESB.
+    pub fn esb_channel(&self) -> Option<u8> {
+        self.esb
+    }
+
+    /// Sends a new packet carrying `payload` in ESB mode, and listens for its acknowledgement.
+    ///
+    /// The `RADIO` interrupt fires when a packet arrives (see `esb_ack_interrupt`). Once the
+    /// acknowledgement is `esb::ACK_TIMEOUT_MICROS` overdue, call `esb_retransmit`.
+    pub fn esb_send(&mut self, sender: &mut esb::Sender, payload: &[u8]) {
+        sender.packet(payload, &mut self.tx_buf[..]);
+        self.esb_retransmit(sender);
+    }
+
+    /// Sends the last packet again, since its acknowledgement didn't arrive in time, and listens
+    /// for the acknowledgement.
+    ///
+    /// Returns `false` once the packet was sent `esb::MAX_ATTEMPTS` times, or was acknowledged in
+    /// the meantime; then the radio is off.
+    pub fn esb_retransmit(&mut self, sender: &mut esb::Sender) -> bool {
+        self.configure_receiver(RadioCmd::Off);
+        if !sender.attempt() {
+            return false;
+        }
+
+        self.transmit();
+        self.esb_listen();
+        true
+    }
+
+    /// Call this when the `RADIO` interrupt fires while a packet sent with `esb_send` is waiting
+    /// for its acknowledgement.
+    ///
+    /// Returns whether the packet was acknowledged; then the radio is off. Otherwise, it keeps
+    /// listening.
+    pub fn esb_ack_interrupt(&mut self, sender: &mut esb::Sender) -> bool {
+        if self.radio.events_disabled.read().bits() == 0 {
+            return false;
+        }
+        self.radio.events_disabled.reset();
+
+        let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
+        if crc_ok && sender.ack(&self.rx_buf.as_ref().unwrap()[..]) {
+            self.configure_receiver(RadioCmd::Off);
+            return true;
+        }
+
+        // Something else; keep listening, unless the packet was given up on
+        if sender.is_pending() {
+            self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+        } else {
+            self.configure_receiver(RadioCmd::Off);
+        }
+        false
+    }
+
+    /// Listens for ESB packets, raising the `RADIO` interrupt when one arrives.
+    pub fn esb_listen(&mut self) {
+        self.configure_receiver(RadioCmd::Off);
+
+        let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
+        self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
+        // Enable `DISABLED` interrupt (packet fully received)
+        self.radio.intenset.write(|w| w.disabled().set());
+        self.radio.events_rssiend.reset();
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Call this when the `RADIO` interrupt fires in ESB mode.
+    ///
+    /// Acknowledges a received packet if `acknowledge` is set, and goes back to listening. Writes
+    /// the payload of new packets to `payload` and returns its length. Packets sent again because
+    /// the acknowledgement got lost are acknowledged, but not returned.
+    pub fn esb_recv_interrupt(
+        &mut self,
+        payload: &mut [u8; esb::MAX_PAYLOAD_LEN],
+        acknowledge: bool,
+    ) -> Option<usize> {
+        if self.radio.events_disabled.read().bits() == 0 {
+            return None;
+        }
+        self.radio.events_disabled.reset();
+
+        let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
+        // Other receivers hear the acknowledgements of the primary. They're not from the
+        // controller, and their PIDs would throw off the deduplication.
+        let is_ack = |buf: &[u8]| esb::decode(buf).is_some_and(|packet| packet.payload.is_empty());
+        if crc_ok && is_ack(&self.rx_buf.as_ref().unwrap()[..]) {
+            self.esb_listen();
+            return None;
+        }
+
+        let rssi = self.sample_rssi();
+        self.reception = Some(Reception { rssi, crc_ok });
+
+        let mut received = None;
+        let mut ack = None;
+        if crc_ok {
+            let crc = self.radio.rxcrc.read().rxcrc().bits();
+            if let Some(packet) = esb::decode(&self.rx_buf.as_ref().unwrap()[..]) {
+                if self.esb_dedup.is_new(packet.pid, crc) {
+                    payload[..packet.payload.len()].copy_from_slice(packet.payload);
+                    received = Some(packet.payload.len());
+                }
+                if acknowledge && !packet.no_ack {
+                    ack = Some(packet.pid);
+                }
+            }
+        }
+
+        if let Some(pid) = ack {
+            self.configure_receiver(RadioCmd::Off);
+            esb::encode(pid, false, &[], &mut self.tx_buf[..]);
+            self.transmit();
+        }
+        self.esb_listen();
+        received
     }
 
     /// Returns the current radio state.
@@ -174,19 +440,7 @@ impl BleRadio {
         match cmd {
             RadioCmd::Off => {}
             RadioCmd::ListenAdvertising { channel } => {
-                self.prepare_txrx_advertising(channel);
-
-                let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
-                self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
-
-                // Enable `DISABLED` interrupt (packet fully received)
-                self.radio.intenset.write(|w| w.disabled().set());
-
-                // Match on logical address 0 only
-                self.radio.rxaddresses.write(|w| w.addr0().enabled());
-
-                // ...and enter RX mode
-                self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+                self.listen_beacons(channel.freq(), channel.whitening_iv());
             }
             RadioCmd::ListenData {
                 channel,
@@ -224,6 +478,35 @@ impl BleRadio {
         }
     }
 
+    /// Listens for beacons on the data channel `channel`, which the throttle beacons hop over (see
+    /// `crate::hopping`).
+    ///
+    /// Received beacons are handled by `recv_interrupt` like the ones on advertising channels.
+    pub fn listen_hopping(&mut self, channel: DataChannel) {
+        self.configure_receiver(RadioCmd::Off);
+        self.listen_beacons(channel.freq(), channel.whitening_iv());
+    }
+
+    /// Enters RX mode for advertising channel PDUs on `freq`, in MHz.
+    fn listen_beacons(&mut self, freq: u16, whitening_iv: u8) {
+        self.prepare_txrx_beacons(freq, whitening_iv);
+
+        let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
+        self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
+
+        // Enable `DISABLED` interrupt (packet fully received)
+        self.radio.intenset.write(|w| w.disabled().set());
+
+        // Match on logical address 0 only
+        self.radio.rxaddresses.write(|w| w.addr0().enabled());
+
+        // Don't mistake a measurement of an earlier packet for the next one's
+        self.radio.events_rssiend.reset();
+
+        // ...and enter RX mode
+        self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+    }
+
     /// Call this when the `RADIO` interrupt fires.
     ///
     /// Automatically reconfigures the radio according to the `RadioCmd` returned by the BLE stack.
@@ -246,6 +529,8 @@ impl BleRadio {
         self.radio.events_disabled.reset();
 
         let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
+        let rssi = self.sample_rssi();
+        self.reception = Some(Reception { rssi, crc_ok });
 
         let cmd = if self.advertising {
             // When we get here, the radio must have transitioned to DISABLED state.
@@ -274,10 +559,19 @@ impl BleRadio {
         cmd.next_update
     }
 
-    /// Perform preparations to receive or send on an advertising channel.
+    /// Returns what the radio measured about the last packet received on an advertising channel,
+    /// if it wasn't taken yet.
+    ///
+    /// Call this after `recv_interrupt` to find out about the packet it passed to the scanner.
+    pub fn take_reception(&mut self) -> Option<Reception> {
+        self.reception.take()
+    }
+
+    /// Perform preparations to receive or send advertising channel PDUs.
     ///
     /// This will disable the radio, configure the packet layout, set initial values for CRC and
-    /// whitening, and set the frequency to the given `channel`.
+    /// whitening, and set the frequency to `freq`, in MHz. That's the frequency of an advertising
+    /// channel, or of a data channel while hopping.
     ///
     /// To **transmit**, the `txaddress` must be set and the `packetptr` must be set to the TX
     /// buffer.
@@ -286,7 +580,7 @@ impl BleRadio {
     /// `packetptr` must be pointed to the RX buffer.
     ///
     /// Of course, other tasks may also be performed.
-    fn prepare_txrx_advertising(&mut self, channel: AdvertisingChannel) {
+    fn prepare_txrx_beacons(&mut self, freq: u16, whitening_iv: u8) {
         self.advertising = true;
 
         unsafe {
@@ -303,22 +597,21 @@ impl BleRadio {
         }
 
         assert!(self.state().is_disabled());
+        Self::write_beacon_shorts(&self.radio);
 
         // Now we can freely configure all registers we need
         unsafe {
-            self.radio
-                .pcnf0
-                .write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
+            self.write_pcnf0();
 
             self.radio
                 .datawhiteiv
-                .write(|w| w.datawhiteiv().bits(channel.whitening_iv()));
+                .write(|w| w.datawhiteiv().bits(whitening_iv));
             self.radio
                 .crcinit
                 .write(|w| w.crcinit().bits(advertising::CRC_PRESET));
             self.radio
                 .frequency
-                .write(|w| w.frequency().bits((channel.freq() - 2400) as u8));
+                .write(|w| w.frequency().bits((freq - 2400) as u8));
         }
     }
 
@@ -326,9 +619,7 @@ impl BleRadio {
         self.advertising = false;
 
         unsafe {
-            self.radio
-                .pcnf0
-                .write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
+            self.write_pcnf0();
 
             self.radio
                 .datawhiteiv
@@ -348,6 +639,37 @@ impl BleRadio {
         }
     }
 
+    /// Configures the packet layout of BLE, whose preamble is 1 Byte on 1M and 2 Bytes on 2M.
+    fn write_pcnf0(&self) {
+        let phy = self.mode.phy;
+        self.radio.pcnf0.write(|w| unsafe {
+            let w = w.s0len().bit(true).lflen().bits(8).s1len().bits(0);
+            match phy {
+                Phy::Ble1M => w.plen()._8bit(),
+                Phy::Ble2M => w.plen()._16bit(),
+            }
+        });
+    }
+
+    /// Transmits an advertising channel PDU on `freq`, in MHz.
+    fn transmit_beacon(&mut self, header: advertising::Header, freq: u16, whitening_iv: u8) {
+        let raw_header = header.to_u16();
+        // S0 = 8 bits (LSB)
+        self.tx_buf[0] = raw_header as u8;
+        // Length = 6 bits, followed by 2 RFU bits (0)
+        self.tx_buf[1] = header.payload_length();
+
+        self.prepare_txrx_beacons(freq, whitening_iv);
+
+        // Set transmission address:
+        // Logical addr. 0 uses BASE0 + PREFIX0, which is the canonical adv. Access Address
+        self.radio
+            .txaddress
+            .write(|w| unsafe { w.txaddress().bits(0) });
+
+        self.transmit();
+    }
+
     /// Transmit a PDU from the internal buffer.
     ///
     /// This will block until the transmission has completed.
@@ -384,21 +706,7 @@ impl Transmitter for BleRadio {
     }
 
     fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
-        let raw_header = header.to_u16();
-        // S0 = 8 bits (LSB)
-        self.tx_buf[0] = raw_header as u8;
-        // Length = 6 bits, followed by 2 RFU bits (0)
-        self.tx_buf[1] = header.payload_length();
-
-        self.prepare_txrx_advertising(channel);
-
-        // Set transmission address:
-        // Logical addr. 0 uses BASE0 + PREFIX0, which is the canonical adv. Access Address
-        self.radio
-            .txaddress
-            .write(|w| unsafe { w.txaddress().bits(0) });
-
-        self.transmit();
+        self.transmit_beacon(header, channel.freq(), channel.whitening_iv());
     }
 
     fn transmit_data(
@@ -432,3 +740,9 @@ impl Transmitter for BleRadio {
             .write(|w| w.ready_start().enabled().end_disable().enabled());
     }
 }
+
+impl HopTransmitter for BleRadio {
+    fn transmit_hopping(&mut self, header: advertising::Header, channel: DataChannel) {
+        self.transmit_beacon(header, channel.freq(), channel.whitening_iv());
+    }
+}
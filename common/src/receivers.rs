@@ -0,0 +1,138 @@
+//! Driving more than one receiver from a controller, like one per motor on dual-motor boards.
+//!
+//! All receivers follow the same throttle beacons. Each one is configured with its number, and the
+//! controller sends a throttle value for each, adjusted by a `Trim` of its own, so that a motor
+//! mounted the other way round turns the other way, or one motor gets less power than the other.
+//! Receiver 0 reads the first Byte of the frame like receivers from before (see
+//! `protocol::throttle_frame`), and the others read the Bytes appended to it (see
+//! `throttle_frame`).
+//!
+//! Only receiver 0, the primary, answers the controller: it answers the PHY requests and channel
+//! maps, acknowledges ESB packets, and takes relayed updates. The others only send the notice that
+//! they disengaged cruise (see `crate::cruise`), and otherwise just listen. So that they can
+//! keep up, the controller requests 1M while it drives more than one receiver. While hopping, the
+//! others keep to all channels, so they miss the copies the controller moves off the channels the
+//! primary blacklisted, which the primary loses most of anyway.
+
+use {
+    crate::{mode::Phy, protocol},
+    rubble::time::Duration,
+};
+
+/// Number of receivers a controller can drive.
+pub const MAX_RECEIVERS: u8 = 2;
+
+/// Number of the receiver that answers the controller.
+pub const PRIMARY: u8 = 0;
+
+/// Maximum length of a throttle frame.
+pub const MAX_FRAME_LEN: usize = 2 + MAX_RECEIVERS as usize;
+
+/// Highest 14-bit throttle reading.
+const MAX_ADC: i32 = 0x3FFF;
+
+/// Adjustments to the throttle sent to one receiver.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Trim {
+    /// Whether the throttle is mirrored around the rest position, for a motor mounted the other
+    /// way round.
+    pub invert: bool,
+    /// Percentage of the throttle's travel from the rest position passed on.
+    pub scale: u8,
+}
+
+impl Trim {
+    /// The throttle as it is.
+    pub const DEFAULT: Self = Self {
+        invert: false,
+        scale: 100,
+    };
+
+    /// Adjusts the 14-bit throttle reading `adc`, given the reading `rest` with the thumb
+    /// released.
+    pub fn apply(&self, adc: u16, rest: u16) -> u16 {
+        let travel = (i32::from(adc) - i32::from(rest)) * i32::from(self.scale) / 100;
+        let travel = if self.invert { -travel } else { travel };
+        (i32::from(rest) + travel).clamp(0, MAX_ADC) as u16
+    }
+}
+
+/// Encodes the payload of a throttle beacon for as many receivers as there are readings in `adc`,
+/// with the reading for each receiver by its number, returning the frame and its length.
+///
+/// The frame starts with `protocol::throttle_frame` for receiver 0, followed by the throttle
+/// values of the other receivers.
+///
+/// # Panics
+///
+/// If `adc` is empty or has more than `MAX_RECEIVERS` readings.
+pub fn throttle_frame(adc: &[u16], interval: Duration, phy: Phy) -> ([u8; MAX_FRAME_LEN], usize) {
+    assert!(!adc.is_empty() && adc.len() <= usize::from(MAX_RECEIVERS));
+
+    let mut frame = [0; MAX_FRAME_LEN];
+    frame[..3].copy_from_slice(&protocol::throttle_frame(adc[0], interval, phy));
+    for (byte, &adc) in frame[3..].iter_mut().zip(&adc[1..]) {
+        *byte = protocol::throttle_frame(adc, interval, phy)[0];
+    }
+    (frame, 2 + adc.len())
+}
+
+/// Decodes the throttle value for receiver `id` from the payload of a throttle beacon, or returns
+/// `None` if the controller doesn't drive that receiver.
+pub fn throttle(frame: &[u8], id: u8) -> Option<u8> {
+    if id == PRIMARY {
+        protocol::throttle(frame)
+    } else {
+        frame.get(2 + usize::from(id)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims() {
+        let rest = 7360;
+        assert_eq!(Trim::DEFAULT.apply(0x3FFF, rest), 0x3FFF);
+
+        let inverted = Trim {
+            invert: true,
+            ..Trim::DEFAULT
+        };
+        assert_eq!(inverted.apply(rest, rest), rest);
+        assert_eq!(inverted.apply(rest + 1000, rest), rest - 1000);
+        // Beyond the ends of the throttle's travel
+        assert_eq!(inverted.apply(0, rest), 2 * rest);
+        assert_eq!(inverted.apply(0x3FFF, rest), 0);
+        assert_eq!(inverted.apply(0, 10_000), 0x3FFF);
+
+        let half = Trim {
+            invert: false,
+            scale: 50,
+        };
+        assert_eq!(half.apply(rest + 1000, rest), rest + 500);
+        assert_eq!(half.apply(rest - 1000, rest), rest - 500);
+        let off = Trim { scale: 0, ..half };
+        assert_eq!(off.apply(0x3FFF, rest), rest);
+    }
+
+    #[test]
+    fn frames() {
+        let interval = Duration::from_millis(20);
+        let single = protocol::throttle_frame(0x3FFF, interval, Phy::Ble1M);
+        let (frame, len) = throttle_frame(&[0x3FFF], interval, Phy::Ble1M);
+        assert_eq!(&frame[..len], &single[..]);
+        assert_eq!(throttle(&frame[..len], 0), Some(255));
+        assert_eq!(throttle(&frame[..len], 1), None);
+
+        let (frame, len) = throttle_frame(&[0x3FFF, 64 * 10], interval, Phy::Ble1M);
+        assert_eq!(&frame[..3], &single[..]);
+        assert_eq!(throttle(&frame[..len], 0), Some(255));
+        assert_eq!(throttle(&frame[..len], 1), Some(10));
+        assert_eq!(protocol::beacon_interval(&frame[..len]), Some(interval));
+
+        // Controllers from before the beacon interval
+        assert_eq!(throttle(&[100], 0), Some(100));
+    }
+}
@@ -0,0 +1,225 @@
+//! Receiving the copies of throttle beacons sent on all three advertising channels.
+//!
+//! Every beacon goes out on channels 37, 38 and 39 right after another, so interference on one of
+//! them (usually WiFi) doesn't cost the receiver a throttle value. Throttle beacons carry a
+//! sequence number, which tells the copies of a frame apart from the next frame.
+//!
+//! The receiver's radio can only listen on one channel at a time. After hearing a copy, it moves on
+//! to the channel the next copy is sent on, and returns to the channel where frames start once the
+//! copies are over. If nothing is heard on that channel for a while, it's probably jammed, and the
+//! receiver moves on to the next one.
+
+use {
+    core::cmp,
+    rubble::{
+        phy::AdvertisingChannel,
+        time::{Duration, Instant},
+    },
+};
+
+/// Time within which the copies after the first one arrive, in microseconds. A copy takes less than
+/// half a millisecond on air, including the radio's ramp-up.
+pub(crate) const COPY_WINDOW_MICROS: u32 = 2000;
+
+/// Beacon interval assumed until a throttle beacon advertises one, in microseconds.
+pub(crate) const DEFAULT_INTERVAL_MICROS: u32 = 100_000;
+
+/// Number of frames of which 1, 2 or all 3 copies arrived.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CopyStats {
+    pub copies: [u32; 3],
+}
+
+impl CopyStats {
+    /// Returns the number of frames received.
+    pub fn frames(&self) -> u32 {
+        self.copies.iter().sum()
+    }
+}
+
+/// Picks the channel to listen on, and the copy of each frame to use.
+pub struct Redundancy {
+    /// The channel frames are expected to start on.
+    home: AdvertisingChannel,
+    listening: AdvertisingChannel,
+    /// When the last copy was heard, or the receiver last moved on to another channel.
+    last_heard: Instant,
+    /// When the first copy of the current frame was heard.
+    frame_start: Instant,
+    interval: u32,
+    seq: Option<u8>,
+    copies: u8,
+    stats: CopyStats,
+}
+
+impl Redundancy {
+    /// Starts out listening on the first advertising channel at `now`.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            home: AdvertisingChannel::first(),
+            listening: AdvertisingChannel::first(),
+            last_heard: now,
+            frame_start: now,
+            interval: DEFAULT_INTERVAL_MICROS,
+            seq: None,
+            copies: 0,
+            stats: CopyStats::default(),
+        }
+    }
+
+    /// Returns the channel to listen on.
+    pub fn channel(&self) -> AdvertisingChannel {
+        self.listening
+    }
+
+    /// Handles a copy of the throttle frame with the sequence number `seq`, heard at `now`, which
+    /// advertises `interval` until the next frame.
+    ///
+    /// Returns whether it's the first copy, which is the one to use. Frames from controllers that
+    /// don't send sequence numbers are always used. Afterwards, `channel` returns the channel the
+    /// next copy is sent on.
+    pub fn copy(&mut self, seq: Option<u8>, interval: Option<Duration>, now: Instant) -> bool {
+        let heard_on = self.listening;
+        self.listening = heard_on.cycle();
+        self.last_heard = now;
+        if let Some(interval) = interval {
+            self.interval = interval.as_micros();
+        }
+
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return true,
+        };
+        // Sequence numbers wrap around, so a copy also has to be close to the first one
+        let in_window =
+            now.raw_micros().wrapping_sub(self.frame_start.raw_micros()) <= COPY_WINDOW_MICROS;
+        if self.seq == Some(seq) && in_window {
+            self.copies = cmp::min(self.copies + 1, 3);
+            false
+        } else {
+            self.finish_frame();
+            self.home = heard_on;
+            self.frame_start = now;
+            self.seq = Some(seq);
+            self.copies = 1;
+            true
+        }
+    }
+
+    /// Updates the channel to listen on for `now`, and returns it if it changed.
+    ///
+    /// Once the copies of a frame are over, the receiver returns to the channel the frame started
+    /// on. If nothing is heard for one and a half beacon intervals, it moves on to the next one.
+    pub fn update(&mut self, now: Instant) -> Option<AdvertisingChannel> {
+        let since_frame = now.raw_micros().wrapping_sub(self.frame_start.raw_micros());
+        let since_heard = now.raw_micros().wrapping_sub(self.last_heard.raw_micros());
+
+        let channel = if since_heard > self.interval + self.interval / 2 {
+            self.home = self.home.cycle();
+            self.last_heard = now;
+            self.home
+        } else if since_frame > COPY_WINDOW_MICROS {
+            self.home
+        } else {
+            self.listening
+        };
+
+        if channel.freq() == self.listening.freq() {
+            None
+        } else {
+            self.listening = channel;
+            Some(channel)
+        }
+    }
+
+    /// Returns the number of copies that arrived of the frames received so far.
+    pub fn stats(&self) -> CopyStats {
+        let mut stats = self.stats;
+        if self.copies > 0 {
+            stats.copies[usize::from(self.copies) - 1] += 1;
+        }
+        stats
+    }
+
+    fn finish_frame(&mut self) {
+        self.stats = self.stats();
+        self.copies = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(micros: u32) -> Instant {
+        Instant::from_raw_micros(micros)
+    }
+
+    fn freq(channel: u8) -> u16 {
+        AdvertisingChannel::iter_all()
+            .nth(usize::from(channel - 37))
+            .unwrap()
+            .freq()
+    }
+
+    #[test]
+    fn copies() {
+        let interval = Some(Duration::from_millis(20));
+        let mut redundancy = Redundancy::new(at(0));
+        assert_eq!(redundancy.channel().freq(), freq(37));
+
+        assert!(redundancy.copy(Some(0), interval, at(10_000)));
+        assert_eq!(redundancy.channel().freq(), freq(38));
+        assert!(!redundancy.copy(Some(0), interval, at(10_400)));
+        assert!(!redundancy.copy(Some(0), interval, at(10_800)));
+        assert_eq!(redundancy.channel().freq(), freq(37));
+        assert_eq!(redundancy.update(at(11_000)).map(|c| c.freq()), None);
+
+        // The copy on 38 is lost, so the receiver waits there until the copies are over
+        assert!(redundancy.copy(Some(1), interval, at(30_000)));
+        assert_eq!(redundancy.update(at(31_000)).map(|c| c.freq()), None);
+        assert_eq!(
+            redundancy.update(at(32_001)).map(|c| c.freq()),
+            Some(freq(37))
+        );
+
+        // The same sequence number, 256 frames later
+        assert!(redundancy.copy(Some(1), interval, at(50_000)));
+        assert!(!redundancy.copy(Some(1), interval, at(50_400)));
+
+        // Controllers without sequence numbers
+        assert!(redundancy.copy(None, None, at(70_000)));
+        assert!(redundancy.copy(None, None, at(70_400)));
+
+        assert_eq!(redundancy.stats(), CopyStats { copies: [1, 1, 1] });
+        assert_eq!(redundancy.stats().frames(), 3);
+    }
+
+    #[test]
+    fn jammed_channel() {
+        let interval = Some(Duration::from_millis(20));
+        let mut redundancy = Redundancy::new(at(0));
+        assert!(redundancy.copy(Some(0), interval, at(10_000)));
+        assert_eq!(
+            redundancy.update(at(12_001)).map(|c| c.freq()),
+            Some(freq(37))
+        );
+
+        // Channel 37 goes quiet, so the receiver moves on after 30 ms
+        assert_eq!(redundancy.update(at(40_000)).map(|c| c.freq()), None);
+        assert_eq!(
+            redundancy.update(at(40_001)).map(|c| c.freq()),
+            Some(freq(38))
+        );
+        assert!(redundancy.copy(Some(2), interval, at(50_400)));
+        assert!(!redundancy.copy(Some(2), interval, at(50_800)));
+
+        // Frames now start on 38 for the receiver
+        assert_eq!(redundancy.channel().freq(), freq(37));
+        assert_eq!(
+            redundancy.update(at(52_401)).map(|c| c.freq()),
+            Some(freq(38))
+        );
+        assert_eq!(redundancy.update(at(80_000)).map(|c| c.freq()), None);
+    }
+}
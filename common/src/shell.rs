@@ -0,0 +1,569 @@
+//! Line-based command shell on the serial port.
+//!
+//! The shell shares the UART with the log output. Both are serviced from `idle`, which drains the
+//! log queue completely before handing received bytes to the shell, so log records and shell
+//! responses are only ever interleaved at line boundaries. When log output arrives while a line is
+//! being typed, the partial line is erased and printed again below the log output.
+//!
+//! The prompt is only shown once the first byte was received, so captured logs of devices nobody
+//! is typing at don't contain any shell output.
+//!
+//! The commands every device has are parsed into a `Command`. Each firmware passes a table of its
+//! own commands to `Command::parse` and `execute`, like the table of its configuration entries.
+
+use {
+    crate::{
+        config::{self, Entry},
+        logger,
+    },
+    core::{
+        fmt::{self, Write},
+        ptr,
+        sync::atomic::{compiler_fence, Ordering},
+    },
+    cortex_m::{
+        asm,
+        peripheral::{NVIC, SCB},
+    },
+    nrf52810_pac::{Interrupt, UARTE0},
+};
+
+/// Maximum length of a command line in Bytes.
+const LINE_LEN: usize = 64;
+
+const PROMPT: &str = "> ";
+
+/// Maximum number of arguments of a `DeviceCommand`.
+pub const MAX_ARGS: usize = 2;
+
+/// Column the descriptions of commands start at in the `help` output.
+const HELP_COLUMN: usize = 25;
+
+/// A command only some devices have.
+pub struct DeviceCommand {
+    /// Words the command line starts with, eg. `["pwm", "test"]`.
+    pub words: &'static [&'static str],
+    /// Names of the numeric arguments following the words, at most `MAX_ARGS`.
+    pub args: &'static [&'static str],
+    /// Description shown by `help`.
+    pub help: &'static str,
+}
+
+/// A command entered on the shell.
+pub enum Command<'a> {
+    Help,
+    Status,
+    ConfigList,
+    ConfigGet(&'a str),
+    ConfigSet(&'a str, u32),
+    /// Prints (`None`) or changes the log filter (see `logger::Filter`).
+    LogLevel(Option<&'a str>),
+    /// Restores the log filter the firmware was built with.
+    LogReset,
+    /// One of the device's own commands, with its arguments (unused ones are 0).
+    Device(&'static DeviceCommand, [u32; MAX_ARGS]),
+    Reboot,
+    /// Switches the serial port into firmware update mode.
+    Dfu,
+}
+
+/// Error returned when a command line couldn't be parsed.
+pub enum ParseError<'a> {
+    UnknownCommand(&'a str),
+    MissingArgument(&'static str),
+    InvalidArgument(&'a str),
+}
+
+impl<'a> fmt::Display for ParseError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand(cmd) => write!(f, "unknown command '{}'", cmd),
+            ParseError::MissingArgument(arg) => write!(f, "missing argument <{}>", arg),
+            ParseError::InvalidArgument(arg) => write!(f, "invalid argument '{}'", arg),
+        }
+    }
+}
+
+impl<'a> Command<'a> {
+    /// Parses a non-empty command line, which may also be one of the device's own `commands`.
+    pub fn parse(
+        line: &'a str,
+        commands: &[&'static DeviceCommand],
+    ) -> Result<Self, ParseError<'a>> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+
+        let cmd = match (cmd, words.next()) {
+            ("help", None) => Command::Help,
+            ("status", None) => Command::Status,
+            ("config", None) | ("config", Some("list")) => Command::ConfigList,
+            ("config", Some("get")) => Command::ConfigGet(arg(&mut words, "key")?),
+            ("config", Some("set")) => {
+                let key = arg(&mut words, "key")?;
+                let value = arg(&mut words, "value")?;
+                Command::ConfigSet(
+                    key,
+                    value
+                        .parse()
+                        .map_err(|_| ParseError::InvalidArgument(value))?,
+                )
+            }
+            ("log", Some("level")) => Command::LogLevel(words.next()),
+            ("log", Some("reset")) => Command::LogReset,
+            ("reboot", None) => Command::Reboot,
+            ("dfu", None) => Command::Dfu,
+            _ => {
+                let command = commands
+                    .iter()
+                    .find(|command| starts_with(line, command.words))
+                    .ok_or(ParseError::UnknownCommand(line))?;
+                words = line.split_whitespace();
+                for _ in command.words {
+                    words.next();
+                }
+
+                let mut args = [0; MAX_ARGS];
+                for (value, name) in args.iter_mut().zip(command.args) {
+                    let word = arg(&mut words, name)?;
+                    *value = word
+                        .parse()
+                        .map_err(|_| ParseError::InvalidArgument(word))?;
+                }
+                Command::Device(command, args)
+            }
+        };
+
+        match words.next() {
+            Some(extra) => Err(ParseError::InvalidArgument(extra)),
+            None => Ok(cmd),
+        }
+    }
+}
+
+/// Returns whether the first words of `line` are `words`.
+fn starts_with(line: &str, words: &[&str]) -> bool {
+    let mut line = line.split_whitespace();
+    words.iter().all(|word| line.next() == Some(*word))
+}
+
+fn arg<'a, I: Iterator<Item = &'a str>>(
+    words: &mut I,
+    name: &'static str,
+) -> Result<&'a str, ParseError<'a>> {
+    words.next().ok_or(ParseError::MissingArgument(name))
+}
+
+/// Collects received bytes into command lines and takes care of echoing them.
+pub struct Shell {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    /// The line in `buf` was returned by `feed` and must be discarded before accepting more input.
+    complete: bool,
+    overflow: bool,
+    /// The previous byte was a `\r`, so a following `\n` must be ignored.
+    last_cr: bool,
+    /// Whether the user has interacted with the shell yet.
+    active: bool,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LINE_LEN],
+            len: 0,
+            complete: false,
+            overflow: false,
+            last_cr: false,
+            active: false,
+        }
+    }
+
+    /// Feeds a received byte into the shell, echoing it to `out`.
+    ///
+    /// Returns the entered line once it is complete. The caller should execute it and then call
+    /// `prompt`.
+    pub fn feed<W: Write>(&mut self, byte: u8, out: &mut W) -> Option<&str> {
+        if self.complete {
+            self.complete = false;
+            self.len = 0;
+        }
+
+        if !self.active {
+            self.active = true;
+            self.prompt(out);
+        }
+
+        let last_cr = self.last_cr;
+        self.last_cr = byte == b'\r';
+
+        match byte {
+            b'\n' if last_cr => None,
+            b'\r' | b'\n' => {
+                out.write_str("\r\n").ok();
+
+                if self.overflow {
+                    self.overflow = false;
+                    self.len = 0;
+                    writeln!(out, "error: line too long\r").ok();
+                    self.prompt(out);
+                    None
+                } else if self.buf[..self.len].iter().all(|b| *b == b' ') {
+                    self.len = 0;
+                    self.prompt(out);
+                    None
+                } else {
+                    self.complete = true;
+                    // Only printable ASCII is ever put into the buffer
+                    core::str::from_utf8(&self.buf[..self.len]).ok()
+                }
+            }
+            // Backspace and DEL
+            0x08 | 0x7F => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    out.write_str("\x08 \x08").ok();
+                }
+                None
+            }
+            b' '..=b'~' => {
+                if self.len < LINE_LEN {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                    out.write_char(byte as char).ok();
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Prints the prompt and any partially entered line.
+    pub fn prompt<W: Write>(&self, out: &mut W) {
+        if !self.active {
+            return;
+        }
+
+        out.write_str(PROMPT).ok();
+        if !self.complete {
+            if let Ok(line) = core::str::from_utf8(&self.buf[..self.len]) {
+                out.write_str(line).ok();
+            }
+        }
+    }
+
+    /// Erases the prompt line so that log output can be printed.
+    ///
+    /// Call `prompt` once the log output is done.
+    pub fn hide<W: Write>(&self, out: &mut W) {
+        if self.active {
+            out.write_str("\r\x1B[K").ok();
+        }
+    }
+}
+
+/// Non-blocking receiver for the RX line of `UARTE0`.
+///
+/// `Uarte` only supports blocking reads, so this drives the RX DMA directly, one Byte at a time.
+/// The TX side is left to `Uarte`, which owns (and configured) the peripheral. The UARTE's RX FIFO
+/// buffers a few Bytes while we restart reception, which is plenty for interactive use.
+///
+/// Received Bytes make the `UARTE0` interrupt pending without it ever running, so with
+/// SEVONPEND set, a sleeping `wfe` wakes up for them.
+pub struct SerialRx {
+    buf: &'static mut [u8; 1],
+    started: bool,
+}
+
+impl SerialRx {
+    pub fn new(buf: &'static mut [u8; 1]) -> Self {
+        Self {
+            buf,
+            started: false,
+        }
+    }
+
+    /// Returns the next received Byte, if any.
+    pub fn read(&mut self) -> Option<u8> {
+        // Safe, since we only touch the RX registers, which `Uarte` doesn't use
+        let uarte = unsafe { &*UARTE0::ptr() };
+
+        if !self.started {
+            self.started = true;
+            self.start(uarte);
+            return None;
+        }
+
+        if uarte.events_endrx.read().bits() == 0 {
+            return None;
+        }
+        uarte.events_endrx.reset();
+        NVIC::unpend(Interrupt::UARTE0_UART0);
+
+        compiler_fence(Ordering::SeqCst);
+        let byte = unsafe { ptr::read_volatile(self.buf.as_ptr()) };
+        let received = uarte.rxd.amount.read().bits() != 0;
+
+        self.start(uarte);
+
+        if received {
+            Some(byte)
+        } else {
+            None
+        }
+    }
+
+    fn start(&mut self, uarte: &nrf52810_pac::uarte0::RegisterBlock) {
+        uarte.intenset.write(|w| w.endrx().set());
+        uarte
+            .rxd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(self.buf.as_mut_ptr() as u32) });
+        uarte.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+        compiler_fence(Ordering::SeqCst);
+        uarte.tasks_startrx.write(|w| unsafe { w.bits(1) });
+    }
+}
+
+/// Executes the commands that behave the same on every device, with the device's configuration
+/// `entries` and its own `commands`.
+///
+/// `Status`, `Device` and `Dfu` depend on the device and must be handled by the caller.
+pub fn execute<W: Write>(
+    cmd: Command,
+    out: &mut W,
+    entries: &[&'static Entry],
+    commands: &[&'static DeviceCommand],
+) {
+    match cmd {
+        Command::Help => {
+            out.write_str(
+                "status                   show device status\r\n\
+                 config [list]            list configuration values\r\n\
+                 config get <key>         show a configuration value\r\n\
+                 config set <key> <value> change a configuration value\r\n\
+                 log level [<filter>]     show or change the log filter, eg. 'warn,rubble=off'\r\n\
+                 log reset                restore the default log filter\r\n",
+            )
+            .ok();
+            for command in commands {
+                let mut len = 0;
+                for word in command.words {
+                    write!(out, "{} ", word).ok();
+                    len += word.len() + 1;
+                }
+                for arg in command.args {
+                    write!(out, "<{}> ", arg).ok();
+                    len += arg.len() + 3;
+                }
+                for _ in len..HELP_COLUMN {
+                    out.write_char(' ').ok();
+                }
+                writeln!(out, "{}\r", command.help).ok();
+            }
+            out.write_str(
+                "reboot                   reset the device\r\n\
+                 dfu                      receive a firmware update on the serial port\r\n",
+            )
+            .ok();
+        }
+        Command::ConfigList => {
+            for entry in entries {
+                writeln!(out, "{} = {}\r", entry.key, entry.get()).ok();
+            }
+        }
+        Command::ConfigGet(key) => match config::find(entries, key) {
+            Some(entry) => {
+                writeln!(out, "{} = {}\r", entry.key, entry.get()).ok();
+            }
+            None => {
+                writeln!(out, "error: unknown key '{}'\r", key).ok();
+            }
+        },
+        Command::ConfigSet(key, value) => match config::find(entries, key) {
+            Some(entry) => match entry.set(value) {
+                Ok(()) => {
+                    writeln!(out, "{} = {}\r", entry.key, value).ok();
+                }
+                Err(e) => {
+                    writeln!(out, "error: {}\r", e).ok();
+                }
+            },
+            None => {
+                writeln!(out, "error: unknown key '{}'\r", key).ok();
+            }
+        },
+        Command::LogLevel(None) => {
+            writeln!(out, "{}\r", logger::filter()).ok();
+        }
+        Command::LogLevel(Some(spec)) => {
+            let mut filter = logger::filter();
+            match filter.apply(spec) {
+                Ok(()) => {
+                    logger::set_filter(filter);
+                    writeln!(out, "{}\r", filter).ok();
+                }
+                Err(e) => {
+                    writeln!(out, "error: {}\r", e).ok();
+                }
+            }
+        }
+        Command::LogReset => match config::log_filter() {
+            Ok(filter) => {
+                logger::set_filter(filter);
+                writeln!(out, "{}\r", filter).ok();
+            }
+            Err(e) => {
+                writeln!(out, "error: {}\r", e).ok();
+            }
+        },
+        Command::Reboot => reboot(),
+        Command::Status | Command::Device(..) | Command::Dfu => {
+            writeln!(out, "error: not supported on this device\r").ok();
+        }
+    }
+}
+
+/// Performs a system reset.
+pub fn reboot() -> ! {
+    asm::dsb();
+    // VECTKEY and SYSRESETREQ
+    unsafe { (*SCB::ptr()).aircr.write(0x05FA_0004) };
+    asm::dsb();
+
+    loop {
+        asm::wfi();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::string::String};
+
+    static PWM_TEST: DeviceCommand = DeviceCommand {
+        words: &["pwm", "test"],
+        args: &["value"],
+        help: "drive the PWM output",
+    };
+
+    static LED: DeviceCommand = DeviceCommand {
+        words: &["led"],
+        args: &["index", "level"],
+        help: "dim an LED",
+    };
+
+    static COMMANDS: [&DeviceCommand; 2] = [&PWM_TEST, &LED];
+
+    fn parse(line: &str) -> Result<Command<'_>, ParseError<'_>> {
+        Command::parse(line, &COMMANDS)
+    }
+
+    #[test]
+    fn commands() {
+        assert!(matches!(
+            parse("config set beacon.rate 20"),
+            Ok(Command::ConfigSet("beacon.rate", 20))
+        ));
+        assert!(matches!(parse("  config   list "), Ok(Command::ConfigList)));
+        assert!(matches!(parse("log level"), Ok(Command::LogLevel(None))));
+        assert!(matches!(
+            parse("log level warn,rubble=off"),
+            Ok(Command::LogLevel(Some("warn,rubble=off")))
+        ));
+        assert!(matches!(
+            parse("config set beacon.rate fast"),
+            Err(ParseError::InvalidArgument("fast"))
+        ));
+        assert!(matches!(
+            parse("config set beacon.rate"),
+            Err(ParseError::MissingArgument("value"))
+        ));
+        assert!(matches!(
+            parse("config get beacon.rate now"),
+            Err(ParseError::InvalidArgument("now"))
+        ));
+        assert!(matches!(
+            parse("reboot now"),
+            Err(ParseError::UnknownCommand("reboot now"))
+        ));
+    }
+
+    #[test]
+    fn device_commands() {
+        assert!(matches!(
+            parse("pwm test 1200"),
+            Ok(Command::Device(cmd, [1200, 0])) if ptr::eq(cmd, &PWM_TEST)
+        ));
+        assert!(matches!(
+            parse("led 1 50"),
+            Ok(Command::Device(cmd, [1, 50])) if ptr::eq(cmd, &LED)
+        ));
+        assert!(matches!(
+            parse("pwm test"),
+            Err(ParseError::MissingArgument("value"))
+        ));
+        assert!(matches!(
+            parse("led 1"),
+            Err(ParseError::MissingArgument("level"))
+        ));
+        assert!(matches!(
+            parse("pwm test -5"),
+            Err(ParseError::InvalidArgument("-5"))
+        ));
+        assert!(matches!(
+            parse("pwm test 1200 1300"),
+            Err(ParseError::InvalidArgument("1300"))
+        ));
+        assert!(matches!(
+            parse("pwm 1200"),
+            Err(ParseError::UnknownCommand("pwm 1200"))
+        ));
+
+        // Devices without the command don't know it
+        assert!(matches!(
+            Command::parse("pwm test 1200", &[]),
+            Err(ParseError::UnknownCommand("pwm test 1200"))
+        ));
+    }
+
+    #[test]
+    fn help() {
+        let mut out = String::new();
+        execute(Command::Help, &mut out, &[], &COMMANDS);
+        assert!(out.contains("log reset                restore the default log filter\r\n"));
+        assert!(out.contains("pwm test <value>         drive the PWM output\r\n"));
+        assert!(out.contains("led <index> <level>      dim an LED\r\n"));
+        assert!(out.ends_with("receive a firmware update on the serial port\r\n"));
+
+        out.clear();
+        execute(Command::Help, &mut out, &[], &[]);
+        assert!(!out.contains("pwm test"));
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut shell = Shell::new();
+        let mut out = String::new();
+
+        let mut line = None;
+        for &byte in b"staz\x08tus\r\n" {
+            if let Some(l) = shell.feed(byte, &mut out) {
+                line = Some(String::from(l));
+            }
+        }
+        assert_eq!(line.as_deref(), Some("status"));
+        assert_eq!(out, "> staz\x08 \x08tus\r\n");
+
+        // The `\n` of the previous `\r\n` didn't start another line
+        out.clear();
+        shell.prompt(&mut out);
+        assert_eq!(out, "> ");
+    }
+}
@@ -0,0 +1,287 @@
+//! Closed-loop speed control: the thumb sets a target speed, and the receiver drives the ESC to
+//! hold it.
+//!
+//! Normally, the throttle is open loop: each throttle value maps straight to a pulse width (see
+//! `output::OutputMap`), which the ESC turns into a motor current or duty cycle. In speed mode,
+//! throttle values above rest set a target motor speed instead, in proportion up to `max_rpm` at
+//! the highest pulse width. `SpeedControl` then runs a PID loop against the motor speed the ESC
+//! reports (a VESC reports it over UART), and commands the ESC through the same pulse widths.
+//! Throttle values at rest and below bypass the loop, so braking and the failsafe work as before.
+//!
+//! The integral term is kept within the range of the output, and stops growing while the output
+//! is saturated, so the loop doesn't wind up while the motor can't keep up (uphill, or at full
+//! power) and overshoot once it can. When the ESC stops reporting the speed for
+//! `FEEDBACK_TIMEOUT_MS`, the loop stops and the output goes back to neutral.
+//!
+//! The receiver PCB doesn't route a UART RX line (see `bluefly_receiver_bsp`), so the firmware
+//! can't read the motor speed yet, and has neither a speed mode nor configuration entries for it.
+//! The loop runs in the host simulation, against a model of the motor and the vehicle, whose
+//! receiver takes the mode, the target speed at full throttle and the gains as settings.
+
+use {crate::output::OutputMap, core::cmp, rubble::time::Instant};
+
+/// Time without a speed reported by the ESC after which the loop stops, in milliseconds.
+pub const FEEDBACK_TIMEOUT_MS: u32 = 100;
+
+/// Gains of the PID loop, in thousandths of a PWM compare tick.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Gains {
+    /// Per RPM of speed error.
+    pub kp: u32,
+    /// Per RPM of speed error and second.
+    pub ki: u32,
+    /// Per RPM per second of change of the speed.
+    pub kd: u32,
+}
+
+impl Gains {
+    /// Gains that hold the speed of the simulation's motor model with little overshoot.
+    pub const DEFAULT: Self = Self {
+        kp: 400,
+        ki: 1000,
+        kd: 50,
+    };
+}
+
+/// A PID controller with an output from 0 to a maximum, in PWM compare ticks.
+pub struct Pid {
+    /// The integral term, in thousandths of a compare tick.
+    integral: i64,
+    last_measured: Option<i32>,
+}
+
+impl Default for Pid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pid {
+    pub const fn new() -> Self {
+        Self {
+            integral: 0,
+            last_measured: None,
+        }
+    }
+
+    /// Computes the output for the `measured` value, `dt_micros` after the last one, given the
+    /// `target` value, and returns it.
+    pub fn update(
+        &mut self,
+        target: i32,
+        measured: i32,
+        dt_micros: u32,
+        gains: Gains,
+        max: u16,
+    ) -> u16 {
+        let max = i64::from(max) * 1000;
+        let error = i64::from(target) - i64::from(measured);
+        let p = i64::from(gains.kp) * error;
+        // On the measured value rather than the error, so changes of the target don't kick
+        let d = match self.last_measured {
+            Some(last) if dt_micros > 0 => {
+                -i64::from(gains.kd) * (i64::from(measured) - i64::from(last)) * 1_000_000
+                    / i64::from(dt_micros)
+            }
+            _ => 0,
+        };
+        self.last_measured = Some(measured);
+
+        // Only integrate while the output isn't saturated in the direction of the error
+        let output = p + self.integral + d;
+        let saturated = (output >= max && error > 0) || (output <= 0 && error < 0);
+        if !saturated {
+            let integral =
+                self.integral + i64::from(gains.ki) * error * i64::from(dt_micros) / 1_000_000;
+            self.integral = cmp::min(cmp::max(integral, 0), max);
+        }
+        (cmp::min(cmp::max(p + self.integral + d, 0), max) / 1000) as u16
+    }
+
+    /// Forgets the history, before the loop starts again.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+/// The receiver's speed loop.
+pub struct SpeedControl {
+    pid: Pid,
+    /// The target speed in RPM, while the throttle is above rest.
+    target: Option<u32>,
+    /// When the loop started, or the ESC last reported the speed.
+    last_update: Option<Instant>,
+    /// Whether the ESC reported the speed since the loop started.
+    reported: bool,
+}
+
+impl Default for SpeedControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpeedControl {
+    pub const fn new() -> Self {
+        Self {
+            pid: Pid::new(),
+            target: None,
+            last_update: None,
+            reported: false,
+        }
+    }
+
+    /// Handles the throttle value `throttle`, received at `now`, which sets the target speed for
+    /// `max_rpm` at the highest pulse width of `map`.
+    ///
+    /// Returns the compare value to output right away if the throttle is at rest or brakes, which
+    /// bypasses the loop. Otherwise, the output is up to `update`.
+    pub fn throttle(
+        &mut self,
+        throttle: u8,
+        max_rpm: u32,
+        map: &OutputMap,
+        now: Instant,
+    ) -> Option<u16> {
+        let pwm = map.pwm(throttle);
+        let highest = cmp::min(map.limit, map.top);
+        if pwm <= map.neutral || highest <= map.neutral {
+            self.stop();
+            return Some(pwm);
+        }
+
+        let travel = u32::from(pwm - map.neutral);
+        self.target = Some(travel * max_rpm / u32::from(highest - map.neutral));
+        if self.last_update.is_none() {
+            self.last_update = Some(now);
+        }
+        None
+    }
+
+    /// Handles the motor speed `rpm` reported by the ESC at `now`, and returns the compare value
+    /// to output, or `None` while the loop doesn't run.
+    pub fn update(&mut self, rpm: i32, now: Instant, gains: Gains, map: &OutputMap) -> Option<u16> {
+        let target = self.target?;
+        let dt = match self.last_update {
+            Some(last) if self.reported => now.raw_micros().wrapping_sub(last.raw_micros()),
+            _ => 0,
+        };
+        self.last_update = Some(now);
+        self.reported = true;
+
+        let range = cmp::min(map.limit, map.top).saturating_sub(map.neutral);
+        let command = self.pid.update(target as i32, rpm, dt, gains, range);
+        Some(map.neutral + command)
+    }
+
+    /// Stops the loop once the ESC stopped reporting the speed, and returns whether it did. The
+    /// output should then be set to neutral.
+    pub fn check(&mut self, now: Instant) -> bool {
+        match self.last_update {
+            Some(last)
+                if now.raw_micros().wrapping_sub(last.raw_micros()) / 1000
+                    > FEEDBACK_TIMEOUT_MS =>
+            {
+                self.stop();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops the loop, when the output goes to neutral or below.
+    pub fn stop(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Returns the target speed in RPM, while the loop runs.
+    pub fn target(&self) -> Option<u32> {
+        self.target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_raw_micros(ms * 1000)
+    }
+
+    #[test]
+    fn pid() {
+        let gains = Gains {
+            kp: 100,
+            ki: 1000,
+            kd: 0,
+        };
+        let mut pid = Pid::new();
+        assert_eq!(pid.update(1000, 0, 0, gains, 280), 100);
+        // 1 tick per RPM of error and second
+        assert_eq!(pid.update(1000, 0, 10_000, gains, 280), 110);
+        assert_eq!(pid.update(1000, 1000, 10_000, gains, 280), 10);
+        assert_eq!(pid.update(0, 1000, 0, gains, 280), 0);
+
+        // While the output is saturated, the integral doesn't grow
+        let mut pid = Pid::new();
+        for _ in 0..100 {
+            assert_eq!(pid.update(5000, 0, 100_000, gains, 280), 280);
+        }
+        assert_eq!(pid.update(1000, 1000, 0, gains, 280), 0);
+
+        // The integral stays within the output range
+        let gains = Gains { kp: 0, ..gains };
+        let mut pid = Pid::new();
+        for _ in 0..5 {
+            pid.update(1000, 0, 100_000, gains, 280);
+        }
+        assert_eq!(pid.update(1000, 1000, 0, gains, 280), 280);
+        assert_eq!(pid.update(0, 1000, 1_000_000, gains, 280), 0);
+        assert_eq!(pid.update(1000, 1000, 0, gains, 280), 0);
+
+        // The derivative works against changes of the measured value
+        let gains = Gains {
+            kp: 0,
+            ki: 0,
+            kd: 100,
+        };
+        let mut pid = Pid::new();
+        pid.update(1000, 0, 0, gains, 280);
+        assert_eq!(pid.update(1000, -100, 100_000, gains, 280), 100);
+        assert_eq!(pid.update(1000, -100, 100_000, gains, 280), 0);
+    }
+
+    #[test]
+    fn speed_control() {
+        let map = OutputMap::DEFAULT;
+        let gains = Gains::DEFAULT;
+        let mut speed = SpeedControl::new();
+
+        // Braking and rest bypass the loop
+        assert_eq!(speed.throttle(0, 5000, &map, at(0)), Some(map.pwm(0)));
+        assert_eq!(speed.throttle(115, 5000, &map, at(0)), Some(map.neutral));
+        assert_eq!(speed.update(0, at(10), gains, &map), None);
+        assert!(!speed.check(at(500)));
+
+        // Halfway between neutral and the limit is half the speed
+        assert_eq!(speed.throttle(185, 5000, &map, at(1000)), None);
+        assert_eq!(speed.target(), Some(2500));
+        assert!(speed.update(0, at(1010), gains, &map).unwrap() > map.neutral);
+        assert!(speed.update(5000, at(1020), gains, &map).unwrap() < map.limit);
+
+        // Going back to rest stops the loop
+        assert_eq!(speed.throttle(115, 5000, &map, at(1030)), Some(map.neutral));
+        assert_eq!(speed.update(0, at(1040), gains, &map), None);
+
+        // So does the ESC going quiet
+        speed.throttle(255, 5000, &map, at(2000));
+        assert_eq!(speed.target(), Some(5000));
+        assert!(!speed.check(at(2100)));
+        assert!(speed.check(at(2101)));
+        assert_eq!(speed.target(), None);
+        speed.throttle(255, 5000, &map, at(3000));
+        speed.update(0, at(3050), gains, &map);
+        assert!(!speed.check(at(3150)));
+        assert!(speed.check(at(3151)));
+    }
+}
@@ -2,7 +2,7 @@
 
 use {
     core::mem,
-    nrf52810_hal::nrf52810_pac::{TIMER0, TIMER1, TIMER2},
+    nrf52810_pac::{TIMER0, TIMER1, TIMER2},
     rubble::{
         link::NextUpdate,
         time::{Instant, Timer},
@@ -107,6 +107,12 @@ mod sealed {
 ///
 /// We use `CC[0]` to read the counter value, and `CC[1]` to set timer interrupts.
 pub trait NrfTimerExt: sealed::Sealed {
+    /// Returns another handle to the same timer peripheral.
+    ///
+    /// # Safety
+    ///
+    /// Only one of the handles may configure the timer; the others must only read the counter, like
+    /// `StampSource` does.
     unsafe fn duplicate(&self) -> Self;
 
     /// Initialize the timer so that it counts at a rate of 1 MHz.
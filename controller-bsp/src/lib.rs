@@ -0,0 +1,82 @@
+//! Board support for the controller PCB (`hardware/controller`).
+//!
+//! `Board::new` splits port 0 into the typed pins of everything on the board, so the firmware never
+//! has to name a pin itself. `pins` lists the same assignments as numbers, checked for conflicts at
+//! compile time.
+
+#![no_std]
+
+pub use nrf52810_hal as hal;
+
+use hal::{
+    gpio::{
+        p0::{P0_02, P0_03},
+        Floating, GpioExt, Input, Level, Output, Pin, PushPull,
+    },
+    nrf52810_pac::P0,
+    uarte,
+};
+
+/// Port 0 pins used by the controller.
+pub mod pins {
+    bluefly_common::pin_map! {
+        /// Throttle potentiometer wiper, AIN0 (J1 pin 3).
+        THROTTLE = 2,
+        /// Battery voltage through the R5/R6 divider, AIN1.
+        BATTERY = 3,
+        /// UART RX. Not routed on the board.
+        SERIAL_RX = 8,
+        /// UART TX, J2 pin 7. The schematic calls this net CS, but the display is driven without
+        /// chip select.
+        SERIAL_TX = 31,
+        /// Display SPI clock. The schematic has this on P0.15 and MOSI on P0.13, swapped.
+        DISPLAY_SCK = 13,
+        /// Display SPI data.
+        DISPLAY_MOSI = 15,
+        /// Display data/command select, J2 pin 5.
+        DISPLAY_DC = 17,
+        /// Display reset, J2 pin 6.
+        DISPLAY_RST = 19,
+    }
+}
+
+/// Pins of the SSD1306 display on J2.
+pub struct DisplayPins {
+    pub sck: Pin<Output<PushPull>>,
+    pub mosi: Pin<Output<PushPull>>,
+    pub dc: Pin<Output<PushPull>>,
+    /// Starts out high, which keeps the display out of reset.
+    pub rst: Pin<Output<PushPull>>,
+}
+
+/// Everything connected to the nRF52810 on the controller.
+pub struct Board {
+    pub serial: uarte::Pins,
+    pub display: DisplayPins,
+    pub throttle: P0_02<Input<Floating>>,
+    pub battery: P0_03<Input<Floating>>,
+}
+
+impl Board {
+    /// Configures the pins of port 0 for the controller.
+    pub fn new(p0: P0) -> Self {
+        let p0 = p0.split();
+
+        Self {
+            serial: uarte::Pins {
+                rxd: p0.p0_08.into_floating_input().degrade(),
+                txd: p0.p0_31.into_push_pull_output(Level::Low).degrade(),
+                cts: None,
+                rts: None,
+            },
+            display: DisplayPins {
+                sck: p0.p0_13.into_push_pull_output(Level::Low).degrade(),
+                mosi: p0.p0_15.into_push_pull_output(Level::Low).degrade(),
+                dc: p0.p0_17.into_push_pull_output(Level::Low).degrade(),
+                rst: p0.p0_19.into_push_pull_output(Level::High).degrade(),
+            },
+            throttle: p0.p0_02.into_floating_input(),
+            battery: p0.p0_03.into_floating_input(),
+        }
+    }
+}
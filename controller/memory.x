@@ -0,0 +1,28 @@
+/* nRF52810: the firmware runs from slot 0, behind the 256 Byte image header (see
+   `bluefly_dfu::layout`) */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00008100, LENGTH = 72K - 256
+  RAM : ORIGIN = 0x20000000, LENGTH = 24K - 256
+  /* Crash reports, which have to survive a reset (see `crash`) */
+  RETAINED : ORIGIN = 0x20005F00, LENGTH = 256
+}
+
+/* Not initialized at startup, so the contents are kept across resets */
+SECTIONS
+{
+  .retained (NOLOAD) :
+  {
+    *(.retained .retained.*);
+  } > RETAINED
+}
+
+/* Format strings of binary log records (see `bluefly_binlog`). The section isn't loaded, so the
+   strings don't take up flash, and the addresses of its symbols are the IDs sent in log records */
+SECTIONS
+{
+  .binlog 0 (INFO) :
+  {
+    *(.binlog .binlog.*);
+  }
+}
@@ -0,0 +1,211 @@
+//! Runtime configuration of the controller (see `bluefly_common::config`).
+
+pub use bluefly_common::config::log_filter;
+
+use {
+    bluefly_common::{
+        config::{self, Entry},
+        esb,
+        mode::{self, Phy},
+        output,
+        power::Timeouts,
+        protocol::{self, MAX_CONTROLLERS},
+        receivers::{self, Trim, MAX_RECEIVERS},
+    },
+    bluefly_dfu::{
+        flash::{FlashError, Nvmc},
+        layout::FLASH_SIZE,
+    },
+    rubble::time::Duration,
+};
+
+/// Number of beacons sent per second while the throttle moves.
+pub static BEACON_RATE: Entry = Entry::new("beacon.rate", 50, output::MIN_BEACON_RATE, 200);
+
+/// Number of beacons sent per second while the throttle is steady. Slower beacons would make the
+/// receiver fail safe between them (see `bluefly_common::output::MIN_BEACON_RATE`).
+pub static MIN_BEACON_RATE: Entry = Entry::new("beacon.min_rate", 20, output::MIN_BEACON_RATE, 200);
+
+/// Number of beacons sent per second while idle.
+pub static IDLE_BEACON_RATE: Entry =
+    Entry::new("beacon.idle_rate", 10, output::MIN_BEACON_RATE, 200);
+
+/// 14-bit throttle reading with the thumb released.
+pub static THROTTLE_REST: Entry = Entry::new("throttle.rest", 7360, 0, 0x3FFF);
+
+/// Seconds without activity until the display is dimmed.
+pub static POWER_DIM: Entry = Entry::new("power.dim", 10, 1, 240);
+
+/// Seconds without activity until the display is turned off and beacons slow down.
+pub static POWER_IDLE: Entry = Entry::new("power.idle", 30, 1, 240);
+
+/// Seconds without activity until the controller turns itself off.
+pub static POWER_OFF: Entry = Entry::new("power.off", 120, 1, 240);
+
+/// PHY the controller asks the receiver to use, in Mbit/s (see `bluefly_common::mode`).
+pub static RADIO_PHY: Entry = Entry::new("radio.phy", 1, 1, 2);
+
+/// TX power, as an index into `bluefly_common::mode::TX_POWERS` (from -40 dBm at 0 to +4 dBm at
+/// 9).
+pub static RADIO_TX_LEVEL: Entry = Entry::new("radio.tx_level", 9, 0, 9);
+
+/// Whether to use ESB instead of BLE beacons for the throttle link (1) or not (0), which has to be
+/// the same on both devices (see `bluefly_common::esb`). The radio can only be switched from BLE to
+/// ESB; switching back takes a reset. Defaults to 1 with the `esb` feature.
+pub static LINK_ESB: Entry = Entry::new("link.esb", cfg!(feature = "esb") as u32, 0, 1);
+
+/// Channel used in ESB mode, as an offset from 2400 MHz.
+pub static ESB_CHANNEL: Entry = Entry::new("esb.channel", esb::DEFAULT_CHANNEL as u32, 0, 100);
+
+/// Whether throttle beacons hop over the data channels (1) or go out on the advertising channels
+/// (0), which has to be the same on both devices (see `bluefly_common::hopping`).
+pub static LINK_HOPPING: Entry = Entry::new("link.hopping", 0, 0, 1);
+
+/// Number of receivers driven, each configured with its number in `link.id` (see
+/// `bluefly_common::receivers`).
+pub static LINK_RECEIVERS: Entry = Entry::persistent("link.receivers", 1, 1, MAX_RECEIVERS as u32);
+
+/// Whether the throttle sent to receiver 0 is mirrored around the rest position (1) or not (0).
+pub static RX0_INVERT: Entry = Entry::persistent("rx0.invert", 0, 0, 1);
+
+/// Percentage of the throttle's travel from the rest position sent to receiver 0.
+pub static RX0_SCALE: Entry = Entry::persistent("rx0.scale", Trim::DEFAULT.scale as u32, 0, 100);
+
+/// Whether the throttle sent to receiver 1 is mirrored around the rest position (1) or not (0).
+pub static RX1_INVERT: Entry = Entry::persistent("rx1.invert", 0, 0, 1);
+
+/// Percentage of the throttle's travel from the rest position sent to receiver 1.
+pub static RX1_SCALE: Entry = Entry::persistent("rx1.scale", Trim::DEFAULT.scale as u32, 0, 100);
+
+/// Number of the controller, which picks the address it sends from (see
+/// `bluefly_common::ownership`). Receivers only follow the controllers they're paired to.
+pub static LINK_CONTROLLER: Entry =
+    Entry::persistent("link.controller", 0, 0, MAX_CONTROLLERS as u32 - 1);
+
+/// Whether cruise can be engaged with a gesture on the throttle (see `bluefly_common::cruise`).
+pub static CRUISE_ENABLE: Entry = Entry::new("cruise.enable", 0, 0, 1);
+
+/// All configuration entries, in the order they're listed in.
+pub static ENTRIES: [&Entry; 19] = [
+    &BEACON_RATE,
+    &MIN_BEACON_RATE,
+    &IDLE_BEACON_RATE,
+    &THROTTLE_REST,
+    &POWER_DIM,
+    &POWER_IDLE,
+    &POWER_OFF,
+    &RADIO_PHY,
+    &RADIO_TX_LEVEL,
+    &LINK_ESB,
+    &ESB_CHANNEL,
+    &LINK_HOPPING,
+    &LINK_RECEIVERS,
+    &RX0_INVERT,
+    &RX0_SCALE,
+    &RX1_INVERT,
+    &RX1_SCALE,
+    &LINK_CONTROLLER,
+    &CRUISE_ENABLE,
+];
+
+/// Restores the persistent entries from flash (see `bluefly_common::config::load`).
+pub fn load() -> Result<(), FlashError> {
+    // Only reads the settings pages
+    let flash = unsafe { Nvmc::new(FLASH_SIZE) };
+    config::load(&flash, &ENTRIES)
+}
+
+/// Stores the persistent entries that changed (see `bluefly_common::config::save`).
+///
+/// This may erase a page, which delays the throttle beacons. The shell that changes them is only
+/// reachable on the bench, so they're saved right after a command.
+pub fn save() -> Result<(), FlashError> {
+    // Only the settings pages are written, and only from `idle`, which also runs the DFU transports
+    let mut flash = unsafe { Nvmc::new(FLASH_SIZE) };
+    config::save(&mut flash, &ENTRIES)
+}
+
+/// Returns the time between beacons sent at the rate `entry`.
+pub fn beacon_interval(entry: &Entry) -> Duration {
+    Duration::from_micros(1_000_000 / entry.get())
+}
+
+/// Returns the times without activity until the lower power states.
+pub fn power_timeouts() -> Timeouts {
+    Timeouts {
+        dim: Duration::from_secs(POWER_DIM.get() as u16),
+        idle: Duration::from_secs(POWER_IDLE.get() as u16),
+        off: Duration::from_secs(POWER_OFF.get() as u16),
+    }
+}
+
+/// Returns the PHY to request: the one configured with `RADIO_PHY`, or 1M while driving more than
+/// one receiver, since only one of them answers.
+pub fn phy() -> Phy {
+    if receivers() > 1 {
+        Phy::Ble1M
+    } else {
+        Phy::from_config(RADIO_PHY.get())
+    }
+}
+
+/// Returns the TX power configured with `RADIO_TX_LEVEL`, in dBm.
+pub fn tx_power() -> i8 {
+    mode::tx_power(RADIO_TX_LEVEL.get())
+}
+
+/// Returns the channel to use if ESB is configured.
+pub fn esb_channel() -> Option<u8> {
+    if LINK_ESB.get() == 1 {
+        Some(ESB_CHANNEL.get() as u8)
+    } else {
+        None
+    }
+}
+
+/// Whether throttle beacons hop over the data channels.
+pub fn hopping() -> bool {
+    LINK_HOPPING.get() == 1
+}
+
+/// Whether cruise can be engaged.
+pub fn cruise() -> bool {
+    CRUISE_ENABLE.get() == 1
+}
+
+/// Returns the number of receivers driven.
+pub fn receivers() -> u8 {
+    LINK_RECEIVERS.get() as u8
+}
+
+/// Returns the adjustments to the throttle sent to receiver `id`.
+pub fn trim(id: u8) -> Trim {
+    let (invert, scale) = match id {
+        0 => (&RX0_INVERT, &RX0_SCALE),
+        _ => (&RX1_INVERT, &RX1_SCALE),
+    };
+    Trim {
+        invert: invert.get() == 1,
+        scale: scale.get() as u8,
+    }
+}
+
+/// Returns the address the controller sends from.
+pub fn address() -> [u8; 6] {
+    protocol::controller_address(LINK_CONTROLLER.get() as u8)
+}
+
+/// Encodes the payload of a throttle beacon carrying the 14-bit throttle reading `adc` to each
+/// receiver driven, adjusted by its trim, and requesting `phy`. Returns the frame and its length.
+pub fn throttle_frame(
+    adc: u16,
+    interval: Duration,
+    phy: Phy,
+) -> ([u8; receivers::MAX_FRAME_LEN], usize) {
+    let rest = THROTTLE_REST.get() as u16;
+    let mut trimmed = [0; MAX_RECEIVERS as usize];
+    for (id, trimmed) in trimmed.iter_mut().enumerate() {
+        *trimmed = trim(id as u8).apply(adc, rest);
+    }
+    receivers::throttle_frame(&trimmed[..usize::from(receivers())], interval, phy)
+}
@@ -0,0 +1,129 @@
+//! Firmware updates over the serial port.
+//!
+//! The serial port carries the messages from `bluefly_dfu::protocol`, and the received image is
+//! written to slot 1. Unlike the receiver, the controller doesn't accept BLE connections, so it
+//! has no DFU GATT service. Only images signed with the built-in key that don't downgrade the
+//! firmware are accepted. Once a transfer is complete, `UPDATE_READY` is set and `idle` resets the
+//! device so the bootloader can install the update.
+//!
+//! Receiver images are accepted as well. They're kept in slot 1 and `RELAY_READY` is set, so they
+//! get relayed to the receiver (see `relay`).
+
+use {
+    bluefly_dfu::{
+        flash::{FlashError, Nvmc},
+        image::Device,
+        layout::{self, FLASH_SIZE},
+        protocol::{Request, RESPONSE_LEN},
+        sign::PUBLIC_KEY,
+        slip, swap,
+        target::DfuTarget,
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    nrf52810_hal::{nrf52810_pac::UARTE0, uarte::Uarte},
+    rubble::time::{Duration, Instant},
+};
+
+/// Set once an update was received and the device should be reset to install it.
+pub static UPDATE_READY: AtomicBool = AtomicBool::new(false);
+
+/// Set once a receiver image was received and should be relayed to the receiver.
+pub static RELAY_READY: AtomicBool = AtomicBool::new(false);
+
+/// How long the serial port stays in DFU mode without receiving a request.
+const SERIAL_TIMEOUT_SECS: u32 = 10;
+
+fn target() -> DfuTarget<Nvmc> {
+    // Only slot 1 and the state pages are written, never the running firmware. The serial port is
+    // only serviced from `idle`, so the NVMC is never accessed from two places at the same time.
+    DfuTarget::new(
+        unsafe { Nvmc::new(FLASH_SIZE) },
+        layout::NRF52810,
+        PUBLIC_KEY,
+    )
+    .relay_to(Device::Receiver)
+}
+
+fn check_complete(target: &DfuTarget<Nvmc>) {
+    if target.is_complete() {
+        UPDATE_READY.store(true, Ordering::SeqCst);
+    }
+    if target.is_ready_for_relay() {
+        RELAY_READY.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Confirms that the running image works, so the bootloader won't roll it back.
+///
+/// Must be called on every boot once the firmware is up and running.
+pub fn confirm() -> Result<(), FlashError> {
+    let mut flash = unsafe { Nvmc::new(FLASH_SIZE) };
+    swap::confirm(&mut flash, &layout::NRF52810)
+}
+
+/// Receives updates in SLIP frames on the serial port.
+///
+/// The shell switches the serial port into DFU mode with the `dfu` command. While in DFU mode, log
+/// output is discarded to keep the port free for the transfer.
+pub struct SerialDfu {
+    target: DfuTarget<Nvmc>,
+    decoder: slip::Decoder,
+    active: bool,
+    last_request: Instant,
+}
+
+impl SerialDfu {
+    pub fn new() -> Self {
+        Self {
+            target: target(),
+            decoder: slip::Decoder::new(),
+            active: false,
+            last_request: Instant::from_raw_micros(0),
+        }
+    }
+
+    /// Switches the serial port into DFU mode.
+    pub fn start(&mut self, now: Instant) {
+        self.active = true;
+        self.last_request = now;
+    }
+
+    /// Returns whether the serial port is in DFU mode.
+    ///
+    /// DFU mode ends when the transfer is aborted, or when no request was received for a while.
+    pub fn is_active(&mut self, now: Instant) -> bool {
+        if self.active
+            && !self.target.is_complete()
+            && now.duration_since(self.last_request) > Duration::from_secs(SERIAL_TIMEOUT_SECS)
+        {
+            self.active = false;
+        }
+
+        self.active
+    }
+
+    /// Handles a received Byte, sending a response when a request is complete.
+    pub fn feed(&mut self, byte: u8, now: Instant, serial: &mut Uarte<UARTE0>) {
+        let request = match self.decoder.feed(byte) {
+            Some(request) => request,
+            None => return,
+        };
+        self.last_request = now;
+
+        let aborted = match Request::decode(request) {
+            Some(Request::Abort) => true,
+            _ => false,
+        };
+        let response = self.target.handle_raw(request);
+
+        let mut buf = [0; slip::encoded_len(RESPONSE_LEN)];
+        if let Some(len) = slip::encode(&response, &mut buf) {
+            serial.write(&buf[..len]).ok();
+        }
+
+        check_complete(&self.target);
+        if aborted {
+            self.active = false;
+        }
+    }
+}
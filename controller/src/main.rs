@@ -1,33 +1,42 @@
 #![no_std]
 #![no_main]
-#![feature(alloc)]
-#![feature(global_allocator)]
-#![feature(lang_items)]
 
-// We need to import this crate explicitly so we have a panic handler
-#[macro_use]
-extern crate alloc;
-extern crate alloc_cortex_m;
-extern crate panic_semihosting;
-#[macro_use]
-extern crate cortex_m_rt as rt;
-
-mod logger;
+mod config;
+mod dfu;
+mod power;
+mod relay;
+mod watchdog;
 
 use {
-    crate::logger::{BbqLogger, StampedLogger},
-    alloc_cortex_m::CortexMHeap,
+    crate::{
+        dfu::SerialDfu,
+        relay::{ReceiverRelay, ResponseCallback},
+        watchdog::Task,
+    },
     bbqueue::{bbq, BBQueue, Consumer},
-    core::alloc::Layout,
-    core::fmt::Write,
-    embedded_graphics::{fonts::Font12x16, image::Image1BPP, prelude::*},
+    bluefly_binlog::{debug, info, warn, Fmt},
+    bluefly_common::{
+        crash,
+        cruise::{Cruise, Event, Reason},
+        display, esb,
+        hopping::{self, Hopper},
+        logger::{self, BbqLogger, Filter},
+        mode::{Negotiation, Phy, RadioMode},
+        power::{Power, PowerState},
+        protocol::{self, BeaconRate},
+        radio::{BleRadio, PacketBuffer},
+        shell::{self, Command, SerialRx, Shell},
+        timer::{BleTimer, StampSource},
+    },
+    bluefly_controller_bsp::Board,
+    bluefly_dfu::relay::{RelayStatus, MAX_FRAME_LEN},
+    core::{fmt::Write, sync::atomic::Ordering},
     embedded_hal::adc::OneShot,
-    log::{info, LevelFilter},
+    log::LevelFilter,
     nrf52810_hal::{
-        self as hal,
         gpio::{
             p0::{P0_02, P0_03},
-            Floating, Input, Level, Output, Pin, PushPull,
+            Floating, Input, Output, Pin, PushPull,
         },
         nrf52810_pac::{self as pac, SPIM0, UARTE0},
         prelude::*,
@@ -37,19 +46,13 @@ use {
     },
     rtfm::app,
     rubble::{
-        beacon::Beacon,
-        gatt::GattServer,
-        l2cap::{BleChannelMap, L2CAPState},
+        beacon::BeaconScanner,
         link::{
-            ad_structure::AdStructure, queue, AddressKind, DeviceAddress, HardwareInterface,
-            LinkLayer, Responder, MAX_PDU_SIZE,
+            filter::WhitelistFilter, AddressKind, DeviceAddress, HardwareInterface, LinkLayer,
+            RadioCmd, MAX_PDU_SIZE,
         },
-        security_manager::NoSecurity,
-        time::Timer,
-    },
-    rubble_nrf52810::{
-        radio::{BleRadio, PacketBuffer},
-        timer::{BleTimer, StampSource},
+        phy::AdvertisingChannel,
+        time::{Duration, Timer},
     },
     ssd1306::{
         displayrotation::DisplayRotation, interface::spi::SpiInterface,
@@ -57,10 +60,10 @@ use {
     },
 };
 
-#[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
-
-type Logger = StampedLogger<StampSource<pac::TIMER0>, BbqLogger>;
+#[cfg(not(feature = "binary-log"))]
+type Logger = logger::WriteLogger<logger::StampedLogger<StampSource<pac::TIMER0>, BbqLogger>>;
+#[cfg(feature = "binary-log")]
+type Logger = logger::BinaryLogger<StampSource<pac::TIMER0>>;
 
 /// Hardware interface for the BLE stack (nRF52810 implementation).
 pub struct HwNRf52810 {}
@@ -71,33 +74,88 @@ impl HardwareInterface for HwNRf52810 {
 }
 
 /// Stores the global logger used by the `log` crate.
-static mut LOGGER: Option<logger::WriteLogger<Logger>> = None;
+static mut LOGGER: Option<Logger> = None;
+
+/// Flags a throttle `frame`: as releasing the receiver while the controller is idle, so that
+/// another controller can take over (see `bluefly_common::ownership`), and as sent while
+/// `cruising`.
+fn flag_frame(state: PowerState, cruising: bool, frame: &mut [u8]) {
+    if let PowerState::Idle | PowerState::Off = state {
+        protocol::release(frame);
+    }
+    if cruising {
+        protocol::set_cruise(frame);
+    }
+}
+
+/// Logs a change of the cruise state.
+fn log_cruise(event: Option<Event>) {
+    match event {
+        Some(Event::Engaged(adc)) => {
+            info!("cruise engaged at {}%", display::throttle_percent(adc))
+        }
+        Some(Event::Disengaged(reason)) => info!("cruise disengaged: {}", reason.name()),
+        None => {}
+    }
+}
+
+/// Makes `TIMER1` fire once the acknowledgement of the ESB packet just sent is overdue.
+///
+/// The timer starts over at the next beacon, which gives up on the packet anyway, so the timeout
+/// doesn't fire if it would come after that.
+fn arm_ack_timeout(timer: &pac::TIMER1) {
+    // The timer ticks every 32 µs
+    let ticks = esb::ACK_TIMEOUT_MICROS.div_ceil(32);
+    timer.tasks_capture[1].write(|w| unsafe { w.bits(1) });
+    let at = timer.cc[1].read().bits() + ticks;
+    timer.cc[1].write(|w| unsafe { w.bits(at) });
+    timer.events_compare[1].reset();
+    timer.intenset.write(|w| w.compare1().set());
+}
 
 #[app(device = nrf52810_hal::nrf52810_pac)]
 const APP: () = {
     static mut BLE_TX_BUF: PacketBuffer = [0; MAX_PDU_SIZE];
     static mut BLE_RX_BUF: PacketBuffer = [0; MAX_PDU_SIZE];
     static mut BLE_LL: LinkLayer<HwNRf52810> = ();
-    static mut BLE_R: Responder<BleChannelMap<GattServer<'static>, NoSecurity>> = ();
     static mut RADIO: BleRadio = ();
+    static mut SCANNER: BeaconScanner<
+        ResponseCallback,
+        WhitelistFilter<core::iter::Once<DeviceAddress>>,
+    > = ();
+    static mut RELAY: ReceiverRelay = ();
     static mut BEACON_TIMER: pac::TIMER1 = ();
+    static mut RATE: BeaconRate = BeaconRate::new();
+    static mut SEQ: u8 = 0;
+    static mut NEGOTIATION: Negotiation = Negotiation::new();
+    static mut ESB_SENDER: esb::Sender = esb::Sender::new();
+    static mut HOPPER: Hopper = ();
+    static mut CRUISE: Cruise = Cruise::new();
+    static mut BEACON_CLOCK: StampSource<pac::TIMER0> = ();
     static mut SERIAL: Uarte<UARTE0> = ();
+    static mut SERIAL_RX_BUF: [u8; 1] = [0; 1];
+    static mut SERIAL_RX: SerialRx = ();
+    static mut SHELL: Shell = Shell::new();
+    static mut SERIAL_DFU: SerialDfu = ();
     static mut LOG_SINK: Consumer = ();
+    static mut UPTIME: StampSource<pac::TIMER0> = ();
+    static mut THROTTLE: u16 = 0;
+    static mut POWER_STATE: PowerState = PowerState::Active;
 
     static mut DISPLAY: GraphicsMode<SpiInterface<Spim<SPIM0>, Pin<Output<PushPull>>>> = ();
+    static mut DISPLAY_RST: Pin<Output<PushPull>> = ();
 
     static mut ADC: Saadc = ();
     static mut ADC_CONTROL_PIN: P0_02<Input<Floating>> = ();
     static mut ADC_BATT_PIN: P0_03<Input<Floating>> = ();
 
-    #[init(resources = [BLE_TX_BUF, BLE_RX_BUF])]
+    #[init(resources = [BLE_TX_BUF, BLE_RX_BUF, SERIAL_RX_BUF])]
     fn init() {
         //hprintln!("\n<< INIT >>\n").ok();
-        {
-            let start = rt::heap_start() as usize;
-            let size = 1024; // in bytes
-            unsafe { ALLOCATOR.init(start, size) }
-        }
+        // Reported once the logger is up
+        let last_crash = crash::take();
+        let reset_reason = watchdog::reset_reason(&device.POWER);
+        let settings = config::load();
 
         {
             // On reset the internal high frequency clock is used, but starting the HFCLK task
@@ -111,6 +169,7 @@ const APP: () = {
         }
 
         let ble_timer = BleTimer::init(device.TIMER0);
+        power::init(&mut core.SCB);
 
         {
             // Configure TIMER1 as the beacon timer. It's only used as a 16-bit timer.
@@ -121,62 +180,88 @@ const APP: () = {
             timer.prescaler.write(|w| unsafe { w.prescaler().bits(9) }); // 0-9
             timer.intenset.write(|w| w.compare0().set());
             timer.shorts.write(|w| w.compare0_clear().enabled());
-            timer.cc[0].write(|w| unsafe { w.bits(31_250 / 50) }); // ~50x per second
+            timer.cc[0].write(|w| unsafe { w.bits(31_250 / config::BEACON_RATE.get()) });
             timer.tasks_clear.write(|w| unsafe { w.bits(1) });
 
             timer.tasks_start.write(|w| unsafe { w.bits(1) });
         }
 
-        let p0 = device.P0.split();
-
-        let mut serial = {
-            let rxd = p0.p0_08.into_floating_input().degrade();
-            let txd = p0.p0_31.into_push_pull_output(Level::Low).degrade();
-
-            let pins = hal::uarte::Pins {
-                rxd,
-                txd,
-                cts: None,
-                rts: None,
-            };
+        let board = Board::new(device.P0);
 
-            device
-                .UARTE0
-                .constrain(pins, Parity::EXCLUDED, Baudrate::BAUD1M)
-        };
+        let mut serial = device
+            .UARTE0
+            .constrain(board.serial, Parity::EXCLUDED, Baudrate::BAUD1M);
         writeln!(serial, "\n--- INIT ---").unwrap();
 
         let radio = BleRadio::new(device.RADIO, resources.BLE_TX_BUF, resources.BLE_RX_BUF);
 
+        let uptime = ble_timer.create_stamp_source();
+        let beacon_clock = ble_timer.create_stamp_source();
         let log_stamper = ble_timer.create_stamp_source();
         let (tx, log_sink) = bbq![10000].unwrap().split();
-        let logger = StampedLogger::new(BbqLogger::new(tx), log_stamper);
+        #[cfg(not(feature = "binary-log"))]
+        let log =
+            logger::WriteLogger::new(logger::StampedLogger::new(BbqLogger::new(tx), log_stamper));
+        #[cfg(feature = "binary-log")]
+        let log = logger::BinaryLogger::new(BbqLogger::new(tx), log_stamper);
 
-        let log = logger::WriteLogger::new(logger);
         // Safe, since we're the only thread and interrupts are off
         unsafe {
             LOGGER = Some(log);
             log::set_logger(LOGGER.as_ref().unwrap()).unwrap();
+            #[cfg(feature = "binary-log")]
+            bluefly_binlog::set_logger(LOGGER.as_ref().unwrap()).unwrap();
         }
-        log::set_max_level(LevelFilter::max());
+        let log_filter = config::log_filter();
+        logger::set_filter(match log_filter {
+            Ok(filter) => filter,
+            Err(_) => Filter::new(LevelFilter::Info),
+        });
 
         info!("READY");
+        if let Err(e) = log_filter {
+            warn!("invalid log filter: {:?}", Fmt(e));
+        }
+        info!("reset reason: {}", reset_reason);
+        if let Some(crash) = &last_crash {
+            warn!("crashed before the last reset: {:?}", Fmt(crash));
+        }
+        if let Err(e) = settings {
+            warn!("failed to load settings: {:?}", Fmt(e));
+        }
 
-        // Create TX/RX queues
-        let (tx, _tx_cons) = queue::create(bbq![1024].unwrap());
-        let (_rx_prod, rx) = queue::create(bbq![1024].unwrap());
+        // Make sure the bootloader doesn't roll back to the previous firmware
+        if let Err(e) = dfu::confirm() {
+            warn!("failed to confirm firmware image: {:?}", Fmt(e));
+        }
 
-        // Create the actual BLE stack objects
+        // The controller doesn't accept connections; the link layer only provides the timer the
+        // scanner runs on
         let ll = LinkLayer::<HwNRf52810>::new(
-            DeviceAddress::new([169, 255, 235, 206, 50, 121], AddressKind::Random),
+            DeviceAddress::new(config::address(), AddressKind::Random),
             ble_timer,
         );
 
-        let resp = Responder::new(
-            tx,
-            rx,
-            L2CAPState::new(BleChannelMap::with_attributes(GattServer::new())),
-        );
+        // Receiver updates are relayed over the beacon link; the receiver answers with beacons
+        let (responses_tx, responses_rx) = bbq![64].unwrap().split();
+        let scanner = {
+            let filter = WhitelistFilter::from_address(DeviceAddress::new(
+                protocol::RECEIVER_ADDRESS,
+                AddressKind::Random,
+            ));
+
+            BeaconScanner::with_filter(
+                ResponseCallback {
+                    responses: responses_tx,
+                },
+                filter,
+            )
+        };
+        let mut receiver_relay = ReceiverRelay::new(responses_rx);
+        // Continue relaying an update that was interrupted by a reset
+        if let Some(pending) = relay::pending() {
+            receiver_relay.start(pending);
+        }
 
         let adc = {
             let config = SaadcConfig {
@@ -191,115 +276,435 @@ const APP: () = {
             Saadc::new(device.SAADC, config)
         };
 
-        let display = {
-            let spi = {
-                let mosi = Some(p0.p0_15.into_push_pull_output(Level::Low).degrade());
-                let sck = p0.p0_13.into_push_pull_output(Level::Low).degrade();
-
-                Spim::new(
-                    device.SPIM0,
-                    spim::Pins {
-                        sck,
-                        mosi,
-                        miso: None,
-                    },
-                    Frequency::M8,
-                    MODE_0,
-                    0u8,
-                )
-            };
+        let (display, display_rst) = {
+            let pins = board.display;
+            let spi = Spim::new(
+                device.SPIM0,
+                spim::Pins {
+                    sck: pins.sck,
+                    mosi: Some(pins.mosi),
+                    miso: None,
+                },
+                Frequency::M8,
+                MODE_0,
+                0u8,
+            );
 
-            let dc = p0.p0_17.into_push_pull_output(Level::Low).degrade();
             let mut display: GraphicsMode<_> = ssd1306::Builder::new()
                 .with_rotation(DisplayRotation::Rotate90)
-                .connect_spi(spi, dc)
+                .connect_spi(spi, pins.dc)
                 .into();
 
             // Reset display
-            let mut rst = p0.p0_19.into_push_pull_output(Level::High).degrade();
+            let mut rst = pins.rst;
             rst.set_low();
             rst.set_high();
 
             display.init().unwrap();
             display.flush().unwrap();
 
-            display
+            (display, rst)
         };
 
+        // Every task checks in from now on
+        watchdog::start(&device.WDT);
+
         RADIO = radio;
+        SCANNER = scanner;
+        RELAY = receiver_relay;
         BLE_LL = ll;
-        BLE_R = resp;
         BEACON_TIMER = device.TIMER1;
         SERIAL = serial;
+        SERIAL_RX = SerialRx::new(resources.SERIAL_RX_BUF);
+        SERIAL_DFU = SerialDfu::new();
         LOG_SINK = log_sink;
+        UPTIME = uptime;
+        BEACON_CLOCK = beacon_clock;
+        HOPPER = Hopper::new(config::address());
 
         DISPLAY = display;
+        DISPLAY_RST = display_rst;
 
         ADC = adc;
-        ADC_CONTROL_PIN = p0.p0_02.into_floating_input();
-        ADC_BATT_PIN = p0.p0_03.into_floating_input();
+        ADC_CONTROL_PIN = board.throttle;
+        ADC_BATT_PIN = board.battery;
     }
 
-    #[interrupt(resources = [RADIO, BLE_LL])]
+    #[interrupt(resources = [RADIO, BLE_LL, SCANNER, ESB_SENDER])]
     fn RADIO() {
+        // In ESB mode, the radio only listens for the acknowledgement of a throttle packet
+        if resources.RADIO.is_esb() {
+            resources.RADIO.esb_ack_interrupt(&mut resources.ESB_SENDER);
+            return;
+        }
+
         let next_update = resources
             .RADIO
-            .recv_interrupt(resources.BLE_LL.timer().now(), &mut resources.BLE_LL);
+            .recv_interrupt(resources.BLE_LL.timer().now(), &mut resources.SCANNER);
         resources.BLE_LL.timer().configure_interrupt(next_update);
     }
 
     /// Fire the beacon.
-    #[interrupt(resources = [BEACON_TIMER, RADIO, ADC, ADC_CONTROL_PIN, DISPLAY])]
+    #[interrupt(resources = [
+        BEACON_TIMER,
+        RATE,
+        SEQ,
+        NEGOTIATION,
+        ESB_SENDER,
+        HOPPER,
+        BEACON_CLOCK,
+        RADIO,
+        RELAY,
+        ADC,
+        ADC_CONTROL_PIN,
+        DISPLAY,
+        THROTTLE,
+        POWER_STATE,
+        CRUISE,
+    ])]
     fn TIMER1() {
+        watchdog::check_in(Task::Beacon);
+
+        // Send the throttle packet again if its acknowledgement is overdue
+        let timer = &*resources.BEACON_TIMER;
+        if timer.events_compare[1].read().bits() != 0 {
+            timer.events_compare[1].reset();
+            timer.intenclr.write(|w| w.compare1().clear());
+            let sender = &mut *resources.ESB_SENDER;
+            if sender.is_pending() {
+                if resources.RADIO.esb_retransmit(sender) {
+                    arm_ack_timeout(timer);
+                } else {
+                    debug!("throttle packet not acknowledged");
+                    let now = resources.BEACON_CLOCK.now();
+                    log_cruise(resources.CRUISE.disengage(Reason::Link, now));
+                }
+            }
+            if timer.events_compare[0].read().bits() == 0 {
+                return;
+            }
+        }
+
         // acknowledge event
         resources.BEACON_TIMER.events_compare[0].reset();
 
-        let device_address = DeviceAddress::new([169, 255, 235, 206, 50, 121], AddressKind::Random);
-
         let val: u16 = resources.ADC.read(resources.ADC_CONTROL_PIN).unwrap();
 
-        //info!("read val: {}", val);
+        // Hold the throttle while cruising, unless the receiver disengaged it
+        let now = resources.BEACON_CLOCK.now();
+        let cruise = &mut *resources.CRUISE;
+        if relay::CRUISE_CANCELLED.swap(false, Ordering::Relaxed) {
+            log_cruise(cruise.disengage(Reason::Link, now));
+        }
+        let rest = config::THROTTLE_REST.get() as u16;
+        log_cruise(cruise.update(val, rest, config::cruise(), now));
+        let val = cruise.throttle(val);
+        *resources.THROTTLE = val;
+
+        // Send the next beacon soon if the throttle moves or an update is relayed, and pick up
+        // changes to the beacon rates and the power state
+        let state = *resources.POWER_STATE;
+        let fastest = config::beacon_interval(&config::BEACON_RATE);
+        let interval = match state {
+            PowerState::Active | PowerState::Dimmed => {
+                let slowest = config::beacon_interval(&config::MIN_BEACON_RATE);
+                let interval = resources.RATE.next(val, fastest, slowest);
+                if resources.RELAY.status().is_some() {
+                    fastest
+                } else {
+                    interval
+                }
+            }
+            PowerState::Idle | PowerState::Off => {
+                config::beacon_interval(&config::IDLE_BEACON_RATE)
+            }
+        };
+        // The timer ticks every 32 µs
+        resources.BEACON_TIMER.cc[0].write(|w| unsafe { w.bits(interval.as_micros() / 32) });
+
+        let radio = &mut *resources.RADIO;
+        if let Some(channel) = config::esb_channel() {
+            if radio.esb_channel() != Some(channel) {
+                info!("radio: ESB on channel {}", channel);
+                radio.start_esb(channel);
+            }
+        }
 
-        let beacon = Beacon::new(
-            device_address,
-            &[AdStructure::Unknown {
-                ty: 0xFF,
-                data: &[(val / 64) as u8],
-            }],
-        )
-        .unwrap();
+        if radio.is_esb() {
+            // There's only one PHY in ESB mode
+            radio.set_mode(RadioMode {
+                phy: Phy::Ble1M,
+                tx_power: config::tx_power(),
+            });
+            let (mut frame, len) = config::throttle_frame(val, interval, Phy::Ble1M);
+            flag_frame(state, cruise.is_engaged(), &mut frame[..len]);
+            let mut payload = [0; esb::MAX_PAYLOAD_LEN];
+            let len = esb::throttle_payload(*resources.SEQ, &frame[..len], &mut payload);
+            let sender = &mut *resources.ESB_SENDER;
+            if sender.is_pending() {
+                debug!("throttle packet not acknowledged");
+                log_cruise(cruise.disengage(Reason::Link, now));
+            }
+            radio.esb_send(sender, &payload[..len]);
+            arm_ack_timeout(&*resources.BEACON_TIMER);
+            *resources.SEQ = resources.SEQ.wrapping_add(1);
+        } else {
+            // Follow the receiver's answers to the PHY requested, and pick up changes to the TX
+            // power
+            let negotiation = &mut *resources.NEGOTIATION;
+            let answer = Phy::from_code(relay::MODE_ANSWER.swap(0, Ordering::Relaxed));
+            let switched = match answer {
+                Some(phy) => negotiation.answer(phy, now),
+                None => negotiation.update(now),
+            };
+            if let Some(phy) = switched {
+                info!("radio phy: {}", phy.name());
+            }
+            // Likewise for the channels to hop over, starting over when the address changes
+            let address = config::address();
+            let hopper = &mut *resources.HOPPER;
+            if hopper.address() != address {
+                *hopper = Hopper::new(address);
+            }
+            let hopping = config::hopping();
+            if hopping {
+                let changed = match relay::take_map_answer() {
+                    Some(map) => hopper.answer(map, now),
+                    None => hopper.update(now),
+                };
+                if let Some(map) = changed {
+                    info!("hopping over {} channels", map.count());
+                }
+            }
 
-        beacon.broadcast(&mut *resources.RADIO);
+            // Stop listening for relay responses while transmitting
+            radio.configure_receiver(RadioCmd::Off);
+            radio.set_mode(RadioMode {
+                phy: negotiation.phy(),
+                tx_power: config::tx_power(),
+            });
+            let seq = *resources.SEQ;
+            let (mut frame, len) = config::throttle_frame(val, interval, config::phy());
+            let frame = &mut frame[..len];
+            flag_frame(state, cruise.is_engaged(), frame);
+            if hopping {
+                let channels = hopper.channels(seq);
+                hopping::broadcast_sequenced(radio, address, seq, frame, channels);
+            } else {
+                protocol::broadcast_sequenced(radio, address, seq, frame);
+            }
+            *resources.SEQ = seq.wrapping_add(1);
+
+            let mut frame = [0; MAX_FRAME_LEN];
+            if let Some(len) = resources.RELAY.poll(&mut frame) {
+                if hopping {
+                    let channel = hopper.relay_channel(seq);
+                    hopping::broadcast(radio, address, &frame[..len], channel);
+                } else {
+                    protocol::broadcast(radio, address, &frame[..len]);
+                }
+            }
+            radio.configure_receiver(RadioCmd::ListenAdvertising {
+                channel: AdvertisingChannel::first(),
+            });
+        }
 
-        resources.DISPLAY.clear();
-        resources.DISPLAY.draw(
-            Font12x16::render_str(&format!("{}%", val / 164))
-                .with_stroke(Some(1u8.into()))
-                .translate(Coord::new(16, 16))
-                .into_iter(),
-        );
-        resources.DISPLAY.draw(
-            Image1BPP::new(include_bytes!("./rust.raw"), 32, 32)
-                .translate(Coord::new(32, 96))
-                .into_iter(),
-        );
-        resources.DISPLAY.flush().unwrap();
+        // `idle` blanks the display when it turns off
+        let display = &mut *resources.DISPLAY;
+        let render = match state {
+            PowerState::Active => display::render,
+            PowerState::Dimmed => display::render_dimmed,
+            PowerState::Idle | PowerState::Off => return,
+        };
+        display.clear();
+        render(&mut *display, val);
+        display::render_cruise(&mut *display, cruise.status(now));
+        display.flush().unwrap();
     }
 
-    #[idle(resources = [LOG_SINK, SERIAL, BLE_R])]
+    #[idle(resources = [
+        LOG_SINK,
+        SERIAL,
+        SERIAL_RX,
+        SHELL,
+        SERIAL_DFU,
+        UPTIME,
+        THROTTLE,
+        RELAY,
+        RADIO,
+        ESB_SENDER,
+        HOPPER,
+        CRUISE,
+        POWER_STATE,
+        DISPLAY,
+        DISPLAY_RST,
+    ])]
     fn idle() -> ! {
-        // Drain the logging buffer through the serial connection
+        let mut update_received = None;
+        let mut power = Power::new(resources.UPTIME.now());
+
         loop {
+            watchdog::check_in(Task::Idle);
+
+            let now = resources.UPTIME.now();
+            let dfu_active = resources.SERIAL_DFU.is_active(now);
+            // Whether there may be more to do right away
+            let mut busy = false;
+
+            // Drain the log through the serial connection
+            let mut logged = false;
             while let Ok(grant) = resources.LOG_SINK.read() {
+                if dfu_active {
+                    // The serial port is busy receiving an update
+                    resources.LOG_SINK.release(grant.buf().len(), grant);
+                    continue;
+                }
+                if !logged {
+                    logged = true;
+                    resources.SHELL.hide(&mut *resources.SERIAL);
+                }
+
                 for chunk in grant.buf().chunks(255) {
                     resources.SERIAL.write(chunk).unwrap();
                 }
 
                 resources.LOG_SINK.release(grant.buf().len(), grant);
             }
+            if logged {
+                busy = true;
+                resources.SHELL.prompt(&mut *resources.SERIAL);
+            }
+
+            if let Some(byte) = resources.SERIAL_RX.read() {
+                busy = true;
+                power.activity(now);
+                let serial = &mut *resources.SERIAL;
+                if dfu_active {
+                    resources.SERIAL_DFU.feed(byte, now, serial);
+                } else if let Some(line) = resources.SHELL.feed(byte, serial) {
+                    match Command::parse(line, &[]) {
+                        Ok(Command::Status) => {
+                            let throttle = resources.THROTTLE.lock(|throttle| *throttle);
+                            let (mode, esb) =
+                                resources.RADIO.lock(|radio| (radio.mode(), radio.is_esb()));
+                            let link_mode = if esb { "ESB" } else { mode.phy.name() };
+                            writeln!(
+                                serial,
+                                "uptime: {}\r\nthrottle: {}\r\npower: {}\r\n\
+                                 beacon rate: {} Hz\r\nradio: {}, {} dBm\r\n\
+                                 log messages dropped: {}\r",
+                                resources.UPTIME.now(),
+                                throttle,
+                                power.state().name(),
+                                config::BEACON_RATE.get(),
+                                link_mode,
+                                mode.tx_power,
+                                logger::DROPPED.load(Ordering::Relaxed),
+                            )
+                            .ok();
+                            if let Some(RelayStatus::Sending { offset, size }) =
+                                resources.RELAY.lock(|relay| relay.status())
+                            {
+                                writeln!(serial, "receiver update: {} / {} Bytes\r", offset, size)
+                                    .ok();
+                            }
+                            if resources.CRUISE.lock(|cruise| cruise.is_engaged()) {
+                                writeln!(serial, "cruise: engaged\r").ok();
+                            }
+                            if config::hopping() && !esb {
+                                let map = resources.HOPPER.lock(|hopper| hopper.map());
+                                writeln!(
+                                    serial,
+                                    "hopping: {} of {} channels\r",
+                                    map.count(),
+                                    hopping::CHANNELS
+                                )
+                                .ok();
+                            }
+                            if esb {
+                                let stats = resources.ESB_SENDER.lock(|sender| sender.stats());
+                                writeln!(
+                                    serial,
+                                    "esb: {} of {} packets acknowledged, {} retransmits\r",
+                                    stats.acked, stats.packets, stats.retransmits
+                                )
+                                .ok();
+                            }
+                        }
+                        Ok(Command::Dfu) => {
+                            writeln!(serial, "waiting for update\r").ok();
+                            resources.SERIAL_DFU.start(now);
+                            continue;
+                        }
+                        Ok(cmd) => shell::execute(cmd, serial, &config::ENTRIES, &[]),
+                        Err(e) => {
+                            writeln!(serial, "error: {}\r", e).ok();
+                        }
+                    }
+                    if let Err(e) = config::save() {
+                        warn!("failed to save settings: {:?}", Fmt(e));
+                    }
+                    resources.SHELL.prompt(serial);
+                }
+            }
+
+            if dfu::RELAY_READY.swap(false, Ordering::SeqCst) {
+                if let Some(pending) = relay::pending() {
+                    resources.RELAY.lock(|relay| relay.start(pending));
+                }
+            }
+            match resources.RELAY.lock(|relay| relay.take_finished()) {
+                Some(RelayStatus::Done) => {
+                    info!("receiver update relayed");
+                    relay::discard();
+                }
+                Some(RelayStatus::Failed(status)) => {
+                    warn!("receiver refused the update: {:?}", Fmt(status));
+                    relay::discard();
+                }
+                _ => {}
+            }
+
+            // Give the updater some time to receive the last response before resetting
+            if update_received.is_none() && dfu::UPDATE_READY.load(Ordering::SeqCst) {
+                info!("update received, rebooting");
+                update_received = Some(now);
+            }
+            if let Some(since) = update_received {
+                if now.duration_since(since) > Duration::from_secs(1) {
+                    shell::reboot();
+                }
+            }
 
-            if resources.BLE_R.has_work() {
-                resources.BLE_R.process_one().unwrap();
+            // Stay up while anything is going on
+            let throttle = resources.THROTTLE.lock(|throttle| *throttle);
+            power.throttle(throttle, config::THROTTLE_REST.get() as u16, now);
+            let relaying = resources.RELAY.lock(|relay| relay.status()).is_some();
+            if dfu_active || relaying || update_received.is_some() {
+                power.activity(now);
+            }
+            if let Some(state) = power.update(now, &config::power_timeouts()) {
+                info!("power state: {}", state.name());
+                resources
+                    .POWER_STATE
+                    .lock(|power_state| *power_state = state);
+                match state {
+                    PowerState::Idle => resources.DISPLAY.lock(|display| {
+                        display.clear();
+                        display.flush().unwrap();
+                    }),
+                    PowerState::Off => {
+                        // Hold the display in reset, which turns it off
+                        resources.DISPLAY_RST.set_low();
+                        power::off();
+                    }
+                    _ => {}
+                }
+            }
+
+            // Interrupts leave work for the next round
+            if !busy {
+                power::sleep();
             }
         }
     }
@@ -323,9 +728,3 @@ const APP: () = {
     }
     */
 };
-
-#[lang = "oom"]
-#[no_mangle]
-pub fn rust_oom(layout: Layout) -> ! {
-    panic!();
-}
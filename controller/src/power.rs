@@ -0,0 +1,46 @@
+//! Sleeping, and turning the controller off (see `bluefly_common::power`).
+//!
+//! The controller has no button, so moving the throttle is what wakes it from System OFF: the
+//! throttle pin senses for the opposite of the logic level it reads when the controller turns off.
+//! The wiper rests close to the input threshold, so the thumb doesn't have to move far.
+
+use {
+    bluefly_controller_bsp::pins,
+    cortex_m::{asm, peripheral::SCB},
+    nrf52810_hal::nrf52810_pac::{P0, POWER},
+};
+
+/// Lets pending interrupts wake `sleep`, even disabled ones.
+pub fn init(scb: &mut SCB) {
+    const SEVONPEND: u32 = 1 << 4;
+    unsafe { scb.scr.modify(|scr| scr | SEVONPEND) };
+}
+
+/// Sleeps until an interrupt or a received serial Byte needs attention.
+///
+/// This can return spuriously.
+pub fn sleep() {
+    asm::wfe();
+}
+
+/// Enters System OFF. The next throttle movement resets the controller.
+pub fn off() -> ! {
+    // Safe, since we're shutting down and nothing else will touch the peripherals again
+    let (p0, power) = unsafe { (&*P0::ptr(), &*POWER::ptr()) };
+
+    let pin = pins::THROTTLE as usize;
+    let high = p0.in_.read().bits() & (1 << pin) != 0;
+    p0.pin_cnf[pin].modify(|_, w| {
+        if high {
+            w.sense().low()
+        } else {
+            w.sense().high()
+        }
+    });
+
+    power.systemoff.write(|w| w.systemoff().enter());
+    // Entering System OFF takes a moment
+    loop {
+        asm::wfe();
+    }
+}
@@ -0,0 +1,184 @@
+//! Relaying receiver updates over the radio link.
+//!
+//! Receiver images sent to the controller are kept in slot 1 (see `dfu`). `TIMER1` relays them to
+//! the receiver in beacons sent along with the throttle beacons, and the receiver answers with
+//! beacons of its own, which the scanner passes on through a queue. The image is only removed from
+//! slot 1 once the receiver accepted or refused it, so the relay resumes after a reset.
+//!
+//! Everything that takes long (verifying the image and erasing flash) is done from `idle`, so the
+//! throttle beacons aren't delayed.
+
+use {
+    bbqueue::{Consumer, Producer},
+    bluefly_binlog::{info, warn, Fmt},
+    bluefly_common::{
+        cruise,
+        hopping::{self, ChannelMap},
+        mode,
+        protocol::{self, Frames},
+    },
+    bluefly_dfu::{
+        flash::Nvmc,
+        image::Device,
+        layout::{self, FLASH_SIZE},
+        relay::{self, Relay, RelayStatus, MAX_FRAME_LEN},
+        sign::PUBLIC_KEY,
+    },
+    core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+    rubble::{beacon::ScanCallback, link::ad_structure::AdStructure, link::DeviceAddress},
+};
+
+/// Number of beacons after which an unanswered request is sent again.
+const RETRY_BEACONS: u32 = 3;
+
+fn flash() -> Nvmc {
+    // Slot 1 is only read here. It is erased by `discard` from `idle`, which also runs the DFU
+    // transports, so the NVMC is never written from two places at the same time.
+    unsafe { Nvmc::new(FLASH_SIZE) }
+}
+
+/// Looks for a receiver image in slot 1 and prepares relaying it.
+///
+/// This verifies the whole image, so it must not be called from an interrupt handler.
+pub fn pending() -> Option<Relay> {
+    let flash = flash();
+    let layout = layout::NRF52810;
+    let size = relay::pending(&flash, &layout, &PUBLIC_KEY, Device::Receiver)?;
+
+    info!("relaying receiver update ({} Bytes)", size);
+    Relay::new(&flash, layout.slot1, size, RETRY_BEACONS).ok()
+}
+
+/// Removes the relayed image from slot 1.
+pub fn discard() {
+    if let Err(e) = relay::discard(&mut flash(), &layout::NRF52810) {
+        warn!("failed to remove receiver update: {:?}", Fmt(e));
+    }
+}
+
+/// The relay shared by `TIMER1`, which exchanges the frames, and `idle`, which starts it.
+pub struct ReceiverRelay {
+    relay: Option<Relay>,
+    flash: Nvmc,
+    /// Response frames picked up by the scanner.
+    responses: Consumer,
+}
+
+impl ReceiverRelay {
+    pub fn new(responses: Consumer) -> Self {
+        Self {
+            relay: None,
+            flash: flash(),
+            responses,
+        }
+    }
+
+    /// Starts relaying an image, replacing the one currently relayed.
+    pub fn start(&mut self, relay: Relay) {
+        self.relay = Some(relay);
+    }
+
+    pub fn status(&self) -> Option<RelayStatus> {
+        self.relay.as_ref().map(Relay::status)
+    }
+
+    /// Removes the relay once it is done or failed, returning its final status.
+    pub fn take_finished(&mut self) -> Option<RelayStatus> {
+        match self.status()? {
+            RelayStatus::Sending { .. } => None,
+            status => {
+                self.relay = None;
+                Some(status)
+            }
+        }
+    }
+
+    /// Called for every throttle beacon. Writes the relay frame to broadcast next into `frame`,
+    /// returning its length.
+    pub fn poll(&mut self, frame: &mut [u8; MAX_FRAME_LEN]) -> Option<usize> {
+        let relay = match &mut self.relay {
+            Some(relay) => relay,
+            None => {
+                // Drop responses that arrive after the relay is over
+                while let Ok(grant) = self.responses.read() {
+                    let len = grant.buf().len();
+                    self.responses.release(len, grant);
+                }
+                return None;
+            }
+        };
+
+        while let Ok(grant) = self.responses.read() {
+            let len = grant.buf().len();
+            for response in Frames(grant.buf()) {
+                relay.handle_frame(response);
+            }
+            self.responses.release(len, grant);
+        }
+
+        relay.poll(&self.flash, frame).unwrap_or(None)
+    }
+}
+
+/// The PHY the receiver last answered a mode request with (see `bluefly_common::mode`), as its
+/// code, or 0 if it didn't answer since `TIMER1` last looked.
+pub static MODE_ANSWER: AtomicU8 = AtomicU8::new(0);
+
+/// The lower 32 bits of the channel map the receiver last answered with (see
+/// `bluefly_common::hopping`).
+static MAP_ANSWER_LOW: AtomicU32 = AtomicU32::new(0);
+
+/// The upper bits of the channel map the receiver last answered with, with bit 7 set, or 0 if it
+/// didn't answer since `TIMER1` last looked.
+///
+/// `RADIO` and `TIMER1` run at the same priority, so `TIMER1` never sees half an answer.
+static MAP_ANSWER_HIGH: AtomicU8 = AtomicU8::new(0);
+
+/// Returns the channel map the receiver last answered with, if it answered since the last call.
+pub fn take_map_answer() -> Option<ChannelMap> {
+    let high = MAP_ANSWER_HIGH.swap(0, Ordering::Relaxed);
+    if high == 0 {
+        return None;
+    }
+    let low = MAP_ANSWER_LOW.load(Ordering::Relaxed);
+    ChannelMap::from_bits(u64::from(high & 0x7F) << 32 | u64::from(low))
+}
+
+/// Whether the receiver disengaged cruise (see `bluefly_common::cruise`) since `TIMER1` last
+/// looked.
+pub static CRUISE_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Passes relay responses broadcast by the receiver on to the `ReceiverRelay`, its answers to mode
+/// requests and channel maps on through `MODE_ANSWER` and `take_map_answer`, and its notices that
+/// it disengaged cruise through `CRUISE_CANCELLED`.
+pub struct ResponseCallback {
+    pub responses: Producer,
+}
+
+impl ScanCallback for ResponseCallback {
+    fn beacon<'a, I>(&mut self, _adv_addr: DeviceAddress, adv_data: I)
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        match protocol::payload(adv_data) {
+            Some(data) if relay::is_frame(data) => {
+                // If the queue is full, the request will be sent again
+                protocol::push_frame(&mut self.responses, data);
+            }
+            Some(data) => {
+                if let Some(phy) = mode::answer(data) {
+                    MODE_ANSWER.store(phy.code(), Ordering::Relaxed);
+                }
+                if let Some(map) = hopping::map_answer(data) {
+                    let bits = map.bits();
+                    MAP_ANSWER_LOW.store(bits as u32, Ordering::Relaxed);
+                    MAP_ANSWER_HIGH.store((bits >> 32) as u8 | 0x80, Ordering::Relaxed);
+                }
+                if cruise::is_cancel(data) {
+                    CRUISE_CANCELLED.store(true, Ordering::Relaxed);
+                }
+            }
+            None => (),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Host tool for building update images and flashing them over the serial port.
+//!
+//! ```notrust
+//! cargo run -p dfu-tool --target x86_64-unknown-linux-gnu -- \
+//!     image target/thumbv7em-none-eabi/release/controller.bin controller.img --device controller \
+//!     --version 0.1.0 --key keys/dev.key
+//! cargo run -p dfu-tool --target x86_64-unknown-linux-gnu -- \
+//!     flash controller.img --port /dev/ttyUSB0
+//! ```
+//!
+//! The firmware binary is the raw output of `arm-none-eabi-objcopy -O binary`. Flashing switches
+//! the shell into DFU mode, so the device must be running a firmware with update support. Receiver
+//! images can also be flashed to the controller, which relays them to the receiver.
+
+use {
+    bluefly_dfu::{
+        image::{
+            Crc32, Device as TargetDevice, ImageHeader, Version, FLAG_ALLOW_DOWNGRADE, HEADER_SIZE,
+        },
+        protocol::{Request, Response, Status, DATA_HEADER_LEN},
+        sign::{self, SEED_LEN},
+        slip,
+    },
+    serialport::SerialPortSettings,
+    std::{
+        error::Error,
+        fs,
+        io::{self, Read, Write},
+        path::PathBuf,
+        str::FromStr,
+        thread,
+        time::{Duration, Instant},
+    },
+    structopt::StructOpt,
+};
+
+/// Number of image Bytes sent per `Data` request.
+const CHUNK_LEN: usize = 128;
+
+/// How often a request is retried before giving up.
+const RETRIES: usize = 5;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "dfu-tool",
+    about = "Firmware updates for the controller and the receiver"
+)]
+enum Opt {
+    /// Generates a new signing key.
+    ///
+    /// Writes the secret seed to `<name>.key` and the public key to `<name>.pub`.
+    #[structopt(name = "keygen")]
+    Keygen {
+        #[structopt(parse(from_os_str))]
+        name: PathBuf,
+    },
+    /// Builds a signed update image from a firmware binary.
+    #[structopt(name = "image")]
+    Image {
+        /// The firmware binary.
+        #[structopt(parse(from_os_str))]
+        binary: PathBuf,
+        /// Where to write the image.
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// The device the firmware was built for (`controller` or `receiver`).
+        #[structopt(long = "device", parse(try_from_str = "parse_device"))]
+        device: TargetDevice,
+        /// Version of the firmware (`major.minor.patch`).
+        #[structopt(long = "version", parse(try_from_str = "parse_version"))]
+        version: Version,
+        /// The secret seed to sign the image with.
+        #[structopt(long = "key", parse(from_os_str))]
+        key: PathBuf,
+        /// Allow the image to replace firmware with a higher version.
+        #[structopt(long = "allow-downgrade")]
+        allow_downgrade: bool,
+    },
+    /// Sends an update image to a device connected to a serial port.
+    #[structopt(name = "flash")]
+    Flash {
+        /// The update image.
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        /// The serial port the device is connected to.
+        #[structopt(long = "port", default_value = "/dev/ttyUSB0")]
+        port: String,
+        #[structopt(long = "baud", default_value = "1000000")]
+        baud: u32,
+    },
+}
+
+fn parse_version(s: &str) -> Result<Version, String> {
+    Version::from_str(s).map_err(|()| format!("invalid version '{}'", s))
+}
+
+fn parse_device(s: &str) -> Result<TargetDevice, String> {
+    TargetDevice::from_str(s).map_err(|()| format!("unknown device '{}'", s))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    match Opt::from_args() {
+        Opt::Keygen { name } => {
+            let (secret, public) = (name.with_extension("key"), name.with_extension("pub"));
+            if secret.exists() {
+                return Err(format!("{} already exists", secret.display()).into());
+            }
+
+            let mut seed = [0; SEED_LEN];
+            getrandom::getrandom(&mut seed).map_err(|e| e.to_string())?;
+            fs::write(&secret, seed)?;
+            fs::write(&public, sign::public_key(&seed))?;
+            println!("wrote {} and {}", secret.display(), public.display());
+        }
+        Opt::Image {
+            binary,
+            output,
+            device,
+            version,
+            key,
+            allow_downgrade,
+        } => {
+            let seed = fs::read(&key)?;
+            if seed.len() != SEED_LEN {
+                return Err(format!("{} isn't a secret seed", key.display()).into());
+            }
+            let mut buf = [0; SEED_LEN];
+            buf.copy_from_slice(&seed);
+
+            let flags = if allow_downgrade {
+                FLAG_ALLOW_DOWNGRADE
+            } else {
+                0
+            };
+            let binary = fs::read(binary)?;
+            let mut header = ImageHeader::for_image(&binary, device, version, flags);
+            header.sign(&buf);
+            let mut buf = [0; HEADER_SIZE as usize];
+            header.write(&mut buf);
+
+            let mut image = buf.to_vec();
+            image.extend(binary);
+            fs::write(output, image)?;
+            println!(
+                "built {} image {} ({} Bytes)",
+                device,
+                version,
+                header.total_size()
+            );
+        }
+        Opt::Flash { image, port, baud } => {
+            let image = fs::read(image)?;
+            let header = ImageHeader::parse(&image).map_err(|e| e.to_string())?;
+            if header.total_size() as usize != image.len() {
+                return Err("image size doesn't match its header".into());
+            }
+
+            let settings = SerialPortSettings {
+                baud_rate: baud,
+                timeout: Duration::from_millis(10),
+                ..Default::default()
+            };
+            let port = serialport::open_with_settings(&port, &settings)?;
+            let mut device = Device {
+                port,
+                decoder: slip::Decoder::new(),
+            };
+
+            device.enter_dfu()?;
+            flash(&mut device, &image)?;
+            // A controller relays receiver images to the receiver before it reboots
+            println!("{} image {} sent", header.device, header.version);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `image`, resuming after timeouts.
+fn flash(device: &mut Device, image: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut crc = Crc32::new();
+    crc.update(image);
+    let start = Request::Start {
+        size: image.len() as u32,
+        crc: crc.finish(),
+    };
+
+    let mut offset = device.request(&start)?.offset as usize;
+    while offset < image.len() {
+        let end = (offset + CHUNK_LEN).min(image.len());
+        let data = Request::Data {
+            offset: offset as u32,
+            data: &image[offset..end],
+        };
+
+        offset = match device.request(&data) {
+            Ok(_) => end,
+            // The device tells us where to continue
+            Err(RequestError::Status(resp)) if resp.status == Status::InvalidOffset => {
+                device.request(&start)?.offset as usize
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        print!("\r{} / {} Bytes", offset, image.len());
+        io::stdout().flush()?;
+    }
+    println!();
+
+    device.request(&Request::Finish)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+enum RequestError {
+    /// The device didn't respond, even after retrying.
+    Timeout,
+    /// The device refused the request.
+    Status(Response),
+    Io(io::Error),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "the device doesn't respond"),
+            RequestError::Status(resp) => write!(
+                f,
+                "the device refused the request: {:?} (at offset {})",
+                resp.status, resp.offset
+            ),
+            RequestError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+/// A device in DFU mode, connected to a serial port.
+struct Device {
+    port: Box<dyn serialport::SerialPort>,
+    decoder: slip::Decoder,
+}
+
+impl Device {
+    /// Switches the device's serial port into DFU mode.
+    fn enter_dfu(&mut self) -> io::Result<()> {
+        // The leading CR ends anything that was typed on the shell before
+        self.port.write_all(b"\rdfu\r")?;
+        thread::sleep(Duration::from_millis(200));
+
+        // Discard the shell's reply and any log output
+        let mut buf = [0; 256];
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Sends a request and waits for the response, retrying if it doesn't arrive.
+    fn request(&mut self, request: &Request) -> Result<Response, RequestError> {
+        let mut buf = [0; DATA_HEADER_LEN + CHUNK_LEN];
+        let len = request.encode(&mut buf);
+        let mut frame = [0; slip::encoded_len(DATA_HEADER_LEN + CHUNK_LEN)];
+        let frame_len = slip::encode(&buf[..len], &mut frame).unwrap();
+
+        for _ in 0..RETRIES {
+            self.port.write_all(&frame[..frame_len])?;
+
+            if let Some(resp) = self.response(Duration::from_millis(500))? {
+                return match resp.status {
+                    Status::Ok => Ok(resp),
+                    _ => Err(RequestError::Status(resp)),
+                };
+            }
+        }
+
+        Err(RequestError::Timeout)
+    }
+
+    fn response(&mut self, timeout: Duration) -> io::Result<Option<Response>> {
+        let deadline = Instant::now() + timeout;
+        let mut byte = [0];
+
+        while Instant::now() < deadline {
+            match self.port.read(&mut byte) {
+                Ok(1) => {
+                    if let Some(frame) = self.decoder.feed(byte[0]) {
+                        if let Some(resp) = Response::decode(frame) {
+                            return Ok(Some(resp));
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,29 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Size of a public key in Bytes (see `sign::PUBLIC_KEY_LEN`).
+const PUBLIC_KEY_LEN: u64 = 32;
+
+fn main() {
+    // Build in the public key images are verified with (see `sign`). Anyone can sign images for
+    // the development key, so release builds must name their key.
+    let key = match env::var_os("BLUEFLY_PUBLIC_KEY") {
+        Some(path) => PathBuf::from(path),
+        None if env::var("PROFILE").unwrap() == "debug" => PathBuf::from("../keys/dev.pub"),
+        None => panic!(
+            "release builds must set BLUEFLY_PUBLIC_KEY to the absolute path of the release key \
+             (or to keys/dev.pub for development)"
+        ),
+    };
+    let len = fs::metadata(&key)
+        .expect("failed to read the public key")
+        .len();
+    assert_eq!(len, PUBLIC_KEY_LEN, "the public key must be 32 Bytes");
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy(&key, out.join("public.key")).expect("failed to read the public key");
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", key.display());
+    println!("cargo:rerun-if-env-changed=BLUEFLY_PUBLIC_KEY");
+}
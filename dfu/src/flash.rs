@@ -0,0 +1,217 @@
+//! Access to NOR flash memory.
+
+use core::{ptr, slice};
+
+/// Errors returned by flash operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlashError {
+    /// The address or length isn't a multiple of the word or page size.
+    Unaligned,
+    /// The accessed range isn't inside the flash memory.
+    OutOfBounds,
+}
+
+/// Interface to a NOR flash memory.
+///
+/// Erasing a page sets all its bits to 1, writing can only change bits from 1 to 0.
+pub trait Flash {
+    /// Reads `buf.len()` Bytes starting at `addr`.
+    fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError>;
+
+    /// Writes `data` to `addr`.
+    ///
+    /// Both `addr` and the length of `data` must be multiples of 4.
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError>;
+
+    /// Erases the page starting at `addr`.
+    fn erase_page(&mut self, addr: u32) -> Result<(), FlashError>;
+
+    /// Reads the little-endian word at `addr`.
+    fn read_word(&self, addr: u32) -> Result<u32, FlashError> {
+        let mut buf = [0; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Writes a little-endian word to `addr`.
+    fn write_word(&mut self, addr: u32, word: u32) -> Result<(), FlashError> {
+        self.write(addr, &word.to_le_bytes())
+    }
+}
+
+/// Base address of the NVMC peripheral.
+const NVMC: usize = 0x4001_E000;
+const NVMC_READY: usize = NVMC + 0x400;
+const NVMC_CONFIG: usize = NVMC + 0x504;
+const NVMC_ERASEPAGE: usize = NVMC + 0x508;
+
+const CONFIG_REN: u32 = 0;
+const CONFIG_WEN: u32 = 1;
+const CONFIG_EEN: u32 = 2;
+
+/// The internal flash of the nRF52, written through its Non-Volatile Memory Controller.
+///
+/// This accesses the NVMC registers directly, so it works the same in the bootloader and in both
+/// firmwares, regardless of which HAL they use.
+///
+/// Note that the CPU is halted while a page is erased (about 85 ms), so interrupts will be
+/// delayed.
+pub struct Nvmc {
+    size: u32,
+}
+
+impl Nvmc {
+    /// Creates an `Nvmc` accessing `size` Bytes of flash starting at address 0.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may use the NVMC while the returned value is in use. The caller must also make
+    /// sure not to overwrite the running code.
+    pub unsafe fn new(size: u32) -> Self {
+        Self { size }
+    }
+
+    fn check(&self, addr: u32, len: usize) -> Result<(), FlashError> {
+        match addr.checked_add(len as u32) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    fn configure(&mut self, config: u32) {
+        unsafe {
+            ptr::write_volatile(NVMC_CONFIG as *mut u32, config);
+        }
+        self.wait_ready();
+    }
+
+    fn wait_ready(&self) {
+        while unsafe { ptr::read_volatile(NVMC_READY as *const u32) } == 0 {}
+    }
+}
+
+impl Flash for Nvmc {
+    fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.check(addr, buf.len())?;
+
+        let mem = unsafe { slice::from_raw_parts(addr as *const u8, buf.len()) };
+        buf.copy_from_slice(mem);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        if !addr.is_multiple_of(4) || !data.len().is_multiple_of(4) {
+            return Err(FlashError::Unaligned);
+        }
+        self.check(addr, data.len())?;
+
+        self.configure(CONFIG_WEN);
+        for (i, word) in data.chunks(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            unsafe {
+                ptr::write_volatile((addr as usize + i * 4) as *mut u32, word);
+            }
+            self.wait_ready();
+        }
+        self.configure(CONFIG_REN);
+
+        Ok(())
+    }
+
+    fn erase_page(&mut self, addr: u32) -> Result<(), FlashError> {
+        if !addr.is_multiple_of(crate::layout::PAGE_SIZE) {
+            return Err(FlashError::Unaligned);
+        }
+        self.check(addr, crate::layout::PAGE_SIZE as usize)?;
+
+        self.configure(CONFIG_EEN);
+        unsafe {
+            ptr::write_volatile(NVMC_ERASEPAGE as *mut u32, addr);
+        }
+        self.wait_ready();
+        self.configure(CONFIG_REN);
+
+        Ok(())
+    }
+}
+
+/// Flash simulation for the tests.
+#[cfg(test)]
+pub mod sim {
+    use {
+        super::{Flash, FlashError},
+        std::vec::Vec,
+    };
+
+    /// Simulated NOR flash that can lose power after a given number of operations.
+    ///
+    /// A power loss is simulated by panicking. Tests catch the panic and continue with the flash
+    /// contents that were written until then, as if the device had been reset.
+    pub struct SimFlash {
+        pub mem: Vec<u8>,
+        page_size: u32,
+        /// Number of write or erase operations left until power is lost.
+        pub budget: Option<usize>,
+    }
+
+    impl SimFlash {
+        pub fn new(size: u32, page_size: u32) -> Self {
+            Self {
+                mem: vec![0xFF; size as usize],
+                page_size,
+                budget: None,
+            }
+        }
+
+        fn spend(&mut self) {
+            if let Some(budget) = &mut self.budget {
+                if *budget == 0 {
+                    panic!("power lost");
+                }
+                *budget -= 1;
+            }
+        }
+
+        fn range(&self, addr: u32, len: usize) -> Result<core::ops::Range<usize>, FlashError> {
+            let start = addr as usize;
+            if start + len > self.mem.len() {
+                return Err(FlashError::OutOfBounds);
+            }
+            Ok(start..start + len)
+        }
+    }
+
+    impl Flash for SimFlash {
+        fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+            let range = self.range(addr, buf.len())?;
+            buf.copy_from_slice(&self.mem[range]);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+            if !addr.is_multiple_of(4) || !data.len().is_multiple_of(4) {
+                return Err(FlashError::Unaligned);
+            }
+            let range = self.range(addr, data.len())?;
+            self.spend();
+
+            for (byte, new) in self.mem[range].iter_mut().zip(data) {
+                *byte &= *new;
+            }
+            Ok(())
+        }
+
+        fn erase_page(&mut self, addr: u32) -> Result<(), FlashError> {
+            if !addr.is_multiple_of(self.page_size) {
+                return Err(FlashError::Unaligned);
+            }
+            let range = self.range(addr, self.page_size as usize)?;
+            self.spend();
+
+            for byte in &mut self.mem[range] {
+                *byte = 0xFF;
+            }
+            Ok(())
+        }
+    }
+}
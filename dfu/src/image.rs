@@ -0,0 +1,619 @@
+//! Firmware image format.
+//!
+//! An image is a fixed-size header followed by the raw firmware binary. The header is padded to
+//! `HEADER_SIZE` Bytes, which keeps the firmware's vector table correctly aligned.
+//!
+//! Header layout (all fields little endian):
+//!
+//! ```notrust
+//! +-------+-------------+-------+--------+------------+---------+--------+-----------+---------+
+//! | Magic | Header size | Flags | Device | Image size | Version |  Hash  | Signature | Padding |
+//! | (4 B) |    (2 B)    | (1 B) | (1 B)  |   (4 B)    |  (4 B)  | (64 B) |  (64 B)   |  (0xFF) |
+//! +-------+-------------+-------+--------+------------+---------+--------+-----------+---------+
+//! ```
+//!
+//! The device is the board the firmware was built for (see [`Device`]). The image size and the
+//! SHA-512 hash only cover the firmware binary, not the header. The version is stored as major
+//! (1 B), minor (1 B) and patch (2 B). The signature is an ed25519 signature of all fields before
+//! it (see [`sign`]), so a valid signature covers the whole image.
+//!
+//! Images built for a different device than the installed one are always refused. Images with a
+//! lower version than the installed one are refused, unless they were signed with the
+//! `FLAG_ALLOW_DOWNGRADE` flag.
+//!
+//! [`Device`]: enum.Device.html
+//! [`sign`]: ../sign/index.html
+
+use {
+    crate::{
+        flash::{Flash, FlashError},
+        sign::{self, Sha512, HASH_LEN, PUBLIC_KEY_LEN, SEED_LEN, SIGNATURE_LEN},
+    },
+    byteorder::{ByteOrder, LittleEndian},
+    core::{fmt, str::FromStr},
+};
+
+/// Size of the image header in Bytes, including padding.
+pub const HEADER_SIZE: u32 = 0x100;
+
+/// Magic number at the start of every image header ("BFLY").
+pub const MAGIC: u32 = 0x594C_4642;
+
+/// Flag allowing the image to replace an image with a higher version.
+pub const FLAG_ALLOW_DOWNGRADE: u8 = 1 << 0;
+
+/// Number of header Bytes covered by the signature.
+const SIGNED_LEN: usize = 16 + HASH_LEN;
+
+/// Number of header Bytes in use, the rest is padding.
+const USED_LEN: usize = SIGNED_LEN + SIGNATURE_LEN;
+
+/// Errors found while checking an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// The header doesn't start with `MAGIC`.
+    BadMagic,
+    /// The header size isn't `HEADER_SIZE`.
+    BadHeaderSize,
+    /// The image doesn't fit into a slot.
+    TooLarge,
+    /// The header isn't signed with the right key.
+    BadSignature,
+    /// The hash of the firmware binary doesn't match the header.
+    HashMismatch,
+    /// The image has a lower version than the installed one and doesn't allow downgrades.
+    Downgrade,
+    /// The header contains an unknown device.
+    UnknownDevice,
+    /// The image was built for a different device than the installed one.
+    WrongDevice,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for ImageError {
+    fn from(e: FlashError) -> Self {
+        ImageError::Flash(e)
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::BadMagic => f.write_str("bad magic number"),
+            ImageError::BadHeaderSize => f.write_str("bad header size"),
+            ImageError::TooLarge => f.write_str("image too large"),
+            ImageError::BadSignature => f.write_str("bad signature"),
+            ImageError::HashMismatch => f.write_str("hash mismatch"),
+            ImageError::Downgrade => f.write_str("downgrade not allowed"),
+            ImageError::UnknownDevice => f.write_str("unknown device"),
+            ImageError::WrongDevice => f.write_str("image is for a different device"),
+            ImageError::Flash(e) => write!(f, "flash error: {:?}", e),
+        }
+    }
+}
+
+/// The board a firmware image was built for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Device {
+    Controller = 1,
+    Receiver = 2,
+}
+
+impl Device {
+    fn from_u8(raw: u8) -> Option<Self> {
+        Some(match raw {
+            1 => Device::Controller,
+            2 => Device::Receiver,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Device::Controller => "controller",
+            Device::Receiver => "receiver",
+        })
+    }
+}
+
+impl FromStr for Device {
+    type Err = ();
+
+    /// Parses `controller` or `receiver`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "controller" => Ok(Device::Controller),
+            "receiver" => Ok(Device::Receiver),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A firmware version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u16,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ();
+
+    /// Parses a version of the form `major.minor.patch`.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut parts = s.split('.');
+        let major = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let minor = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let patch = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        if parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(Self {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+/// The parsed header of an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub flags: u8,
+    pub device: Device,
+    /// Size of the firmware binary following the header.
+    pub image_size: u32,
+    pub version: Version,
+    /// SHA-512 hash of the firmware binary.
+    pub hash: [u8; HASH_LEN],
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+impl ImageHeader {
+    /// Creates the (unsigned) header for the firmware binary `image`.
+    pub fn for_image(image: &[u8], device: Device, version: Version, flags: u8) -> Self {
+        let mut sha = Sha512::new();
+        sha.update(image);
+
+        Self {
+            flags,
+            device,
+            image_size: image.len() as u32,
+            version,
+            hash: sha.finish(),
+            signature: [0; SIGNATURE_LEN],
+        }
+    }
+
+    /// Parses an image header without checking its signature.
+    ///
+    /// `buf` must contain at least the first 144 Bytes of the header.
+    pub fn parse(buf: &[u8]) -> Result<Self, ImageError> {
+        if buf.len() < USED_LEN || LittleEndian::read_u32(&buf[0..4]) != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        if u32::from(LittleEndian::read_u16(&buf[4..6])) != HEADER_SIZE {
+            return Err(ImageError::BadHeaderSize);
+        }
+
+        let mut hash = [0; HASH_LEN];
+        hash.copy_from_slice(&buf[16..SIGNED_LEN]);
+        let mut signature = [0; SIGNATURE_LEN];
+        signature.copy_from_slice(&buf[SIGNED_LEN..USED_LEN]);
+
+        Ok(Self {
+            flags: buf[6],
+            device: Device::from_u8(buf[7]).ok_or(ImageError::UnknownDevice)?,
+            image_size: LittleEndian::read_u32(&buf[8..12]),
+            version: Version {
+                major: buf[12],
+                minor: buf[13],
+                patch: LittleEndian::read_u16(&buf[14..16]),
+            },
+            hash,
+            signature,
+        })
+    }
+
+    /// Writes the header, including padding, to `buf`.
+    pub fn write(&self, buf: &mut [u8; HEADER_SIZE as usize]) {
+        for byte in buf.iter_mut() {
+            *byte = 0xFF;
+        }
+
+        buf[..SIGNED_LEN].copy_from_slice(&self.signed_part());
+        buf[SIGNED_LEN..USED_LEN].copy_from_slice(&self.signature);
+    }
+
+    /// Encodes the fields covered by the signature.
+    fn signed_part(&self) -> [u8; SIGNED_LEN] {
+        let mut buf = [0; SIGNED_LEN];
+        LittleEndian::write_u32(&mut buf[0..4], MAGIC);
+        LittleEndian::write_u16(&mut buf[4..6], HEADER_SIZE as u16);
+        buf[6] = self.flags;
+        buf[7] = self.device as u8;
+        LittleEndian::write_u32(&mut buf[8..12], self.image_size);
+        buf[12] = self.version.major;
+        buf[13] = self.version.minor;
+        LittleEndian::write_u16(&mut buf[14..16], self.version.patch);
+        buf[16..].copy_from_slice(&self.hash);
+        buf
+    }
+
+    /// Signs the header with a secret seed.
+    pub fn sign(&mut self, seed: &[u8; SEED_LEN]) {
+        self.signature = sign::sign(seed, &self.signed_part());
+    }
+
+    /// Checks that the header was signed with the seed belonging to `key`.
+    pub fn check_signature(&self, key: &[u8; PUBLIC_KEY_LEN]) -> Result<(), ImageError> {
+        if sign::verify(key, &self.signed_part(), &self.signature) {
+            Ok(())
+        } else {
+            Err(ImageError::BadSignature)
+        }
+    }
+
+    /// Checks that the image may replace the image with the header `installed`.
+    ///
+    /// Without an installed header (eg. after flashing a bare firmware with a debugger), any image
+    /// is accepted.
+    pub fn check_replaces(&self, installed: Option<&ImageHeader>) -> Result<(), ImageError> {
+        match installed {
+            Some(installed) if installed.device != self.device => Err(ImageError::WrongDevice),
+            _ => self.check_version(installed.map(|installed| installed.version)),
+        }
+    }
+
+    /// Checks that the image may replace an image with the version `installed`.
+    pub fn check_version(&self, installed: Option<Version>) -> Result<(), ImageError> {
+        match installed {
+            Some(installed)
+                if self.version < installed && self.flags & FLAG_ALLOW_DOWNGRADE == 0 =>
+            {
+                Err(ImageError::Downgrade)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the size of the whole image, including the header.
+    pub fn total_size(&self) -> u32 {
+        HEADER_SIZE + self.image_size
+    }
+}
+
+/// Reads the header of the image stored at `addr`, without verifying anything.
+pub fn read_header<F: Flash>(flash: &F, addr: u32) -> Result<ImageHeader, ImageError> {
+    let mut buf = [0; USED_LEN];
+    flash.read(addr, &mut buf)?;
+    ImageHeader::parse(&buf)
+}
+
+/// Reads the header of the image stored at `addr` and verifies its signature and the hash of the
+/// firmware binary.
+///
+/// `max_size` is the size of the slot the image is stored in.
+pub fn verify<F: Flash>(
+    flash: &F,
+    addr: u32,
+    max_size: u32,
+    key: &[u8; PUBLIC_KEY_LEN],
+) -> Result<ImageHeader, ImageError> {
+    let header = read_header(flash, addr)?;
+
+    if header.image_size > max_size - HEADER_SIZE {
+        return Err(ImageError::TooLarge);
+    }
+    header.check_signature(key)?;
+
+    let mut sha = Sha512::new();
+    let mut chunk = [0; 64];
+    let mut offset = 0;
+    while offset < header.image_size {
+        let len = (header.image_size - offset).min(chunk.len() as u32) as usize;
+        flash.read(addr + HEADER_SIZE + offset, &mut chunk[..len])?;
+        sha.update(&chunk[..len]);
+        offset += len as u32;
+    }
+
+    if sha.finish()[..] == header.hash[..] {
+        Ok(header)
+    } else {
+        Err(ImageError::HashMismatch)
+    }
+}
+
+/// Incremental CRC-32 (IEEE 802.3) calculation.
+///
+/// This is computed bit by bit to avoid spending 1 KB of flash on a lookup table.
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { crc: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Signed images for the tests.
+#[cfg(test)]
+pub mod testing {
+    use {
+        super::*,
+        crate::sign::{self, PUBLIC_KEY_LEN, SEED_LEN},
+        std::vec::Vec,
+    };
+
+    /// Seed the test images are signed with.
+    pub const SEED: [u8; SEED_LEN] = [7; SEED_LEN];
+
+    /// Returns the public key belonging to `SEED`.
+    pub fn key() -> [u8; PUBLIC_KEY_LEN] {
+        sign::public_key(&SEED)
+    }
+
+    /// Builds an image of `binary`, signed with `SEED` and padded to a whole number of words.
+    pub fn signed_image(binary: &[u8], device: Device, version: &str, flags: u8) -> Vec<u8> {
+        let mut header = ImageHeader::for_image(binary, device, version.parse().unwrap(), flags);
+        header.sign(&SEED);
+        let mut buf = [0; HEADER_SIZE as usize];
+        header.write(&mut buf);
+
+        let mut image = buf.to_vec();
+        image.extend(binary);
+        while image.len() % 4 != 0 {
+            image.push(0xFF);
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{testing::*, *},
+        crate::{flash::sim::SimFlash, layout::PAGE_SIZE},
+        std::{string::ToString, vec::Vec},
+    };
+
+    #[test]
+    fn crc32_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc32_incremental() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let version = "1.2.300".parse().unwrap();
+        let mut header = ImageHeader::for_image(
+            &[1, 2, 3, 4, 5],
+            Device::Receiver,
+            version,
+            FLAG_ALLOW_DOWNGRADE,
+        );
+        header.sign(&SEED);
+        let mut buf = [0; HEADER_SIZE as usize];
+        header.write(&mut buf);
+
+        assert_eq!(ImageHeader::parse(&buf), Ok(header));
+        assert_eq!(header.version.to_string(), "1.2.300");
+        assert!(buf[USED_LEN..].iter().all(|b| *b == 0xFF));
+    }
+
+    /// Signature of `header_test_vector`, checked against an independent ed25519 implementation.
+    const SIGNATURE_VECTOR: [u8; SIGNATURE_LEN] = [
+        0xd7, 0x8f, 0xbf, 0x75, 0x3b, 0x89, 0x4d, 0x46, 0x7d, 0xfb, 0x94, 0xcf, 0xcf, 0xfa, 0x86,
+        0xdf, 0x63, 0x8e, 0xc5, 0x9d, 0x90, 0x78, 0x8c, 0xb7, 0x6e, 0x5b, 0x32, 0xbc, 0x6c, 0x28,
+        0xd3, 0xc2, 0x98, 0x5d, 0x0d, 0x46, 0x84, 0x0f, 0x66, 0xf9, 0x13, 0xab, 0xc1, 0x44, 0x72,
+        0x6f, 0xb2, 0x90, 0x1b, 0x72, 0x3e, 0x97, 0x25, 0x63, 0x97, 0x5e, 0xcd, 0x2a, 0xce, 0x82,
+        0x0f, 0x77, 0x7e, 0x0c,
+    ];
+
+    /// A header signed with the RFC 8032 test key, to catch accidental changes of the format.
+    #[test]
+    fn header_test_vector() {
+        let seed = [
+            0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec,
+            0x2c, 0xc4, 0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03,
+            0x1c, 0xae, 0x7f, 0x60,
+        ];
+        let mut header =
+            ImageHeader::for_image(b"abc", Device::Controller, "1.2.3".parse().unwrap(), 0);
+        header.sign(&seed);
+        let mut buf = [0; HEADER_SIZE as usize];
+        header.write(&mut buf);
+
+        assert_eq!(
+            &buf[..16],
+            &[
+                0x42, 0x46, 0x4C, 0x59, 0x00, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x01, 0x02,
+                0x03, 0x00
+            ]
+        );
+        // SHA-512 of "abc"
+        assert_eq!(&buf[16..20], &[0xdd, 0xaf, 0x35, 0xa1]);
+        assert_eq!(&buf[76..80], &[0xa5, 0x4c, 0xa4, 0x9f]);
+        assert_eq!(&buf[SIGNED_LEN..USED_LEN], &SIGNATURE_VECTOR[..]);
+        assert!(header.check_signature(&sign::public_key(&seed)).is_ok());
+    }
+
+    #[test]
+    fn header_rejects_garbage() {
+        assert_eq!(
+            ImageHeader::parse(&[0xFF; USED_LEN]),
+            Err(ImageError::BadMagic)
+        );
+        assert_eq!(ImageHeader::parse(&[0x42]), Err(ImageError::BadMagic));
+
+        let mut buf = [0; HEADER_SIZE as usize];
+        let version = Version::from_str("0.0.1").unwrap();
+        ImageHeader::for_image(&[], Device::Controller, version, 0).write(&mut buf);
+        buf[7] = 3;
+        assert_eq!(ImageHeader::parse(&buf), Err(ImageError::UnknownDevice));
+        buf[4] = 0x80;
+        assert_eq!(ImageHeader::parse(&buf), Err(ImageError::BadHeaderSize));
+    }
+
+    #[test]
+    fn signature_covers_header() {
+        let mut header =
+            ImageHeader::for_image(&[1, 2, 3], Device::Controller, "1.0.0".parse().unwrap(), 0);
+        assert_eq!(
+            header.check_signature(&key()),
+            Err(ImageError::BadSignature)
+        );
+
+        header.sign(&SEED);
+        assert_eq!(header.check_signature(&key()), Ok(()));
+        assert_eq!(
+            header.check_signature(&sign::public_key(&[8; 32])),
+            Err(ImageError::BadSignature)
+        );
+
+        let mut modified = header;
+        modified.version.patch = 1;
+        assert_eq!(
+            modified.check_signature(&key()),
+            Err(ImageError::BadSignature)
+        );
+        let mut modified = header;
+        modified.flags |= FLAG_ALLOW_DOWNGRADE;
+        assert_eq!(
+            modified.check_signature(&key()),
+            Err(ImageError::BadSignature)
+        );
+        let mut modified = header;
+        modified.device = Device::Receiver;
+        assert_eq!(
+            modified.check_signature(&key()),
+            Err(ImageError::BadSignature)
+        );
+        let mut modified = header;
+        modified.hash[63] ^= 1;
+        assert_eq!(
+            modified.check_signature(&key()),
+            Err(ImageError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn downgrades() {
+        let version = |v: &str| Some(Version::from_str(v).unwrap());
+        let header = ImageHeader::for_image(&[], Device::Controller, "1.2.0".parse().unwrap(), 0);
+
+        assert_eq!(header.check_version(None), Ok(()));
+        assert_eq!(header.check_version(version("1.1.9")), Ok(()));
+        assert_eq!(header.check_version(version("1.2.0")), Ok(()));
+        assert_eq!(
+            header.check_version(version("1.2.1")),
+            Err(ImageError::Downgrade)
+        );
+
+        let header = ImageHeader::for_image(
+            &[],
+            Device::Controller,
+            "1.2.0".parse().unwrap(),
+            FLAG_ALLOW_DOWNGRADE,
+        );
+        assert_eq!(header.check_version(version("2.0.0")), Ok(()));
+    }
+
+    #[test]
+    fn devices() {
+        let header = |device, version: &str| {
+            ImageHeader::for_image(&[], device, version.parse().unwrap(), 0)
+        };
+        let installed = header(Device::Controller, "1.0.0");
+
+        assert_eq!(
+            header(Device::Controller, "1.0.1").check_replaces(None),
+            Ok(())
+        );
+        assert_eq!(
+            header(Device::Controller, "1.0.1").check_replaces(Some(&installed)),
+            Ok(())
+        );
+        assert_eq!(
+            header(Device::Controller, "0.9.0").check_replaces(Some(&installed)),
+            Err(ImageError::Downgrade)
+        );
+        assert_eq!(
+            header(Device::Receiver, "1.0.1").check_replaces(Some(&installed)),
+            Err(ImageError::WrongDevice)
+        );
+        assert_eq!("receiver".parse(), Ok(Device::Receiver));
+        assert_eq!(Device::Controller.to_string(), "controller");
+    }
+
+    #[test]
+    fn version_parsing() {
+        assert!(Version::from_str("1.2").is_err());
+        assert!(Version::from_str("1.2.3.4").is_err());
+        assert!(Version::from_str("256.0.0").is_err());
+        assert!(Version::from_str("1.2.3").unwrap() < Version::from_str("1.10.0").unwrap());
+    }
+
+    #[test]
+    fn verify_image_in_flash() {
+        let mut flash = SimFlash::new(2 * PAGE_SIZE, PAGE_SIZE);
+        let binary: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let image = signed_image(&binary, Device::Controller, "0.1.0", 0);
+        flash.write(0, &image).unwrap();
+        let header = ImageHeader::parse(&image).unwrap();
+
+        assert_eq!(verify(&flash, 0, 2 * PAGE_SIZE, &key()), Ok(header));
+        assert_eq!(verify(&flash, 0, 512, &key()), Err(ImageError::TooLarge));
+        assert_eq!(
+            verify(&flash, 0, 2 * PAGE_SIZE, &sign::public_key(&[8; 32])),
+            Err(ImageError::BadSignature)
+        );
+
+        // Flip a bit of the binary
+        flash.write(HEADER_SIZE + 500, &[0, 0, 0, 0]).unwrap();
+        assert_eq!(
+            verify(&flash, 0, 2 * PAGE_SIZE, &key()),
+            Err(ImageError::HashMismatch)
+        );
+    }
+}
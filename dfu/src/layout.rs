@@ -0,0 +1,68 @@
+//! Flash layout used by the bootloader and the firmwares.
+//!
+//! ```notrust
+//! 0x0_0000 +------------+
+//!          | Bootloader |  32 KB
+//! 0x0_8000 +------------+
+//!          |   Slot 0   |  72 KB, the running image
+//! 0x1_A000 +------------+
+//!          |   Slot 1   |  72 KB, receives updates
+//! 0x2_C000 +------------+
+//!          |  Settings  |  2 pages, written by the firmwares
+//! 0x2_E000 +------------+
+//!          |  Scratch   |  1 page, used while swapping
+//! 0x2_F000 +------------+
+//!          | Swap state |  1 page
+//! 0x3_0000 +------------+
+//! ```
+//!
+//! The firmwares are linked to run from slot 0, right behind the image header (see the
+//! `memory.x` files).
+
+/// Size of an erasable flash page on the nRF52.
+pub const PAGE_SIZE: u32 = 4096;
+
+/// Size of the nRF52810's flash memory.
+pub const FLASH_SIZE: u32 = 0x3_0000;
+
+/// Start of the two pages the firmwares store their settings in (see
+/// `bluefly_common::config::save`). Updates leave them alone.
+pub const SETTINGS: u32 = 0x2_C000;
+
+/// Addresses of the flash regions used for updates.
+#[derive(Debug, Copy, Clone)]
+pub struct Layout {
+    /// Start of the slot containing the image that is booted.
+    pub slot0: u32,
+    /// Start of the slot new images are downloaded to.
+    pub slot1: u32,
+    /// Size of each slot. Must be a multiple of `page_size`.
+    pub slot_size: u32,
+    /// Page used to temporarily store a page of slot 0 while swapping.
+    pub scratch: u32,
+    /// Page storing the swap progress and whether the booted image was confirmed.
+    pub state: u32,
+    pub page_size: u32,
+}
+
+impl Layout {
+    /// Returns the number of pages in each slot.
+    pub fn slot_pages(&self) -> u32 {
+        self.slot_size / self.page_size
+    }
+
+    /// Returns the number of pages needed to store `size` Bytes.
+    pub fn pages_for(&self, size: u32) -> u32 {
+        size.div_ceil(self.page_size)
+    }
+}
+
+/// The layout of the nRF52810.
+pub const NRF52810: Layout = Layout {
+    slot0: 0x0_8000,
+    slot1: 0x1_A000,
+    slot_size: 0x1_2000,
+    scratch: 0x2_E000,
+    state: 0x2_F000,
+    page_size: PAGE_SIZE,
+};
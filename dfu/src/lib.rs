@@ -0,0 +1,40 @@
+//! Firmware update support shared by the bootloader, both firmwares and the host tool.
+//!
+//! Images are signed with ed25519 (see [`sign`]) and are only installed if their signature is
+//! valid and they don't downgrade the firmware.
+//!
+//! Updates use two equally sized flash slots. The running firmware receives a new image into slot
+//! 1 (see [`target`]) and marks it as pending. On the next reset the bootloader swaps the contents
+//! of both slots page by page (see [`swap`]) and boots the new image on trial. If the new firmware
+//! doesn't confirm itself before the following reset, the bootloader swaps the old image back.
+//!
+//! The controller also accepts receiver images, which it relays to the receiver (see [`relay`]).
+//!
+//! Everything in here is independent of the hardware except for [`flash::Nvmc`], so the image
+//! format and the swap logic can be tested on the host:
+//!
+//! ```notrust
+//! cargo test -p bluefly-dfu --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! [`sign`]: sign/index.html
+//! [`target`]: target/index.html
+//! [`relay`]: relay/index.html
+//! [`swap`]: swap/index.html
+//! [`flash::Nvmc`]: flash/struct.Nvmc.html
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub mod flash;
+pub mod image;
+pub mod layout;
+pub mod protocol;
+pub mod relay;
+pub mod sign;
+pub mod slip;
+pub mod swap;
+pub mod target;
@@ -0,0 +1,211 @@
+//! Messages exchanged between the host tool and a device receiving an update.
+//!
+//! The same messages are used for every transport: they're written to the DFU characteristic over
+//! BLE and sent in SLIP frames over the serial port. Every request is answered with a response
+//! before the next request is sent.
+//!
+//! All integers are little endian.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const OP_START: u8 = 0x01;
+const OP_DATA: u8 = 0x02;
+const OP_FINISH: u8 = 0x03;
+const OP_ABORT: u8 = 0x04;
+const OP_STATUS: u8 = 0x05;
+
+/// Size of an encoded `Response`.
+pub const RESPONSE_LEN: usize = 5;
+
+/// Size of the header of an encoded `Request::Data`.
+pub const DATA_HEADER_LEN: usize = 5;
+
+/// A request sent to the device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// Starts (or resumes) the transfer of an image.
+    ///
+    /// If a transfer of an image with the same size and CRC was interrupted, it is resumed, and the
+    /// response contains the offset to continue at.
+    Start {
+        /// Size of the whole image, including the header.
+        size: u32,
+        /// CRC-32 of the whole image, used to recognize a transfer that can be resumed.
+        crc: u32,
+    },
+    /// A chunk of the image. Chunks must be sent in order.
+    Data { offset: u32, data: &'a [u8] },
+    /// Ends the transfer. The device verifies the image and installs it on the next reset.
+    Finish,
+    /// Cancels the transfer.
+    Abort,
+    /// Asks for the current state of the transfer.
+    Status,
+}
+
+impl<'a> Request<'a> {
+    /// Decodes a request, returning `None` if it is malformed.
+    pub fn decode(buf: &'a [u8]) -> Option<Self> {
+        let (&op, args) = buf.split_first()?;
+
+        Some(match (op, args.len()) {
+            (OP_START, 8) => Request::Start {
+                size: LittleEndian::read_u32(&args[0..4]),
+                crc: LittleEndian::read_u32(&args[4..8]),
+            },
+            (OP_DATA, len) if len >= 4 => Request::Data {
+                offset: LittleEndian::read_u32(&args[0..4]),
+                data: &args[4..],
+            },
+            (OP_FINISH, 0) => Request::Finish,
+            (OP_ABORT, 0) => Request::Abort,
+            (OP_STATUS, 0) => Request::Status,
+            _ => return None,
+        })
+    }
+
+    /// Encodes the request into `buf`, returning the number of Bytes used.
+    ///
+    /// Panics if `buf` is too small.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        match self {
+            Request::Start { size, crc } => {
+                buf[0] = OP_START;
+                LittleEndian::write_u32(&mut buf[1..5], *size);
+                LittleEndian::write_u32(&mut buf[5..9], *crc);
+                9
+            }
+            Request::Data { offset, data } => {
+                buf[0] = OP_DATA;
+                LittleEndian::write_u32(&mut buf[1..5], *offset);
+                buf[DATA_HEADER_LEN..DATA_HEADER_LEN + data.len()].copy_from_slice(data);
+                DATA_HEADER_LEN + data.len()
+            }
+            Request::Finish => {
+                buf[0] = OP_FINISH;
+                1
+            }
+            Request::Abort => {
+                buf[0] = OP_ABORT;
+                1
+            }
+            Request::Status => {
+                buf[0] = OP_STATUS;
+                1
+            }
+        }
+    }
+}
+
+/// Result of a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    /// The request couldn't be decoded.
+    InvalidRequest = 1,
+    /// The request isn't valid in the current state (eg. data without a started transfer).
+    InvalidState = 2,
+    /// A chunk didn't start at the expected offset. The response contains the expected offset.
+    InvalidOffset = 3,
+    /// The image doesn't fit into the update slot.
+    TooLarge = 4,
+    /// The received image is corrupted.
+    InvalidImage = 5,
+    /// Writing to flash failed.
+    FlashError = 6,
+    /// The image has a lower version than the installed one and doesn't allow downgrades.
+    Downgrade = 7,
+}
+
+impl Status {
+    fn from_u8(raw: u8) -> Option<Self> {
+        Some(match raw {
+            0 => Status::Ok,
+            1 => Status::InvalidRequest,
+            2 => Status::InvalidState,
+            3 => Status::InvalidOffset,
+            4 => Status::TooLarge,
+            5 => Status::InvalidImage,
+            6 => Status::FlashError,
+            7 => Status::Downgrade,
+            _ => return None,
+        })
+    }
+}
+
+/// The response to a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+    /// Number of Bytes of the image received so far.
+    pub offset: u32,
+}
+
+impl Response {
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != RESPONSE_LEN {
+            return None;
+        }
+
+        Some(Self {
+            status: Status::from_u8(buf[0])?,
+            offset: LittleEndian::read_u32(&buf[1..5]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; RESPONSE_LEN] {
+        let mut buf = [0; RESPONSE_LEN];
+        buf[0] = self.status as u8;
+        LittleEndian::write_u32(&mut buf[1..5], self.offset);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(req: Request) {
+        let mut buf = [0; 32];
+        let len = req.encode(&mut buf);
+        assert_eq!(Request::decode(&buf[..len]), Some(req));
+    }
+
+    #[test]
+    fn requests() {
+        roundtrip(Request::Start {
+            size: 0x1234_5678,
+            crc: 0xDEAD_BEEF,
+        });
+        roundtrip(Request::Data {
+            offset: 256,
+            data: &[1, 2, 3],
+        });
+        roundtrip(Request::Data {
+            offset: 0,
+            data: &[],
+        });
+        roundtrip(Request::Finish);
+        roundtrip(Request::Abort);
+        roundtrip(Request::Status);
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert_eq!(Request::decode(&[]), None);
+        assert_eq!(Request::decode(&[0xFF]), None);
+        assert_eq!(Request::decode(&[OP_START, 1, 2, 3]), None);
+        assert_eq!(Request::decode(&[OP_DATA, 1, 2]), None);
+        assert_eq!(Request::decode(&[OP_FINISH, 0]), None);
+    }
+
+    #[test]
+    fn responses() {
+        let resp = Response {
+            status: Status::InvalidOffset,
+            offset: 4096,
+        };
+        assert_eq!(Response::decode(&resp.encode()), Some(resp));
+        assert_eq!(Response::decode(&[8, 0, 0, 0, 0]), None);
+    }
+}
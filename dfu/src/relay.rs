@@ -0,0 +1,501 @@
+//! Relaying updates to another device over a lossy link.
+//!
+//! The controller relays receiver images to the receiver over the radio link it uses for throttle
+//! beacons. The image is first sent to the controller like any other update and kept in its slot 1
+//! (see `DfuTarget::relay_to`), then the `Relay` sends it to the receiver in small frames, which
+//! the receiver handles with `handle_frame`.
+//!
+//! Frames carry the messages from [`protocol`], prefixed with `TAG` and a sequence number:
+//!
+//! ```notrust
+//! +-------+-------+---------------------+
+//! |  Tag  |  Seq  | Request or response |
+//! | (1 B) | (1 B) |                     |
+//! +-------+-------+---------------------+
+//! ```
+//!
+//! Frames may be lost or duplicated. The relay sends one request at a time and repeats it until
+//! the response with the same sequence number arrives. A repeated request that was already handled
+//! is answered with `InvalidOffset` or `InvalidState`, in which case the relay sends `Start` again
+//! to find out where to continue. The same mechanism resumes the transfer when the relay is
+//! restarted after a reset of the controller, and starts it over after a reset of the receiver.
+//!
+//! [`protocol`]: ../protocol/index.html
+
+use crate::{
+    flash::{Flash, FlashError},
+    image::{self, Crc32, Device},
+    layout::Layout,
+    protocol::{Request, Response, Status, DATA_HEADER_LEN, RESPONSE_LEN},
+    sign::PUBLIC_KEY_LEN,
+    target::DfuTarget,
+};
+
+/// First Byte of every relay frame.
+pub const TAG: u8 = 0xB1;
+
+/// Maximum size of a frame, limited by the payload of a beacon.
+pub const MAX_FRAME_LEN: usize = 29;
+
+/// Size of a response frame.
+pub const RESPONSE_FRAME_LEN: usize = FRAME_HEADER_LEN + RESPONSE_LEN;
+
+/// Number of image Bytes sent per `Data` request.
+pub const CHUNK_LEN: usize = MAX_FRAME_LEN - FRAME_HEADER_LEN - DATA_HEADER_LEN;
+
+const FRAME_HEADER_LEN: usize = 2;
+
+/// Returns whether `frame` is a relay frame (and not eg. a throttle beacon).
+pub fn is_frame(frame: &[u8]) -> bool {
+    frame.len() > FRAME_HEADER_LEN && frame[0] == TAG
+}
+
+/// Handles a relayed request on the device receiving the update, returning the response frame.
+///
+/// Returns `None` if `frame` isn't a relay frame.
+pub fn handle_frame<F: Flash>(
+    target: &mut DfuTarget<F>,
+    frame: &[u8],
+) -> Option<[u8; RESPONSE_FRAME_LEN]> {
+    if !is_frame(frame) {
+        return None;
+    }
+
+    let mut response = [0; RESPONSE_FRAME_LEN];
+    response[0] = TAG;
+    response[1] = frame[1];
+    response[FRAME_HEADER_LEN..].copy_from_slice(&target.handle_raw(&frame[FRAME_HEADER_LEN..]));
+    Some(response)
+}
+
+/// Returns the size of a verified image for `device` that is waiting in slot 1 to be relayed.
+pub fn pending<F: Flash>(
+    flash: &F,
+    layout: &Layout,
+    key: &[u8; PUBLIC_KEY_LEN],
+    device: Device,
+) -> Option<u32> {
+    match image::verify(flash, layout.slot1, layout.slot_size, key) {
+        Ok(header) if header.device == device => Some(header.total_size()),
+        _ => None,
+    }
+}
+
+/// Removes a relayed (or refused) image from slot 1, so it isn't relayed again.
+pub fn discard<F: Flash>(flash: &mut F, layout: &Layout) -> Result<(), FlashError> {
+    flash.erase_page(layout.slot1)
+}
+
+/// Progress of a `Relay`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RelayStatus {
+    /// The image is being sent. `offset` Bytes were acknowledged by the receiving device.
+    Sending { offset: u32, size: u32 },
+    /// The receiving device accepted the image and will install it.
+    Done,
+    /// The receiving device refused the image.
+    Failed(Status),
+}
+
+/// The request the relay sends next.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Step {
+    Start,
+    Data,
+    Finish,
+    Done,
+    Failed(Status),
+}
+
+/// Sends an image stored in flash to another device, one frame at a time.
+pub struct Relay {
+    /// Address of the image.
+    addr: u32,
+    size: u32,
+    crc: u32,
+    step: Step,
+    /// Number of Bytes acknowledged by the receiving device.
+    offset: u32,
+    /// Sequence number of the last request sent.
+    seq: u8,
+    /// Number of polls since the last request was sent, or `None` if it was answered.
+    waiting: Option<u32>,
+    /// Number of polls after which an unanswered request is sent again.
+    retry_polls: u32,
+}
+
+impl Relay {
+    /// Creates a relay for the `size` Bytes long image at `addr`.
+    ///
+    /// Requests that aren't answered within `retry_polls` calls to `poll` are sent again.
+    pub fn new<F: Flash>(
+        flash: &F,
+        addr: u32,
+        size: u32,
+        retry_polls: u32,
+    ) -> Result<Self, FlashError> {
+        let mut crc = Crc32::new();
+        let mut chunk = [0; 64];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(chunk.len() as u32) as usize;
+            flash.read(addr + offset, &mut chunk[..len])?;
+            crc.update(&chunk[..len]);
+            offset += len as u32;
+        }
+
+        Ok(Self {
+            addr,
+            size,
+            crc: crc.finish(),
+            step: Step::Start,
+            offset: 0,
+            seq: 0,
+            waiting: None,
+            retry_polls: retry_polls.max(1),
+        })
+    }
+
+    pub fn status(&self) -> RelayStatus {
+        match self.step {
+            Step::Done => RelayStatus::Done,
+            Step::Failed(status) => RelayStatus::Failed(status),
+            _ => RelayStatus::Sending {
+                offset: self.offset,
+                size: self.size,
+            },
+        }
+    }
+
+    /// Must be called periodically. Writes the next frame to send into `frame`, returning its
+    /// length, or returns `None` if nothing should be sent now.
+    pub fn poll<F: Flash>(
+        &mut self,
+        flash: &F,
+        frame: &mut [u8; MAX_FRAME_LEN],
+    ) -> Result<Option<usize>, FlashError> {
+        match self.step {
+            Step::Done | Step::Failed(_) => return Ok(None),
+            _ => {}
+        }
+
+        match self.waiting {
+            Some(polls) if polls + 1 < self.retry_polls => {
+                self.waiting = Some(polls + 1);
+                return Ok(None);
+            }
+            // Send the unanswered request again, with the same sequence number
+            Some(_) => {}
+            None => self.seq = self.seq.wrapping_add(1),
+        }
+
+        let mut chunk = [0; CHUNK_LEN];
+        let request = match self.step {
+            Step::Start => Request::Start {
+                size: self.size,
+                crc: self.crc,
+            },
+            Step::Data => {
+                let len = (self.size - self.offset).min(CHUNK_LEN as u32) as usize;
+                flash.read(self.addr + self.offset, &mut chunk[..len])?;
+                Request::Data {
+                    offset: self.offset,
+                    data: &chunk[..len],
+                }
+            }
+            _ => Request::Finish,
+        };
+
+        frame[0] = TAG;
+        frame[1] = self.seq;
+        let len = request.encode(&mut frame[FRAME_HEADER_LEN..]);
+        self.waiting = Some(0);
+        Ok(Some(FRAME_HEADER_LEN + len))
+    }
+
+    /// Handles a response frame received from the other device.
+    ///
+    /// Responses to anything but the last request are ignored.
+    pub fn handle_frame(&mut self, frame: &[u8]) {
+        if !is_frame(frame) || frame[1] != self.seq || self.waiting.is_none() {
+            return;
+        }
+        let response = match Response::decode(&frame[FRAME_HEADER_LEN..]) {
+            Some(response) => response,
+            None => return,
+        };
+        self.waiting = None;
+
+        self.step = match (self.step, response.status) {
+            (Step::Finish, Status::Ok) => Step::Done,
+            (_, Status::Ok) => {
+                self.offset = response.offset.min(self.size);
+                if self.offset == self.size {
+                    Step::Finish
+                } else {
+                    Step::Data
+                }
+            }
+            // Starting over won't help
+            (Step::Start, status) => Step::Failed(status),
+            // A repeated request, or the other device lost its progress
+            (_, Status::InvalidOffset) | (_, Status::InvalidState) => Step::Start,
+            (_, status) => Step::Failed(status),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            flash::sim::SimFlash,
+            image::{
+                testing::{key, signed_image},
+                HEADER_SIZE,
+            },
+            swap::{self, State},
+        },
+        std::vec::Vec,
+    };
+
+    const PAGE: u32 = 256;
+
+    const LAYOUT: Layout = Layout {
+        slot0: 0,
+        slot1: 4 * PAGE,
+        slot_size: 4 * PAGE,
+        scratch: 8 * PAGE,
+        state: 9 * PAGE,
+        page_size: PAGE,
+    };
+
+    /// Gives up if a transfer takes more polls than this.
+    const MAX_POLLS: usize = 10_000;
+
+    /// The controller's flash, with a receiver image of version `version` in slot 1.
+    fn controller(version: &str) -> (SimFlash, Vec<u8>) {
+        let binary: Vec<u8> = (0..700).map(|i| (i * 3) as u8).collect();
+        let image = signed_image(&binary, Device::Receiver, version, 0);
+        let mut flash = SimFlash::new(10 * PAGE, PAGE);
+        flash.write(LAYOUT.slot1, &image).unwrap();
+        (flash, image)
+    }
+
+    /// A receiver running version 1.0.0.
+    fn receiver() -> DfuTarget<SimFlash> {
+        let mut flash = SimFlash::new(10 * PAGE, PAGE);
+        let installed = signed_image(&[1; 100], Device::Receiver, "1.0.0", 0);
+        flash.write(LAYOUT.slot0, &installed).unwrap();
+        DfuTarget::new(flash, LAYOUT, key())
+    }
+
+    fn relay(flash: &SimFlash, image: &[u8]) -> Relay {
+        Relay::new(flash, LAYOUT.slot1, image.len() as u32, 3).unwrap()
+    }
+
+    /// A link losing frames with a fixed pseudo-random pattern.
+    struct Link {
+        state: u32,
+        /// Chance of losing a frame, in percent.
+        loss: u32,
+    }
+
+    impl Link {
+        fn new(loss: u32) -> Self {
+            Self { state: 1, loss }
+        }
+
+        fn lose(&mut self) -> bool {
+            // xorshift32
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            self.state % 100 < self.loss
+        }
+    }
+
+    /// Runs the relay for at most `polls` polls, returning its status.
+    fn run(
+        relay: &mut Relay,
+        flash: &SimFlash,
+        receiver: &mut DfuTarget<SimFlash>,
+        link: &mut Link,
+        polls: usize,
+    ) -> RelayStatus {
+        let mut frame = [0; MAX_FRAME_LEN];
+
+        for _ in 0..polls {
+            if let Some(len) = relay.poll(flash, &mut frame).unwrap() {
+                assert!(len <= MAX_FRAME_LEN);
+                if !link.lose() {
+                    let response = handle_frame(receiver, &frame[..len]).unwrap();
+                    if !link.lose() {
+                        relay.handle_frame(&response);
+                    }
+                }
+            }
+
+            match relay.status() {
+                RelayStatus::Sending { .. } => {}
+                status => return status,
+            }
+        }
+
+        relay.status()
+    }
+
+    fn assert_installed(receiver: &DfuTarget<SimFlash>, image: &[u8]) {
+        assert!(receiver.is_complete());
+        let flash = receiver.flash();
+        assert_eq!(&flash.mem[LAYOUT.slot1 as usize..][..image.len()], image);
+        assert_eq!(swap::state(flash, &LAYOUT), Ok(State::Pending));
+    }
+
+    #[test]
+    fn relay_image() {
+        let (flash, image) = controller("1.1.0");
+        let mut receiver = receiver();
+        let mut relay = relay(&flash, &image);
+
+        let status = run(
+            &mut relay,
+            &flash,
+            &mut receiver,
+            &mut Link::new(0),
+            MAX_POLLS,
+        );
+        assert_eq!(status, RelayStatus::Done);
+        assert_installed(&receiver, &image);
+    }
+
+    #[test]
+    fn lossy_link() {
+        let (flash, image) = controller("1.1.0");
+        let mut receiver = receiver();
+        let mut relay = relay(&flash, &image);
+
+        let status = run(
+            &mut relay,
+            &flash,
+            &mut receiver,
+            &mut Link::new(30),
+            MAX_POLLS,
+        );
+        assert_eq!(status, RelayStatus::Done);
+        assert_installed(&receiver, &image);
+    }
+
+    #[test]
+    fn stale_and_duplicate_responses() {
+        let (flash, image) = controller("1.1.0");
+        let mut receiver = receiver();
+        let mut relay = relay(&flash, &image);
+        let mut frame = [0; MAX_FRAME_LEN];
+
+        let len = relay.poll(&flash, &mut frame).unwrap().unwrap();
+        let start = handle_frame(&mut receiver, &frame[..len]).unwrap();
+        relay.handle_frame(&start);
+        assert_eq!(
+            relay.status(),
+            RelayStatus::Sending {
+                offset: 0,
+                size: image.len() as u32
+            }
+        );
+
+        let len = relay.poll(&flash, &mut frame).unwrap().unwrap();
+        let data = handle_frame(&mut receiver, &frame[..len]).unwrap();
+        // The response to `Start` arrives again, and is ignored
+        relay.handle_frame(&start);
+        assert_eq!(relay.poll(&flash, &mut frame).unwrap(), None);
+
+        relay.handle_frame(&data);
+        relay.handle_frame(&data);
+        assert_eq!(
+            relay.status(),
+            RelayStatus::Sending {
+                offset: CHUNK_LEN as u32,
+                size: image.len() as u32
+            }
+        );
+
+        // Not relay frames
+        relay.handle_frame(&[0x42]);
+        relay.handle_frame(&[TAG, relay.seq]);
+        assert_eq!(handle_frame(&mut receiver, &[0x42]), None);
+    }
+
+    #[test]
+    fn resume_after_controller_reset() {
+        let (flash, image) = controller("1.1.0");
+        let mut receiver = receiver();
+        let mut link = Link::new(0);
+
+        let mut relay = relay(&flash, &image);
+        run(&mut relay, &flash, &mut receiver, &mut link, 40);
+
+        // The controller finds the image in slot 1 again after the reset
+        let size = pending(&flash, &LAYOUT, &key(), Device::Receiver).unwrap();
+        let mut relay = Relay::new(&flash, LAYOUT.slot1, size, 3).unwrap();
+        run(&mut relay, &flash, &mut receiver, &mut link, 2);
+        match relay.status() {
+            RelayStatus::Sending { offset, .. } => assert!(offset > 0),
+            status => panic!("unexpected status {:?}", status),
+        }
+
+        let status = run(&mut relay, &flash, &mut receiver, &mut link, MAX_POLLS);
+        assert_eq!(status, RelayStatus::Done);
+        assert_installed(&receiver, &image);
+    }
+
+    #[test]
+    fn restart_after_receiver_reset() {
+        let (flash, image) = controller("1.1.0");
+        let mut link = Link::new(10);
+        let mut relay = relay(&flash, &image);
+
+        // The receiver loses its progress
+        match run(&mut relay, &flash, &mut receiver(), &mut link, 30) {
+            RelayStatus::Sending { offset, .. } => assert!(offset > 0),
+            status => panic!("unexpected status {:?}", status),
+        }
+        let mut receiver = receiver();
+
+        let status = run(&mut relay, &flash, &mut receiver, &mut link, MAX_POLLS);
+        assert_eq!(status, RelayStatus::Done);
+        assert_installed(&receiver, &image);
+    }
+
+    #[test]
+    fn refused_image() {
+        let (flash, image) = controller("0.9.0");
+        let mut receiver = receiver();
+        let mut relay = relay(&flash, &image);
+
+        let status = run(
+            &mut relay,
+            &flash,
+            &mut receiver,
+            &mut Link::new(0),
+            MAX_POLLS,
+        );
+        assert_eq!(status, RelayStatus::Failed(Status::Downgrade));
+        assert!(!receiver.is_complete());
+    }
+
+    #[test]
+    fn pending_images() {
+        let (mut flash, _) = controller("1.1.0");
+        assert_eq!(
+            pending(&flash, &LAYOUT, &key(), Device::Receiver),
+            Some(HEADER_SIZE + 700)
+        );
+        assert_eq!(pending(&flash, &LAYOUT, &key(), Device::Controller), None);
+        assert_eq!(pending(&flash, &LAYOUT, &[0; 32], Device::Receiver), None);
+
+        discard(&mut flash, &LAYOUT).unwrap();
+        assert_eq!(pending(&flash, &LAYOUT, &key(), Device::Receiver), None);
+    }
+}
@@ -0,0 +1,173 @@
+//! Ed25519 signatures of firmware images.
+//!
+//! Images are signed on the host with a secret seed (see `dfu-tool keygen`) and verified on the
+//! device with the matching public key, which is built into the bootloader and both firmwares.
+//! Debug builds read the key from `keys/dev.pub` by default. The development seed is public, so
+//! release builds fail unless `BLUEFLY_PUBLIC_KEY` is set to the absolute path of the release key:
+//!
+//! ```notrust
+//! BLUEFLY_PUBLIC_KEY=/path/to/release.pub cargo build --release
+//! ```
+//!
+//! Keys are stored as raw Bytes: 32 Bytes for the seed and for the public key.
+
+use ed25519_compact::{sha512, KeyPair, PublicKey, Seed, Signature};
+
+/// Size of a public key in Bytes.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Size of a secret seed in Bytes.
+pub const SEED_LEN: usize = 32;
+
+/// Size of a signature in Bytes.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Size of a SHA-512 hash in Bytes.
+pub const HASH_LEN: usize = 64;
+
+/// The key images must be signed with to be installed.
+pub const PUBLIC_KEY: [u8; PUBLIC_KEY_LEN] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/public.key"));
+
+/// Incremental SHA-512 calculation.
+pub struct Sha512(sha512::Hash);
+
+impl Sha512 {
+    pub fn new() -> Self {
+        Sha512(sha512::Hash::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; HASH_LEN] {
+        self.0.finalize()
+    }
+}
+
+impl Default for Sha512 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether `signature` is a valid signature of `msg` made with the secret seed matching
+/// `key`.
+pub fn verify(key: &[u8; PUBLIC_KEY_LEN], msg: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    PublicKey::new(*key)
+        .verify(msg, &Signature::new(*signature))
+        .is_ok()
+}
+
+/// Signs `msg` with a secret seed.
+pub fn sign(seed: &[u8; SEED_LEN], msg: &[u8]) -> [u8; SIGNATURE_LEN] {
+    *KeyPair::from_seed(Seed::new(*seed)).sk.sign(msg, None)
+}
+
+/// Returns the public key belonging to a secret seed.
+pub fn public_key(seed: &[u8; SEED_LEN]) -> [u8; PUBLIC_KEY_LEN] {
+    *KeyPair::from_seed(Seed::new(*seed)).pk
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::vec::Vec};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn array32(s: &str) -> [u8; 32] {
+        let mut buf = [0; 32];
+        buf.copy_from_slice(&hex(s));
+        buf
+    }
+
+    fn array64(s: &str) -> [u8; 64] {
+        let mut buf = [0; 64];
+        buf.copy_from_slice(&hex(s));
+        buf
+    }
+
+    /// Test vectors from RFC 8032, section 7.1.
+    const RFC8032: [(&str, &str, &str, &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032_vectors() {
+        for &(seed, key, msg, signature) in RFC8032.iter() {
+            let (seed, key) = (array32(seed), array32(key));
+            let (msg, signature) = (hex(msg), array64(signature));
+
+            assert_eq!(public_key(&seed), key);
+            assert_eq!(&sign(&seed, &msg)[..], &signature[..]);
+            assert!(verify(&key, &msg, &signature));
+        }
+    }
+
+    #[test]
+    fn reject_modified_signatures() {
+        let (seed, key, _, signature) = RFC8032[2];
+        let (key, signature) = (array32(key), array64(signature));
+        let msg = hex("af82");
+
+        assert!(!verify(&key, &hex("af83"), &signature));
+        assert!(!verify(&array32(RFC8032[0].1), &msg, &signature));
+        for i in 0..SIGNATURE_LEN {
+            let mut bad = signature;
+            bad[i] ^= 0x10;
+            assert!(!verify(&key, &msg, &bad), "modified Byte {}", i);
+        }
+        assert_eq!(public_key(&array32(seed)), key);
+    }
+
+    #[test]
+    fn sha512_vectors() {
+        // From FIPS 180-2, appendix C
+        let mut sha = Sha512::new();
+        sha.update(b"abc");
+        assert_eq!(
+            &sha.finish()[..],
+            &hex(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )[..]
+        );
+
+        let mut sha = Sha512::new();
+        sha.update(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmno");
+        sha.update(b"ijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu");
+        assert_eq!(
+            &sha.finish()[..],
+            &hex(
+                "8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018\
+                 501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909"
+            )[..]
+        );
+    }
+}
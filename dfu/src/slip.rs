@@ -0,0 +1,155 @@
+//! SLIP (RFC 1055) framing, used to send DFU messages over the serial port.
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Maximum size of a decoded frame.
+pub const MAX_FRAME_LEN: usize = 256;
+
+/// Returns the worst-case size of `len` Bytes after encoding.
+pub const fn encoded_len(len: usize) -> usize {
+    2 * len + 2
+}
+
+/// Encodes `data` into `buf` as a single frame, returning the number of Bytes written.
+///
+/// Returns `None` if `buf` is too small.
+pub fn encode(data: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut push = |byte| {
+        *buf.get_mut(len)? = byte;
+        len += 1;
+        Some(())
+    };
+
+    // A leading END flushes any line noise received before the frame
+    push(END)?;
+    for &byte in data {
+        match byte {
+            END => {
+                push(ESC)?;
+                push(ESC_END)?;
+            }
+            ESC => {
+                push(ESC)?;
+                push(ESC_ESC)?;
+            }
+            _ => push(byte)?,
+        }
+    }
+    push(END)?;
+
+    Some(len)
+}
+
+/// Incrementally decodes received Bytes into frames.
+pub struct Decoder {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    escape: bool,
+    /// The current frame is too long or malformed and will be dropped.
+    error: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            escape: false,
+            error: false,
+        }
+    }
+
+    /// Feeds a received Byte into the decoder, returning a frame once it is complete.
+    ///
+    /// Empty frames as well as frames that are too long or contain invalid escape sequences are
+    /// dropped.
+    pub fn feed(&mut self, byte: u8) -> Option<&[u8]> {
+        if byte == END {
+            let len = self.len;
+            let error = self.error;
+            self.len = 0;
+            self.escape = false;
+            self.error = false;
+
+            return if len > 0 && !error {
+                Some(&self.buf[..len])
+            } else {
+                None
+            };
+        }
+
+        let byte = if self.escape {
+            self.escape = false;
+            match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                _ => {
+                    self.error = true;
+                    return None;
+                }
+            }
+        } else if byte == ESC {
+            self.escape = true;
+            return None;
+        } else {
+            byte
+        };
+
+        if self.len < MAX_FRAME_LEN {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.error = true;
+        }
+
+        None
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::vec::Vec};
+
+    fn decode_all(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::new();
+        data.iter()
+            .filter_map(|b| decoder.feed(*b).map(|frame| frame.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = [1, END, 2, ESC, 3, ESC_END, ESC_ESC];
+        let mut buf = [0; encoded_len(7)];
+        let len = encode(&data, &mut buf).unwrap();
+
+        assert_eq!(len, 2 + 7 + 2);
+        assert_eq!(decode_all(&buf[..len]), vec![data.to_vec()]);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0; 4];
+        assert_eq!(encode(&[END, END], &mut buf), None);
+        assert_eq!(encode(&[1, 2], &mut buf), Some(4));
+    }
+
+    #[test]
+    fn drops_bad_frames() {
+        let mut data = vec![END, 1, ESC, 7, END, END, 2, END];
+        data.extend(std::iter::repeat_n(0, MAX_FRAME_LEN + 1));
+        data.extend(&[END, 3, END]);
+
+        assert_eq!(decode_all(&data), vec![vec![2], vec![3]]);
+    }
+}
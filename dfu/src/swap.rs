@@ -0,0 +1,440 @@
+//! Installing updates by swapping the contents of both slots, with rollback.
+//!
+//! The swap is performed by the bootloader, one page at a time, using the scratch page:
+//!
+//! 1. Copy the page of slot 0 to the scratch page.
+//! 2. Copy the page of slot 1 to slot 0.
+//! 3. Copy the scratch page to slot 1.
+//!
+//! After each step, a word in the state page is cleared to record the progress. Every step only
+//! overwrites data that is still available elsewhere, so after a reset the swap can continue by
+//! redoing the first step that wasn't recorded as done.
+//!
+//! Once the swap is complete, the new image is booted on trial. If it doesn't call [`confirm`]
+//! before the next reset, the bootloader assumes it is broken and swaps the slots back.
+//!
+//! [`confirm`]: fn.confirm.html
+
+use crate::{
+    flash::{Flash, FlashError},
+    image::{self, ImageError, ImageHeader},
+    layout::Layout,
+    sign::PUBLIC_KEY_LEN,
+};
+
+/// Value of the first word of the state page when an update was requested.
+const MAGIC_PENDING: u32 = 0x444E_4550;
+
+/// Value written to a word to set a flag or to record a step as done.
+const DONE: u32 = 0;
+
+// Word indices in the state page
+const WORD_PENDING: u32 = 0;
+const WORD_PAGES: u32 = 1;
+const WORD_TRIAL: u32 = 2;
+const WORD_CONFIRMED: u32 = 3;
+const WORD_REVERTED: u32 = 4;
+/// First word of the progress records. The records of the swap back follow those of the swap.
+const WORD_PROGRESS: u32 = 16;
+
+/// Steps performed for each page.
+const STEPS: u32 = 3;
+
+/// State of the update process, as recorded in the state page.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    /// No update was installed since the state was last cleared.
+    None,
+    /// A new image is waiting in slot 1 and will be swapped in on the next reset.
+    Pending,
+    /// The new image was swapped in and is running on trial. It must call `confirm`.
+    Trial,
+    /// The new image confirmed that it works.
+    Confirmed,
+    /// The new image wasn't confirmed and the previous image was restored.
+    Reverted,
+}
+
+fn word_addr(layout: &Layout, word: u32) -> u32 {
+    layout.state + word * 4
+}
+
+fn is_set<F: Flash>(flash: &F, layout: &Layout, word: u32) -> Result<bool, FlashError> {
+    Ok(flash.read_word(word_addr(layout, word))? == DONE)
+}
+
+fn set<F: Flash>(flash: &mut F, layout: &Layout, word: u32) -> Result<(), FlashError> {
+    flash.write_word(word_addr(layout, word), DONE)
+}
+
+/// Reads the update state from flash.
+pub fn state<F: Flash>(flash: &F, layout: &Layout) -> Result<State, FlashError> {
+    if flash.read_word(word_addr(layout, WORD_PENDING))? != MAGIC_PENDING {
+        return Ok(State::None);
+    }
+
+    Ok(if is_set(flash, layout, WORD_REVERTED)? {
+        State::Reverted
+    } else if is_set(flash, layout, WORD_CONFIRMED)? {
+        State::Confirmed
+    } else if is_set(flash, layout, WORD_TRIAL)? {
+        State::Trial
+    } else {
+        State::Pending
+    })
+}
+
+/// Requests the installation of the image in slot 1 on the next reset.
+///
+/// `size` is the total size of the new image. The image must have been verified already.
+pub fn request<F: Flash>(flash: &mut F, layout: &Layout, size: u32) -> Result<(), FlashError> {
+    // Both images must be swapped completely. If there's no image in slot 0, swap the whole slot.
+    let old_size = match image::read_header(flash, layout.slot0) {
+        Ok(header) => header.total_size().min(layout.slot_size),
+        Err(_) => layout.slot_size,
+    };
+    let pages = layout.pages_for(size.max(old_size));
+
+    flash.erase_page(layout.state)?;
+    flash.write_word(word_addr(layout, WORD_PAGES), pages)?;
+    // Written last, so an interrupted request leaves the state empty
+    flash.write_word(word_addr(layout, WORD_PENDING), MAGIC_PENDING)
+}
+
+/// Checks that the image in slot 1 is signed with `key` and may replace the image in slot 0.
+pub fn check_update<F: Flash>(
+    flash: &F,
+    layout: &Layout,
+    key: &[u8; PUBLIC_KEY_LEN],
+) -> Result<ImageHeader, ImageError> {
+    let header = image::verify(flash, layout.slot1, layout.slot_size, key)?;
+    let installed = image::read_header(flash, layout.slot0).ok();
+    header.check_replaces(installed.as_ref())?;
+    Ok(header)
+}
+
+/// Marks the running image as working, so it is kept on the next reset.
+///
+/// This must be called by the firmware on every boot. It only writes to flash when the image is
+/// running on trial.
+pub fn confirm<F: Flash>(flash: &mut F, layout: &Layout) -> Result<(), FlashError> {
+    if state(flash, layout)? == State::Trial {
+        set(flash, layout, WORD_CONFIRMED)?;
+    }
+
+    Ok(())
+}
+
+/// Forgets about any update, so the next reset will boot the image in slot 0 as it is.
+///
+/// This must not be called while an image runs on trial, since it would skip the rollback.
+pub fn clear<F: Flash>(flash: &mut F, layout: &Layout) -> Result<(), FlashError> {
+    flash.erase_page(layout.state)
+}
+
+/// Performs the update steps pending at boot time, returning the resulting state.
+///
+/// This is called by the bootloader before booting slot 0. It installs a pending update, or
+/// restores the previous image if the last image booted on trial never confirmed itself. If this
+/// is interrupted by a reset, it will continue where it left off the next time it is called.
+///
+/// Updates are discarded unless they pass `check_update` with `key`.
+pub fn boot<F: Flash>(
+    flash: &mut F,
+    layout: &Layout,
+    key: &[u8; PUBLIC_KEY_LEN],
+) -> Result<State, FlashError> {
+    let pages = flash.read_word(word_addr(layout, WORD_PAGES))?;
+
+    match state(flash, layout)? {
+        State::Pending => {
+            if pages > layout.slot_pages() {
+                clear(flash, layout)?;
+                return Ok(State::None);
+            }
+
+            // Once the swap has started, slot 1 no longer holds the complete new image
+            let started = is_set(flash, layout, WORD_PROGRESS)?;
+            if !started && check_update(flash, layout, key).is_err() {
+                clear(flash, layout)?;
+                return Ok(State::None);
+            }
+
+            swap(flash, layout, pages, WORD_PROGRESS)?;
+            set(flash, layout, WORD_TRIAL)?;
+            Ok(State::Trial)
+        }
+        State::Trial => {
+            // The trial boot failed, swap the old image back
+            swap(
+                flash,
+                layout,
+                pages,
+                WORD_PROGRESS + STEPS * layout.slot_pages(),
+            )?;
+            set(flash, layout, WORD_REVERTED)?;
+            Ok(State::Reverted)
+        }
+        state => Ok(state),
+    }
+}
+
+/// Swaps the first `pages` pages of both slots, recording progress starting at word `progress`.
+fn swap<F: Flash>(
+    flash: &mut F,
+    layout: &Layout,
+    pages: u32,
+    progress: u32,
+) -> Result<(), FlashError> {
+    for page in 0..pages {
+        let offset = page * layout.page_size;
+        let slot0 = layout.slot0 + offset;
+        let slot1 = layout.slot1 + offset;
+
+        let steps = [
+            (slot0, layout.scratch),
+            (slot1, slot0),
+            (layout.scratch, slot1),
+        ];
+
+        for (step, &(from, to)) in steps.iter().enumerate() {
+            let record = progress + page * STEPS + step as u32;
+            if is_set(flash, layout, record)? {
+                continue;
+            }
+
+            copy_page(flash, layout, from, to)?;
+            set(flash, layout, record)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_page<F: Flash>(
+    flash: &mut F,
+    layout: &Layout,
+    from: u32,
+    to: u32,
+) -> Result<(), FlashError> {
+    flash.erase_page(to)?;
+
+    let mut buf = [0; 64];
+    for offset in (0..layout.page_size).step_by(buf.len()) {
+        flash.read(from + offset, &mut buf)?;
+        // Don't spend time (and flash endurance) writing erased words
+        if buf.iter().any(|b| *b != 0xFF) {
+            flash.write(to + offset, &buf)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            flash::sim::SimFlash,
+            image::{
+                testing::{key, signed_image},
+                Device, ImageHeader, FLAG_ALLOW_DOWNGRADE, HEADER_SIZE,
+            },
+        },
+        std::{
+            panic::{self, AssertUnwindSafe},
+            vec::Vec,
+        },
+    };
+
+    const PAGE: u32 = 256;
+
+    /// A small layout: 4 pages per slot.
+    const LAYOUT: Layout = Layout {
+        slot0: 0,
+        slot1: 4 * PAGE,
+        slot_size: 4 * PAGE,
+        scratch: 8 * PAGE,
+        state: 9 * PAGE,
+        page_size: PAGE,
+    };
+
+    fn image(len: usize, seed: u8, version: &str) -> Vec<u8> {
+        let binary: Vec<u8> = (0..len).map(|i| (i as u8).wrapping_mul(seed)).collect();
+        signed_image(&binary, Device::Controller, version, 0)
+    }
+
+    fn slot(flash: &SimFlash, addr: u32, len: usize) -> &[u8] {
+        &flash.mem[addr as usize..addr as usize + len]
+    }
+
+    /// Sets up slot 0 with image `a` and requests the installation of image `b`.
+    fn setup(a: &[u8], b: &[u8]) -> SimFlash {
+        let mut flash = SimFlash::new(10 * PAGE, PAGE);
+        flash.write(LAYOUT.slot0, a).unwrap();
+        flash.write(LAYOUT.slot1, b).unwrap();
+        request(&mut flash, &LAYOUT, b.len() as u32).unwrap();
+        flash
+    }
+
+    #[test]
+    fn update_and_confirm() {
+        let (a, b) = (image(700, 3, "1.0.0"), image(300, 5, "1.1.0"));
+        let mut flash = setup(&a, &b);
+        assert_eq!(state(&flash, &LAYOUT), Ok(State::Pending));
+
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Trial));
+        assert_eq!(slot(&flash, LAYOUT.slot0, b.len()), &b[..]);
+        assert_eq!(slot(&flash, LAYOUT.slot1, a.len()), &a[..]);
+
+        confirm(&mut flash, &LAYOUT).unwrap();
+        assert_eq!(state(&flash, &LAYOUT), Ok(State::Confirmed));
+
+        // Nothing happens on subsequent boots
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Confirmed));
+        confirm(&mut flash, &LAYOUT).unwrap();
+        assert_eq!(slot(&flash, LAYOUT.slot0, b.len()), &b[..]);
+    }
+
+    #[test]
+    fn rollback_without_confirm() {
+        let (a, b) = (image(700, 3, "1.0.0"), image(600, 5, "1.1.0"));
+        let mut flash = setup(&a, &b);
+
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Trial));
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Reverted));
+        assert_eq!(slot(&flash, LAYOUT.slot0, a.len()), &a[..]);
+        assert_eq!(slot(&flash, LAYOUT.slot1, b.len()), &b[..]);
+
+        // The old image doesn't need to confirm
+        confirm(&mut flash, &LAYOUT).unwrap();
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Reverted));
+        assert_eq!(slot(&flash, LAYOUT.slot0, a.len()), &a[..]);
+    }
+
+    #[test]
+    fn corrupted_update_is_discarded() {
+        let (a, mut b) = (image(700, 3, "1.0.0"), image(300, 5, "1.1.0"));
+        b[HEADER_SIZE as usize + 10] ^= 0xFF;
+        let mut flash = setup(&a, &b);
+
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::None));
+        assert_eq!(slot(&flash, LAYOUT.slot0, a.len()), &a[..]);
+        assert_eq!(state(&flash, &LAYOUT), Ok(State::None));
+    }
+
+    #[test]
+    fn unsigned_update_is_discarded() {
+        let a = image(700, 3, "1.0.0");
+        let binary = [0x55; 300];
+        let mut b = signed_image(&binary, Device::Controller, "1.1.0", 0);
+        // Sign with a different key
+        let mut header = ImageHeader::parse(&b).unwrap();
+        header.sign(&[8; 32]);
+        let mut buf = [0; HEADER_SIZE as usize];
+        header.write(&mut buf);
+        b[..buf.len()].copy_from_slice(&buf);
+
+        let mut flash = setup(&a, &b);
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::None));
+        assert_eq!(slot(&flash, LAYOUT.slot0, a.len()), &a[..]);
+    }
+
+    #[test]
+    fn downgrade_is_discarded() {
+        let (a, b) = (image(700, 3, "1.1.0"), image(300, 5, "1.0.9"));
+        let mut flash = setup(&a, &b);
+
+        assert_eq!(
+            check_update(&flash, &LAYOUT, &key()),
+            Err(ImageError::Downgrade)
+        );
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::None));
+        assert_eq!(slot(&flash, LAYOUT.slot0, a.len()), &a[..]);
+
+        // Unless the image explicitly allows it
+        let binary: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let b = signed_image(&binary, Device::Controller, "1.0.9", FLAG_ALLOW_DOWNGRADE);
+        let mut flash = setup(&a, &b);
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Trial));
+        assert_eq!(slot(&flash, LAYOUT.slot0, b.len()), &b[..]);
+    }
+
+    #[test]
+    fn image_for_other_device_is_discarded() {
+        let a = image(700, 3, "1.0.0");
+        let binary: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let b = signed_image(&binary, Device::Receiver, "1.1.0", 0);
+        let mut flash = setup(&a, &b);
+
+        assert_eq!(
+            check_update(&flash, &LAYOUT, &key()),
+            Err(ImageError::WrongDevice)
+        );
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::None));
+        assert_eq!(slot(&flash, LAYOUT.slot0, a.len()), &a[..]);
+    }
+
+    #[test]
+    fn blank_slot0_is_swapped_completely() {
+        let b = image(300, 5, "1.1.0");
+        let mut flash = setup(&[], &b);
+
+        assert_eq!(flash.read_word(word_addr(&LAYOUT, WORD_PAGES)), Ok(4));
+        assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Trial));
+        assert_eq!(slot(&flash, LAYOUT.slot0, b.len()), &b[..]);
+    }
+
+    /// Boots with power failing after `budget` flash operations, then boots again normally.
+    fn boot_interrupted(flash: &mut SimFlash, budget: usize) -> State {
+        flash.budget = Some(budget);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| boot(flash, &LAYOUT, &key())));
+        flash.budget = None;
+
+        match result {
+            Ok(state) => state.unwrap(),
+            Err(_) => boot(flash, &LAYOUT, &key()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn power_loss_during_update() {
+        let (a, b) = (image(700, 3, "1.0.0"), image(600, 5, "1.1.0"));
+
+        // Fail at every single flash operation, up to a complete, uninterrupted swap
+        for budget in 0..100 {
+            let mut flash = setup(&a, &b);
+            assert_eq!(boot_interrupted(&mut flash, budget), State::Trial);
+            assert_eq!(
+                slot(&flash, LAYOUT.slot0, b.len()),
+                &b[..],
+                "budget {}",
+                budget
+            );
+            assert_eq!(
+                slot(&flash, LAYOUT.slot1, a.len()),
+                &a[..],
+                "budget {}",
+                budget
+            );
+        }
+    }
+
+    #[test]
+    fn power_loss_during_rollback() {
+        let (a, b) = (image(700, 3, "1.0.0"), image(600, 5, "1.1.0"));
+
+        for budget in 0..100 {
+            let mut flash = setup(&a, &b);
+            assert_eq!(boot(&mut flash, &LAYOUT, &key()), Ok(State::Trial));
+            assert_eq!(boot_interrupted(&mut flash, budget), State::Reverted);
+            assert_eq!(
+                slot(&flash, LAYOUT.slot0, a.len()),
+                &a[..],
+                "budget {}",
+                budget
+            );
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Receiving an update on the device.
+
+use crate::{
+    flash::Flash,
+    image::{self, Crc32, Device, ImageError},
+    layout::Layout,
+    protocol::{Request, Response, Status},
+    sign::PUBLIC_KEY_LEN,
+    swap::{self, State},
+};
+
+/// State of a `DfuTarget`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Transfer {
+    Idle,
+    Receiving {
+        size: u32,
+        crc: u32,
+    },
+    /// The image was received and verified, and will be installed on the next reset.
+    Complete,
+    /// An image for the relay device was received and verified. It stays in slot 1 until it was
+    /// relayed.
+    Relay,
+}
+
+/// Receives an image into slot 1 and requests its installation.
+///
+/// Received data is written to flash as it arrives, erasing pages as needed. The progress is kept
+/// in RAM, so an interrupted transfer can be resumed by sending the same `Start` request again,
+/// as long as the device wasn't reset.
+///
+/// A target can also accept images for another device, which are kept in slot 1 without being
+/// installed so they can be relayed (see `relay_to`).
+pub struct DfuTarget<F: Flash> {
+    flash: F,
+    layout: Layout,
+    /// Key the image must be signed with.
+    key: [u8; PUBLIC_KEY_LEN],
+    /// Device whose images are stored for relaying instead of being installed.
+    relay: Option<Device>,
+    transfer: Transfer,
+    /// Number of Bytes received.
+    received: u32,
+    /// CRC of the Bytes received so far.
+    received_crc: Crc32,
+    /// Address up to which slot 1 has been erased.
+    erased: u32,
+    /// Received Bytes that don't fill a whole word yet.
+    word: [u8; 4],
+}
+
+impl<F: Flash> DfuTarget<F> {
+    pub fn new(flash: F, layout: Layout, key: [u8; PUBLIC_KEY_LEN]) -> Self {
+        Self {
+            flash,
+            layout,
+            key,
+            relay: None,
+            transfer: Transfer::Idle,
+            received: 0,
+            received_crc: Crc32::new(),
+            erased: layout.slot1,
+            word: [0xFF; 4],
+        }
+    }
+
+    /// Accepts images for `device` and keeps them in slot 1 instead of installing them.
+    ///
+    /// Their version isn't checked, that's up to the device they're relayed to.
+    pub fn relay_to(mut self, device: Device) -> Self {
+        self.relay = Some(device);
+        self
+    }
+
+    /// Returns whether an image was received and will be installed on the next reset.
+    pub fn is_complete(&self) -> bool {
+        self.transfer == Transfer::Complete
+    }
+
+    /// Returns whether an image for the relay device was received into slot 1.
+    pub fn is_ready_for_relay(&self) -> bool {
+        self.transfer == Transfer::Relay
+    }
+
+    /// Returns the flash memory the image is written to.
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Returns whether a transfer is in progress.
+    pub fn is_receiving(&self) -> bool {
+        matches!(self.transfer, Transfer::Receiving { .. })
+    }
+
+    /// Decodes and handles a request, returning the encoded response.
+    pub fn handle_raw(&mut self, request: &[u8]) -> [u8; crate::protocol::RESPONSE_LEN] {
+        match Request::decode(request) {
+            Some(request) => self.handle(request),
+            None => self.respond(Status::InvalidRequest),
+        }
+        .encode()
+    }
+
+    /// Handles a request.
+    pub fn handle(&mut self, request: Request) -> Response {
+        match (request, self.transfer) {
+            (Request::Status, _) => self.respond(Status::Ok),
+            (Request::Abort, _) => {
+                self.transfer = Transfer::Idle;
+                self.received = 0;
+                self.respond(Status::Ok)
+            }
+            (Request::Start { size, crc }, Transfer::Receiving { size: s, crc: c })
+                if size == s && crc == c =>
+            {
+                // Resume the interrupted transfer
+                self.respond(Status::Ok)
+            }
+            (Request::Start { size, crc }, _) => self.start(size, crc),
+            (Request::Data { offset, data }, Transfer::Receiving { size, .. }) => {
+                if offset != self.received {
+                    self.respond(Status::InvalidOffset)
+                } else if offset + data.len() as u32 > size {
+                    self.respond(Status::TooLarge)
+                } else {
+                    match self.write(data) {
+                        Ok(()) => self.respond(Status::Ok),
+                        Err(status) => self.fail(status),
+                    }
+                }
+            }
+            (Request::Finish, Transfer::Receiving { size, crc }) => {
+                if self.received != size {
+                    self.respond(Status::InvalidOffset)
+                } else if self.received_crc.finish() != crc {
+                    self.fail(Status::InvalidImage)
+                } else {
+                    match self.finish() {
+                        Ok(transfer) => {
+                            self.transfer = transfer;
+                            self.respond(Status::Ok)
+                        }
+                        Err(status) => self.fail(status),
+                    }
+                }
+            }
+            (Request::Finish, Transfer::Complete) | (Request::Finish, Transfer::Relay) => {
+                self.respond(Status::Ok)
+            }
+            (Request::Data { .. }, _) | (Request::Finish, _) => self.respond(Status::InvalidState),
+        }
+    }
+
+    fn respond(&self, status: Status) -> Response {
+        Response {
+            status,
+            offset: self.received,
+        }
+    }
+
+    /// Cancels the transfer because of an error.
+    fn fail(&mut self, status: Status) -> Response {
+        self.transfer = Transfer::Idle;
+        self.received = 0;
+        self.respond(status)
+    }
+
+    fn start(&mut self, size: u32, crc: u32) -> Response {
+        if size > self.layout.slot_size {
+            return self.fail(Status::TooLarge);
+        }
+        if size < image::HEADER_SIZE {
+            return self.fail(Status::InvalidImage);
+        }
+
+        match swap::state(&self.flash, &self.layout) {
+            // Rolling back must stay possible until the running image confirmed itself
+            Ok(State::Trial) => return self.fail(Status::InvalidState),
+            // Don't install a half-overwritten image if we're reset during the transfer
+            Ok(State::Pending) => {
+                if swap::clear(&mut self.flash, &self.layout).is_err() {
+                    return self.fail(Status::FlashError);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => return self.fail(Status::FlashError),
+        }
+
+        self.transfer = Transfer::Receiving { size, crc };
+        self.received = 0;
+        self.received_crc = Crc32::new();
+        self.erased = self.layout.slot1;
+        self.respond(Status::Ok)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+        self.received_crc.update(data);
+
+        for &byte in data {
+            let index = (self.received % 4) as usize;
+            self.word[index] = byte;
+            self.received += 1;
+
+            if index == 3 {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current (possibly incomplete) word to flash.
+    fn flush(&mut self) -> Result<(), Status> {
+        let addr = self.layout.slot1 + (self.received - 1) / 4 * 4;
+
+        if addr >= self.erased {
+            self.flash
+                .erase_page(self.erased)
+                .map_err(|_| Status::FlashError)?;
+            self.erased += self.layout.page_size;
+        }
+
+        self.flash
+            .write(addr, &self.word)
+            .map_err(|_| Status::FlashError)?;
+        self.word = [0xFF; 4];
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Transfer, Status> {
+        if !self.received.is_multiple_of(4) {
+            self.flush()?;
+        }
+
+        let header = image::read_header(&self.flash, self.layout.slot1).map_err(image_status)?;
+        if Some(header.device) == self.relay {
+            image::verify(
+                &self.flash,
+                self.layout.slot1,
+                self.layout.slot_size,
+                &self.key,
+            )
+            .map_err(image_status)?;
+            return Ok(Transfer::Relay);
+        }
+
+        swap::check_update(&self.flash, &self.layout, &self.key).map_err(image_status)?;
+        swap::request(&mut self.flash, &self.layout, self.received)
+            .map_err(|_| Status::FlashError)?;
+        Ok(Transfer::Complete)
+    }
+}
+
+fn image_status(e: ImageError) -> Status {
+    match e {
+        ImageError::Downgrade => Status::Downgrade,
+        ImageError::Flash(_) => Status::FlashError,
+        _ => Status::InvalidImage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            flash::{sim::SimFlash, Flash},
+            image::{
+                testing::{key, signed_image},
+                HEADER_SIZE,
+            },
+        },
+        std::vec::Vec,
+    };
+
+    const PAGE: u32 = 256;
+
+    const LAYOUT: Layout = Layout {
+        slot0: 0,
+        slot1: 4 * PAGE,
+        slot_size: 4 * PAGE,
+        scratch: 8 * PAGE,
+        state: 9 * PAGE,
+        page_size: PAGE,
+    };
+
+    fn device_image(len: usize, device: Device, version: &str) -> Vec<u8> {
+        let binary: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+        let mut image = signed_image(&binary, device, version, 0);
+        // Not padded, to test images that don't end on a word boundary
+        image.truncate(HEADER_SIZE as usize + len);
+        image
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        device_image(len, Device::Controller, "0.2.0")
+    }
+
+    fn target() -> DfuTarget<SimFlash> {
+        DfuTarget::new(SimFlash::new(10 * PAGE, PAGE), LAYOUT, key())
+    }
+
+    fn start(image: &[u8]) -> Request<'static> {
+        Request::Start {
+            size: image.len() as u32,
+            crc: crc(image),
+        }
+    }
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    fn status(target: &mut DfuTarget<SimFlash>, request: Request) -> Status {
+        target.handle(request).status
+    }
+
+    fn send(target: &mut DfuTarget<SimFlash>, image: &[u8], chunk: usize) {
+        for (i, data) in image.chunks(chunk).enumerate() {
+            let offset = (i * chunk) as u32;
+            assert_eq!(status(target, Request::Data { offset, data }), Status::Ok);
+        }
+    }
+
+    #[test]
+    fn receive_image() {
+        let image = image(555);
+        let mut target = target();
+
+        let start = Request::Start {
+            size: image.len() as u32,
+            crc: crc(&image),
+        };
+        assert_eq!(status(&mut target, start), Status::Ok);
+        send(&mut target, &image, 13);
+        assert_eq!(status(&mut target, Request::Finish), Status::Ok);
+        assert!(target.is_complete());
+
+        assert_eq!(
+            &target.flash.mem[LAYOUT.slot1 as usize..][..image.len()],
+            &image[..]
+        );
+        assert_eq!(swap::state(&target.flash, &LAYOUT), Ok(State::Pending));
+    }
+
+    #[test]
+    fn resume_transfer() {
+        let image = image(700);
+        let mut target = target();
+        let start = Request::Start {
+            size: image.len() as u32,
+            crc: crc(&image),
+        };
+
+        assert_eq!(status(&mut target, start), Status::Ok);
+        send(&mut target, &image[..400], 20);
+
+        // The connection was lost, and the host starts over
+        let resp = target.handle(start);
+        assert_eq!(resp.status, Status::Ok);
+        assert_eq!(resp.offset, 400);
+
+        // Chunks must continue where the transfer stopped
+        let data = Request::Data {
+            offset: 0,
+            data: &image[..20],
+        };
+        assert_eq!(status(&mut target, data), Status::InvalidOffset);
+
+        for (i, data) in image[400..].chunks(20).enumerate() {
+            let offset = 400 + (i * 20) as u32;
+            assert_eq!(
+                status(&mut target, Request::Data { offset, data }),
+                Status::Ok
+            );
+        }
+        assert_eq!(status(&mut target, Request::Finish), Status::Ok);
+    }
+
+    #[test]
+    fn reject_bad_images() {
+        // Installed image with a higher version
+        let mut target = target();
+        let installed = signed_image(&[1; 100], Device::Controller, "0.3.0", 0);
+        target.flash.write(LAYOUT.slot0, &installed).unwrap();
+
+        let image = image(555);
+        assert_eq!(status(&mut target, start(&image)), Status::Ok);
+        send(&mut target, &image, 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::Downgrade);
+        assert_eq!(swap::state(&target.flash, &LAYOUT), Ok(State::None));
+
+        // Built for another device
+        let mut target = DfuTarget::new(SimFlash::new(10 * PAGE, PAGE), LAYOUT, key());
+        target.flash.write(LAYOUT.slot0, &installed).unwrap();
+        let image = device_image(555, Device::Receiver, "0.4.0");
+        assert_eq!(status(&mut target, start(&image)), Status::Ok);
+        send(&mut target, &image, 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::InvalidImage);
+        assert_eq!(swap::state(&target.flash, &LAYOUT), Ok(State::None));
+
+        // Signed with the wrong key
+        let mut target = DfuTarget::new(SimFlash::new(10 * PAGE, PAGE), LAYOUT, [0; 32]);
+        assert_eq!(status(&mut target, start(&image)), Status::Ok);
+        send(&mut target, &image, 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::InvalidImage);
+        assert_eq!(swap::state(&target.flash, &LAYOUT), Ok(State::None));
+    }
+
+    #[test]
+    fn receive_image_for_relay() {
+        let mut target = target().relay_to(Device::Receiver);
+        let installed = signed_image(&[1; 100], Device::Controller, "0.3.0", 0);
+        target.flash.write(LAYOUT.slot0, &installed).unwrap();
+
+        // The receiver decides whether to accept a lower version
+        let image = device_image(555, Device::Receiver, "0.1.0");
+        assert_eq!(status(&mut target, start(&image)), Status::Ok);
+        send(&mut target, &image, 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::Ok);
+        assert!(target.is_ready_for_relay());
+        assert!(!target.is_complete());
+        assert_eq!(status(&mut target, Request::Finish), Status::Ok);
+
+        assert_eq!(
+            &target.flash.mem[LAYOUT.slot1 as usize..][..image.len()],
+            &image[..]
+        );
+        assert_eq!(swap::state(&target.flash, &LAYOUT), Ok(State::None));
+
+        // Images for the device itself are still installed
+        let image = device_image(555, Device::Controller, "0.4.0");
+        assert_eq!(status(&mut target, start(&image)), Status::Ok);
+        send(&mut target, &image, 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::Ok);
+        assert!(target.is_complete());
+    }
+
+    #[test]
+    fn reject_bad_transfers() {
+        let image = image(700);
+        let mut target = target();
+
+        assert_eq!(status(&mut target, Request::Finish), Status::InvalidState);
+        let start = Request::Start {
+            size: 5 * PAGE,
+            crc: 0,
+        };
+        assert_eq!(status(&mut target, start), Status::TooLarge);
+
+        // Wrong CRC
+        let start = Request::Start {
+            size: image.len() as u32,
+            crc: crc(&image) ^ 1,
+        };
+        assert_eq!(status(&mut target, start), Status::Ok);
+        send(&mut target, &image, 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::InvalidImage);
+        assert!(!target.is_receiving());
+        assert_eq!(swap::state(&target.flash, &LAYOUT), Ok(State::None));
+
+        // Incomplete
+        let start = Request::Start {
+            size: image.len() as u32,
+            crc: crc(&image),
+        };
+        assert_eq!(status(&mut target, start), Status::Ok);
+        send(&mut target, &image[..256], 64);
+        assert_eq!(status(&mut target, Request::Finish), Status::InvalidOffset);
+    }
+}
@@ -0,0 +1,173 @@
+//! Reading the format strings of binary log records from a firmware's ELF file.
+//!
+//! Every format string is the name of a symbol in the `.binlog` section, and the symbol's address
+//! is the ID sent in log records (see `bluefly_binlog`). Only the section headers and the symbol
+//! table of the 32-bit little endian ELF files built for the nRF52810 are read.
+
+use std::{collections::HashMap, convert::TryInto};
+
+const SHT_SYMTAB: u32 = 2;
+const SECTION_HEADER_LEN: usize = 40;
+const SYMBOL_LEN: usize = 16;
+
+struct Section {
+    name: u32,
+    ty: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+fn read_u16(elf: &[u8], offset: usize) -> Result<u16, String> {
+    elf.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+fn read_u32(elf: &[u8], offset: usize) -> Result<u32, String> {
+    elf.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF file".to_string())
+}
+
+/// Reads a NUL terminated string from a string table.
+fn read_str(elf: &[u8], table: &Section, offset: u32) -> Result<String, String> {
+    let start = table.offset + offset as usize;
+    let bytes = elf
+        .get(start..table.offset + table.size)
+        .ok_or_else(|| "invalid string table offset".to_string())?;
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    if elf.get(..6) != Some(b"\x7fELF\x01\x01") {
+        return Err("not a 32-bit little endian ELF file".into());
+    }
+
+    let offset = read_u32(elf, 0x20)? as usize;
+    let count = usize::from(read_u16(elf, 0x30)?);
+    (0..count)
+        .map(|i| {
+            let header = offset + i * SECTION_HEADER_LEN;
+            Ok(Section {
+                name: read_u32(elf, header)?,
+                ty: read_u32(elf, header + 0x04)?,
+                offset: read_u32(elf, header + 0x10)? as usize,
+                size: read_u32(elf, header + 0x14)? as usize,
+                link: read_u32(elf, header + 0x18)? as usize,
+            })
+        })
+        .collect()
+}
+
+/// Returns the format strings in the `.binlog` section, by their ID.
+pub fn format_strings(elf: &[u8]) -> Result<HashMap<u16, String>, String> {
+    let sections = sections(elf)?;
+    let names = sections
+        .get(usize::from(read_u16(elf, 0x32)?))
+        .ok_or("invalid section name table")?;
+
+    let mut binlog = None;
+    for (i, section) in sections.iter().enumerate() {
+        if read_str(elf, names, section.name)? == ".binlog" {
+            binlog = Some(i);
+        }
+    }
+    let binlog = binlog.ok_or("no .binlog section; was the firmware built with `binary-log`?")?;
+
+    let symtab = sections
+        .iter()
+        .find(|s| s.ty == SHT_SYMTAB)
+        .ok_or("no symbol table")?;
+    let strtab = sections.get(symtab.link).ok_or("invalid symbol table")?;
+
+    let mut strings = HashMap::new();
+    for i in 0..symtab.size / SYMBOL_LEN {
+        let symbol = symtab.offset + i * SYMBOL_LEN;
+        let section = usize::from(read_u16(elf, symbol + 0x0E)?);
+        let name = read_str(elf, strtab, read_u32(elf, symbol)?)?;
+        // Skips section symbols and ARM mapping symbols like `$d`
+        if section == binlog && !name.is_empty() && !name.starts_with('$') {
+            strings.insert(read_u32(elf, symbol + 0x04)? as u16, name);
+        }
+    }
+
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section_header(
+        elf: &mut Vec<u8>,
+        name: u32,
+        ty: u32,
+        offset: usize,
+        size: usize,
+        link: u32,
+    ) {
+        for value in &[name, ty, 0, 0, offset as u32, size as u32, link, 0, 0, 0] {
+            elf.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn symbol(symtab: &mut Vec<u8>, name: u32, value: u32, section: u16) {
+        symtab.extend_from_slice(&name.to_le_bytes());
+        symtab.extend_from_slice(&value.to_le_bytes());
+        symtab.extend_from_slice(&0u32.to_le_bytes());
+        symtab.extend_from_slice(&[0, 0]);
+        symtab.extend_from_slice(&section.to_le_bytes());
+    }
+
+    /// Builds an ELF file with the sections `.binlog` (1), `.symtab` (2), `.strtab` (3) and
+    /// `.shstrtab` (4).
+    fn elf() -> Vec<u8> {
+        let shstrtab = b"\0.binlog\0.symtab\0.strtab\0.shstrtab\0";
+        let strtab = b"\0READY\0got val: {}\0main\0$d\0";
+        let mut symtab = Vec::new();
+        symbol(&mut symtab, 0, 0, 0);
+        symbol(&mut symtab, 1, 0, 1);
+        symbol(&mut symtab, 7, 1, 1);
+        symbol(&mut symtab, 19, 0x100, 5);
+        symbol(&mut symtab, 24, 0, 1);
+
+        let mut elf = vec![0; 52];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        let symtab_offset = elf.len();
+        elf.extend_from_slice(&symtab);
+        let strtab_offset = elf.len();
+        elf.extend_from_slice(strtab);
+        let shstrtab_offset = elf.len();
+        elf.extend_from_slice(shstrtab);
+
+        let headers = elf.len() as u32;
+        elf[0x20..0x24].copy_from_slice(&headers.to_le_bytes());
+        elf[0x30..0x32].copy_from_slice(&5u16.to_le_bytes());
+        elf[0x32..0x34].copy_from_slice(&4u16.to_le_bytes());
+        section_header(&mut elf, 0, 0, 0, 0, 0);
+        section_header(&mut elf, 1, 1, 0, 2, 0);
+        section_header(&mut elf, 9, SHT_SYMTAB, symtab_offset, symtab.len(), 3);
+        section_header(&mut elf, 17, 3, strtab_offset, strtab.len(), 0);
+        section_header(&mut elf, 25, 3, shstrtab_offset, shstrtab.len(), 0);
+        elf
+    }
+
+    #[test]
+    fn reads_format_strings() {
+        let strings = format_strings(&elf()).unwrap();
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[&0], "READY");
+        assert_eq!(strings[&1], "got val: {}");
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(format_strings(b"not an ELF file").is_err());
+
+        let mut elf = elf();
+        elf.truncate(100);
+        assert!(format_strings(&elf).is_err());
+    }
+}
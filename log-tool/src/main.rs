@@ -0,0 +1,113 @@
+//! Host tool for reading the binary log output of the firmwares.
+//!
+//! ```notrust
+//! cargo run -p log-tool --target x86_64-unknown-linux-gnu -- \
+//!     target/thumbv7em-none-eabi/release/controller --port /dev/ttyUSB0
+//! ```
+//!
+//! The firmware must be built with the `binary-log` feature, and the ELF file must be the one
+//! running on the device: the format strings are looked up in it (see `bluefly_binlog`). Text
+//! sent over the serial port, like shell output, is passed through.
+
+mod elf;
+
+use {
+    bluefly_binlog::{
+        format,
+        record::{RawRecord, TEXT_ID},
+        stream::{Decoder, Item},
+    },
+    serialport::SerialPortSettings,
+    std::{
+        collections::HashMap,
+        error::Error,
+        fs::{self, File},
+        io::{self, Read, Write},
+        path::PathBuf,
+        time::Duration,
+    },
+    structopt::StructOpt,
+};
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "log-tool",
+    about = "Decodes the binary log output of the controller and the receiver"
+)]
+struct Opt {
+    /// The firmware's ELF file.
+    #[structopt(parse(from_os_str))]
+    elf: PathBuf,
+    /// The serial port the device is connected to.
+    #[structopt(long = "port", default_value = "/dev/ttyUSB0")]
+    port: String,
+    #[structopt(long = "baud", default_value = "1000000")]
+    baud: u32,
+    /// Decode a capture of the serial output instead of reading from the serial port.
+    #[structopt(long = "input", parse(from_os_str))]
+    input: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let strings = elf::format_strings(&fs::read(&opt.elf)?)?;
+
+    let mut input: Box<dyn Read> = match opt.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => {
+            let settings = SerialPortSettings {
+                baud_rate: opt.baud,
+                timeout: Duration::from_millis(100),
+                ..Default::default()
+            };
+            serialport::open_with_settings(&opt.port, &settings)?
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut decoder = Decoder::new();
+    let mut buf = [0; 256];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for &byte in &buf[..len] {
+            match decoder.feed(byte) {
+                Some(Item::Text(byte)) => out.write_all(&[byte])?,
+                Some(Item::Record(record)) => match RawRecord::decode(record) {
+                    Some(record) => writeln!(out, "{}", format_record(&record, &strings))?,
+                    None => writeln!(out, "<malformed record>")?,
+                },
+                None => {}
+            }
+        }
+        out.flush()?;
+    }
+}
+
+/// Formats a record like the firmware's text logger does.
+fn format_record(record: &RawRecord, strings: &HashMap<u16, String>) -> String {
+    let mut line = format!(
+        "{}.{:06}s - {} - ",
+        record.timestamp / 1_000_000,
+        record.timestamp % 1_000_000,
+        record.level
+    );
+
+    let fmt = match (record.id, strings.get(&record.id)) {
+        (TEXT_ID, _) => "{}",
+        (_, Some(fmt)) => fmt,
+        (id, None) => {
+            line.push_str(&format!("<unknown format string {}>", id));
+            return line;
+        }
+    };
+    format::format(&mut line, fmt, record.args()).unwrap();
+
+    line
+}
@@ -0,0 +1,65 @@
+//! Board support for the receiver PCB (`hardware/receiver`).
+//!
+//! `Board::new` splits port 0 into the typed pins of everything on the board, so the firmware never
+//! has to name a pin itself. `pins` lists the same assignments as numbers, checked for conflicts at
+//! compile time.
+//!
+//! The only signals on the board are the two on J1, labelled RX and TX in the schematic. RX (P0.08)
+//! drives the ESC, so the UART can't receive, and the firmware disconnects its RX pin. The receiver
+//! is configured over BLE and updated over BLE or through the controller instead of over serial.
+
+#![no_std]
+
+pub use nrf52810_hal as hal;
+
+use hal::{
+    gpio::{p0::P0_08, GpioExt, Level, Output, PushPull},
+    nrf52810_pac::P0,
+    uarte,
+};
+
+/// Port 0 pins used by the receiver.
+pub mod pins {
+    bluefly_common::pin_map! {
+        /// Servo signal to the ESC, J1 pin 1.
+        ESC = 8,
+        /// UART TX, J1 pin 2.
+        SERIAL_TX = 6,
+        /// UART RX. Not routed on the board, since J1 pin 1 is taken by the ESC; the firmware
+        /// disconnects it from the UARTE.
+        SERIAL_RX = 7,
+    }
+}
+
+/// The servo signal output to the ESC.
+pub struct Esc(P0_08<Output<PushPull>>);
+
+impl Esc {
+    /// Returns the pin number, for the PWM peripheral's PSEL register.
+    pub fn pin(&self) -> u8 {
+        pins::ESC
+    }
+}
+
+/// Everything connected to the nRF52810 on the receiver.
+pub struct Board {
+    pub serial: uarte::Pins,
+    pub esc: Esc,
+}
+
+impl Board {
+    /// Configures the pins of port 0 for the receiver.
+    pub fn new(p0: P0) -> Self {
+        let p0 = p0.split();
+
+        Self {
+            serial: uarte::Pins {
+                rxd: p0.p0_07.into_floating_input().degrade(),
+                txd: p0.p0_06.into_push_pull_output(Level::Low).degrade(),
+                cts: None,
+                rts: None,
+            },
+            esc: Esc(p0.p0_08.into_push_pull_output(Level::Low)),
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! Shell commands only the receiver has (see `bluefly_common::shell`).
+
+use bluefly_common::shell::DeviceCommand;
+
+/// Drives the PWM output with a compare value, to try out the ESC.
+pub static PWM_TEST: DeviceCommand = DeviceCommand {
+    words: &["pwm", "test"],
+    args: &["value"],
+    help: "drive the PWM output",
+};
+
+/// All receiver commands.
+pub static COMMANDS: [&DeviceCommand; 1] = [&PWM_TEST];
@@ -0,0 +1,196 @@
+//! Runtime configuration of the receiver (see `bluefly_common::config`).
+
+pub use bluefly_common::config::log_filter;
+
+use {
+    crate::pwm::COUNTERTOP,
+    bluefly_common::{
+        config::{self, Entry},
+        esb,
+        mode::{self, Phy},
+        output::{self, OutputMap},
+        protocol::MAX_CONTROLLERS,
+        receivers::{self, MAX_RECEIVERS},
+    },
+    bluefly_dfu::{
+        flash::{FlashError, Nvmc},
+        layout::FLASH_SIZE,
+    },
+    rubble::time::Duration,
+};
+
+/// PWM compare value output before the first throttle value is received.
+pub static PWM_NEUTRAL: Entry = Entry::new(
+    "pwm.neutral",
+    OutputMap::DEFAULT.neutral as u32,
+    0,
+    COUNTERTOP as u32,
+);
+
+/// PWM compare value for a throttle value of 0.
+pub static PWM_BASE: Entry = Entry::new(
+    "pwm.base",
+    OutputMap::DEFAULT.base as u32,
+    0,
+    COUNTERTOP as u32,
+);
+
+/// PWM compare ticks added per step of the throttle value.
+pub static PWM_SCALE: Entry = Entry::new("pwm.scale", OutputMap::DEFAULT.scale as u32, 0, 31);
+
+/// Highest PWM compare value output, limiting the speed.
+pub static PWM_LIMIT: Entry = Entry::new(
+    "pwm.limit",
+    OutputMap::DEFAULT.limit as u32,
+    0,
+    COUNTERTOP as u32,
+);
+
+/// Minimum milliseconds without a throttle value after which the output goes back to neutral.
+pub static FAILSAFE_TIMEOUT: Entry = Entry::new(
+    "failsafe.timeout",
+    output::DEFAULT_FAILSAFE_MS as u32,
+    20,
+    1000,
+);
+
+/// Number of beacon intervals advertised by the controller without a throttle value after which
+/// the output goes back to neutral.
+pub static FAILSAFE_BEACONS: Entry =
+    Entry::new("failsafe.beacons", output::DEFAULT_FAILSAFE_BEACONS, 1, 20);
+
+/// Maximum milliseconds without a throttle value after which the output goes back to neutral,
+/// however long the beacon interval advertised by the controller.
+pub static FAILSAFE_MAX: Entry = Entry::new(
+    "failsafe.max_ms",
+    output::DEFAULT_FAILSAFE_MAX_MS as u32,
+    20,
+    5000,
+);
+
+/// Fastest PHY the receiver agrees to use, in Mbit/s (see `bluefly_common::mode`).
+pub static RADIO_PHY: Entry = Entry::new("radio.phy", 1, 1, 2);
+
+/// TX power, as an index into `bluefly_common::mode::TX_POWERS` (from -40 dBm at 0 to +4 dBm at
+/// 9).
+pub static RADIO_TX_LEVEL: Entry = Entry::new("radio.tx_level", 9, 0, 9);
+
+/// Whether to use ESB instead of BLE beacons for the throttle link (1) or not (0), which has to be
+/// the same on both devices (see `bluefly_common::esb`). The radio can only be switched from BLE to
+/// ESB; switching back takes a reset. Defaults to 1 with the `esb` feature.
+pub static LINK_ESB: Entry = Entry::new("link.esb", cfg!(feature = "esb") as u32, 0, 1);
+
+/// Channel used in ESB mode, as an offset from 2400 MHz.
+pub static ESB_CHANNEL: Entry = Entry::new("esb.channel", esb::DEFAULT_CHANNEL as u32, 0, 100);
+
+/// Whether throttle beacons hop over the data channels (1) or go out on the advertising channels
+/// (0), which has to be the same on both devices (see `bluefly_common::hopping`).
+pub static LINK_HOPPING: Entry = Entry::new("link.hopping", 0, 0, 1);
+
+/// Number of the receiver among those driven by the controller, whose throttle value it follows
+/// (see `bluefly_common::receivers`). Only receiver 0 answers the controller.
+pub static LINK_ID: Entry = Entry::persistent("link.id", 0, 0, MAX_RECEIVERS as u32 - 1);
+
+/// Controllers the receiver is paired to, as a bit mask with bit `n` set for controller `n` (see
+/// `bluefly_common::ownership`).
+pub static PAIR_CONTROLLERS: Entry =
+    Entry::persistent("pair.controllers", 1, 1, (1 << MAX_CONTROLLERS) - 1);
+
+/// All configuration entries, in the order they're listed in.
+pub static ENTRIES: [&Entry; 14] = [
+    &PWM_NEUTRAL,
+    &PWM_BASE,
+    &PWM_SCALE,
+    &PWM_LIMIT,
+    &FAILSAFE_TIMEOUT,
+    &FAILSAFE_BEACONS,
+    &FAILSAFE_MAX,
+    &RADIO_PHY,
+    &RADIO_TX_LEVEL,
+    &LINK_ESB,
+    &ESB_CHANNEL,
+    &LINK_HOPPING,
+    &LINK_ID,
+    &PAIR_CONTROLLERS,
+];
+
+/// Restores the persistent entries from flash (see `bluefly_common::config::load`).
+pub fn load() -> Result<(), FlashError> {
+    // Only reads the settings pages
+    let flash = unsafe { Nvmc::new(FLASH_SIZE) };
+    config::load(&flash, &ENTRIES)
+}
+
+/// Stores the persistent entries that changed (see `bluefly_common::config::save`).
+///
+/// This may erase a page, so it must only be called while the motor is stopped.
+pub fn save() -> Result<(), FlashError> {
+    // Only the settings pages are written, and only from `idle`, which also runs the DFU transports
+    let mut flash = unsafe { Nvmc::new(FLASH_SIZE) };
+    config::save(&mut flash, &ENTRIES)
+}
+
+/// Returns the mapping from throttle values to PWM compare values.
+pub fn output_map() -> OutputMap {
+    OutputMap {
+        neutral: PWM_NEUTRAL.get() as u16,
+        base: PWM_BASE.get() as u16,
+        scale: PWM_SCALE.get() as u16,
+        limit: PWM_LIMIT.get() as u16,
+        top: COUNTERTOP,
+    }
+}
+
+/// Returns the failsafe timeout for a throttle beacon advertising `interval` until the next one.
+pub fn failsafe_timeout(interval: Option<Duration>) -> Duration {
+    output::failsafe_timeout(
+        interval,
+        FAILSAFE_BEACONS.get(),
+        Duration::from_millis(FAILSAFE_TIMEOUT.get() as u16),
+        Duration::from_millis(FAILSAFE_MAX.get() as u16),
+    )
+}
+
+/// Returns the PHY configured with `RADIO_PHY`.
+pub fn phy() -> Phy {
+    Phy::from_config(RADIO_PHY.get())
+}
+
+/// Returns the TX power configured with `RADIO_TX_LEVEL`, in dBm.
+pub fn tx_power() -> i8 {
+    mode::tx_power(RADIO_TX_LEVEL.get())
+}
+
+/// Returns the channel to use if ESB is configured.
+pub fn esb_channel() -> Option<u8> {
+    if LINK_ESB.get() == 1 {
+        Some(ESB_CHANNEL.get() as u8)
+    } else {
+        None
+    }
+}
+
+/// Whether throttle beacons hop over the data channels.
+pub fn hopping() -> bool {
+    LINK_HOPPING.get() == 1
+}
+
+/// Returns the number of the receiver.
+pub fn link_id() -> u8 {
+    LINK_ID.get() as u8
+}
+
+/// Whether the receiver answers the controller.
+pub fn is_primary() -> bool {
+    link_id() == receivers::PRIMARY
+}
+
+/// Returns the controllers the receiver is paired to, as a bit mask.
+pub fn paired() -> u32 {
+    PAIR_CONTROLLERS.get()
+}
+
+/// Whether the receiver is paired to controller `id`.
+pub fn is_paired(id: u8) -> bool {
+    paired() & 1 << id != 0
+}
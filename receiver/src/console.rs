@@ -0,0 +1,159 @@
+//! The shell over BLE.
+//!
+//! The receiver's UART can't receive (see `bluefly_receiver_bsp`), so its shell is reached through
+//! the console service instead. Bytes written to the console input characteristic are fed to the
+//! shell like typed characters, and a client subscribing to notifications of the console output
+//! characteristic receives what the shell prints, including the echo. Lines can span several
+//! writes; they end with a `\r` or `\n` like on a terminal.
+
+use {
+    bbqueue::{Consumer, Producer},
+    bluefly_common::gatt::{CHARACTERISTIC, CLIENT_CONFIG, PRIMARY_SERVICE, USER_DESCRIPTION},
+    core::{
+        fmt,
+        sync::atomic::{AtomicBool, Ordering},
+    },
+    rubble::{
+        att::{AttUuid, Attribute, Handle},
+        uuid::Uuid128,
+        Error,
+    },
+};
+
+/// Whether a BLE client subscribed to the console output characteristic.
+pub static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+/// UUID of the console service (`b1ef1d00-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const SERVICE_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x00, 0x1D, 0xEF, 0xB1,
+];
+
+/// UUID of the console input characteristic (`b1ef1d01-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const INPUT_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01, 0x1D, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: write and write without response, value handle 23, followed by
+/// the UUID.
+const INPUT_DECL: [u8; 19] = [
+    0x0C, 0x17, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01,
+    0x1D, 0xEF, 0xB1,
+];
+
+const INPUT_DESC: &[u8] = b"Console input";
+
+/// UUID of the console output characteristic (`b1ef1d02-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const OUTPUT_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x02, 0x1D, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: notify, value handle 26, followed by the UUID.
+const OUTPUT_DECL: [u8; 19] = [
+    0x10, 0x1A, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x02,
+    0x1D, 0xEF, 0xB1,
+];
+
+const OUTPUT_DESC: &[u8] = b"Console output";
+
+/// The console service of the GATT server (see `gatt`).
+pub struct ConsoleService {
+    /// Bytes written by the client, which `idle` feeds to the shell.
+    input: Producer,
+    /// The last attribute of the service, which ends its group.
+    description: Attribute<'static>,
+}
+
+impl ConsoleService {
+    /// Handle of the service declaration.
+    pub const FIRST_HANDLE: u16 = 21;
+    /// Handle of the last attribute of the service.
+    pub const LAST_HANDLE: u16 = 28;
+    /// Handle of the console output characteristic's value, which shell output is notified on.
+    pub const OUTPUT_HANDLE: u16 = 26;
+
+    pub fn new(input: Producer) -> Self {
+        Self {
+            input,
+            description: Attribute::new(USER_DESCRIPTION.into(), Handle::from_raw(28), OUTPUT_DESC),
+        }
+    }
+
+    pub fn attribute(&self, handle: u16) -> Option<Attribute<'_>> {
+        let (uuid, value): (AttUuid, &[u8]) = match handle {
+            21 => (PRIMARY_SERVICE.into(), &SERVICE_UUID),
+            22 => (CHARACTERISTIC.into(), &INPUT_DECL),
+            23 => (Uuid128::from_bytes(INPUT_UUID).into(), &[]),
+            24 => (USER_DESCRIPTION.into(), INPUT_DESC),
+            25 => (CHARACTERISTIC.into(), &OUTPUT_DECL),
+            // Output is only sent in notifications
+            26 => (Uuid128::from_bytes(OUTPUT_UUID).into(), &[]),
+            27 if SUBSCRIBED.load(Ordering::Relaxed) => (CLIENT_CONFIG.into(), &[0x01, 0x00]),
+            27 => (CLIENT_CONFIG.into(), &[0x00, 0x00]),
+            28 => return Some(self.description.clone()),
+            _ => return None,
+        };
+
+        Some(Attribute::new(uuid, Handle::from_raw(handle), value))
+    }
+
+    /// Returns the last attribute of the service, which ends its group.
+    pub fn group_end(&self) -> &Attribute<'_> {
+        &self.description
+    }
+
+    pub fn is_writeable(&self, handle: u16) -> bool {
+        handle == 23 || handle == 27
+    }
+
+    pub fn write(&mut self, handle: u16, data: &[u8]) -> Result<(), Error> {
+        match (handle, data) {
+            (23, _) => {
+                // Input that doesn't fit is dropped, like characters typed too fast
+                if let Ok(mut grant) = self.input.grant_max(data.len()) {
+                    let len = grant.buf().len();
+                    grant.buf().copy_from_slice(&data[..len]);
+                    self.input.commit(len, grant);
+                }
+                Ok(())
+            }
+            // The client characteristic configuration; bit 0 enables notifications
+            (27, &[flags, _]) => {
+                SUBSCRIBED.store(flags & 0x01 != 0, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// A `fmt::Write` sink for the shell's output, queued until `gatt::stream_console` notifies the
+/// client of it.
+///
+/// Output that doesn't fit into the queue is dropped.
+pub struct ConsoleWriter(pub Producer);
+
+impl fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let mut grant = match self.0.grant_max(bytes.len()) {
+                Ok(grant) => grant,
+                Err(_) => break,
+            };
+            let len = grant.buf().len();
+            grant.buf().copy_from_slice(&bytes[..len]);
+            bytes = &bytes[len..];
+            self.0.commit(len, grant);
+        }
+
+        Ok(())
+    }
+}
+
+/// Discards the shell output that wasn't sent, once the client unsubscribed or disconnected.
+pub fn discard(output: &mut Consumer) {
+    while let Ok(grant) = output.read() {
+        let len = grant.buf().len();
+        output.release(len, grant);
+    }
+}
@@ -0,0 +1,154 @@
+//! Firmware updates over BLE and the beacon link.
+//!
+//! Both transports carry the messages from `bluefly_dfu::protocol` and write the received image
+//! to slot 1. Updates sent over the beacon link are relayed by the controller. The serial port
+//! can't receive anything, since RX isn't routed on the board (see `bluefly_receiver_bsp`). Only
+//! images signed with the built-in key that don't downgrade the firmware are accepted. Once a
+//! transfer is complete, `UPDATE_READY` is set and `idle` resets the device so the bootloader can
+//! install the update.
+
+use {
+    bluefly_common::gatt::{CHARACTERISTIC, PRIMARY_SERVICE, USER_DESCRIPTION},
+    bluefly_dfu::{
+        flash::{FlashError, Nvmc},
+        layout::{self, FLASH_SIZE},
+        protocol::RESPONSE_LEN,
+        relay::{self, RESPONSE_FRAME_LEN},
+        sign::PUBLIC_KEY,
+        swap,
+        target::DfuTarget,
+    },
+    core::sync::atomic::{AtomicBool, Ordering},
+    rubble::{
+        att::{AttUuid, Attribute, Handle},
+        uuid::Uuid128,
+        Error,
+    },
+};
+
+/// Set once an update was received and the device should be reset to install it.
+pub static UPDATE_READY: AtomicBool = AtomicBool::new(false);
+
+fn target() -> DfuTarget<Nvmc> {
+    // Only slot 1 and the state pages are written, never the running firmware. The transports
+    // are only used from `idle`, so they never access the NVMC at the same time.
+    DfuTarget::new(
+        unsafe { Nvmc::new(FLASH_SIZE) },
+        layout::NRF52810,
+        PUBLIC_KEY,
+    )
+}
+
+/// Confirms that the running image works, so the bootloader won't roll it back.
+///
+/// Must be called on every boot once the firmware is up and running.
+pub fn confirm() -> Result<(), FlashError> {
+    let mut flash = unsafe { Nvmc::new(FLASH_SIZE) };
+    swap::confirm(&mut flash, &layout::NRF52810)
+}
+
+/// Receives updates relayed by the controller.
+pub struct RelayDfu {
+    target: DfuTarget<Nvmc>,
+}
+
+impl RelayDfu {
+    pub fn new() -> Self {
+        Self { target: target() }
+    }
+
+    /// Handles a relay frame, returning the response frame to broadcast.
+    pub fn handle(&mut self, frame: &[u8]) -> Option<[u8; RESPONSE_FRAME_LEN]> {
+        let response = relay::handle_frame(&mut self.target, frame)?;
+        if self.target.is_complete() {
+            UPDATE_READY.store(true, Ordering::SeqCst);
+        }
+
+        Some(response)
+    }
+}
+
+/// UUID of the DFU service (`b1ef1a00-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const SERVICE_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x00, 0x1A, 0xEF, 0xB1,
+];
+
+/// UUID of the DFU control point characteristic (`b1ef1a01-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const CONTROL_POINT_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01, 0x1A, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: read and write, value handle 3, followed by the UUID.
+const CONTROL_POINT_DECL: [u8; 19] = [
+    0x0A, 0x03, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01,
+    0x1A, 0xEF, 0xB1,
+];
+
+const CONTROL_POINT_DESC: &[u8] = b"DFU control point";
+
+/// The DFU service of the GATT server (see `gatt`).
+///
+/// Requests are written to the control point characteristic. Reading the characteristic returns
+/// the response to the last request, so clients write a request and read back the response.
+pub struct DfuService {
+    target: DfuTarget<Nvmc>,
+    response: [u8; RESPONSE_LEN],
+    /// The last attribute of the service, which ends its group.
+    description: Attribute<'static>,
+}
+
+impl DfuService {
+    /// Handle of the service declaration.
+    pub const FIRST_HANDLE: u16 = 1;
+    /// Handle of the last attribute of the service.
+    pub const LAST_HANDLE: u16 = 4;
+
+    pub fn new() -> Self {
+        Self {
+            target: target(),
+            response: [0; RESPONSE_LEN],
+            description: Attribute::new(
+                USER_DESCRIPTION.into(),
+                Handle::from_raw(4),
+                CONTROL_POINT_DESC,
+            ),
+        }
+    }
+
+    pub fn attribute(&self, handle: u16) -> Option<Attribute<'_>> {
+        let (uuid, value): (AttUuid, &[u8]) = match handle {
+            1 => (PRIMARY_SERVICE.into(), &SERVICE_UUID),
+            2 => (CHARACTERISTIC.into(), &CONTROL_POINT_DECL),
+            3 => (
+                Uuid128::from_bytes(CONTROL_POINT_UUID).into(),
+                &self.response,
+            ),
+            4 => return Some(self.description.clone()),
+            _ => return None,
+        };
+
+        Some(Attribute::new(uuid, Handle::from_raw(handle), value))
+    }
+
+    /// Returns the last attribute of the service, which ends its group.
+    pub fn group_end(&self) -> &Attribute<'_> {
+        &self.description
+    }
+
+    pub fn is_writeable(&self, handle: u16) -> bool {
+        handle == 3
+    }
+
+    pub fn write(&mut self, handle: u16, data: &[u8]) -> Result<(), Error> {
+        if handle != 3 {
+            return Err(Error::InvalidValue);
+        }
+
+        self.response = self.target.handle_raw(data);
+        if self.target.is_complete() {
+            UPDATE_READY.store(true, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! The GATT server.
+//!
+//! Every service owns a fixed range of attribute handles. `Services` puts them together into the
+//! attribute table served over BLE and passes reads and writes on to the service owning the
+//! handle.
+
+use {
+    crate::{
+        console::{self, ConsoleService},
+        dfu::DfuService,
+        telemetry::{self, TelemetryService},
+    },
+    bbqueue::{Consumer, Producer},
+    bluefly_common::{
+        crash::Crash,
+        gatt::PRIMARY_SERVICE,
+        link_stats::Summary,
+        logger::{LogService, STREAMING},
+    },
+    core::sync::atomic::Ordering,
+    rubble::{
+        att::{
+            AttUuid, Attribute, AttributeAccessPermissions, AttributeProvider, Handle, HandleRange,
+        },
+        l2cap::BleChannelMap,
+        link::Responder,
+        security_manager::NoSecurity,
+        Error,
+    },
+};
+
+/// Longest value that fits into a notification with the default ATT MTU of 23 Bytes.
+const NOTIFY_LEN: usize = 20;
+
+/// Handle of the last attribute in the table.
+const LAST_HANDLE: u16 = ConsoleService::LAST_HANDLE;
+
+/// All services of the GATT server.
+pub struct Services {
+    pub dfu: DfuService,
+    pub log: LogService,
+    pub telemetry: TelemetryService,
+    pub console: ConsoleService,
+}
+
+impl Services {
+    /// Creates the services, with `crash` reported by the log service and console input going to
+    /// `console_input`.
+    pub fn new(crash: Option<&Crash>, console_input: Producer) -> Self {
+        Self {
+            dfu: DfuService::new(),
+            log: LogService::new(crash),
+            telemetry: TelemetryService::new(),
+            console: ConsoleService::new(console_input),
+        }
+    }
+
+    fn attribute(&self, handle: u16) -> Option<Attribute<'_>> {
+        match handle {
+            DfuService::FIRST_HANDLE..=DfuService::LAST_HANDLE => self.dfu.attribute(handle),
+            LogService::FIRST_HANDLE..=LogService::LAST_HANDLE => self.log.attribute(handle),
+            TelemetryService::FIRST_HANDLE..=TelemetryService::LAST_HANDLE => {
+                self.telemetry.attribute(handle)
+            }
+            ConsoleService::FIRST_HANDLE..=ConsoleService::LAST_HANDLE => {
+                self.console.attribute(handle)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AttributeProvider for Services {
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, Attribute) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        // The filter can also be changed from the shell
+        self.log.refresh();
+
+        let start = range.start().as_u16();
+        let end = range.end().as_u16().min(LAST_HANDLE);
+
+        for handle in start..=end {
+            if let Some(attr) = self.attribute(handle) {
+                f(self, attr)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        uuid == PRIMARY_SERVICE.into()
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute> {
+        match handle.as_u16() {
+            DfuService::FIRST_HANDLE..=DfuService::LAST_HANDLE => Some(self.dfu.group_end()),
+            LogService::FIRST_HANDLE..=LogService::LAST_HANDLE => Some(self.log.group_end()),
+            TelemetryService::FIRST_HANDLE..=TelemetryService::LAST_HANDLE => {
+                Some(self.telemetry.group_end())
+            }
+            ConsoleService::FIRST_HANDLE..=ConsoleService::LAST_HANDLE => {
+                Some(self.console.group_end())
+            }
+            _ => None,
+        }
+    }
+
+    fn attr_access_permissions(&self, handle: Handle) -> AttributeAccessPermissions {
+        let handle = handle.as_u16();
+        if self.dfu.is_writeable(handle)
+            || self.log.is_writeable(handle)
+            || self.telemetry.is_writeable(handle)
+            || self.console.is_writeable(handle)
+        {
+            AttributeAccessPermissions::ReadableAndWriteable
+        } else {
+            AttributeAccessPermissions::Readable
+        }
+    }
+
+    fn write_attr(&mut self, handle: Handle, data: &[u8]) -> Result<(), Error> {
+        match handle.as_u16() {
+            DfuService::FIRST_HANDLE..=DfuService::LAST_HANDLE => {
+                self.dfu.write(handle.as_u16(), data)
+            }
+            LogService::FIRST_HANDLE..=LogService::LAST_HANDLE => {
+                self.log.write(handle.as_u16(), data)
+            }
+            TelemetryService::FIRST_HANDLE..=TelemetryService::LAST_HANDLE => {
+                self.telemetry.write(handle.as_u16(), data)
+            }
+            ConsoleService::FIRST_HANDLE..=ConsoleService::LAST_HANDLE => {
+                self.console.write(handle.as_u16(), data)
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
+
+/// Sends the log output to a client subscribed to the log stream characteristic.
+///
+/// Returns `false` without reading from `log` if nobody is subscribed, so that the log can go to
+/// the serial port instead. Output that doesn't fit into the TX queue stays in `log` until the next
+/// call.
+pub fn stream_log(
+    log: &mut Consumer,
+    responder: &mut Responder<BleChannelMap<Services, NoSecurity>>,
+) -> bool {
+    if !STREAMING.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    while let Ok(grant) = log.read() {
+        let len = grant.buf().len().min(NOTIFY_LEN);
+        let sent = responder.l2cap().att().notify_raw(
+            Handle::from_raw(LogService::STREAM_HANDLE),
+            &grant.buf()[..len],
+        );
+        if sent.is_err() {
+            // The TX queue is full
+            log.release(0, grant);
+            break;
+        }
+        log.release(len, grant);
+    }
+
+    true
+}
+
+/// Sends the shell output to a client subscribed to the console output characteristic.
+///
+/// The output is discarded if nobody is subscribed. Output that doesn't fit into the TX queue stays
+/// in `output` until the next call.
+pub fn stream_console(
+    output: &mut Consumer,
+    responder: &mut Responder<BleChannelMap<Services, NoSecurity>>,
+) {
+    if !console::SUBSCRIBED.load(Ordering::Relaxed) {
+        console::discard(output);
+        return;
+    }
+
+    while let Ok(grant) = output.read() {
+        let len = grant.buf().len().min(NOTIFY_LEN);
+        let sent = responder.l2cap().att().notify_raw(
+            Handle::from_raw(ConsoleService::OUTPUT_HANDLE),
+            &grant.buf()[..len],
+        );
+        if sent.is_err() {
+            // The TX queue is full
+            output.release(0, grant);
+            break;
+        }
+        output.release(len, grant);
+    }
+}
+
+/// Notifies a client subscribed to the link quality characteristic of `summary`.
+///
+/// Does nothing if nobody is subscribed, or if the TX queue is full.
+pub fn send_telemetry(
+    summary: &Summary,
+    responder: &mut Responder<BleChannelMap<Services, NoSecurity>>,
+) {
+    if !telemetry::SUBSCRIBED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    responder
+        .l2cap()
+        .att()
+        .notify_raw(
+            Handle::from_raw(TelemetryService::LINK_HANDLE),
+            &summary.to_bytes(),
+        )
+        .ok();
+}
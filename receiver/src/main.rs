@@ -1,25 +1,47 @@
 #![no_std]
 #![no_main]
 
-// We need to import this crate explicitly so we have a panic handler
-extern crate panic_semihosting;
-
-mod logger;
-mod radio;
-mod timer;
+mod commands;
+mod config;
+mod console;
+mod dfu;
+mod gatt;
+mod pwm;
+mod telemetry;
+mod watchdog;
 
 use {
-    crate::logger::{BbqLogger, StampedLogger},
     crate::{
+        console::{self, ConsoleWriter},
+        dfu::RelayDfu,
+        gatt::Services,
+        pwm::{Pwm, COUNTERTOP},
+        watchdog::Task,
+    },
+    bbqueue::{bbq, BBQueue, Consumer, Producer},
+    bluefly_binlog::{debug, info, warn, Fmt},
+    bluefly_common::{
+        crash,
+        cruise::{self, Guard},
+        esb,
+        hopping::{self, Follower},
+        link_stats::LinkStats,
+        logger::{self, BbqLogger, Filter},
+        mode::{self, Answering, RadioMode},
+        output::{self, Failsafe},
+        ownership::{self, Ownership, PairedFilter},
+        protocol::{self, Frames},
         radio::{BleRadio, PacketBuffer},
+        receivers,
+        redundancy::Redundancy,
+        shell::{self, Command, Shell},
         timer::{BleTimer, StampSource},
     },
-    bbqueue::{bbq, BBQueue, Consumer},
-    core::fmt::Write,
-    log::{info, LevelFilter},
+    bluefly_dfu::relay,
+    bluefly_receiver_bsp::Board,
+    core::{fmt::Write, ptr, sync::atomic::Ordering},
+    log::LevelFilter,
     nrf52810_hal::{
-        self as hal,
-        gpio::Level,
         nrf52810_pac::{self as pac, UARTE0},
         prelude::*,
         uarte::{Baudrate, Parity, Uarte},
@@ -27,18 +49,20 @@ use {
     rtfm::app,
     rubble::{
         beacon::{BeaconScanner, ScanCallback},
-        gatt::GattServer,
         l2cap::{BleChannelMap, L2CAPState},
         link::{
-            ad_structure::AdStructure, filter::WhitelistFilter, queue, AddressKind, DeviceAddress,
-            HardwareInterface, LinkLayer, Responder, MAX_PDU_SIZE,
+            ad_structure::AdStructure, queue, AddressKind, DeviceAddress, HardwareInterface,
+            LinkLayer, NextUpdate, RadioCmd, Responder, MAX_PDU_SIZE,
         },
         security_manager::NoSecurity,
-        time::{Duration, Timer},
+        time::{Duration, Instant, Timer},
     },
 };
 
-type Logger = StampedLogger<StampSource<pac::TIMER0>, BbqLogger>;
+#[cfg(not(feature = "binary-log"))]
+type Logger = logger::WriteLogger<logger::StampedLogger<StampSource<pac::TIMER0>, BbqLogger>>;
+#[cfg(feature = "binary-log")]
+type Logger = logger::BinaryLogger<StampSource<pac::TIMER0>>;
 
 /// Hardware interface for the BLE stack (nRF52810 implementation).
 pub struct HwNRf52810 {}
@@ -54,25 +78,64 @@ impl HardwareInterface for HwNRf52810 {
 /// at the same time unless you also generate separate device addresses.
 const TEST_BEACON: bool = false;
 
+/// Time between log messages about the link quality, in seconds.
+const LINK_LOG_SECS: u16 = 10;
+
+/// Time between `TIMER0` interrupts once the link layer is stopped in ESB mode, in milliseconds.
+const ESB_TICK_MILLIS: u32 = 200;
+
 /// Stores the global logger used by the `log` crate.
-static mut LOGGER: Option<logger::WriteLogger<Logger>> = None;
+static mut LOGGER: Option<Logger> = None;
 
 #[app(device = nrf52810_hal::nrf52810_pac)]
 const APP: () = {
     static mut BLE_TX_BUF: PacketBuffer = [0; MAX_PDU_SIZE];
     static mut BLE_RX_BUF: PacketBuffer = [0; MAX_PDU_SIZE];
     static mut BLE_LL: LinkLayer<HwNRf52810> = ();
-    static mut BLE_R: Responder<BleChannelMap<GattServer<'static>, NoSecurity>> = ();
+    static mut BLE_R: Responder<BleChannelMap<Services, NoSecurity>> = ();
     static mut RADIO: BleRadio = ();
-    static mut SCANNER: rubble::beacon::BeaconScanner<
-        PwmCallback,
-        rubble::link::filter::WhitelistFilter<core::iter::Once<rubble::link::DeviceAddress>>,
-    > = ();
+    static mut SCANNER: rubble::beacon::BeaconScanner<ThrottleCallback, PairedFilter<fn() -> u32>> =
+        ();
+    static mut FRAMES: Consumer = ();
+    static mut RELAY_FRAMES: Consumer = ();
+    static mut RELAY_DFU: RelayDfu = ();
+    static mut PWM_SEQ: [u16; 1] = [0; 1];
+    static mut PWM: Pwm = ();
+    static mut LAST_THROTTLE: Option<u8> = None;
+    static mut FAILSAFE: Failsafe = Failsafe::new();
+    static mut FRAMES_RECEIVED: u32 = 0;
+    static mut REDUNDANCY: Redundancy = ();
+    static mut LINK_STATS: LinkStats = LinkStats::new();
+    static mut ANSWERING: Answering = Answering::new();
+    static mut FOLLOWER: Follower = ();
+    static mut OWNERSHIP: Ownership = Ownership::new();
+    static mut CRUISE_GUARD: Guard = Guard::new();
     static mut SERIAL: Uarte<UARTE0> = ();
+    static mut CONSOLE_IN: Consumer = ();
+    static mut CONSOLE: ConsoleWriter = ();
+    static mut CONSOLE_SINK: Consumer = ();
+    static mut SHELL: Shell = Shell::new();
     static mut LOG_SINK: Consumer = ();
+    static mut UPTIME: StampSource<pac::TIMER0> = ();
 
-    #[init(resources = [BLE_TX_BUF, BLE_RX_BUF])]
+    #[init(resources = [BLE_TX_BUF, BLE_RX_BUF, PWM_SEQ])]
     fn init() {
+        // Reported once the logger is up
+        let last_crash = crash::take();
+        let reset_reason = watchdog::reset_reason(&device.POWER);
+        let settings = config::load();
+
+        let board = Board::new(device.P0);
+
+        // The output stage is reset along with the device, so bring it back to neutral before
+        // anything else
+        let pwm = Pwm::new(
+            device.PWM0,
+            board.esc,
+            resources.PWM_SEQ,
+            config::PWM_NEUTRAL.get() as u16,
+        );
+
         {
             // On reset the internal high frequency clock is used, but starting the HFCLK task
             // switches to the external crystal; this is needed for Bluetooth to work.
@@ -86,54 +149,83 @@ const APP: () = {
 
         let ble_timer = BleTimer::init(device.TIMER0);
 
-        let p0 = device.P0.split();
-
-        let mut serial = {
-            let rxd = p0.p0_08.into_floating_input().degrade();
-            let txd = p0.p0_06.into_push_pull_output(Level::Low).degrade();
-
-            let pins = hal::uarte::Pins {
-                rxd,
-                txd,
-                cts: None,
-                rts: None,
-            };
-
-            device
-                .UARTE0
-                .constrain(pins, Parity::EXCLUDED, Baudrate::BAUD1M)
+        let mut serial = device
+            .UARTE0
+            .constrain(board.serial, Parity::EXCLUDED, Baudrate::BAUD1M);
+        // RX isn't routed on the board, so don't leave the UARTE listening to a floating pin.
+        // Safe, since `Uarte` only uses the TX registers.
+        unsafe {
+            (*UARTE0::ptr())
+                .psel
+                .rxd
+                .write(|w| w.connect().disconnected())
         };
         writeln!(serial, "\n--- INIT ---").unwrap();
 
-        let device_address = DeviceAddress::new([0, 0, 0, 0, 0, 0], AddressKind::Random);
+        let device_address = DeviceAddress::new(protocol::RECEIVER_ADDRESS, AddressKind::Random);
 
         let mut radio = BleRadio::new(device.RADIO, resources.BLE_TX_BUF, resources.BLE_RX_BUF);
 
+        let uptime = ble_timer.create_stamp_source();
         let log_stamper = ble_timer.create_stamp_source();
         let (tx, log_sink) = bbq![10000].unwrap().split();
-        let logger = StampedLogger::new(BbqLogger::new(tx), log_stamper);
+        #[cfg(not(feature = "binary-log"))]
+        let log =
+            logger::WriteLogger::new(logger::StampedLogger::new(BbqLogger::new(tx), log_stamper));
+        #[cfg(feature = "binary-log")]
+        let log = logger::BinaryLogger::new(BbqLogger::new(tx), log_stamper);
 
-        let log = logger::WriteLogger::new(logger);
         // Safe, since we're the only thread and interrupts are off
         unsafe {
             LOGGER = Some(log);
             log::set_logger(LOGGER.as_ref().unwrap()).unwrap();
+            #[cfg(feature = "binary-log")]
+            bluefly_binlog::set_logger(LOGGER.as_ref().unwrap()).unwrap();
         }
-        log::set_max_level(LevelFilter::max());
+        let log_filter = config::log_filter();
+        logger::set_filter(match log_filter {
+            Ok(filter) => filter,
+            Err(_) => Filter::new(LevelFilter::Info),
+        });
 
         info!("READY");
+        if let Err(e) = log_filter {
+            warn!("invalid log filter: {:?}", Fmt(e));
+        }
+        info!("reset reason: {}", reset_reason);
+        if let Some(crash) = &last_crash {
+            warn!("crashed before the last reset: {:?}", Fmt(crash));
+        }
+        if let Err(e) = settings {
+            warn!("failed to load settings: {:?}", Fmt(e));
+        }
+
+        // Make sure the bootloader doesn't roll back to the previous firmware
+        if let Err(e) = dfu::confirm() {
+            warn!("failed to confirm firmware image: {:?}", Fmt(e));
+        }
 
         // Create TX/RX queues
         let (tx, tx_cons) = queue::create(bbq![1024].unwrap());
         let (rx_prod, rx) = queue::create(bbq![1024].unwrap());
 
+        let redundancy = Redundancy::new(ble_timer.now());
+        let follower = Follower::new(protocol::CONTROLLER_ADDRESS, ble_timer.now());
+
         // Create the actual BLE stack objects
         let mut ll = LinkLayer::<HwNRf52810>::new(device_address, ble_timer);
 
+        // The shell is reached over BLE
+        let (console_in_tx, console_in) = bbq![128].unwrap().split();
+        let (console_out, console_sink) = bbq![512].unwrap().split();
+
         let resp = Responder::new(
             tx,
             rx,
-            L2CAPState::new(BleChannelMap::with_attributes(GattServer::new())),
+            L2CAPState::new(BleChannelMap::with_attributes(Services::new(
+                last_crash.as_ref(),
+                console_in_tx,
+            ))),
         );
 
         if !TEST_BEACON {
@@ -150,69 +242,187 @@ const APP: () = {
             ll.timer().configure_interrupt(next_update);
         }
 
-        let scanner = {
-            let filter = WhitelistFilter::from_address(DeviceAddress::new(
-                [169, 255, 235, 206, 50, 121],
-                AddressKind::Random,
-            ));
-
-            let callback = {
-                device.PWM0.psel.out[0]
-                    .write(|w| unsafe { w.pin().bits(0x08).connect().connected() });
-                device.PWM0.enable.write(|w| w.enable().enabled());
-                device.PWM0.mode.write(|w| w.updown().up());
-                device.PWM0.prescaler.write(|w| w.prescaler().div_32());
-                device
-                    .PWM0
-                    .countertop
-                    .write(|w| unsafe { w.countertop().bits(8_000) }); // 20ms at div_32
-                device.PWM0.loop_.write(|w| w.cnt().disabled());
-                device
-                    .PWM0
-                    .decoder
-                    .write(|w| w.load().common().mode().next_step());
-                device.PWM0.seq0.refresh.write(|w| w.cnt().continuous());
-                device
-                    .PWM0
-                    .seq0
-                    .enddelay
-                    .write(|w| unsafe { w.cnt().bits(0) });
-                let mut val: u16 = 7220;
-                device.PWM0.seq0.cnt.write(|w| unsafe { w.cnt().bits(1) });
-                device
-                    .PWM0
-                    .seq0
-                    .ptr
-                    .write(|w| unsafe { w.ptr().bits(((&mut val) as *mut _) as u32) });
-
-                device.PWM0.tasks_seqstart[0].write(|w| w.tasks_seqstart().trigger());
-                device
-                    .PWM0
-                    .tasks_nextstep
-                    .write(|w| w.tasks_nextstep().trigger());
-
-                PwmCallback(device.PWM0)
-            };
+        let (frames_tx, frames_rx) = bbq![64].unwrap().split();
+        let (relay_tx, relay_rx) = bbq![256].unwrap().split();
 
-            BeaconScanner::with_filter(callback, filter)
+        let scanner = {
+            let filter: PairedFilter<fn() -> u32> = PairedFilter::new(config::paired);
+
+            BeaconScanner::with_filter(
+                ThrottleCallback {
+                    frames: frames_tx,
+                    relay_frames: relay_tx,
+                },
+                filter,
+            )
         };
 
+        // Every task checks in from now on
+        watchdog::start(&device.WDT);
+
         RADIO = radio;
         BLE_LL = ll;
         BLE_R = resp;
         SCANNER = scanner;
+        FRAMES = frames_rx;
+        REDUNDANCY = redundancy;
+        FOLLOWER = follower;
+        RELAY_FRAMES = relay_rx;
+        RELAY_DFU = RelayDfu::new();
+        PWM = pwm;
         SERIAL = serial;
+        CONSOLE_IN = console_in;
+        CONSOLE = ConsoleWriter(console_out);
+        CONSOLE_SINK = console_sink;
         LOG_SINK = log_sink;
+        UPTIME = uptime;
     }
 
-    #[interrupt(resources = [RADIO, BLE_LL, SCANNER])]
+    #[interrupt(resources = [
+        RADIO,
+        BLE_LL,
+        SCANNER,
+        FRAMES,
+        PWM,
+        LAST_THROTTLE,
+        FRAMES_RECEIVED,
+        FAILSAFE,
+        REDUNDANCY,
+        LINK_STATS,
+        ANSWERING,
+        FOLLOWER,
+        OWNERSHIP,
+        CRUISE_GUARD,
+    ])]
     fn RADIO() {
-        let next_update = resources
-            .RADIO
-            .recv_interrupt(resources.BLE_LL.timer().now(), &mut resources.SCANNER);
+        let now = resources.BLE_LL.timer().now();
+        if resources.RADIO.is_esb() {
+            // Throttle packets are acknowledged right away, and sent again until they are, so
+            // there are no copies to catch
+            let mut payload = [0; esb::MAX_PAYLOAD_LEN];
+            let len = resources
+                .RADIO
+                .esb_recv_interrupt(&mut payload, config::is_primary());
+            let reception = match resources.RADIO.take_reception() {
+                Some(reception) => reception,
+                None => return,
+            };
+            if !reception.crc_ok {
+                resources.LINK_STATS.crc_error();
+            }
+            let (seq, frame) = match len.and_then(|len| esb::split_throttle(&payload[..len])) {
+                Some(throttle) => throttle,
+                None => return,
+            };
+            // Packets don't carry the address of the controller, so they count as controller 0's
+            let stopped =
+                ownership::is_stopped(resources.PWM.get(), config::PWM_NEUTRAL.get() as u16);
+            let released = protocol::is_released(frame);
+            if !config::is_paired(0) || !resources.OWNERSHIP.frame(0, released, stopped, now) {
+                return;
+            }
+
+            let interval = protocol::beacon_interval(frame);
+            resources
+                .LINK_STATS
+                .frame(Some(seq), reception.rssi, interval, now);
+            if !guard_cruise(&mut resources.CRUISE_GUARD, Some(seq), frame, now) {
+                resources.PWM.set(config::output_map().neutral);
+                return;
+            }
+            if let Some(val) = receivers::throttle(frame, config::link_id()) {
+                debug!("got val: {}", val);
+
+                resources.PWM.set(config::output_map().pwm(val));
+                let timeout = config::failsafe_timeout(interval);
+                resources.FAILSAFE.frame(now, timeout);
+                *resources.LAST_THROTTLE = Some(val);
+                *resources.FRAMES_RECEIVED = resources.FRAMES_RECEIVED.wrapping_add(1);
+            }
+            return;
+        }
+
+        let next_update = resources.RADIO.recv_interrupt(now, &mut resources.SCANNER);
+        let reception = resources.RADIO.take_reception();
+        if let Some(reception) = reception {
+            if !reception.crc_ok {
+                resources.LINK_STATS.crc_error();
+            }
+        }
 
         //let cmd = resources.SCANNER.process_adv_packet()
         resources.BLE_LL.timer().configure_interrupt(next_update);
+
+        // Apply the first copy of the throttle frames passed on by the scanner
+        let hopping = config::hopping();
+        let mut heard = false;
+        while let Ok(grant) = resources.FRAMES.read() {
+            let len = grant.buf().len();
+            for frame in Frames(grant.buf()) {
+                let (id, seq, frame) = split_throttle(frame);
+                heard = true;
+
+                // Only follow the controller that owns the receiver, and its hops
+                let stopped =
+                    ownership::is_stopped(resources.PWM.get(), config::PWM_NEUTRAL.get() as u16);
+                let released = protocol::is_released(frame);
+                let owner = resources.OWNERSHIP.owner();
+                if !resources.OWNERSHIP.frame(id, released, stopped, now) {
+                    continue;
+                }
+                if resources.OWNERSHIP.owner() != owner {
+                    info!("controller {} took over", id);
+                }
+                // Until there's an owner, the follower follows controller 0
+                if owner.unwrap_or(0) != id {
+                    *resources.FOLLOWER = Follower::new(protocol::controller_address(id), now);
+                }
+
+                let interval = protocol::beacon_interval(frame);
+                if let (true, Some(seq)) = (hopping, seq) {
+                    resources.FOLLOWER.copy(seq, interval, now);
+                }
+                if !resources.REDUNDANCY.copy(seq, interval, now) {
+                    continue;
+                }
+                if let Some(reception) = reception {
+                    resources
+                        .LINK_STATS
+                        .frame(seq, reception.rssi, interval, now);
+                    if let (true, Some(rssi)) = (config::is_primary(), reception.rssi) {
+                        let requested = protocol::requested_phy(frame);
+                        resources
+                            .ANSWERING
+                            .request(requested, config::phy(), rssi, interval, now);
+                    }
+                }
+                if !guard_cruise(&mut resources.CRUISE_GUARD, seq, frame, now) {
+                    resources.PWM.set(config::output_map().neutral);
+                    continue;
+                }
+
+                if let Some(val) = receivers::throttle(frame, config::link_id()) {
+                    debug!("got val: {}", val);
+
+                    resources.PWM.set(config::output_map().pwm(val));
+                    let timeout = config::failsafe_timeout(interval);
+                    resources.FAILSAFE.frame(now, timeout);
+                    *resources.LAST_THROTTLE = Some(val);
+                    *resources.FRAMES_RECEIVED = resources.FRAMES_RECEIVED.wrapping_add(1);
+                }
+            }
+            resources.FRAMES.release(len, grant);
+        }
+
+        // Catch the next copy. The scanner leaves the radio on an advertising channel, so go back
+        // to the hop channel after anything else it received as well.
+        if heard || hopping {
+            listen(
+                &mut resources.RADIO,
+                &resources.REDUNDANCY,
+                &resources.FOLLOWER,
+            );
+        }
     }
 
     #[interrupt(resources = [RADIO, BLE_LL, SCANNER])]
@@ -222,6 +432,14 @@ const APP: () = {
             return;
         }
         timer.clear_interrupt();
+        watchdog::check_in(Task::Radio);
+
+        // The link layer is stopped in ESB mode, but the watchdog still expects this task
+        if resources.RADIO.is_esb() {
+            let next_update = timer.now() + Duration::from_millis(ESB_TICK_MILLIS);
+            timer.configure_interrupt(NextUpdate::At(next_update));
+            return;
+        }
 
         let cmd = resources.BLE_LL.update(&mut *resources.RADIO);
         resources.RADIO.configure_receiver(cmd.radio);
@@ -232,49 +450,460 @@ const APP: () = {
             .configure_interrupt(cmd.next_update);
     }
 
-    #[idle(resources = [LOG_SINK, SERIAL, BLE_R])]
+    #[idle(resources = [
+        LOG_SINK,
+        SERIAL,
+        CONSOLE_IN,
+        CONSOLE,
+        CONSOLE_SINK,
+        SHELL,
+        RELAY_FRAMES,
+        RELAY_DFU,
+        RADIO,
+        UPTIME,
+        PWM,
+        LAST_THROTTLE,
+        FRAMES_RECEIVED,
+        FAILSAFE,
+        REDUNDANCY,
+        LINK_STATS,
+        ANSWERING,
+        FOLLOWER,
+        OWNERSHIP,
+        CRUISE_GUARD,
+        BLE_LL,
+        BLE_R,
+    ])]
     fn idle() -> ! {
-        // Drain the logging buffer through the serial connection
+        let mut update_received = None;
+        let start = resources.UPTIME.now();
+        let mut last_telemetry = start;
+        let mut last_link_log = start;
+
         loop {
-            while let Ok(grant) = resources.LOG_SINK.read() {
-                for chunk in grant.buf().chunks(255) {
-                    resources.SERIAL.write(chunk).unwrap();
+            watchdog::check_in(Task::Idle);
+
+            let now = resources.UPTIME.now();
+            // State the radio task updates is checked against the time read inside its lock: a
+            // frame received after `now` was read would look like it came from the future
+            let uptime = &*resources.UPTIME;
+
+            // Stop the motor when the controller goes quiet
+            let timeout = resources
+                .FAILSAFE
+                .lock(|failsafe| failsafe.check(uptime.now()));
+            if let Some(timeout) = timeout {
+                let neutral = config::output_map().neutral;
+                resources.PWM.lock(|pwm| pwm.set(neutral));
+                resources.OWNERSHIP.lock(|ownership| ownership.link_lost());
+                resources.CRUISE_GUARD.lock(|guard| guard.link_lost());
+                warn!(
+                    "no throttle for {} ms, output back to neutral",
+                    timeout.as_micros() / 1000
+                );
+            }
+
+            // Switch to ESB once it's configured, or to another channel. The radio stays in ESB
+            // until the next reset.
+            let esb = resources.RADIO.lock(|radio| {
+                if let Some(channel) = config::esb_channel() {
+                    if radio.esb_channel() != Some(channel) {
+                        info!("radio: ESB on channel {}", channel);
+                        radio.start_esb(channel);
+                        radio.esb_listen();
+                    }
+                }
+                radio.is_esb()
+            });
+            if esb {
+                // There's no PHY to negotiate and no copies to catch, only the TX power to keep up
+                // with
+                resources.RADIO.lock(|radio| {
+                    let mode = RadioMode {
+                        phy: radio.mode().phy,
+                        tx_power: config::tx_power(),
+                    };
+                    if radio.mode() != mode {
+                        radio.set_mode(mode);
+                        radio.esb_listen();
+                    }
+                });
+            } else {
+                // Answer the controller's request for a PHY and switch to the one agreed on, going
+                // back if the controller doesn't follow, or fall back to 1M when the link is lost,
+                // as the controller does. Do the same for the channels to hop over, and pick up
+                // changes to the TX power. Every receiver tells the controller when it drops cruise
+                // frames.
+                let notice = resources
+                    .CRUISE_GUARD
+                    .lock(|guard| guard.take_notice(uptime.now()));
+                let radio = &mut resources.RADIO;
+                let redundancy = &mut resources.REDUNDANCY;
+                let follower = &mut resources.FOLLOWER;
+                resources.ANSWERING.lock(|answering| {
+                    let now = uptime.now();
+                    if let Some(phy) = answering.update(now) {
+                        warn!("phy answer not confirmed, back to {}", phy.name());
+                    }
+                    let answer = answering.take_answer(now);
+                    if timeout.is_some() {
+                        answering.link_lost();
+                    }
+                    let mode = RadioMode {
+                        phy: answering.phy(),
+                        tx_power: config::tx_power(),
+                    };
+                    follower.lock(|follower| {
+                        let map = follower.map();
+                        // Only the primary receiver answers
+                        let map_answer = if config::is_primary() {
+                            follower.take_answer(now)
+                        } else {
+                            None
+                        };
+                        if timeout.is_some() {
+                            follower.link_lost();
+                        }
+                        if follower.map() != map {
+                            info!("hopping over {} channels", follower.map().count());
+                        }
+                        redundancy.lock(|redundancy| {
+                            radio.lock(|radio| {
+                                if let Some(phy) = answer {
+                                    radio.configure_receiver(RadioCmd::Off);
+                                    let frame = mode::answer_frame(phy);
+                                    protocol::broadcast(radio, protocol::RECEIVER_ADDRESS, &frame);
+                                }
+                                if let Some(map) = map_answer {
+                                    radio.configure_receiver(RadioCmd::Off);
+                                    let frame = hopping::map_answer_frame(map);
+                                    protocol::broadcast(radio, protocol::RECEIVER_ADDRESS, &frame);
+                                }
+                                if notice {
+                                    radio.configure_receiver(RadioCmd::Off);
+                                    let frame = cruise::cancel_frame();
+                                    protocol::broadcast(radio, protocol::RECEIVER_ADDRESS, &frame);
+                                }
+                                if radio.mode().phy != mode.phy {
+                                    info!("radio phy: {}", mode.phy.name());
+                                }
+                                let answered = answer.is_some() || map_answer.is_some() || notice;
+                                if answered || timeout.is_some() || radio.mode() != mode {
+                                    radio.set_mode(mode);
+                                    listen(radio, redundancy, follower);
+                                }
+                            })
+                        });
+                    });
+                });
+
+                // Return to the channel frames start on once the copies are over, or move on from a
+                // jammed one. While hopping, move on to the channel of the next copy or frame.
+                let radio = &mut resources.RADIO;
+                if config::hopping() {
+                    resources.FOLLOWER.lock(|follower| {
+                        if let Some(channel) = follower.update(uptime.now()) {
+                            radio.lock(|radio| radio.listen_hopping(channel));
+                        }
+                    });
+                } else {
+                    resources.REDUNDANCY.lock(|redundancy| {
+                        if let Some(channel) = redundancy.update(uptime.now()) {
+                            radio.lock(|radio| {
+                                radio.configure_receiver(RadioCmd::ListenAdvertising { channel })
+                            });
+                        }
+                    });
+                }
+            }
+
+            // Subscriptions to the log stream, telemetry and console end with the connection
+            if !resources.BLE_LL.lock(|ll| ll.is_connected()) {
+                logger::STREAMING.store(false, Ordering::Relaxed);
+                telemetry::SUBSCRIBED.store(false, Ordering::Relaxed);
+                console::SUBSCRIBED.store(false, Ordering::Relaxed);
+            }
+
+            if now.duration_since(last_telemetry) > Duration::from_secs(1) {
+                last_telemetry = now;
+                let summary = resources.LINK_STATS.lock(|stats| stats.summary());
+                gatt::send_telemetry(&summary, &mut *resources.BLE_R);
+            }
+            if now.duration_since(last_link_log) > Duration::from_secs(LINK_LOG_SECS)
+                && resources.FAILSAFE.lock(|failsafe| failsafe.is_linked())
+            {
+                last_link_log = now;
+                let summary = resources.LINK_STATS.lock(|stats| stats.summary());
+                info!(
+                    "link: {} of {} frames lost, rssi {} dBm (min {}), jitter {} us, {} crc errors",
+                    summary.missed,
+                    summary.received + summary.missed,
+                    summary.rssi,
+                    summary.rssi_min,
+                    summary.jitter,
+                    summary.crc_errors,
+                );
+            }
+
+            // Send the log to a subscribed BLE client, or else drain it through the serial
+            // connection
+            let streamed = gatt::stream_log(&mut *resources.LOG_SINK, &mut *resources.BLE_R);
+            if !streamed {
+                while let Ok(grant) = resources.LOG_SINK.read() {
+                    for chunk in grant.buf().chunks(255) {
+                        resources.SERIAL.write(chunk).unwrap();
+                    }
+
+                    resources.LOG_SINK.release(grant.buf().len(), grant);
                 }
+            }
 
-                resources.LOG_SINK.release(grant.buf().len(), grant);
+            // Feed what a BLE client wrote to the console to the shell
+            let byte = match resources.CONSOLE_IN.read() {
+                Ok(grant) => {
+                    let byte = grant.buf()[0];
+                    resources.CONSOLE_IN.release(1, grant);
+                    Some(byte)
+                }
+                Err(_) => None,
+            };
+            if let Some(byte) = byte {
+                let console = &mut *resources.CONSOLE;
+                if let Some(line) = resources.SHELL.feed(byte, console) {
+                    match Command::parse(line, &commands::COMMANDS) {
+                        Ok(Command::Status) => {
+                            let throttle = resources.LAST_THROTTLE.lock(|throttle| *throttle);
+                            let frames = resources.FRAMES_RECEIVED.lock(|frames| *frames);
+                            let copies = resources.REDUNDANCY.lock(|r| r.stats()).copies;
+                            let link = resources.LINK_STATS.lock(|stats| stats.summary());
+                            let pwm = resources.PWM.lock(|pwm| pwm.get());
+                            let (mode, esb) =
+                                resources.RADIO.lock(|radio| (radio.mode(), radio.is_esb()));
+                            let link_mode = if esb { "ESB" } else { mode.phy.name() };
+                            writeln!(
+                                console,
+                                "uptime: {}\r\nframes received: {}\r\n\
+                                 copies per frame: 1: {}, 2: {}, 3: {}\r\nlink: {}\r\n\
+                                 radio: {}, {} dBm\r\nthrottle: {:?}\r\npwm: {}\r\n\
+                                 log messages dropped: {}\r",
+                                resources.UPTIME.now(),
+                                frames,
+                                copies[0],
+                                copies[1],
+                                copies[2],
+                                link,
+                                link_mode,
+                                mode.tx_power,
+                                throttle,
+                                pwm,
+                                logger::DROPPED.load(Ordering::Relaxed),
+                            )
+                            .ok();
+                            if config::hopping() && !esb {
+                                let (map, synced) = resources
+                                    .FOLLOWER
+                                    .lock(|follower| (follower.map(), follower.is_synced()));
+                                writeln!(
+                                    console,
+                                    "hopping: {} of {} channels, {}\r",
+                                    map.count(),
+                                    hopping::CHANNELS,
+                                    if synced { "in sync" } else { "searching" }
+                                )
+                                .ok();
+                            }
+                            match resources.OWNERSHIP.lock(|ownership| ownership.owner()) {
+                                Some(id) => writeln!(console, "owner: controller {}\r", id).ok(),
+                                None => writeln!(console, "owner: none\r").ok(),
+                            };
+                        }
+                        Ok(Command::Device(cmd, [value, _]))
+                            if ptr::eq(cmd, &commands::PWM_TEST) =>
+                        {
+                            if value > u32::from(COUNTERTOP) {
+                                writeln!(console, "error: value must be at most {}\r", COUNTERTOP)
+                                    .ok();
+                            } else {
+                                resources.PWM.lock(|pwm| pwm.set(value as u16));
+                                writeln!(console, "pwm: {}\r", value).ok();
+                            }
+                        }
+                        Ok(cmd) => {
+                            shell::execute(cmd, console, &config::ENTRIES, &commands::COMMANDS)
+                        }
+                        Err(e) => {
+                            writeln!(console, "error: {}\r", e).ok();
+                        }
+                    }
+                    resources.SHELL.prompt(console);
+                }
             }
 
+            // Store changed settings once the motor is stopped, since that may erase a page
+            let pwm = resources.PWM.lock(|pwm| pwm.get());
+            if output::may_write_flash(pwm, config::PWM_NEUTRAL.get() as u16) {
+                if let Err(e) = config::save() {
+                    warn!("failed to save settings: {:?}", Fmt(e));
+                }
+            }
+
+            gatt::stream_console(&mut *resources.CONSOLE_SINK, &mut *resources.BLE_R);
+
             if resources.BLE_R.has_work() {
                 resources.BLE_R.process_one().unwrap();
             }
+
+            // Handle updates relayed by the controller that owns the receiver
+            while let Ok(grant) = resources.RELAY_FRAMES.read() {
+                let len = grant.buf().len();
+                // Writing to flash stalls the CPU, so only accept updates while the motor is
+                // stopped. Only the primary receiver answers, so the others can't be updated this
+                // way.
+                let pwm = resources.PWM.lock(|pwm| pwm.get());
+                let owner = resources.OWNERSHIP.lock(|ownership| ownership.owner());
+                let neutral = config::PWM_NEUTRAL.get() as u16;
+                if output::may_write_flash(pwm, neutral) && config::is_primary() {
+                    for frame in Frames(grant.buf()) {
+                        let (&id, frame) = match frame.split_first() {
+                            Some(split) => split,
+                            None => continue,
+                        };
+                        if owner != Some(id) {
+                            continue;
+                        }
+                        if let Some(response) = resources.RELAY_DFU.handle(frame) {
+                            let radio = &mut resources.RADIO;
+                            let follower = &mut resources.FOLLOWER;
+                            resources.REDUNDANCY.lock(|redundancy| {
+                                follower.lock(|follower| {
+                                    radio.lock(|radio| {
+                                        broadcast_response(radio, &response, redundancy, follower)
+                                    })
+                                })
+                            });
+                        }
+                    }
+                }
+                resources.RELAY_FRAMES.release(len, grant);
+            }
+
+            // Give the updater some time to receive the last response before resetting
+            if update_received.is_none() && dfu::UPDATE_READY.load(Ordering::SeqCst) {
+                info!("update received, rebooting");
+                update_received = Some(now);
+            }
+            if let Some(since) = update_received {
+                if now.duration_since(since) > Duration::from_secs(1) {
+                    shell::reboot();
+                }
+            }
         }
     }
 };
 
-pub struct PwmCallback(pac::PWM0);
+/// Passes the payload of received throttle beacons on to the `RADIO` interrupt handler, and
+/// relayed updates on to `idle`.
+///
+/// Frames are queued with the number of the controller that sent them in front, and throttle frames
+/// with their sequence number in front of that (see `split_throttle`).
+///
+/// The scanner owns its callback, so the callback can't drive the outputs directly without
+/// keeping them out of reach of everything else.
+pub struct ThrottleCallback {
+    frames: Producer,
+    relay_frames: Producer,
+}
 
-impl ScanCallback for PwmCallback {
-    fn beacon<'a, I>(&mut self, _adv_addr: DeviceAddress, adv_data: I)
+impl ScanCallback for ThrottleCallback {
+    fn beacon<'a, I>(&mut self, adv_addr: DeviceAddress, adv_data: I)
     where
         I: Iterator<Item = AdStructure<'a>>,
     {
-        match adv_data.last() {
-            Some(AdStructure::Unknown { ty: _, data }) => {
-                info!("got val: {}", data[0]);
-
-                let mut val: u16 = 6990 + (u16::from(data[0]) * 2);
-                self.0.seq0.cnt.write(|w| unsafe { w.cnt().bits(1) });
-                self.0
-                    .seq0
-                    .ptr
-                    .write(|w| unsafe { w.ptr().bits(((&mut val) as *mut _) as u32) });
-
-                self.0.tasks_seqstart[0].write(|w| w.tasks_seqstart().trigger());
-                self.0
-                    .tasks_nextstep
-                    .write(|w| w.tasks_nextstep().trigger());
+        let id = match protocol::controller_id(*adv_addr.raw()) {
+            Some(id) => id,
+            None => return,
+        };
+        let (seq, data) = match protocol::sequenced_payload(adv_data) {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        // If a queue is full, the frame is dropped; a newer one will be along shortly
+        if seq.is_none() && relay::is_frame(data) {
+            if data.len() > relay::MAX_FRAME_LEN {
+                return;
+            }
+            let mut frame = [0; relay::MAX_FRAME_LEN + 1];
+            frame[0] = id;
+            frame[1..][..data.len()].copy_from_slice(data);
+            protocol::push_frame(&mut self.relay_frames, &frame[..data.len() + 1]);
+        } else if data.len() <= MAX_THROTTLE_FRAME_LEN {
+            let mut frame = [0; MAX_THROTTLE_FRAME_LEN + 3];
+            if let Some(seq) = seq {
+                frame[0] = 1;
+                frame[1] = seq;
             }
-            _ => (),
+            frame[2] = id;
+            frame[3..][..data.len()].copy_from_slice(data);
+            protocol::push_frame(&mut self.frames, &frame[..data.len() + 3]);
         }
     }
 }
+
+/// Passes a throttle frame with the sequence number `seq`, received at `now`, on to the cruise
+/// guard, and returns whether its throttle value is to be applied.
+fn guard_cruise(guard: &mut Guard, seq: Option<u8>, frame: &[u8], now: Instant) -> bool {
+    let cancelled = guard.is_cancelled();
+    let apply = guard.frame(seq, protocol::is_cruise(frame), now);
+    if guard.is_cancelled() && !cancelled {
+        warn!("link degraded while cruising, output back to neutral");
+    }
+    apply
+}
+
+/// Maximum length of the payload of a throttle beacon.
+const MAX_THROTTLE_FRAME_LEN: usize = 6;
+
+/// Splits a queued throttle frame into the number of the controller that sent it, its sequence
+/// number, if it has one, and its payload.
+///
+/// Queued frames start with 1 and the sequence number, or with 2 zero Bytes for controllers that
+/// don't send one, followed by the number of the controller.
+fn split_throttle(frame: &[u8]) -> (u8, Option<u8>, &[u8]) {
+    if frame.len() < 3 {
+        return (0, None, &[]);
+    }
+
+    let (header, payload) = frame.split_at(3);
+    let seq = if header[0] == 1 {
+        Some(header[1])
+    } else {
+        None
+    };
+    (header[2], seq, payload)
+}
+
+/// Broadcasts the response to a relayed update request to the controller, then goes back to
+/// listening for throttle beacons.
+fn broadcast_response(
+    radio: &mut BleRadio,
+    response: &[u8],
+    redundancy: &Redundancy,
+    follower: &Follower,
+) {
+    radio.configure_receiver(RadioCmd::Off);
+    protocol::broadcast(radio, protocol::RECEIVER_ADDRESS, response);
+    listen(radio, redundancy, follower);
+}
+
+/// Listens for the next throttle beacon: on the data channel `follower` picked while hopping, or
+/// else on the advertising channel `redundancy` picked.
+fn listen(radio: &mut BleRadio, redundancy: &Redundancy, follower: &Follower) {
+    if config::hopping() {
+        radio.listen_hopping(follower.channel());
+    } else {
+        radio.configure_receiver(RadioCmd::ListenAdvertising {
+            channel: redundancy.channel(),
+        });
+    }
+}
@@ -0,0 +1,60 @@
+//! Servo-style PWM output driving the ESC.
+
+use {bluefly_receiver_bsp::Esc, nrf52810_hal::nrf52810_pac::PWM0};
+
+/// PWM period in counter ticks: 20ms at `div_32`.
+pub const COUNTERTOP: u16 = 8_000;
+
+/// A PWM output producing a single channel of servo pulses.
+pub struct Pwm {
+    pwm: PWM0,
+    /// Owned, so nothing else can use the pin.
+    _esc: Esc,
+    /// The sequence the PWM peripheral reads the compare value from via DMA.
+    seq: &'static mut [u16; 1],
+}
+
+impl Pwm {
+    /// Configures `pwm` to output to `esc`, starting with the compare value `initial`.
+    pub fn new(pwm: PWM0, esc: Esc, seq: &'static mut [u16; 1], initial: u16) -> Self {
+        pwm.psel.out[0].write(|w| unsafe { w.pin().bits(esc.pin()).connect().connected() });
+        pwm.enable.write(|w| w.enable().enabled());
+        pwm.mode.write(|w| w.updown().up());
+        pwm.prescaler.write(|w| w.prescaler().div_32());
+        pwm.countertop
+            .write(|w| unsafe { w.countertop().bits(COUNTERTOP) });
+        pwm.loop_.write(|w| w.cnt().disabled());
+        pwm.decoder.write(|w| w.load().common().mode().next_step());
+        pwm.seq0.refresh.write(|w| w.cnt().continuous());
+        pwm.seq0.enddelay.write(|w| unsafe { w.cnt().bits(0) });
+
+        let mut pwm = Self {
+            pwm,
+            _esc: esc,
+            seq,
+        };
+        pwm.set(initial);
+        pwm
+    }
+
+    /// Changes the compare value of the output.
+    pub fn set(&mut self, value: u16) {
+        self.seq[0] = value;
+
+        self.pwm.seq0.cnt.write(|w| unsafe { w.cnt().bits(1) });
+        self.pwm
+            .seq0
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(self.seq.as_ptr() as u32) });
+
+        self.pwm.tasks_seqstart[0].write(|w| w.tasks_seqstart().trigger());
+        self.pwm
+            .tasks_nextstep
+            .write(|w| w.tasks_nextstep().trigger());
+    }
+
+    /// Returns the current compare value.
+    pub fn get(&self) -> u16 {
+        self.seq[0]
+    }
+}
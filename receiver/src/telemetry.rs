@@ -0,0 +1,92 @@
+//! Link quality telemetry over BLE.
+//!
+//! A client subscribing to notifications of the link quality characteristic receives a summary of
+//! the link statistics every second (see `bluefly_common::link_stats::Summary::to_bytes` for
+//! the format).
+
+use {
+    bluefly_common::gatt::{CHARACTERISTIC, CLIENT_CONFIG, PRIMARY_SERVICE, USER_DESCRIPTION},
+    core::sync::atomic::{AtomicBool, Ordering},
+    rubble::{
+        att::{AttUuid, Attribute, Handle},
+        uuid::Uuid128,
+        Error,
+    },
+};
+
+/// Whether a BLE client subscribed to the link quality characteristic.
+pub static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+/// UUID of the telemetry service (`b1ef1c00-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const SERVICE_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x00, 0x1C, 0xEF, 0xB1,
+];
+
+/// UUID of the link quality characteristic (`b1ef1c01-d0f0-4c6b-9d3e-5a2f7c1e8b40`).
+const LINK_UUID: [u8; 16] = [
+    0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01, 0x1C, 0xEF, 0xB1,
+];
+
+/// Characteristic declaration: notify, value handle 18, followed by the UUID.
+const LINK_DECL: [u8; 19] = [
+    0x10, 0x12, 0x00, 0x40, 0x8B, 0x1E, 0x7C, 0x2F, 0x5A, 0x3E, 0x9D, 0x6B, 0x4C, 0xF0, 0xD0, 0x01,
+    0x1C, 0xEF, 0xB1,
+];
+
+const LINK_DESC: &[u8] = b"Link quality";
+
+/// The telemetry service of the GATT server (see `gatt`).
+pub struct TelemetryService {
+    /// The last attribute of the service, which ends its group.
+    description: Attribute<'static>,
+}
+
+impl TelemetryService {
+    /// Handle of the service declaration.
+    pub const FIRST_HANDLE: u16 = 16;
+    /// Handle of the last attribute of the service.
+    pub const LAST_HANDLE: u16 = 20;
+    /// Handle of the link quality characteristic's value, which summaries are notified on.
+    pub const LINK_HANDLE: u16 = 18;
+
+    pub fn new() -> Self {
+        Self {
+            description: Attribute::new(USER_DESCRIPTION.into(), Handle::from_raw(20), LINK_DESC),
+        }
+    }
+
+    pub fn attribute(&self, handle: u16) -> Option<Attribute<'_>> {
+        let (uuid, value): (AttUuid, &[u8]) = match handle {
+            16 => (PRIMARY_SERVICE.into(), &SERVICE_UUID),
+            17 => (CHARACTERISTIC.into(), &LINK_DECL),
+            // Summaries are only sent in notifications
+            18 => (Uuid128::from_bytes(LINK_UUID).into(), &[]),
+            19 if SUBSCRIBED.load(Ordering::Relaxed) => (CLIENT_CONFIG.into(), &[0x01, 0x00]),
+            19 => (CLIENT_CONFIG.into(), &[0x00, 0x00]),
+            20 => return Some(self.description.clone()),
+            _ => return None,
+        };
+
+        Some(Attribute::new(uuid, Handle::from_raw(handle), value))
+    }
+
+    /// Returns the last attribute of the service, which ends its group.
+    pub fn group_end(&self) -> &Attribute<'_> {
+        &self.description
+    }
+
+    pub fn is_writeable(&self, handle: u16) -> bool {
+        handle == 19
+    }
+
+    pub fn write(&mut self, handle: u16, data: &[u8]) -> Result<(), Error> {
+        match (handle, data) {
+            // The client characteristic configuration; bit 0 enables notifications
+            (19, &[flags, _]) => {
+                SUBSCRIBED.store(flags & 0x01 != 0, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(Error::InvalidValue),
+        }
+    }
+}
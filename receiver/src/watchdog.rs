@@ -0,0 +1,86 @@
+//! The hardware watchdog.
+//!
+//! Every task in `Task` gets its own reload request register of the WDT, which resets the device
+//! unless all of them were reloaded within `TIMEOUT_MS`. So a single stuck task is enough to cause
+//! a reset, even if the others keep running.
+//!
+//! Once started, the watchdog keeps running until the next reset that isn't a soft reset, so it
+//! may already run when the firmware starts (see the bootloader).
+
+use {
+    core::sync::atomic::{AtomicU32, Ordering},
+    nrf52810_hal::nrf52810_pac::{POWER, WDT},
+};
+
+/// Time after which the device is reset if a task didn't check in.
+///
+/// Ten times the advertising interval, which is the longest time between link layer updates.
+const TIMEOUT_MS: u32 = 2_000;
+
+/// Value reloading a reload request register.
+const RELOAD: u32 = 0x6E52_4635;
+
+/// The tasks that have to check in.
+#[derive(Copy, Clone)]
+pub enum Task {
+    /// The `idle` loop: the shell, log output and the GATT server.
+    Idle = 0,
+    /// The link layer updates, which reconfigure the radio.
+    Radio = 1,
+}
+
+const TASKS: u32 = 2;
+
+/// Reload request registers enabled by the firmware that started the watchdog, which this one
+/// doesn't use. `idle` reloads them along with its own.
+static STALE: AtomicU32 = AtomicU32::new(0);
+
+/// Starts the watchdog, unless it's already running.
+pub fn start(wdt: &WDT) {
+    let used = (1 << TASKS) - 1;
+    if wdt.runstatus.read().bits() != 0 {
+        // The configuration can't be changed anymore
+        STALE.store(wdt.rren.read().bits() & !used, Ordering::Relaxed);
+        return;
+    }
+
+    // Don't reset while the CPU is halted by a debugger
+    wdt.config.write(|w| w.sleep().run().halt().pause());
+    wdt.crv
+        .write(|w| unsafe { w.bits(TIMEOUT_MS * 32_768 / 1_000) });
+    wdt.rren.write(|w| unsafe { w.bits(used) });
+    wdt.tasks_start.write(|w| unsafe { w.bits(1) });
+}
+
+/// Tells the watchdog that `task` is still running.
+pub fn check_in(task: Task) {
+    // Safe, since writing a reload request register has no other effect
+    let wdt = unsafe { &*WDT::ptr() };
+    wdt.rr[task as usize].write(|w| unsafe { w.bits(RELOAD) });
+
+    if let Task::Idle = task {
+        let stale = STALE.load(Ordering::Relaxed);
+        for i in TASKS..8 {
+            if stale & (1 << i) != 0 {
+                wdt.rr[i as usize].write(|w| unsafe { w.bits(RELOAD) });
+            }
+        }
+    }
+}
+
+/// Returns what caused the last reset, and clears it.
+pub fn reset_reason(power: &POWER) -> &'static str {
+    let reasons = power.resetreas.read().bits();
+    // The bits are sticky, so they have to be cleared for the next reset
+    power.resetreas.write(|w| unsafe { w.bits(reasons) });
+
+    match reasons {
+        0 => "power-on",
+        r if r & (1 << 1) != 0 => "watchdog",
+        r if r & (1 << 3) != 0 => "lockup",
+        r if r & (1 << 2) != 0 => "soft reset",
+        r if r & (1 << 0) != 0 => "reset pin",
+        r if r & (1 << 16) != 0 => "wakeup from System OFF",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,387 @@
+//! The virtual radio channel between the simulated devices.
+
+use {
+    crate::clock::Clock,
+    bluefly_common::{
+        hopping::{self, HopTransmitter},
+        mode::{self, Phy, RadioMode},
+    },
+    rubble::{
+        link::{advertising, data, RadioCmd, Transmitter, MAX_PDU_SIZE},
+        phy::{AdvertisingChannel, DataChannel},
+        time::{Duration, Instant, Timer},
+    },
+    std::{cell::RefCell, rc::Rc},
+};
+
+/// A packet on its way through the air.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    /// Frequency of the channel it was sent on, in MHz.
+    pub freq: u16,
+    /// Header and payload, laid out like in the nRF radio's packet buffer.
+    pub pdu: Vec<u8>,
+    /// When it was sent.
+    pub sent: Instant,
+    /// When it arrives at the other side.
+    pub arrives: Instant,
+    /// PHY it was sent with.
+    pub phy: Phy,
+    /// TX power it was sent with, in dBm.
+    pub tx_power: i8,
+    /// Signal strength it arrives with, in dBm.
+    pub rssi: i8,
+    /// Whether it arrives intact, or corrupted so that its CRC doesn't match.
+    pub crc_ok: bool,
+}
+
+impl Packet {
+    /// Returns the header of the advertising channel PDU.
+    pub fn header(&self) -> advertising::Header {
+        advertising::Header::parse(&self.pdu)
+    }
+
+    /// Returns the payload of the advertising channel PDU, or `None` if the length in the header
+    /// doesn't match.
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.pdu
+            .get(2..2 + usize::from(self.header().payload_length()))
+    }
+}
+
+/// Radio conditions between the devices.
+///
+/// The default is a perfect link: every packet arrives once, in order, as soon as it's sent.
+#[derive(Clone, Debug)]
+pub struct Conditions {
+    /// Probability of losing a packet.
+    pub loss: f32,
+    /// Probability of losing a packet sent on advertising channel 37, 38 or 39, on top of `loss`,
+    /// like with a WiFi network next to one of them.
+    pub channel_loss: [f32; 3],
+    /// Probability of losing a packet sent on each data channel, on top of `loss`, for beacons
+    /// that hop.
+    pub data_channel_loss: [f32; hopping::CHANNELS as usize],
+    /// Probability of a burst of interference starting at a packet.
+    pub burst: f32,
+    /// Number of packets lost in a row once a burst starts.
+    pub burst_len: u32,
+    /// Probability of a packet arriving twice.
+    pub duplication: f32,
+    /// Probability of a packet being held back by `reorder_delay`, arriving after packets sent
+    /// later.
+    pub reordering: f32,
+    pub reorder_delay: Duration,
+    /// Time every packet takes to arrive.
+    pub latency: Duration,
+    /// Maximum random delay added to `latency`.
+    pub jitter: Duration,
+    /// Probability of a packet arriving with a bad CRC.
+    pub corruption: f32,
+    /// Signal strength packets sent at the highest TX power arrive with, in dBm. Packets sent at
+    /// a lower power arrive weaker by the difference. Packets too weak for the PHY they're sent
+    /// with are lost.
+    pub rssi: i8,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            channel_loss: [0.0; 3],
+            data_channel_loss: [0.0; hopping::CHANNELS as usize],
+            burst: 0.0,
+            burst_len: 0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_micros(0),
+            latency: Duration::from_micros(0),
+            jitter: Duration::from_micros(0),
+            corruption: 0.0,
+            rssi: -50,
+        }
+    }
+}
+
+/// Weakest signal the nRF52810 receives on each PHY, in dBm.
+fn sensitivity(phy: Phy) -> i8 {
+    match phy {
+        Phy::Ble1M => -96,
+        Phy::Ble2M => -92,
+    }
+}
+
+/// Counts of what the air did to the packets sent through it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub sent: u32,
+    pub lost: u32,
+    pub duplicated: u32,
+    pub reordered: u32,
+    pub corrupted: u32,
+}
+
+/// Xorshift generator, so that lossy runs are random but repeatable.
+struct Rng(u64);
+
+impl Rng {
+    /// Returns a number in `0.0..1.0`.
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.next() < probability
+    }
+
+    /// Returns a random duration up to `max`.
+    fn delay(&mut self, max: Duration) -> Duration {
+        Duration::from_micros((self.next() * max.as_micros() as f32) as u32)
+    }
+}
+
+struct Medium {
+    conditions: Conditions,
+    rng: Rng,
+    /// Packets still to be lost in the current burst.
+    burst_left: u32,
+    /// Picks packets to lose on top of the conditions.
+    lose: Box<dyn Fn(&Packet) -> bool>,
+    stats: Stats,
+    in_flight: Vec<Packet>,
+}
+
+/// The medium all virtual radios transmit into.
+///
+/// Packets are delivered once their arrival time has come, after the `Conditions` had their way
+/// with them.
+#[derive(Clone)]
+pub struct Air {
+    medium: Rc<RefCell<Medium>>,
+}
+
+impl Air {
+    /// Seed used by `new`.
+    pub const DEFAULT_SEED: u64 = 0x5EED_B1F1;
+
+    pub fn new() -> Self {
+        Self::with_seed(Self::DEFAULT_SEED)
+    }
+
+    /// Creates an air whose random conditions are driven by `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            medium: Rc::new(RefCell::new(Medium {
+                conditions: Conditions::default(),
+                // Xorshift gets stuck at 0
+                rng: Rng(seed | 1),
+                burst_left: 0,
+                lose: Box::new(|_| false),
+                stats: Stats::default(),
+                in_flight: Vec::new(),
+            })),
+        }
+    }
+
+    /// Changes the conditions for packets sent from now on, ending a burst in progress.
+    pub fn set_conditions(&self, conditions: Conditions) {
+        let medium = &mut *self.medium.borrow_mut();
+        medium.conditions = conditions;
+        medium.burst_left = 0;
+    }
+
+    /// Loses every packet sent from now on that `lose` returns `true` for, on top of the
+    /// conditions. `|_| false` stops losing packets.
+    pub fn lose_matching<F>(&self, lose: F)
+    where
+        F: Fn(&Packet) -> bool + 'static,
+    {
+        self.medium.borrow_mut().lose = Box::new(lose);
+    }
+
+    pub fn conditions(&self) -> Conditions {
+        self.medium.borrow().conditions.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.medium.borrow().stats
+    }
+
+    fn transmit(&self, mut packet: Packet) {
+        let medium = &mut *self.medium.borrow_mut();
+        let conditions = &medium.conditions;
+        let rng = &mut medium.rng;
+        medium.stats.sent += 1;
+
+        if medium.burst_left == 0 && rng.chance(conditions.burst) {
+            medium.burst_left = conditions.burst_len;
+        }
+        if medium.burst_left > 0 {
+            medium.burst_left -= 1;
+            medium.stats.lost += 1;
+            return;
+        }
+        if (medium.lose)(&packet) {
+            medium.stats.lost += 1;
+            return;
+        }
+        let channel_loss = AdvertisingChannel::iter_all()
+            .position(|channel| channel.freq() == packet.freq)
+            .map(|i| conditions.channel_loss[i])
+            .or_else(|| {
+                (0..hopping::CHANNELS)
+                    .position(|i| DataChannel::new(i).freq() == packet.freq)
+                    .map(|i| conditions.data_channel_loss[i])
+            })
+            .unwrap_or(0.0);
+        let max_power = mode::TX_POWERS[mode::TX_POWERS.len() - 1];
+        let rssi = i16::from(conditions.rssi) - i16::from(max_power - packet.tx_power);
+        if rssi < i16::from(sensitivity(packet.phy))
+            || rng.chance(conditions.loss)
+            || rng.chance(channel_loss)
+        {
+            medium.stats.lost += 1;
+            return;
+        }
+
+        let mut delay = conditions.latency + rng.delay(conditions.jitter);
+        if rng.chance(conditions.reordering) {
+            delay += conditions.reorder_delay;
+            medium.stats.reordered += 1;
+        }
+        packet.arrives = packet.sent + delay;
+        packet.rssi = rssi as i8;
+        if rng.chance(conditions.corruption) {
+            packet.crc_ok = false;
+            medium.stats.corrupted += 1;
+        }
+
+        if rng.chance(conditions.duplication) {
+            let mut copy = packet.clone();
+            copy.arrives = copy.arrives + conditions.latency + rng.delay(conditions.jitter);
+            medium.in_flight.push(copy);
+            medium.stats.duplicated += 1;
+        }
+        medium.in_flight.push(packet);
+    }
+
+    /// Removes and returns every packet that has arrived by `now`, in the order they arrived.
+    pub fn deliver(&self, now: Instant) -> Vec<Packet> {
+        let in_flight = &mut self.medium.borrow_mut().in_flight;
+        let (mut arrived, waiting) = in_flight
+            .drain(..)
+            .partition::<Vec<_>, _>(|p| p.arrives.raw_micros() <= now.raw_micros());
+        *in_flight = waiting;
+        arrived.sort_by_key(|p| p.arrives.raw_micros());
+        arrived
+    }
+}
+
+impl Default for Air {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A radio transmitting into the `Air`, standing in for `BleRadio`.
+pub struct VirtualRadio {
+    air: Air,
+    clock: Clock,
+    tx_buf: [u8; MAX_PDU_SIZE],
+    /// Frequency of the channel the radio listens on, if any.
+    listening: Option<u16>,
+    mode: RadioMode,
+}
+
+impl VirtualRadio {
+    pub fn new(air: Air, clock: Clock) -> Self {
+        Self {
+            air,
+            clock,
+            tx_buf: [0; MAX_PDU_SIZE],
+            listening: None,
+            mode: RadioMode::DEFAULT,
+        }
+    }
+
+    /// Returns the PHY and TX power the radio currently uses.
+    pub fn mode(&self) -> RadioMode {
+        self.mode
+    }
+
+    /// Switches the radio to `mode`, which stops reception, like `BleRadio::set_mode`.
+    pub fn set_mode(&mut self, mode: RadioMode) {
+        if mode != self.mode {
+            self.listening = None;
+            self.mode = mode;
+        }
+    }
+
+    /// Configures the radio for (not) receiving according to `cmd`, like
+    /// `BleRadio::configure_receiver`.
+    pub fn configure_receiver(&mut self, cmd: RadioCmd) {
+        self.listening = match cmd {
+            RadioCmd::ListenAdvertising { channel } => Some(channel.freq()),
+            _ => None,
+        };
+    }
+
+    /// Listens for beacons hopping onto the data channel `channel`, like
+    /// `BleRadio::listen_hopping`.
+    pub fn listen_hopping(&mut self, channel: DataChannel) {
+        self.listening = Some(channel.freq());
+    }
+
+    /// Whether the radio would pick up `packet`.
+    pub fn hears(&self, packet: &Packet) -> bool {
+        self.listening == Some(packet.freq) && packet.phy == self.mode.phy
+    }
+
+    /// Sends the advertising channel PDU in the TX buffer on the channel at `freq`.
+    fn send(&mut self, header: advertising::Header, freq: u16) {
+        self.tx_buf[0] = header.to_u16() as u8;
+        self.tx_buf[1] = header.payload_length();
+
+        let len = 2 + usize::from(header.payload_length());
+        self.air.transmit(Packet {
+            freq,
+            pdu: self.tx_buf[..len].to_vec(),
+            sent: self.clock.now(),
+            arrives: self.clock.now(),
+            phy: self.mode.phy,
+            tx_power: self.mode.tx_power,
+            rssi: 0,
+            crc_ok: true,
+        });
+    }
+}
+
+impl Transmitter for VirtualRadio {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        // Leave 2 Bytes for the advertising PDU header
+        &mut self.tx_buf[2..]
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        self.send(header, channel.freq());
+    }
+
+    fn transmit_data(
+        &mut self,
+        _access_address: u32,
+        _crc_iv: u32,
+        _header: data::Header,
+        _channel: DataChannel,
+    ) {
+        unimplemented!("the simulation only covers the beacon link")
+    }
+}
+
+impl HopTransmitter for VirtualRadio {
+    fn transmit_hopping(&mut self, header: advertising::Header, channel: DataChannel) {
+        self.send(header, channel.freq());
+    }
+}
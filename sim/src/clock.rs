@@ -0,0 +1,37 @@
+//! Simulated time.
+
+use {
+    rubble::time::{Duration, Instant, Timer},
+    std::{cell::Cell, rc::Rc},
+};
+
+/// The simulation's clock, shared by every device.
+///
+/// It only moves when the simulation advances it, so every run is deterministic.
+#[derive(Clone, Default)]
+pub struct Clock {
+    micros: Rc<Cell<u32>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        self.micros
+            .set(self.micros.get().wrapping_add(by.as_micros()));
+    }
+
+    /// Returns the time elapsed since the start of the simulation.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_micros(self.micros.get())
+    }
+}
+
+impl Timer for Clock {
+    fn now(&self) -> Instant {
+        Instant::from_raw_micros(self.micros.get())
+    }
+}
@@ -0,0 +1,282 @@
+//! The simulated controller.
+
+use {
+    crate::{
+        air::{Air, Packet, VirtualRadio},
+        clock::Clock,
+        peripherals::{Adc, Display, ThrottlePin},
+    },
+    bluefly_common::{
+        cruise::{self, Cruise, Reason, Status},
+        display,
+        hopping::{self, ChannelMap, Hopper},
+        mode::{self, Negotiation, Phy, RadioMode},
+        power::{Power, PowerState, Timeouts},
+        protocol::{self, BeaconRate},
+        receivers::{self, Trim, MAX_RECEIVERS},
+    },
+    embedded_hal::adc::OneShot,
+    rubble::{
+        beacon::{BeaconScanner, ScanCallback},
+        link::{
+            ad_structure::AdStructure, filter::WhitelistFilter, AddressKind, DeviceAddress,
+            RadioCmd,
+        },
+        phy::AdvertisingChannel,
+        time::{Duration, Instant},
+    },
+    std::{cell::Cell, iter, rc::Rc},
+};
+
+/// Picks up the receiver's answers to mode requests and channel maps, and its notices that it
+/// disengaged cruise, like the firmware's `ResponseCallback`.
+struct Callback {
+    answer: Rc<Cell<Option<Phy>>>,
+    map_answer: Rc<Cell<Option<ChannelMap>>>,
+    cruise_cancelled: Rc<Cell<bool>>,
+}
+
+impl ScanCallback for Callback {
+    fn beacon<'a, I>(&mut self, _adv_addr: DeviceAddress, adv_data: I)
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        if let Some(data) = protocol::payload(adv_data) {
+            if let Some(phy) = mode::answer(data) {
+                self.answer.set(Some(phy));
+            }
+            if let Some(map) = hopping::map_answer(data) {
+                self.map_answer.set(Some(map));
+            }
+            if cruise::is_cancel(data) {
+                self.cruise_cancelled.set(true);
+            }
+        }
+    }
+}
+
+/// Runs what the controller's beacon timer does: reading the throttle, broadcasting it and
+/// updating the display. It also keeps track of the power state, which the firmware does in
+/// `idle`.
+pub struct Controller {
+    radio: VirtualRadio,
+    scanner: BeaconScanner<Callback, WhitelistFilter<iter::Once<DeviceAddress>>>,
+    /// The last answer to a mode request, until the next beacon.
+    answer: Rc<Cell<Option<Phy>>>,
+    /// The last channel map the receiver answered with, until the next beacon.
+    map_answer: Rc<Cell<Option<ChannelMap>>>,
+    /// Whether the receiver disengaged cruise, until the next beacon.
+    cruise_cancelled: Rc<Cell<bool>>,
+    negotiation: Negotiation,
+    hopper: Hopper,
+    /// Number of the controller, which picks the address it sends from (`link.controller`).
+    pub id: u8,
+    /// Whether beacons hop over the data channels (`link.hopping`).
+    pub hopping: bool,
+    /// Whether cruise can be engaged (`cruise.enable`).
+    pub cruise_enable: bool,
+    cruise: Cruise,
+    /// Number of receivers driven (`link.receivers`).
+    pub receivers: u8,
+    /// Adjustments to the throttle sent to each receiver (`rx0.*` and `rx1.*`).
+    pub trims: [Trim; MAX_RECEIVERS as usize],
+    /// PHY requested from the receiver (`radio.phy`).
+    pub phy: Phy,
+    /// TX power in dBm (`radio.tx_level`).
+    pub tx_power: i8,
+    pub adc: Adc,
+    throttle_pin: ThrottlePin,
+    pub display: Display,
+    /// Number of beacons sent per second while the throttle moves (`beacon.rate`).
+    pub beacon_rate: u32,
+    /// Number of beacons sent per second while the throttle is steady (`beacon.min_rate`).
+    pub min_beacon_rate: u32,
+    rate: BeaconRate,
+    /// Number of beacons sent per second while idle (`beacon.idle_rate`).
+    pub idle_beacon_rate: u32,
+    /// Throttle reading with the thumb released (`throttle.rest`).
+    pub throttle_rest: u16,
+    /// Times until the lower power states (`power.*`).
+    pub power_timeouts: Timeouts,
+    pub power: Power,
+    /// Whether the controller is running. When it isn't, it doesn't send anything. It stops
+    /// running when it turns itself off.
+    pub on: bool,
+    next_beacon: Instant,
+    seq: u8,
+}
+
+impl Controller {
+    pub fn new(air: Air, clock: Clock) -> Self {
+        let answer = Rc::new(Cell::new(None));
+        let map_answer = Rc::new(Cell::new(None));
+        let cruise_cancelled = Rc::new(Cell::new(false));
+        let filter = WhitelistFilter::from_address(DeviceAddress::new(
+            protocol::RECEIVER_ADDRESS,
+            AddressKind::Random,
+        ));
+
+        Self {
+            radio: VirtualRadio::new(air, clock),
+            scanner: BeaconScanner::with_filter(
+                Callback {
+                    answer: answer.clone(),
+                    map_answer: map_answer.clone(),
+                    cruise_cancelled: cruise_cancelled.clone(),
+                },
+                filter,
+            ),
+            answer,
+            map_answer,
+            cruise_cancelled,
+            negotiation: Negotiation::new(),
+            hopper: Hopper::new(protocol::CONTROLLER_ADDRESS),
+            id: 0,
+            hopping: false,
+            cruise_enable: false,
+            cruise: Cruise::new(),
+            receivers: 1,
+            trims: [Trim::DEFAULT; MAX_RECEIVERS as usize],
+            phy: Phy::Ble1M,
+            tx_power: RadioMode::DEFAULT.tx_power,
+            adc: Adc::default(),
+            throttle_pin: ThrottlePin,
+            display: Display::default(),
+            beacon_rate: 50,
+            min_beacon_rate: 20,
+            rate: BeaconRate::new(),
+            idle_beacon_rate: 10,
+            throttle_rest: 7360,
+            power_timeouts: Timeouts {
+                dim: Duration::from_secs(10),
+                idle: Duration::from_secs(30),
+                off: Duration::from_secs(120),
+            },
+            power: Power::new(Instant::from_raw_micros(0)),
+            on: true,
+            next_beacon: Instant::from_raw_micros(0),
+            seq: 0,
+        }
+    }
+
+    /// Sends a beacon if the beacon timer has fired by `now`.
+    pub fn update(&mut self, now: Instant) {
+        if !self.on || now.raw_micros() < self.next_beacon.raw_micros() {
+            return;
+        }
+        let val = self.adc.read(&mut self.throttle_pin).unwrap();
+        if self.cruise_cancelled.take() {
+            self.cruise.disengage(Reason::Link, now);
+        }
+        self.cruise
+            .update(val, self.throttle_rest, self.cruise_enable, now);
+        let val = self.cruise.throttle(val);
+
+        self.power.throttle(val, self.throttle_rest, now);
+        match self.power.update(now, &self.power_timeouts) {
+            Some(PowerState::Idle) => self.display.clear(),
+            Some(PowerState::Off) => {
+                self.on = false;
+                self.display.clear();
+                return;
+            }
+            _ => {}
+        }
+        let state = self.power.state();
+
+        let interval = |rate| Duration::from_micros(1_000_000 / rate);
+        let interval = match state {
+            PowerState::Active | PowerState::Dimmed => self.rate.next(
+                val,
+                interval(self.beacon_rate),
+                interval(self.min_beacon_rate),
+            ),
+            PowerState::Idle | PowerState::Off => interval(self.idle_beacon_rate),
+        };
+        self.next_beacon += interval;
+
+        match self.answer.take() {
+            Some(phy) => self.negotiation.answer(phy, now),
+            None => self.negotiation.update(now),
+        };
+
+        self.radio.configure_receiver(RadioCmd::Off);
+        self.radio.set_mode(RadioMode {
+            phy: self.negotiation.phy(),
+            tx_power: self.tx_power,
+        });
+        let mut trimmed = [0; MAX_RECEIVERS as usize];
+        for (trimmed, trim) in trimmed.iter_mut().zip(&self.trims) {
+            *trimmed = trim.apply(val, self.throttle_rest);
+        }
+        let phy = if self.receivers > 1 {
+            Phy::Ble1M
+        } else {
+            self.phy
+        };
+        let (mut frame, len) =
+            receivers::throttle_frame(&trimmed[..usize::from(self.receivers)], interval, phy);
+        let frame = &mut frame[..len];
+        if let PowerState::Idle | PowerState::Off = state {
+            protocol::release(frame);
+        }
+        if self.cruise.is_engaged() {
+            protocol::set_cruise(frame);
+        }
+        let address = protocol::controller_address(self.id);
+        if self.hopper.address() != address {
+            self.hopper = Hopper::new(address);
+        }
+        if self.hopping {
+            match self.map_answer.take() {
+                Some(map) => self.hopper.answer(map, now),
+                None => self.hopper.update(now),
+            };
+            let channels = self.hopper.channels(self.seq);
+            hopping::broadcast_sequenced(&mut self.radio, address, self.seq, frame, channels);
+        } else {
+            protocol::broadcast_sequenced(&mut self.radio, address, self.seq, frame);
+        }
+        self.seq = self.seq.wrapping_add(1);
+        self.radio.configure_receiver(RadioCmd::ListenAdvertising {
+            channel: AdvertisingChannel::first(),
+        });
+
+        let render = match state {
+            PowerState::Active => display::render,
+            PowerState::Dimmed => display::render_dimmed,
+            PowerState::Idle | PowerState::Off => return,
+        };
+        self.display.clear();
+        render(&mut self.display, val);
+        display::render_cruise(&mut self.display, self.cruise.status(now));
+    }
+
+    /// Handles a packet arriving, if the radio is listening on its channel.
+    pub fn receive(&mut self, packet: &Packet) {
+        if !self.on || !self.radio.hears(packet) {
+            return;
+        }
+        if let Some(payload) = packet.payload() {
+            let cmd = self
+                .scanner
+                .process_adv_packet(packet.header(), payload, packet.crc_ok);
+            self.radio.configure_receiver(cmd.radio);
+        }
+    }
+
+    /// Returns the PHY and TX power the radio currently uses.
+    pub fn radio_mode(&self) -> RadioMode {
+        self.radio.mode()
+    }
+
+    /// Returns the channels beacons hop over.
+    pub fn channel_map(&self) -> ChannelMap {
+        self.hopper.map()
+    }
+
+    /// Returns what the display shows about cruise at `now`.
+    pub fn cruise_status(&self, now: Instant) -> Status {
+        self.cruise.status(now)
+    }
+}
@@ -0,0 +1,491 @@
+//! Host simulation of the controller and the receiver.
+//!
+//! Runs the application logic of both firmwares (the throttle pipeline, the beacon protocol, the
+//! receiver's output mapping and failsafe, and the controller's display) on the host. The
+//! peripherals are replaced by simulated ones: a shared `Clock` stands in for the BLE timers, and
+//! each device's `VirtualRadio` transmits into the same `Air`, which delivers the beacons to the
+//! other side. The code running on top of them is the same Rubble and `bluefly-common` code the
+//! firmwares use.
+//!
+//! `Scenario` drives the link through bad radio `Conditions` and checks the receiver's safety
+//! properties at every step. Scenarios are plain tests:
+//!
+//! ```notrust
+//! cargo test -p bluefly-sim --target x86_64-unknown-linux-gnu
+//! ```
+
+pub mod air;
+pub mod clock;
+pub mod controller;
+pub mod motor;
+pub mod peripherals;
+pub mod receiver;
+pub mod scenario;
+
+pub use crate::{
+    air::{Air, Conditions, VirtualRadio},
+    clock::Clock,
+    controller::Controller,
+    motor::Motor,
+    receiver::Receiver,
+    scenario::{Invariants, Scenario, Violation},
+};
+
+use rubble::{
+    link::HardwareInterface,
+    time::{Duration, Timer},
+};
+
+/// Hardware interface for the BLE stack, running in the simulation.
+pub struct SimHw {}
+
+impl HardwareInterface for SimHw {
+    type Timer = Clock;
+    type Tx = VirtualRadio;
+}
+
+/// Time the simulation advances by in every step, in microseconds.
+pub const STEP_MICROS: u32 = 100;
+
+/// A controller and a receiver, linked through the air.
+pub struct Sim {
+    pub clock: Clock,
+    pub air: Air,
+    pub controller: Controller,
+    pub receiver: Receiver,
+    /// The motor driven by `receiver`.
+    pub motor: Motor,
+    /// Receiver 1, if the controller drives a second one (see `add_receiver`).
+    pub second_receiver: Option<Receiver>,
+    /// Controller 1, if the receivers are paired to a second one (see `add_controller`).
+    pub second_controller: Option<Controller>,
+}
+
+impl Sim {
+    pub fn new() -> Self {
+        Self::with_seed(Air::DEFAULT_SEED)
+    }
+
+    /// Creates a simulation whose radio conditions are driven by `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        let clock = Clock::new();
+        let air = Air::with_seed(seed);
+
+        Self {
+            controller: Controller::new(air.clone(), clock.clone()),
+            receiver: Receiver::new(air.clone(), clock.clone()),
+            motor: Motor::default(),
+            second_receiver: None,
+            second_controller: None,
+            clock,
+            air,
+        }
+    }
+
+    /// Runs both devices for `STEP_MICROS`.
+    pub fn step(&mut self) {
+        let now = self.clock.now();
+
+        self.controller.update(now);
+        if let Some(controller) = &mut self.second_controller {
+            controller.update(now);
+        }
+        for packet in self.air.deliver(now) {
+            self.controller.receive(&packet);
+            if let Some(controller) = &mut self.second_controller {
+                controller.receive(&packet);
+            }
+            self.receiver.receive(&packet, now);
+            if let Some(receiver) = &mut self.second_receiver {
+                receiver.receive(&packet, now);
+            }
+        }
+        self.receiver.idle(now);
+        if let Some(receiver) = &mut self.second_receiver {
+            receiver.idle(now);
+        }
+
+        let pwm = self.receiver.pwm.get();
+        let secs = STEP_MICROS as f32 / 1_000_000.0;
+        self.motor.update(pwm, &self.receiver.map, secs);
+        if now.raw_micros().is_multiple_of(motor::REPORT_MICROS) {
+            self.receiver.motor_rpm(self.motor.rpm as i32, now);
+        }
+
+        self.clock.advance(Duration::from_micros(STEP_MICROS));
+    }
+
+    /// Adds receiver 1, and configures the controller to drive it.
+    pub fn add_receiver(&mut self) {
+        let mut receiver = Receiver::new(self.air.clone(), self.clock.clone());
+        receiver.id = 1;
+        self.second_receiver = Some(receiver);
+        self.controller.receivers = 2;
+    }
+
+    /// Adds controller 1, and pairs the receivers to it as well as to controller 0.
+    pub fn add_controller(&mut self) {
+        let mut controller = Controller::new(self.air.clone(), self.clock.clone());
+        controller.id = 1;
+        controller.receivers = self.controller.receivers;
+        self.second_controller = Some(controller);
+        self.receiver.pair(0b11);
+        if let Some(receiver) = &mut self.second_receiver {
+            receiver.pair(0b11);
+        }
+    }
+
+    /// Runs both devices for `millis` milliseconds.
+    pub fn run_for(&mut self, millis: u32) {
+        let end = self.clock.elapsed().as_micros() + millis * 1000;
+        while self.clock.elapsed().as_micros() < end {
+            self.step();
+        }
+    }
+}
+
+impl Default for Sim {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        bluefly_common::{
+            cruise::Status,
+            display,
+            mode::Phy,
+            power::{PowerState, Timeouts},
+            receivers::Trim,
+        },
+    };
+
+    #[test]
+    fn throttle_reaches_output() {
+        let mut sim = Sim::new();
+        let neutral = sim.receiver.map.neutral;
+        assert_eq!(sim.receiver.pwm.get(), neutral);
+
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(100);
+        assert!(sim.receiver.is_linked());
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.pwm(255));
+
+        sim.controller.adc.value = 0;
+        sim.run_for(100);
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.pwm(0));
+    }
+
+    #[test]
+    fn dual_receivers() {
+        let mut sim = Sim::new();
+        sim.add_receiver();
+        let rest = sim.controller.throttle_rest;
+        sim.controller.trims[1] = Trim {
+            invert: true,
+            scale: 50,
+        };
+        sim.controller.adc.value = rest + 64 * 40;
+        sim.run_for(100);
+
+        // Receiver 1 turns the other way, at half the speed
+        let second = sim.second_receiver.as_ref().unwrap();
+        assert!(sim.receiver.is_linked() && second.is_linked());
+        let throttle = u32::from(rest) / 64;
+        assert_eq!(
+            sim.receiver.pwm.get(),
+            sim.receiver.map.pwm(throttle as u8 + 40)
+        );
+        assert_eq!(second.pwm.get(), second.map.pwm(throttle as u8 - 20));
+
+        // Both stay on 1M, even if they'd agree to 2M
+        sim.controller.phy = Phy::Ble2M;
+        sim.receiver.phy = Phy::Ble2M;
+        sim.run_for(1000);
+        assert_eq!(sim.controller.radio_mode().phy, Phy::Ble1M);
+        assert!(sim.second_receiver.as_ref().unwrap().is_linked());
+
+        // A receiver the controller doesn't drive stays at neutral
+        let mut sim = Sim::new();
+        sim.receiver.id = 1;
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(100);
+        assert!(!sim.receiver.is_linked());
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+    }
+
+    #[test]
+    fn paired_controllers() {
+        let mut sim = Sim::new();
+        sim.add_controller();
+        let rest = sim.controller.throttle_rest;
+        sim.controller.power_timeouts = Timeouts {
+            dim: Duration::from_secs(1),
+            idle: Duration::from_secs(3),
+            off: Duration::from_secs(60),
+        };
+        sim.controller.adc.value = 0x3FFF;
+        sim.second_controller.as_mut().unwrap().adc.value = rest;
+        sim.run_for(500);
+        assert_eq!(sim.receiver.owner(), Some(0));
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.pwm(255));
+
+        // Controller 1 can't take over while controller 0 drives, nor once the vehicle stands
+        // still, since controller 0 has priority
+        let half = rest + 64 * 40;
+        sim.second_controller.as_mut().unwrap().adc.value = half;
+        sim.run_for(1000);
+        assert_eq!(sim.receiver.owner(), Some(0));
+        sim.controller.adc.value = rest;
+        sim.second_controller.as_mut().unwrap().adc.value = rest;
+        sim.run_for(2500);
+        assert_eq!(sim.controller.power.state(), PowerState::Dimmed);
+        assert_eq!(sim.receiver.owner(), Some(0));
+
+        // It can once controller 0 goes idle and releases the receiver
+        sim.run_for(1000);
+        assert_eq!(sim.controller.power.state(), PowerState::Idle);
+        assert_eq!(sim.receiver.owner(), Some(1));
+        sim.second_controller.as_mut().unwrap().adc.value = half;
+        sim.run_for(200);
+        let throttle = (u32::from(rest) / 64) as u8 + 40;
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.pwm(throttle));
+
+        // Controller 0 waking up doesn't take over while the vehicle moves
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(200);
+        sim.controller.adc.value = rest;
+        sim.run_for(500);
+        assert_eq!(sim.receiver.owner(), Some(1));
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.pwm(throttle));
+
+        // But it takes back the receiver once it stands still
+        sim.second_controller.as_mut().unwrap().adc.value = rest;
+        sim.run_for(2300);
+        assert_eq!(sim.receiver.owner(), Some(0));
+        assert!(sim.receiver.is_linked());
+
+        // Unpaired controllers are ignored
+        let mut sim = Sim::new();
+        sim.controller.id = 1;
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(100);
+        assert!(!sim.receiver.is_linked());
+        assert_eq!(sim.receiver.owner(), None);
+    }
+
+    /// Rides at `riding` and engages cruise with two flicks of the thumb.
+    fn engage_cruise(sim: &mut Sim, riding: u16) {
+        let rest = sim.controller.throttle_rest;
+        for &adc in &[riding, rest, riding, rest, riding] {
+            sim.controller.adc.value = adc;
+            sim.run_for(100);
+        }
+        assert_eq!(
+            sim.controller.cruise_status(sim.clock.now()),
+            Status::Engaged
+        );
+    }
+
+    #[test]
+    fn cruise_control() {
+        let mut sim = Sim::new();
+        sim.controller.cruise_enable = true;
+        let rest = sim.controller.throttle_rest;
+        let riding = rest + 64 * 60;
+        let cruising = sim.receiver.map.pwm((riding / 64) as u8);
+
+        // The output stays at the held throttle with the thumb resting
+        engage_cruise(&mut sim, riding);
+        sim.controller.adc.value = rest;
+        sim.run_for(2000);
+        assert_eq!(sim.receiver.pwm.get(), cruising);
+        assert!(sim.controller.display.to_string().contains('#'));
+
+        // Braking disengages it
+        sim.controller.adc.value = 0;
+        sim.run_for(100);
+        assert_eq!(
+            sim.controller.cruise_status(sim.clock.now()),
+            Status::Disengaged
+        );
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.pwm(0));
+        sim.controller.adc.value = rest;
+        sim.run_for(2000);
+        assert_eq!(sim.controller.cruise_status(sim.clock.now()), Status::Off);
+
+        // So does a beacon lost on the way, well before the failsafe would kick in
+        engage_cruise(&mut sim, riding);
+        sim.controller.adc.value = rest;
+        sim.run_for(1000);
+        sim.air.set_conditions(Conditions {
+            loss: 1.0,
+            ..Conditions::default()
+        });
+        sim.run_for(80);
+        sim.air.set_conditions(Conditions::default());
+        sim.run_for(100);
+        assert!(sim.receiver.is_linked());
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+        assert_eq!(
+            sim.controller.cruise_status(sim.clock.now()),
+            Status::Disengaged
+        );
+        sim.run_for(1000);
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+
+        // The gesture does nothing unless cruise is enabled
+        sim.controller.cruise_enable = false;
+        for &adc in &[riding, rest, riding, rest, riding] {
+            sim.controller.adc.value = adc;
+            sim.run_for(100);
+        }
+        sim.controller.adc.value = rest;
+        sim.run_for(100);
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+    }
+
+    #[test]
+    fn speed_control() {
+        // Halfway between rest and the limit
+        let half = 185 * 64;
+        let target = 2500.0;
+        let within = |rpm: f32, share: f32| (rpm - target).abs() < target * share;
+
+        // Open loop, the motor slows down uphill
+        let mut sim = Sim::new();
+        sim.controller.adc.value = half;
+        sim.run_for(10_000);
+        let flat = sim.motor.rpm;
+        sim.motor.slope = 500.0;
+        sim.run_for(10_000);
+        assert!(sim.motor.rpm < flat * 0.6, "{} rpm uphill", sim.motor.rpm);
+
+        // In speed mode, it holds the target speed on the flat and uphill
+        let mut sim = Sim::new();
+        sim.receiver.speed_mode = true;
+        sim.controller.adc.value = half;
+        sim.run_for(5000);
+        assert!(within(sim.motor.rpm, 0.02), "{} rpm", sim.motor.rpm);
+        sim.motor.slope = 500.0;
+        sim.run_for(5000);
+        assert!(within(sim.motor.rpm, 0.02), "{} rpm uphill", sim.motor.rpm);
+
+        // On a slope too steep for the motor, the loop doesn't wind up, so the speed doesn't
+        // overshoot much once the road flattens
+        sim.motor.slope = 2500.0;
+        sim.run_for(3000);
+        assert!(sim.motor.rpm < target * 0.8, "{} rpm", sim.motor.rpm);
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.limit);
+        sim.motor.slope = 0.0;
+        let mut peak: f32 = 0.0;
+        for _ in 0..50 {
+            sim.run_for(100);
+            peak = peak.max(sim.motor.rpm);
+        }
+        assert!(peak < target * 1.1, "{} rpm peak", peak);
+        assert!(within(sim.motor.rpm, 0.02), "{} rpm", sim.motor.rpm);
+
+        // Resting the thumb goes back to neutral right away
+        sim.controller.adc.value = sim.controller.throttle_rest;
+        sim.run_for(100);
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+    }
+
+    #[test]
+    fn beacon_rate_follows_throttle() {
+        let mut sim = Sim::new();
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(1000);
+
+        // Each beacon counts once, whichever of its copies arrived. Once the throttle is steady,
+        // beacons back off to the minimum rate.
+        let received = sim.receiver.frames_received;
+        sim.run_for(1000);
+        assert_eq!(sim.receiver.frames_received - received, 20);
+
+        let received = sim.receiver.frames_received;
+        for i in 0..100 {
+            sim.controller.adc.value = i * 160;
+            sim.run_for(10);
+        }
+        assert_eq!(sim.receiver.frames_received - received, 50);
+    }
+
+    #[test]
+    fn failsafe_when_controller_turns_off() {
+        let mut sim = Sim::new();
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(100);
+
+        sim.controller.on = false;
+        let off = sim.clock.now();
+        sim.run_for(300);
+        assert!(!sim.receiver.is_linked());
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+
+        // The last beacon advertised the minimum rate, 50 ms, and the failsafe allows 4 of those
+        let (neutral, _) = *sim.receiver.pwm.history().last().unwrap();
+        let delay = neutral.duration_since(off).as_micros();
+        assert!(delay <= 200_000 + STEP_MICROS, "{} µs", delay);
+    }
+
+    #[test]
+    fn controller_powers_down() {
+        let mut sim = Sim::new();
+        let controller = &mut sim.controller;
+        controller.adc.value = controller.throttle_rest;
+        controller.power_timeouts = Timeouts {
+            dim: Duration::from_secs(1),
+            idle: Duration::from_secs(2),
+            off: Duration::from_secs(4),
+        };
+
+        sim.run_for(500);
+        let active = sim.controller.display.to_string();
+        sim.run_for(1000);
+        assert_eq!(sim.controller.power.state(), PowerState::Dimmed);
+        let dimmed = sim.controller.display.to_string();
+        assert!(dimmed.contains('#'));
+        assert!(dimmed.matches('#').count() < active.matches('#').count());
+
+        // Beacons slow down, and the display goes dark
+        sim.run_for(1000);
+        assert_eq!(sim.controller.power.state(), PowerState::Idle);
+        let received = sim.receiver.frames_received;
+        sim.run_for(1000);
+        assert_eq!(sim.receiver.frames_received - received, 10);
+        assert!(!sim.controller.display.to_string().contains('#'));
+
+        // Thumb movement wakes it up
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(200);
+        assert_eq!(sim.controller.power.state(), PowerState::Active);
+        assert!(sim.controller.display.to_string().contains('#'));
+
+        sim.controller.adc.value = sim.controller.throttle_rest;
+        sim.run_for(5000);
+        assert!(!sim.controller.on);
+        assert_eq!(sim.receiver.pwm.get(), sim.receiver.map.neutral);
+    }
+
+    #[test]
+    fn display_shows_throttle() {
+        let mut sim = Sim::new();
+
+        sim.controller.adc.value = 0;
+        sim.run_for(20);
+        let idle = sim.controller.display.to_string();
+
+        sim.controller.adc.value = 0x3FFF;
+        sim.run_for(20);
+        let full = sim.controller.display.to_string();
+
+        assert_eq!(display::throttle_percent(0x3FFF), 99);
+        assert!(full.contains('#'));
+        assert_ne!(idle, full);
+    }
+}
@@ -0,0 +1,60 @@
+//! The ESC, the motor and the vehicle the receiver drives.
+//!
+//! The ESC turns the servo pulse into a motor current, like a VESC set up for current control: from
+//! no current at the neutral pulse width up to `max_current` at the highest one, and braking with
+//! up to `max_current` below neutral. The current accelerates the motor, drag and rolling
+//! resistance slow it down in proportion to its speed, and a slope slows it down by a fixed rate.
+//! Brakes don't make it turn backwards.
+
+use bluefly_common::output::OutputMap;
+
+/// Interval the ESC reports the motor speed at, in microseconds.
+pub const REPORT_MICROS: u32 = 20_000;
+
+/// The motor and the vehicle, as seen from the ESC.
+#[derive(Clone, Debug)]
+pub struct Motor {
+    /// Motor speed in RPM.
+    pub rpm: f32,
+    /// Motor current at the highest and the lowest pulse width, in A.
+    pub max_current: f32,
+    /// Motor speed gained per second and A of motor current, in RPM.
+    pub accel_per_amp: f32,
+    /// Share of the motor speed lost per second to drag and rolling resistance.
+    pub drag: f32,
+    /// Motor speed lost per second to riding uphill, in RPM.
+    pub slope: f32,
+}
+
+impl Default for Motor {
+    fn default() -> Self {
+        Self {
+            rpm: 0.0,
+            max_current: 40.0,
+            accel_per_amp: 50.0,
+            drag: 0.3,
+            slope: 0.0,
+        }
+    }
+}
+
+impl Motor {
+    /// Returns the motor current the ESC drives for the PWM compare value `pwm`, in A.
+    pub fn current(&self, pwm: u16, map: &OutputMap) -> f32 {
+        let highest = map.limit.min(map.top);
+        let share = if pwm > map.neutral && highest > map.neutral {
+            f32::from(pwm - map.neutral) / f32::from(highest - map.neutral)
+        } else if pwm < map.neutral && map.base < map.neutral {
+            -f32::from(map.neutral - pwm) / f32::from(map.neutral - map.base)
+        } else {
+            0.0
+        };
+        share.clamp(-1.0, 1.0) * self.max_current
+    }
+
+    /// Runs the motor for `secs` seconds with the PWM compare value `pwm`.
+    pub fn update(&mut self, pwm: u16, map: &OutputMap, secs: f32) {
+        let accel = self.current(pwm, map) * self.accel_per_amp - self.drag * self.rpm - self.slope;
+        self.rpm = (self.rpm + accel * secs).max(0.0);
+    }
+}
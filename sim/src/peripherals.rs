@@ -0,0 +1,118 @@
+//! Simulated ADC, PWM and display.
+
+use {
+    bluefly_common::display::{HEIGHT, WIDTH},
+    embedded_graphics::{drawable::Pixel, pixelcolor::PixelColorU8, Drawing},
+    embedded_hal::adc::{Channel, OneShot},
+    rubble::time::Instant,
+    std::fmt,
+};
+
+/// The controller's SAADC, returning whatever 14-bit value the test sets.
+#[derive(Default)]
+pub struct Adc {
+    pub value: u16,
+}
+
+/// The analog input the throttle is connected to.
+pub struct ThrottlePin;
+
+impl Channel<Adc> for ThrottlePin {
+    type ID = u8;
+
+    fn channel() -> u8 {
+        0
+    }
+}
+
+impl OneShot<Adc, u16, ThrottlePin> for Adc {
+    type Error = ();
+
+    fn read(&mut self, _pin: &mut ThrottlePin) -> nb::Result<u16, ()> {
+        Ok(self.value)
+    }
+}
+
+/// The receiver's PWM output, recording every change.
+pub struct Pwm {
+    value: u16,
+    history: Vec<(Instant, u16)>,
+}
+
+impl Pwm {
+    pub fn new(initial: u16) -> Self {
+        Self {
+            value: initial,
+            history: Vec::new(),
+        }
+    }
+
+    /// Changes the compare value at `now`.
+    pub fn set(&mut self, value: u16, now: Instant) {
+        if value != self.value {
+            self.history.push((now, value));
+        }
+        self.value = value;
+    }
+
+    /// Returns the current compare value.
+    pub fn get(&self) -> u16 {
+        self.value
+    }
+
+    /// Returns every change of the compare value, with the time it happened.
+    pub fn history(&self) -> &[(Instant, u16)] {
+        &self.history
+    }
+}
+
+/// The controller's display, as a frame buffer.
+pub struct Display {
+    pixels: Vec<bool>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self {
+            pixels: vec![false; (WIDTH * HEIGHT) as usize],
+        }
+    }
+}
+
+impl Display {
+    /// Turns every pixel off.
+    pub fn clear(&mut self) {
+        self.pixels.iter_mut().for_each(|pixel| *pixel = false);
+    }
+
+    /// Whether the pixel at `x`, `y` is lit.
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        x < WIDTH && y < HEIGHT && self.pixels[(y * WIDTH + x) as usize]
+    }
+}
+
+impl Drawing<PixelColorU8> for Display {
+    fn draw<T>(&mut self, item_pixels: T)
+    where
+        T: Iterator<Item = Pixel<PixelColorU8>>,
+    {
+        for Pixel(coord, color) in item_pixels {
+            if coord.0 < WIDTH && coord.1 < HEIGHT {
+                self.pixels[(coord.1 * WIDTH + coord.0) as usize] = color.0 != 0;
+            }
+        }
+    }
+}
+
+/// Renders the frame buffer as text, one character per pixel.
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                f.write_str(if self.pixel(x, y) { "#" } else { "." })?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
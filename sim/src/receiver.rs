@@ -0,0 +1,359 @@
+//! The simulated receiver.
+
+use {
+    crate::{
+        air::{Packet, VirtualRadio},
+        peripherals::Pwm,
+        Air, Clock,
+    },
+    bluefly_common::{
+        cruise::{self, Guard},
+        hopping::{self, ChannelMap, Follower},
+        link_stats::{LinkStats, Summary},
+        mode::{self, Answering, Phy, RadioMode},
+        output::{self, Failsafe, OutputMap},
+        ownership::{self, Ownership, PairedFilter},
+        protocol, receivers,
+        redundancy::{CopyStats, Redundancy},
+        speed::{Gains, SpeedControl},
+    },
+    rubble::{
+        beacon::{BeaconScanner, ScanCallback},
+        link::{ad_structure::AdStructure, DeviceAddress, RadioCmd},
+        phy::AdvertisingChannel,
+        time::{Duration, Instant},
+    },
+    std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    },
+};
+
+/// A received payload, the number of the controller that sent it, and its sequence number.
+type Frame = (u8, Option<u8>, Vec<u8>);
+
+/// Collects the payloads of received beacons, like the firmware's `ThrottleCallback`.
+struct Callback {
+    frames: Rc<RefCell<Vec<Frame>>>,
+}
+
+impl ScanCallback for Callback {
+    fn beacon<'a, I>(&mut self, adv_addr: DeviceAddress, adv_data: I)
+    where
+        I: Iterator<Item = AdStructure<'a>>,
+    {
+        let id = match protocol::controller_id(*adv_addr.raw()) {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some((seq, data)) = protocol::sequenced_payload(adv_data) {
+            self.frames.borrow_mut().push((id, seq, data.to_vec()));
+        }
+    }
+}
+
+/// Runs what the receiver's `RADIO` interrupt and `idle` do with throttle beacons: driving the
+/// PWM output, bringing it back to neutral when the link is lost or degrades while cruising, and
+/// answering the controller's requests for a PHY and channel map. Of the paired controllers, it
+/// follows the one that owns it.
+pub struct Receiver {
+    radio: VirtualRadio,
+    scanner: BeaconScanner<Callback, PairedFilter<Box<dyn Fn() -> u32>>>,
+    frames: Rc<RefCell<Vec<Frame>>>,
+    /// The paired controllers as a bit mask (`pair.controllers`).
+    paired: Rc<Cell<u32>>,
+    ownership: Ownership,
+    redundancy: Redundancy,
+    follower: Follower,
+    link_stats: LinkStats,
+    answering: Answering,
+    cruise_guard: Guard,
+    speed: SpeedControl,
+    failsafe: Failsafe,
+    /// Whether beacons hop over the data channels (`link.hopping`).
+    pub hopping: bool,
+    /// Number of the receiver among those driven by the controller (`link.id`).
+    pub id: u8,
+    /// Fastest PHY the receiver agrees to (`radio.phy`).
+    pub phy: Phy,
+    /// TX power in dBm (`radio.tx_level`).
+    pub tx_power: i8,
+    pub pwm: Pwm,
+    /// The PWM configuration (`pwm.*`).
+    pub map: OutputMap,
+    /// Whether throttle values set a target speed, which a PID loop holds against the motor speed
+    /// reported by the ESC. Unlike the other settings, this has no configuration entry in the
+    /// firmware, which can't read the motor speed (see `bluefly_common::speed`).
+    pub speed_mode: bool,
+    /// Target speed at the highest pulse width in speed mode, in RPM.
+    pub max_rpm: u32,
+    /// Gains of the speed loop.
+    pub gains: Gains,
+    /// Minimum time without a throttle value until the failsafe kicks in (`failsafe.timeout`).
+    pub failsafe_timeout: Duration,
+    /// Number of advertised beacon intervals without a throttle value until the failsafe kicks in
+    /// (`failsafe.beacons`).
+    pub failsafe_beacons: u32,
+    /// Maximum time without a throttle value until the failsafe kicks in (`failsafe.max_ms`).
+    pub failsafe_max: Duration,
+    /// Number of throttle values received, counting each frame once.
+    pub frames_received: u32,
+    /// When the last throttle value was received.
+    pub last_frame: Option<Instant>,
+}
+
+impl Receiver {
+    pub fn new(air: Air, clock: Clock) -> Self {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let paired = Rc::new(Cell::new(1));
+        let filter = {
+            let paired = paired.clone();
+            PairedFilter::new(Box::new(move || paired.get()) as Box<dyn Fn() -> u32>)
+        };
+
+        let mut radio = VirtualRadio::new(air, clock);
+        radio.configure_receiver(RadioCmd::ListenAdvertising {
+            channel: AdvertisingChannel::first(),
+        });
+
+        Self {
+            radio,
+            scanner: BeaconScanner::with_filter(
+                Callback {
+                    frames: frames.clone(),
+                },
+                filter,
+            ),
+            frames,
+            paired,
+            ownership: Ownership::new(),
+            redundancy: Redundancy::new(Instant::from_raw_micros(0)),
+            follower: Follower::new(protocol::CONTROLLER_ADDRESS, Instant::from_raw_micros(0)),
+            link_stats: LinkStats::new(),
+            answering: Answering::new(),
+            cruise_guard: Guard::new(),
+            speed: SpeedControl::new(),
+            failsafe: Failsafe::new(),
+            hopping: false,
+            id: receivers::PRIMARY,
+            phy: Phy::Ble1M,
+            tx_power: RadioMode::DEFAULT.tx_power,
+            pwm: Pwm::new(OutputMap::DEFAULT.neutral),
+            map: OutputMap::DEFAULT,
+            speed_mode: false,
+            max_rpm: 5000,
+            gains: Gains::DEFAULT,
+            failsafe_timeout: Duration::from_millis(output::DEFAULT_FAILSAFE_MS),
+            failsafe_beacons: output::DEFAULT_FAILSAFE_BEACONS,
+            failsafe_max: Duration::from_millis(output::DEFAULT_FAILSAFE_MAX_MS),
+            frames_received: 0,
+            last_frame: None,
+        }
+    }
+
+    /// Handles a packet arriving at `now`, if the radio is listening on its channel.
+    pub fn receive(&mut self, packet: &Packet, now: Instant) {
+        if !self.radio.hears(packet) {
+            return;
+        }
+        let payload = match packet.payload() {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        let cmd = self
+            .scanner
+            .process_adv_packet(packet.header(), payload, packet.crc_ok);
+        self.radio.configure_receiver(cmd.radio);
+        if !packet.crc_ok {
+            self.link_stats.crc_error();
+        }
+
+        let frames = self.frames.borrow_mut().drain(..).collect::<Vec<_>>();
+        let heard = !frames.is_empty();
+        for (id, seq, frame) in frames {
+            // Only follow the controller that owns the receiver, and its hops
+            let stopped = ownership::is_stopped(self.pwm.get(), self.map.neutral);
+            let owner = self.ownership.owner();
+            if !self
+                .ownership
+                .frame(id, protocol::is_released(&frame), stopped, now)
+            {
+                continue;
+            }
+            // Until there's an owner, the follower follows controller 0
+            if owner.unwrap_or(0) != id {
+                self.follower = Follower::new(protocol::controller_address(id), now);
+            }
+
+            let interval = protocol::beacon_interval(&frame);
+            if let (true, Some(seq)) = (self.hopping, seq) {
+                self.follower.copy(seq, interval, now);
+            }
+            let first = self.redundancy.copy(seq, interval, now);
+            if !first {
+                continue;
+            }
+            self.link_stats.frame(seq, Some(packet.rssi), interval, now);
+            if self.id == receivers::PRIMARY {
+                let requested = protocol::requested_phy(&frame);
+                self.answering
+                    .request(requested, self.phy, packet.rssi, interval, now);
+            }
+            if !self
+                .cruise_guard
+                .frame(seq, protocol::is_cruise(&frame), now)
+            {
+                self.pwm.set(self.map.neutral, now);
+                self.speed.stop();
+                continue;
+            }
+
+            if let Some(val) = receivers::throttle(&frame, self.id) {
+                let pwm = if self.speed_mode {
+                    self.speed.throttle(val, self.max_rpm, &self.map, now)
+                } else {
+                    Some(self.map.pwm(val))
+                };
+                if let Some(pwm) = pwm {
+                    self.pwm.set(pwm, now);
+                }
+                let timeout = output::failsafe_timeout(
+                    interval,
+                    self.failsafe_beacons,
+                    self.failsafe_timeout,
+                    self.failsafe_max,
+                );
+                self.failsafe.frame(now, timeout);
+                self.frames_received += 1;
+                self.last_frame = Some(now);
+            }
+        }
+        // Catch the next copy. The scanner leaves the radio on an advertising channel, so go back
+        // to the hop channel after anything else it received as well.
+        if heard || self.hopping {
+            self.listen();
+        }
+    }
+
+    /// Runs the receiver's `idle` loop once.
+    pub fn idle(&mut self, now: Instant) {
+        if self.hopping {
+            if let Some(channel) = self.follower.update(now) {
+                self.radio.listen_hopping(channel);
+            }
+        } else if let Some(channel) = self.redundancy.update(now) {
+            self.radio
+                .configure_receiver(RadioCmd::ListenAdvertising { channel });
+        }
+        let lost = self.failsafe.check(now).is_some();
+        if lost {
+            self.pwm.set(self.map.neutral, now);
+            self.speed.stop();
+        }
+        if self.speed.check(now) {
+            self.pwm.set(self.map.neutral, now);
+        }
+
+        self.answering.update(now);
+        let answer = self.answering.take_answer(now);
+        let notice = self.cruise_guard.take_notice(now);
+        let map_answer = if self.id == receivers::PRIMARY {
+            self.follower.take_answer(now)
+        } else {
+            None
+        };
+        if lost {
+            self.answering.link_lost();
+            self.follower.link_lost();
+            self.ownership.link_lost();
+            self.cruise_guard.link_lost();
+        }
+        let mode = RadioMode {
+            phy: self.answering.phy(),
+            tx_power: self.tx_power,
+        };
+        if let Some(phy) = answer {
+            self.radio.configure_receiver(RadioCmd::Off);
+            protocol::broadcast(
+                &mut self.radio,
+                protocol::RECEIVER_ADDRESS,
+                &mode::answer_frame(phy),
+            );
+        }
+        if let Some(map) = map_answer {
+            self.radio.configure_receiver(RadioCmd::Off);
+            protocol::broadcast(
+                &mut self.radio,
+                protocol::RECEIVER_ADDRESS,
+                &hopping::map_answer_frame(map),
+            );
+        }
+        if notice {
+            self.radio.configure_receiver(RadioCmd::Off);
+            protocol::broadcast(
+                &mut self.radio,
+                protocol::RECEIVER_ADDRESS,
+                &cruise::cancel_frame(),
+            );
+        }
+        let answered = answer.is_some() || map_answer.is_some() || notice;
+        if answered || lost || self.radio.mode() != mode {
+            self.radio.set_mode(mode);
+            self.listen();
+        }
+    }
+
+    /// Listens for the next throttle beacon, like the firmware's `listen`.
+    fn listen(&mut self) {
+        if self.hopping {
+            self.radio.listen_hopping(self.follower.channel());
+        } else {
+            self.radio.configure_receiver(RadioCmd::ListenAdvertising {
+                channel: self.redundancy.channel(),
+            });
+        }
+    }
+
+    /// Handles the motor speed `rpm` reported by the ESC at `now`, running the speed loop.
+    pub fn motor_rpm(&mut self, rpm: i32, now: Instant) {
+        if let Some(pwm) = self.speed.update(rpm, now, self.gains, &self.map) {
+            self.pwm.set(pwm, now);
+        }
+    }
+
+    /// Pairs the receiver to the controllers set in the bit mask `paired` (`pair.controllers`).
+    pub fn pair(&mut self, paired: u32) {
+        self.paired.set(paired);
+    }
+
+    /// Returns the number of the controller driving the receiver, if any.
+    pub fn owner(&self) -> Option<u8> {
+        self.ownership.owner()
+    }
+
+    /// Returns the PHY and TX power the radio currently uses.
+    pub fn radio_mode(&self) -> RadioMode {
+        self.radio.mode()
+    }
+
+    /// Returns the channels beacons hop over.
+    pub fn channel_map(&self) -> ChannelMap {
+        self.follower.map()
+    }
+
+    /// Returns the number of copies that arrived of the frames received so far.
+    pub fn copy_stats(&self) -> CopyStats {
+        self.redundancy.stats()
+    }
+
+    /// Summarizes the quality of the link over the last frames received.
+    pub fn link_summary(&self) -> Summary {
+        self.link_stats.summary()
+    }
+
+    /// Whether a throttle value was received within the failsafe timeout.
+    pub fn is_linked(&self) -> bool {
+        self.failsafe.is_linked()
+    }
+}
@@ -0,0 +1,535 @@
+//! Driving the link through bad radio conditions while checking the receiver's safety
+//! properties.
+
+use {
+    crate::{Conditions, Sim},
+    rubble::time::{Duration, Instant, Timer},
+};
+
+/// Time after the last received throttle value by which the output must be back at neutral, in
+/// milliseconds, unless a scenario tightens it.
+///
+/// This is fixed rather than derived from the failsafe configuration, so a failsafe that gets
+/// slower breaks the invariant instead of moving it.
+pub const NEUTRAL_WITHIN_MS: u16 = 250;
+
+/// Properties the receiver's output must have at every step of a scenario.
+#[derive(Copy, Clone, Debug)]
+pub struct Invariants {
+    /// Time after the last received throttle value by which the output must be back at neutral.
+    pub neutral_within: Duration,
+    /// Highest compare value the output may be driven to, other than neutral.
+    pub limit: u16,
+}
+
+impl Invariants {
+    /// The invariants the devices of `sim` have to uphold.
+    ///
+    /// The output has to be at neutral within `NEUTRAL_WITHIN_MS` of the last throttle value, and
+    /// must never exceed the limit of the receiver's PWM configuration.
+    pub fn of(sim: &Sim) -> Self {
+        Self {
+            neutral_within: Duration::from_millis(NEUTRAL_WITHIN_MS),
+            limit: sim.receiver.map.limit,
+        }
+    }
+}
+
+/// A broken invariant. Times are in microseconds of simulation time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Violation {
+    /// The output was still at `value`, `since` after the last throttle value.
+    NotNeutral { at: u32, since: u32, value: u16 },
+    /// The output was at `value`, above the limit.
+    OverLimit { at: u32, value: u16 },
+}
+
+/// A controller and a receiver running over a link with some `Conditions`.
+pub struct Scenario {
+    pub sim: Sim,
+    pub invariants: Invariants,
+    /// Returns the controller's ADC value for a time in milliseconds.
+    throttle: Box<dyn FnMut(u32) -> u16>,
+    violations: Vec<Violation>,
+}
+
+impl Scenario {
+    pub fn new(conditions: Conditions) -> Self {
+        Self::with_sim(Sim::new(), conditions)
+    }
+
+    /// Creates a scenario whose random conditions are driven by `seed`.
+    pub fn with_seed(conditions: Conditions, seed: u64) -> Self {
+        Self::with_sim(Sim::with_seed(seed), conditions)
+    }
+
+    fn with_sim(sim: Sim, conditions: Conditions) -> Self {
+        sim.air.set_conditions(conditions);
+        Self {
+            invariants: Invariants::of(&sim),
+            sim,
+            throttle: Box::new(|_| 0x3FFF),
+            violations: Vec::new(),
+        }
+    }
+
+    /// Makes the controller's throttle follow `throttle`, which returns the ADC value for a time
+    /// in milliseconds. By default, the throttle is fully open.
+    pub fn throttle<F>(mut self, throttle: F) -> Self
+    where
+        F: FnMut(u32) -> u16 + 'static,
+    {
+        self.throttle = Box::new(throttle);
+        self
+    }
+
+    /// Runs the scenario for `millis` milliseconds, checking the invariants after every step.
+    pub fn run_for(&mut self, millis: u32) {
+        let end = self.sim.clock.elapsed().as_micros() + millis * 1000;
+        while self.sim.clock.elapsed().as_micros() < end {
+            let now = self.sim.clock.now();
+            self.sim.controller.adc.value = (self.throttle)(now.raw_micros() / 1000);
+            self.sim.step();
+            self.check(now);
+        }
+    }
+
+    fn check(&mut self, now: Instant) {
+        let receiver = &self.sim.receiver;
+        let value = receiver.pwm.get();
+        if value == receiver.map.neutral {
+            return;
+        }
+
+        if value > self.invariants.limit {
+            self.violations.push(Violation::OverLimit {
+                at: now.raw_micros(),
+                value,
+            });
+        }
+        if let Some(last) = receiver.last_frame {
+            let since = now.duration_since(last);
+            if since.as_micros() > self.invariants.neutral_within.as_micros() {
+                self.violations.push(Violation::NotNeutral {
+                    at: now.raw_micros(),
+                    since: since.as_micros(),
+                    value,
+                });
+            }
+        }
+    }
+
+    /// Returns every invariant broken so far.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Panics if an invariant was broken.
+    pub fn assert_safe(&self) {
+        assert!(
+            self.violations.is_empty(),
+            "{} violations, starting with {:?}",
+            self.violations.len(),
+            &self.violations[..self.violations.len().min(5)]
+        );
+    }
+
+    /// Whether the output went back to neutral after having been driven.
+    pub fn failsafe_triggered(&self) -> bool {
+        let neutral = self.sim.receiver.map.neutral;
+        let history = self.sim.receiver.pwm.history();
+        history
+            .windows(2)
+            .any(|w| w[0].1 != neutral && w[1].1 == neutral)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        bluefly_common::{
+            hopping::{self, ChannelMap},
+            link_stats,
+            mode::{self, Phy},
+        },
+    };
+
+    /// Sweeps the throttle from closed to open and back every second.
+    fn sweep(ms: u32) -> u16 {
+        let phase = ms % 1000;
+        let ramp = if phase < 500 { phase } else { 1000 - phase };
+        (u32::from(0x3FFFu16) * ramp / 500) as u16
+    }
+
+    #[test]
+    fn clean_link() {
+        let mut scenario = Scenario::new(Conditions::default()).throttle(sweep);
+        scenario.run_for(2000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+        assert_eq!(scenario.sim.air.stats().lost, 0);
+    }
+
+    #[test]
+    fn random_loss() {
+        for seed in 1..5 {
+            let mut scenario = Scenario::with_seed(
+                Conditions {
+                    loss: 0.5,
+                    ..Conditions::default()
+                },
+                seed,
+            )
+            .throttle(sweep);
+            scenario.run_for(3000);
+            scenario.assert_safe();
+            assert!(scenario.sim.air.stats().lost > 0);
+            assert!(scenario.sim.receiver.frames_received > 0);
+        }
+    }
+
+    #[test]
+    fn copies_on_every_channel() {
+        let mut scenario = Scenario::new(Conditions::default()).throttle(sweep);
+        scenario.run_for(1000);
+        let stats = scenario.sim.receiver.copy_stats();
+        assert_eq!(stats.frames(), scenario.sim.receiver.frames_received);
+        assert_eq!(stats.copies[2], stats.frames());
+    }
+
+    #[test]
+    fn jammed_channel() {
+        // The receiver starts out listening on the jammed channel
+        let mut scenario = Scenario::new(Conditions {
+            channel_loss: [1.0, 0.0, 0.0],
+            ..Conditions::default()
+        })
+        .throttle(sweep);
+        scenario.run_for(3000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+
+        let stats = scenario.sim.receiver.copy_stats();
+        assert_eq!(stats.copies[2], 0);
+        assert_eq!(stats.copies[1], stats.frames());
+    }
+
+    #[test]
+    fn noisy_channel() {
+        let mut scenario = Scenario::new(Conditions {
+            channel_loss: [0.5, 0.0, 0.0],
+            ..Conditions::default()
+        })
+        .throttle(sweep);
+        scenario.run_for(3000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+
+        // The receiver moves on to a clean channel after missing a frame, and stays there
+        let stats = scenario.sim.receiver.copy_stats();
+        let sent = scenario.sim.air.stats().sent / 3;
+        assert!(stats.frames() + 3 >= sent);
+        assert!(stats.copies[1] > stats.copies[2]);
+    }
+
+    #[test]
+    fn link_quality() {
+        let mut scenario = Scenario::new(Conditions::default()).throttle(sweep);
+        scenario.run_for(1000);
+        let summary = scenario.sim.receiver.link_summary();
+        assert_eq!(summary.received, link_stats::WINDOW as u32);
+        assert_eq!(summary.missed, 0);
+        assert_eq!(summary.jitter, 0);
+        assert_eq!(summary.crc_errors, 0);
+        assert_eq!(summary.rssi, -50);
+
+        scenario.sim.air.set_conditions(Conditions {
+            loss: 0.2,
+            corruption: 0.1,
+            jitter: Duration::from_millis(4),
+            rssi: -85,
+            ..Conditions::default()
+        });
+        scenario.run_for(3000);
+        scenario.assert_safe();
+        let summary = scenario.sim.receiver.link_summary();
+        assert!(summary.missed > 0);
+        assert!(summary.crc_errors > 0);
+        assert!(summary.jitter > 0 && summary.jitter < 4000);
+        assert_eq!((summary.rssi, summary.rssi_min), (-85, -85));
+    }
+
+    /// Runs a scenario with both devices configured for 2M.
+    fn fast(conditions: Conditions) -> Scenario {
+        let mut scenario = Scenario::new(conditions).throttle(sweep);
+        scenario.sim.controller.phy = Phy::Ble2M;
+        scenario.sim.receiver.phy = Phy::Ble2M;
+        scenario
+    }
+
+    fn phys(scenario: &Scenario) -> (Phy, Phy) {
+        let sim = &scenario.sim;
+        (
+            sim.controller.radio_mode().phy,
+            sim.receiver.radio_mode().phy,
+        )
+    }
+
+    #[test]
+    fn phy_negotiation() {
+        let mut scenario = fast(Conditions::default());
+        scenario.run_for(1000);
+        assert_eq!(phys(&scenario), (Phy::Ble2M, Phy::Ble2M));
+
+        // The receiver's answers keep the controller on 2M
+        scenario.run_for(5000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+        assert_eq!(phys(&scenario), (Phy::Ble2M, Phy::Ble2M));
+
+        // A lower TX power weakens the signal
+        scenario.sim.controller.tx_power = -20;
+        scenario.run_for(1000);
+        assert_eq!(scenario.sim.receiver.link_summary().rssi, -74);
+        assert_eq!(phys(&scenario), (Phy::Ble2M, Phy::Ble2M));
+
+        // Unless the receiver is only allowed 1M
+        let mut scenario = fast(Conditions::default());
+        scenario.sim.receiver.phy = Phy::Ble1M;
+        scenario.run_for(1000);
+        assert_eq!(phys(&scenario), (Phy::Ble1M, Phy::Ble1M));
+        assert!(scenario.sim.receiver.is_linked());
+    }
+
+    #[test]
+    fn phy_answer_lost() {
+        // The controller misses the receiver's answers agreeing to 2M
+        let mut scenario = fast(Conditions::default());
+        let answer = mode::answer_frame(Phy::Ble2M);
+        scenario
+            .sim
+            .air
+            .lose_matching(move |packet| packet.payload().is_some_and(|p| p.ends_with(&answer)));
+        scenario.run_for(1000);
+
+        // So the receiver keeps going back to 1M, where the controller still is, before its
+        // failsafe kicks in
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+        assert!(scenario.sim.air.stats().lost > 0);
+        assert_eq!(phys(&scenario).0, Phy::Ble1M);
+
+        // Once an answer gets through, both end up on 2M
+        scenario.sim.air.lose_matching(|_| false);
+        scenario.run_for(1000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+        assert_eq!(phys(&scenario), (Phy::Ble2M, Phy::Ble2M));
+    }
+
+    #[test]
+    fn phy_follows_signal() {
+        // Too weak to switch
+        let mut scenario = fast(Conditions {
+            rssi: -85,
+            ..Conditions::default()
+        });
+        scenario.run_for(1000);
+        assert_eq!(phys(&scenario), (Phy::Ble1M, Phy::Ble1M));
+
+        // The signal fades after switching, so the receiver asks for 1M again
+        let mut scenario = fast(Conditions::default());
+        scenario.run_for(1000);
+        scenario.sim.air.set_conditions(Conditions {
+            rssi: -89,
+            ..Conditions::default()
+        });
+        scenario.run_for(1000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+        assert_eq!(phys(&scenario), (Phy::Ble1M, Phy::Ble1M));
+    }
+
+    #[test]
+    fn phy_fallback() {
+        let mut scenario = fast(Conditions::default());
+        scenario.run_for(1000);
+
+        // Out of range for 2M, but not for 1M
+        scenario.sim.air.set_conditions(Conditions {
+            rssi: -93,
+            ..Conditions::default()
+        });
+        scenario.run_for(1000);
+        assert!(scenario.failsafe_triggered());
+        assert_eq!(phys(&scenario), (Phy::Ble2M, Phy::Ble1M));
+
+        // The controller falls back once the answers stop
+        scenario.run_for(3000);
+        scenario.assert_safe();
+        assert_eq!(phys(&scenario), (Phy::Ble1M, Phy::Ble1M));
+        assert!(scenario.sim.receiver.is_linked());
+    }
+
+    /// Runs a scenario with both devices hopping over the data channels.
+    fn hopping(conditions: Conditions) -> Scenario {
+        let mut scenario = Scenario::new(conditions).throttle(sweep);
+        scenario.sim.controller.hopping = true;
+        scenario.sim.receiver.hopping = true;
+        scenario
+    }
+
+    #[test]
+    fn hopping_avoids_jammed_channels() {
+        let mut data_channel_loss = [0.0; hopping::CHANNELS as usize];
+        data_channel_loss[..9].copy_from_slice(&[1.0; 9]);
+        let mut scenario = hopping(Conditions {
+            data_channel_loss,
+            ..Conditions::default()
+        });
+        scenario.run_for(5000);
+        scenario.assert_safe();
+        assert!(!scenario.failsafe_triggered());
+
+        // Both devices stopped using the jammed channels
+        let map = scenario.sim.receiver.channel_map();
+        assert_eq!(scenario.sim.controller.channel_map(), map);
+        assert!((0..9).all(|channel| !map.contains(channel)));
+        assert_eq!(map.count(), hopping::CHANNELS - 9);
+
+        // So every copy arrives from then on
+        let before = scenario.sim.receiver.copy_stats();
+        scenario.run_for(1000);
+        let after = scenario.sim.receiver.copy_stats();
+        assert_eq!(
+            after.copies[2] - before.copies[2],
+            after.frames() - before.frames()
+        );
+    }
+
+    #[test]
+    fn hopping_fallback() {
+        let mut data_channel_loss = [0.0; hopping::CHANNELS as usize];
+        data_channel_loss[..9].copy_from_slice(&[1.0; 9]);
+        let mut scenario = hopping(Conditions {
+            data_channel_loss,
+            ..Conditions::default()
+        });
+        scenario.run_for(5000);
+        assert!(scenario.sim.receiver.channel_map() != ChannelMap::ALL);
+
+        // Both go back to all channels once the link is lost
+        scenario.sim.air.set_conditions(Conditions {
+            loss: 1.0,
+            ..Conditions::default()
+        });
+        scenario.run_for(4000);
+        assert!(scenario.failsafe_triggered());
+        assert_eq!(scenario.sim.receiver.channel_map(), ChannelMap::ALL);
+        assert_eq!(scenario.sim.controller.channel_map(), ChannelMap::ALL);
+
+        // And find each other again
+        scenario.sim.air.set_conditions(Conditions::default());
+        scenario.run_for(3000);
+        scenario.assert_safe();
+        assert!(scenario.sim.receiver.is_linked());
+    }
+
+    #[test]
+    fn burst_loss() {
+        // 30 packets are 10 beacons, or 500 ms with the throttle steady
+        let mut scenario = Scenario::new(Conditions {
+            burst: 0.02,
+            burst_len: 30,
+            ..Conditions::default()
+        });
+        scenario.run_for(5000);
+        scenario.assert_safe();
+        assert!(scenario.failsafe_triggered());
+
+        // The link recovers once the interference is over
+        scenario.sim.air.set_conditions(Conditions::default());
+        scenario.run_for(100);
+        assert!(scenario.sim.receiver.is_linked());
+    }
+
+    #[test]
+    fn duplication_reordering_and_latency() {
+        let mut scenario = Scenario::new(Conditions {
+            duplication: 0.2,
+            reordering: 0.2,
+            reorder_delay: Duration::from_millis(30),
+            latency: Duration::from_millis(2),
+            jitter: Duration::from_millis(15),
+            ..Conditions::default()
+        })
+        .throttle(sweep);
+        scenario.run_for(3000);
+        scenario.assert_safe();
+
+        let stats = scenario.sim.air.stats();
+        assert!(stats.duplicated > 0);
+        assert!(stats.reordered > 0);
+        assert!(!scenario.failsafe_triggered());
+    }
+
+    #[test]
+    fn neutral_after_link_loss() {
+        let mut scenario = Scenario::new(Conditions {
+            latency: Duration::from_millis(5),
+            ..Conditions::default()
+        });
+        // 4 beacon intervals at the controller's minimum rate of 20 Hz
+        scenario.invariants.neutral_within = Duration::from_millis(200);
+        scenario.run_for(500);
+        assert_eq!(
+            scenario.sim.receiver.pwm.get(),
+            scenario.sim.receiver.map.pwm(255)
+        );
+
+        let lost = scenario.sim.clock.now();
+        scenario.sim.air.set_conditions(Conditions {
+            loss: 1.0,
+            ..scenario.sim.air.conditions()
+        });
+        scenario.run_for(500);
+        scenario.assert_safe();
+
+        // Within one beacon interval, latency and the failsafe timeout of the last packet sent
+        let (neutral, value) = *scenario.sim.receiver.pwm.history().last().unwrap();
+        assert_eq!(value, scenario.sim.receiver.map.neutral);
+        let timeout = scenario.invariants.neutral_within.as_micros();
+        assert!(neutral.duration_since(lost).as_micros() <= 50_000 + 5_000 + timeout);
+    }
+
+    #[test]
+    fn output_limited() {
+        let mut scenario = Scenario::new(Conditions::default());
+        scenario.sim.receiver.map.limit = 7300;
+        scenario.invariants.limit = 7300;
+        scenario.run_for(500);
+        scenario.assert_safe();
+        assert_eq!(scenario.sim.receiver.pwm.get(), 7300);
+    }
+
+    #[test]
+    fn violations_are_detected() {
+        let mut scenario = Scenario::new(Conditions::default());
+        // A failsafe slower than the fixed bound breaks it, rather than moving it along
+        scenario.sim.receiver.failsafe_timeout = Duration::from_millis(300);
+        scenario.invariants.limit = 7300;
+        scenario.run_for(100);
+        scenario.sim.controller.on = false;
+        scenario.run_for(500);
+
+        let violations = scenario.violations();
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::OverLimit { value: 7500, .. })));
+        assert!(violations.iter().any(|v| match v {
+            Violation::NotNeutral { since, .. } => *since > 250_000,
+            _ => false,
+        }));
+    }
+}